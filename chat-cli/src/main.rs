@@ -1,6 +1,6 @@
 #![deny(unsafe_code)]
 
-use client::{Client, Event};
+use client::{Client, Error, Event};
 use common::{clock::Clock, comp};
 use log::{error, info};
 use std::{io, net::ToSocketAddrs, sync::mpsc, thread, time::Duration};
@@ -36,15 +36,25 @@ fn main() {
     let password = read_input();
 
     // Create a client.
-    let mut client = Client::new(
+    let mut client = match Client::new(
         server_addr
             .to_socket_addrs()
             .expect("Invalid server address")
             .next()
             .unwrap(),
         None,
-    )
-    .expect("Failed to create client instance");
+    ) {
+        Ok(client) => client,
+        Err(Error::IncompatibleVersion { server, client }) => {
+            println!(
+                "Incompatible version: the server uses protocol version {}, but this client uses \
+                 version {}",
+                server, client
+            );
+            return;
+        }
+        Err(err) => panic!("Failed to create client instance: {:?}", err),
+    };
 
     println!("Server info: {:?}", client.server_info);
 
@@ -67,6 +77,10 @@ fn main() {
 
         let events = match client.tick(comp::Controller::default(), clock.get_last_delta()) {
             Ok(events) => events,
+            Err(Error::ServerShutdown { reason }) => {
+                println!("The server shut down: {}", reason);
+                break;
+            }
             Err(err) => {
                 error!("Error: {:?}", err);
                 break;
@@ -5,9 +5,13 @@ pub enum Error {
     Network(PostError),
     ServerWentMad,
     ServerTimeout,
-    ServerShutdown,
+    ServerShutdown { reason: String },
+    IncompatibleVersion { server: u32, client: u32 },
     TooManyPlayers,
     InvalidAuth,
+    InvalidToken { expired: bool },
+    Banned { reason: String, until: Option<u64> },
+    NotWhitelisted,
     //TODO: InvalidAlias,
     Other(String),
 }
@@ -0,0 +1,382 @@
+//! Smooth movement of remote entities.
+//!
+//! Physics updates arrive at the server's tick rate and with uneven delays. Instead of jumping to
+//! each update as it arrives, remote entities are shown slightly in the past, where there is
+//! usually a later update to interpolate towards. When updates are late, entities keep moving
+//! along their last velocity for a short while.
+
+use common::{
+    comp,
+    state::{State, Uid},
+};
+use hashbrown::{HashMap, HashSet};
+use specs::Join;
+use std::collections::VecDeque;
+use vek::*;
+
+/// How far in the past remote entities are shown, in seconds.
+pub const INTERPOLATION_DELAY: f64 = 0.1;
+/// How far past the newest update an entity is moved along its velocity before it stops to wait
+/// for the next one, in seconds.
+pub const MAX_EXTRAPOLATION: f64 = 0.25;
+/// Entities that move further than this between two updates were teleported, so they aren't
+/// moved through the space in between.
+const TELEPORT_DISTANCE: f32 = 32.0;
+
+/// The transform of an entity at some point in time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    pub pos: Vec3<f32>,
+    pub vel: Vec3<f32>,
+    pub ori: Vec3<f32>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Sample {
+    time: f64,
+    transform: Transform,
+}
+
+/// The recent updates of a single entity, with the times at which they arrived.
+pub struct TransformBuffer {
+    samples: VecDeque<Sample>,
+    /// What the first sample starts out with, until there is one. Velocities and orientations may
+    /// arrive before the first position.
+    initial: Transform,
+    /// Whether a velocity or orientation was ever received, since some entities only have a
+    /// position.
+    has_vel: bool,
+    has_ori: bool,
+}
+
+impl Default for TransformBuffer {
+    fn default() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            initial: Transform {
+                pos: Vec3::zero(),
+                vel: Vec3::zero(),
+                ori: Vec3::unit_y(),
+            },
+            has_vel: false,
+            has_ori: false,
+        }
+    }
+}
+
+impl TransformBuffer {
+    /// Record that the entity was at `pos` at `time`.
+    pub fn push_pos(&mut self, time: f64, pos: Vec3<f32>) {
+        let teleported = self.samples.back().map_or(false, |last| {
+            last.transform.pos.distance(pos) > TELEPORT_DISTANCE
+        });
+        if teleported {
+            self.initial = self.samples.back().unwrap().transform; // Can't fail
+            self.samples.clear();
+        }
+        if self.samples.is_empty() {
+            let transform = Transform {
+                pos,
+                ..self.initial
+            };
+            self.samples.push_back(Sample { time, transform });
+        } else {
+            self.update(time, |transform| transform.pos = pos);
+        }
+    }
+
+    pub fn push_vel(&mut self, time: f64, vel: Vec3<f32>) {
+        self.has_vel = true;
+        self.update(time, |transform| transform.vel = vel);
+    }
+
+    pub fn push_ori(&mut self, time: f64, ori: Vec3<f32>) {
+        self.has_ori = true;
+        self.update(time, |transform| transform.ori = ori);
+    }
+
+    /// Updates of the same tick usually arrive together, so they are merged into one sample.
+    /// Samples only start with a position, anything before that is kept for the first one.
+    fn update(&mut self, time: f64, f: impl FnOnce(&mut Transform)) {
+        match self.samples.back_mut() {
+            Some(last) if last.time >= time => f(&mut last.transform),
+            Some(last) => {
+                let mut transform = last.transform;
+                f(&mut transform);
+                self.samples.push_back(Sample { time, transform });
+            }
+            None => f(&mut self.initial),
+        }
+    }
+
+    /// The transform to show at `time`, which is usually `INTERPOLATION_DELAY` in the past.
+    pub fn sample(&self, time: f64) -> Option<Transform> {
+        let first = self.samples.front()?;
+        if time <= first.time {
+            return Some(first.transform);
+        }
+
+        // `samples` is ordered by time and has at least one sample before `time`.
+        let next_idx = self.samples.iter().position(|sample| sample.time > time);
+        match next_idx {
+            Some(next_idx) => {
+                let a = self.samples[next_idx - 1];
+                let b = self.samples[next_idx];
+                let t = ((time - a.time) / (b.time - a.time)) as f32;
+                let ori = Lerp::lerp(a.transform.ori, b.transform.ori, t);
+                Some(Transform {
+                    pos: Lerp::lerp(a.transform.pos, b.transform.pos, t),
+                    vel: Lerp::lerp(a.transform.vel, b.transform.vel, t),
+                    ori: if ori.magnitude_squared() > 0.0 {
+                        ori.normalized()
+                    } else {
+                        b.transform.ori
+                    },
+                })
+            }
+            None => {
+                let last = self.samples.back()?;
+                let dt = (time - last.time).min(MAX_EXTRAPOLATION) as f32;
+                Some(Transform {
+                    pos: last.transform.pos + last.transform.vel * dt,
+                    ..last.transform
+                })
+            }
+        }
+    }
+
+    /// Forget the samples that aren't needed to show `time` or anything after it.
+    pub fn prune(&mut self, time: f64) {
+        while self.samples.len() > 1 && self.samples[1].time <= time {
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// The transform buffers of all remote entities.
+#[derive(Default)]
+pub struct Interpolation {
+    buffers: HashMap<u64, TransformBuffer>,
+}
+
+impl Interpolation {
+    pub fn buffer_mut(&mut self, uid: u64) -> &mut TransformBuffer {
+        self.buffers.entry(uid).or_default()
+    }
+
+    /// The interpolated transform of the entity with the given `Uid`, as it is shown at `time`.
+    pub fn transform(&self, uid: u64, time: f64) -> Option<Transform> {
+        self.buffers
+            .get(&uid)
+            .and_then(|buffer| buffer.sample(time - INTERPOLATION_DELAY))
+    }
+
+    /// Write the transforms of all remote entities at `time` into the ECS. Entities that didn't
+    /// have a position, velocity or orientation yet get one once it has been received.
+    pub fn apply(&mut self, state: &mut State, time: f64) {
+        let ecs = state.ecs();
+        let mut positions = ecs.write_storage::<comp::Pos>();
+        let mut velocities = ecs.write_storage::<comp::Vel>();
+        let mut orientations = ecs.write_storage::<comp::Ori>();
+        // Entities that have been deleted don't need a buffer anymore.
+        let mut alive = HashSet::new();
+        for (entity, uid) in (&ecs.entities(), &ecs.read_storage::<Uid>()).join() {
+            let uid = uid.id();
+            let buffer = match self.buffers.get(&uid) {
+                Some(buffer) => buffer,
+                None => continue,
+            };
+            alive.insert(uid);
+            if let Some(transform) = buffer.sample(time - INTERPOLATION_DELAY) {
+                let _ = positions.insert(entity, comp::Pos(transform.pos));
+                if buffer.has_vel || velocities.contains(entity) {
+                    let _ = velocities.insert(entity, comp::Vel(transform.vel));
+                }
+                if buffer.has_ori || orientations.contains(entity) {
+                    let _ = orientations.insert(entity, comp::Ori(transform.ori));
+                }
+            }
+        }
+        self.buffers.retain(|uid, _| alive.contains(uid));
+
+        for buffer in self.buffers.values_mut() {
+            buffer.prune(time - INTERPOLATION_DELAY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use specs::Builder;
+
+    /// The server's tick rate.
+    const TICK: f64 = 1.0 / 30.0;
+    /// How often the frontend renders.
+    const FRAME: f64 = 1.0 / 144.0;
+
+    /// Feed `buffer` with updates of an entity moving along `path` at the server's tick rate,
+    /// delayed by a random amount of up to `jitter` seconds each and skipping those that are
+    /// `lost`. Returns what is shown at every frame, up to `duration`.
+    fn simulate(
+        path: impl Fn(f64) -> Vec3<f32>,
+        vel: Vec3<f32>,
+        jitter: f64,
+        lost: impl Fn(usize) -> bool,
+        duration: f64,
+    ) -> Vec<(f64, Vec3<f32>)> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut arrivals = (0..)
+            .map(|i| i as f64 * TICK)
+            .take_while(|sent| *sent < duration)
+            .enumerate()
+            .filter(|(i, _)| !lost(*i))
+            .map(|(_, sent)| (sent + rng.gen_range(0.0, jitter), sent))
+            .collect::<Vec<_>>();
+        // Updates are sent over a reliable connection, so they can't overtake each other.
+        for i in 1..arrivals.len() {
+            arrivals[i].0 = arrivals[i].0.max(arrivals[i - 1].0);
+        }
+
+        let mut buffer = TransformBuffer::default();
+        let mut arrivals = arrivals.into_iter().peekable();
+        let mut shown = Vec::new();
+        let mut time = 0.0;
+        while time < duration {
+            while let Some((arrival, sent)) = arrivals.peek().copied() {
+                if arrival > time {
+                    break;
+                }
+                arrivals.next();
+                buffer.push_pos(time, path(sent));
+                buffer.push_vel(time, vel);
+            }
+            if let Some(transform) = buffer.sample(time - INTERPOLATION_DELAY) {
+                shown.push((time, transform.pos));
+            }
+            buffer.prune(time - INTERPOLATION_DELAY);
+            time += FRAME;
+        }
+        shown
+    }
+
+    #[test]
+    fn interpolates_between_updates() {
+        let mut buffer = TransformBuffer::default();
+        buffer.push_pos(0.0, Vec3::zero());
+        buffer.push_pos(1.0, Vec3::new(10.0, 0.0, 0.0));
+
+        assert_eq!(buffer.sample(-1.0).unwrap().pos, Vec3::zero());
+        assert_eq!(buffer.sample(0.5).unwrap().pos, Vec3::new(5.0, 0.0, 0.0));
+        assert_eq!(buffer.sample(1.0).unwrap().pos, Vec3::new(10.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn extrapolation_is_bounded() {
+        let mut buffer = TransformBuffer::default();
+        buffer.push_pos(0.0, Vec3::zero());
+        buffer.push_vel(0.0, Vec3::new(4.0, 0.0, 0.0));
+
+        assert_eq!(buffer.sample(0.1).unwrap().pos, Vec3::new(0.4, 0.0, 0.0));
+        let max = 4.0 * MAX_EXTRAPOLATION as f32;
+        assert_eq!(buffer.sample(10.0).unwrap().pos, Vec3::new(max, 0.0, 0.0));
+    }
+
+    #[test]
+    fn teleports_are_not_interpolated() {
+        let mut buffer = TransformBuffer::default();
+        buffer.push_pos(0.0, Vec3::zero());
+        buffer.push_pos(1.0, Vec3::new(1000.0, 0.0, 0.0));
+        assert_eq!(buffer.sample(0.5).unwrap().pos, Vec3::new(1000.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn velocity_before_position_is_kept() {
+        let mut buffer = TransformBuffer::default();
+        buffer.push_vel(0.0, Vec3::new(4.0, 0.0, 0.0));
+        // There is nowhere to show the entity yet, rather than at the origin.
+        assert_eq!(buffer.sample(0.0), None);
+
+        buffer.push_pos(0.0, Vec3::new(100.0, 0.0, 0.0));
+        let transform = buffer.sample(0.0).unwrap();
+        assert_eq!(transform.pos, Vec3::new(100.0, 0.0, 0.0));
+        assert_eq!(transform.vel, Vec3::new(4.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn remote_entities_without_a_position_get_one() {
+        let mut state = State::default();
+        let entity = state.ecs_mut().create_entity_synced().build();
+        let uid = state.ecs().uid_from_entity(entity).unwrap().id();
+        let mut interpolation = Interpolation::default();
+
+        interpolation
+            .buffer_mut(uid)
+            .push_vel(0.0, Vec3::new(4.0, 0.0, 0.0));
+        interpolation.apply(&mut state, INTERPOLATION_DELAY);
+        assert_eq!(state.ecs().read_storage::<comp::Pos>().get(entity), None);
+
+        interpolation
+            .buffer_mut(uid)
+            .push_pos(0.1, Vec3::new(1.0, 2.0, 3.0));
+        interpolation.apply(&mut state, 0.1 + INTERPOLATION_DELAY);
+        assert_eq!(
+            state.ecs().read_storage::<comp::Pos>().get(entity),
+            Some(&comp::Pos(Vec3::new(1.0, 2.0, 3.0)))
+        );
+        assert_eq!(
+            state.ecs().read_storage::<comp::Vel>().get(entity),
+            Some(&comp::Vel(Vec3::new(4.0, 0.0, 0.0)))
+        );
+        // No orientation was ever sent, like for entities that only have a position.
+        assert_eq!(state.ecs().read_storage::<comp::Ori>().get(entity), None);
+    }
+
+    #[test]
+    fn smooth_under_jitter() {
+        let speed = 10.0;
+        let path = |t: f64| Vec3::new(t as f32 * speed, 0.0, 0.0);
+        let shown = simulate(path, Vec3::new(speed, 0.0, 0.0), 0.02, |_| false, 5.0);
+
+        // The entity never moves backwards, and never jumps much further than it would in a frame
+        // at its actual speed.
+        let max_step = speed * FRAME as f32 * 4.0;
+        for w in shown.windows(2) {
+            let step = w[1].1.x - w[0].1.x;
+            assert!(step >= -1e-4, "Moved backwards by {} at {}", -step, w[1].0);
+            assert!(step <= max_step, "Jumped by {} at {}", step, w[1].0);
+        }
+        // Once the buffer has filled, the entity is shown close to where it was
+        // `INTERPOLATION_DELAY` ago.
+        for (time, pos) in shown.iter().filter(|(time, _)| *time > 0.5) {
+            let expected = path(time - INTERPOLATION_DELAY);
+            assert!(
+                pos.distance(expected) < speed * 0.06,
+                "Shown at {:?} instead of {:?} at {}",
+                pos,
+                expected,
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn smooth_with_lost_updates() {
+        let speed = 10.0;
+        let path = |t: f64| Vec3::new(t as f32 * speed, 0.0, 0.0);
+        // Every few ticks, several updates in a row get lost on the UDP channel.
+        let shown = simulate(path, Vec3::new(speed, 0.0, 0.0), 0.02, |i| i % 10 >= 7, 5.0);
+
+        let max_step = speed * FRAME as f32 * 4.0;
+        for w in shown.windows(2) {
+            let step = (w[1].1.x - w[0].1.x).abs();
+            assert!(step <= max_step, "Jumped by {} at {}", step, w[1].0);
+        }
+        // Extrapolation keeps the entity close to where it actually is.
+        for (time, pos) in shown.iter().filter(|(time, _)| *time > 0.5) {
+            let expected = path(time - INTERPOLATION_DELAY);
+            assert!(pos.distance(expected) < speed * 0.06);
+        }
+    }
+}
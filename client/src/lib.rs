@@ -2,15 +2,29 @@
 #![feature(label_break_value, duration_float, euclidean_division)]
 
 pub mod error;
+pub mod interpolation;
+pub mod prediction;
+pub mod status;
 
 // Reexports
-pub use crate::error::Error;
+pub use crate::{
+    error::Error,
+    interpolation::Transform,
+    status::{ping_servers, query_status, ServerPing},
+};
 pub use specs::{join::Join, saveload::Marker, Entity as EcsEntity, ReadStorage};
 
+use crate::{
+    interpolation::Interpolation,
+    prediction::{PredictedState, Prediction},
+};
 use common::{
     comp,
-    msg::{ClientMsg, ClientState, RequestStateError, ServerError, ServerInfo, ServerMsg},
-    net::PostBox,
+    msg::{
+        ClientMsg, ClientState, RequestStateError, ServerError, ServerInfo, ServerMsg,
+        PROTOCOL_VERSION,
+    },
+    net::{PostBox, UdpClient},
     state::{State, Uid},
     terrain::{block::Block, TerrainChunk, TerrainChunkSize},
     vol::RectVolSize,
@@ -19,6 +33,7 @@ use common::{
 use hashbrown::HashMap;
 use log::warn;
 use std::{
+    collections::VecDeque,
     net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
@@ -27,6 +42,8 @@ use uvth::{ThreadPool, ThreadPoolBuilder};
 use vek::*;
 
 const SERVER_TIMEOUT: Duration = Duration::from_secs(20);
+/// How many chunk load times are kept until the frontend takes them.
+const MAX_CHUNK_LOAD_TIMES: usize = 1024;
 
 pub enum Event {
     Chat {
@@ -42,6 +59,9 @@ pub struct Client {
     pub server_info: ServerInfo,
 
     postbox: PostBox<ClientMsg, ServerMsg>,
+    server_addr: SocketAddr,
+    /// The UDP side channel for physics updates, if the server offered one.
+    udp: Option<UdpClient<ClientMsg, ServerMsg>>,
 
     last_server_ping: Instant,
     last_ping_delta: f64,
@@ -49,11 +69,16 @@ pub struct Client {
     tick: u64,
     state: State,
     entity: EcsEntity,
+    /// Smooths the movement of all entities except our own.
+    interpolation: Interpolation,
+    /// Our own entity's inputs that the server hasn't acknowledged yet.
+    prediction: Prediction,
 
     view_distance: Option<u32>,
     loaded_distance: Option<u32>,
 
     pending_chunks: HashMap<Vec2<i32>, Instant>,
+    chunk_load_times: VecDeque<Duration>,
 }
 
 impl Client {
@@ -61,7 +86,12 @@ impl Client {
     #[allow(dead_code)]
     pub fn new<A: Into<SocketAddr>>(addr: A, view_distance: Option<u32>) -> Result<Self, Error> {
         let client_state = ClientState::Connected;
-        let mut postbox = PostBox::to(addr)?;
+        let server_addr = addr.into();
+        let mut postbox = PostBox::to(server_addr)?;
+
+        postbox.send_message(ClientMsg::Connect {
+            protocol_version: PROTOCOL_VERSION,
+        });
 
         // Wait for initial sync
         let (state, entity, server_info) = match postbox.next_message() {
@@ -70,15 +100,6 @@ impl Client {
                 entity_uid,
                 server_info,
             }) => {
-                // TODO: Voxygen should display this.
-                if server_info.git_hash != common::util::GIT_HASH.to_string() {
-                    log::warn!(
-                        "Git hash mismatch between client and server: {} vs {}",
-                        server_info.git_hash,
-                        common::util::GIT_HASH
-                    );
-                }
-
                 let state = State::from_state_package(ecs_state);
                 let entity = state
                     .ecs()
@@ -89,6 +110,9 @@ impl Client {
             Some(ServerMsg::Error(ServerError::TooManyPlayers)) => {
                 return Err(Error::TooManyPlayers)
             }
+            Some(ServerMsg::Error(ServerError::IncompatibleVersion { server, client })) => {
+                return Err(Error::IncompatibleVersion { server, client })
+            }
             _ => return Err(Error::ServerWentMad),
         };
 
@@ -106,6 +130,8 @@ impl Client {
             server_info,
 
             postbox,
+            server_addr,
+            udp: None,
 
             last_server_ping: Instant::now(),
             last_ping_delta: 0.0,
@@ -113,10 +139,13 @@ impl Client {
             tick: 0,
             state,
             entity,
+            interpolation: Interpolation::default(),
+            prediction: Prediction::default(),
             view_distance,
             loaded_distance: None,
 
             pending_chunks: HashMap::new(),
+            chunk_load_times: VecDeque::new(),
         })
     }
 
@@ -126,7 +155,8 @@ impl Client {
         self
     }
 
-    /// Request a state transition to `ClientState::Registered`.
+    /// Request a state transition to `ClientState::Registered`. On servers using token
+    /// authentication, `password` is the login token.
     pub fn register(&mut self, player: comp::Player, password: String) -> Result<(), Error> {
         self.postbox
             .send_message(ClientMsg::Register { player, password });
@@ -136,8 +166,23 @@ impl Client {
                 Some(ServerMsg::StateAnswer(Err((RequestStateError::Denied, _)))) => {
                     break Err(Error::InvalidAuth)
                 }
+                Some(ServerMsg::Error(ServerError::InvalidToken { expired })) => {
+                    break Err(Error::InvalidToken { expired })
+                }
+                Some(ServerMsg::Error(ServerError::Banned { reason, until })) => {
+                    break Err(Error::Banned { reason, until })
+                }
+                Some(ServerMsg::Error(ServerError::NotWhitelisted)) => {
+                    break Err(Error::NotWhitelisted)
+                }
                 Some(ServerMsg::StateAnswer(Ok(ClientState::Registered))) => break Ok(()),
-                _ => {}
+                Some(_) => {}
+                None => {
+                    break Err(match self.postbox.error() {
+                        Some(err) => err.into(),
+                        None => Error::ServerWentMad,
+                    })
+                }
             }
         }
     }
@@ -273,10 +318,18 @@ impl Client {
 
         // 1) Handle input from frontend.
         // Pass character actions from frontend input to the player's entity.
-        if let ClientState::Character | ClientState::Dead = self.client_state {
-            self.state.write_component(self.entity, controller.clone());
-            self.postbox.send_message(ClientMsg::Controller(controller));
-        }
+        let seq = match self.client_state {
+            ClientState::Character | ClientState::Dead => {
+                let seq = self.prediction.next_seq();
+                self.state.write_component(self.entity, controller.clone());
+                self.postbox.send_message(ClientMsg::Controller {
+                    controller: controller.clone(),
+                    seq,
+                });
+                Some(seq)
+            }
+            _ => None,
+        };
 
         // 2) Build up a list of events for this frame, to be passed to the frontend.
         let mut frontend_events = Vec::new();
@@ -308,6 +361,16 @@ impl Client {
 
         // 4) Tick the client's LocalState
         self.state.tick(dt);
+        // Remember what the input did, in case the server disagrees.
+        if let (ClientState::Character, Some(seq)) = (self.client_state, seq) {
+            if let Some(result) = PredictedState::read(&self.state, self.entity) {
+                self.prediction.record(seq, controller, dt, result);
+            }
+        }
+        // Remote entities are shown where the server said they were, rather than where our own
+        // physics would have moved them.
+        let time = self.state.get_time();
+        self.interpolation.apply(&mut self.state, time);
 
         // 5) Terrain
         let pos = self
@@ -395,16 +458,26 @@ impl Client {
         }
 
         // 6) Update the server about the player's physics attributes.
-        if let ClientState::Character = self.client_state {
+        if let (ClientState::Character, Some(seq)) = (self.client_state, seq) {
             if let (Some(pos), Some(vel), Some(ori)) = (
                 self.state.read_storage().get(self.entity).cloned(),
                 self.state.read_storage().get(self.entity).cloned(),
                 self.state.read_storage().get(self.entity).cloned(),
             ) {
-                self.postbox
-                    .send_message(ClientMsg::PlayerPhysics { pos, vel, ori });
+                let msg = ClientMsg::PlayerPhysics { seq, pos, vel, ori };
+                // Physics updates go over UDP when possible.
+                let msg = match &mut self.udp {
+                    Some(udp) => udp.send(msg).err(),
+                    None => Some(msg),
+                };
+                if let Some(msg) = msg {
+                    self.postbox.send_message(msg);
+                }
             }
         }
+        if let Some(udp) = &mut self.udp {
+            udp.flush();
+        }
 
         /*
         // Output debug metrics
@@ -434,6 +507,14 @@ impl Client {
     fn handle_new_messages(&mut self) -> Result<Vec<Event>, Error> {
         let mut frontend_events = Vec::new();
 
+        let udp_msgs = match &mut self.udp {
+            Some(udp) => udp.receive(),
+            None => Vec::new(),
+        };
+        for msg in udp_msgs {
+            self.apply_physics_update(msg);
+        }
+
         let new_msgs = self.postbox.new_messages();
 
         if new_msgs.len() > 0 {
@@ -441,11 +522,23 @@ impl Client {
                 match msg {
                     ServerMsg::Error(e) => match e {
                         ServerError::TooManyPlayers => return Err(Error::ServerWentMad),
+                        ServerError::IncompatibleVersion { server, client } => {
+                            return Err(Error::IncompatibleVersion { server, client })
+                        }
+                        ServerError::InvalidToken { expired } => {
+                            return Err(Error::InvalidToken { expired })
+                        }
+                        ServerError::Banned { reason, until } => {
+                            return Err(Error::Banned { reason, until })
+                        }
+                        ServerError::NotWhitelisted => return Err(Error::NotWhitelisted),
                         ServerError::InvalidAuth => return Err(Error::InvalidAuth),
                         //TODO: ServerError::InvalidAlias => return Err(Error::InvalidAlias),
                     },
-                    ServerMsg::Shutdown => return Err(Error::ServerShutdown),
-                    ServerMsg::InitialSync { .. } => return Err(Error::ServerWentMad),
+                    ServerMsg::Shutdown { reason } => return Err(Error::ServerShutdown { reason }),
+                    ServerMsg::InitialSync { .. } | ServerMsg::Status(_) => {
+                        return Err(Error::ServerWentMad)
+                    }
                     ServerMsg::Ping => self.postbox.send_message(ClientMsg::Pong),
                     ServerMsg::Pong => {
                         self.last_ping_delta = Instant::now()
@@ -461,21 +554,10 @@ impl Client {
                     ServerMsg::EcsSync(sync_package) => {
                         self.state.ecs_mut().sync_with_package(sync_package)
                     }
-                    ServerMsg::EntityPos { entity, pos } => {
-                        if let Some(entity) = self.state.ecs().entity_from_uid(entity) {
-                            self.state.write_component(entity, pos);
-                        }
-                    }
-                    ServerMsg::EntityVel { entity, vel } => {
-                        if let Some(entity) = self.state.ecs().entity_from_uid(entity) {
-                            self.state.write_component(entity, vel);
-                        }
-                    }
-                    ServerMsg::EntityOri { entity, ori } => {
-                        if let Some(entity) = self.state.ecs().entity_from_uid(entity) {
-                            self.state.write_component(entity, ori);
-                        }
-                    }
+                    msg @ ServerMsg::EntityPos { .. }
+                    | msg @ ServerMsg::EntityVel { .. }
+                    | msg @ ServerMsg::EntityOri { .. }
+                    | msg @ ServerMsg::InputAck { .. } => self.apply_physics_update(msg),
                     ServerMsg::EntityCharacterState {
                         entity,
                         character_state,
@@ -488,8 +570,31 @@ impl Client {
                         self.state.write_component(self.entity, inventory)
                     }
                     ServerMsg::TerrainChunkUpdate { key, chunk } => {
-                        self.state.insert_chunk(key, *chunk);
-                        self.pending_chunks.remove(&key);
+                        match chunk.decode() {
+                            Ok(chunk) => self.state.insert_chunk(key, chunk),
+                            Err(err) => warn!("Received invalid chunk {:?}: {:?}", key, err),
+                        }
+                        if let Some(requested) = self.pending_chunks.remove(&key) {
+                            if self.chunk_load_times.len() >= MAX_CHUNK_LOAD_TIMES {
+                                self.chunk_load_times.pop_front();
+                            }
+                            self.chunk_load_times.push_back(requested.elapsed());
+                        }
+                    }
+                    ServerMsg::TerrainChunkDelta { key, delta } => {
+                        // Chunks that we don't have yet will be requested in full anyway.
+                        let chunk = match self.state.terrain().get_key(key) {
+                            Some(old) => delta.apply(old),
+                            None => continue,
+                        };
+                        match chunk {
+                            Ok(chunk) => self.state.insert_chunk(key, chunk),
+                            Err(err) => {
+                                // Our copy is out of date, so request the whole chunk again.
+                                warn!("Failed to update chunk {:?}: {:?}", key, err);
+                                self.state.remove_chunk(key);
+                            }
+                        }
                     }
                     ServerMsg::TerrainBlockUpdates(mut blocks) => blocks
                         .drain()
@@ -513,6 +618,13 @@ impl Client {
                     ServerMsg::Disconnect => {
                         frontend_events.push(Event::Disconnect);
                     }
+                    ServerMsg::UdpOffer { port, token, key } => {
+                        let addr = SocketAddr::new(self.server_addr.ip(), port);
+                        match UdpClient::connect(addr, token, key) {
+                            Ok(udp) => self.udp = Some(udp),
+                            Err(err) => warn!("Failed to set up UDP, using TCP only: {:?}", err),
+                        }
+                    }
                 }
             }
         } else if let Some(err) = self.postbox.error() {
@@ -524,6 +636,61 @@ impl Client {
         Ok(frontend_events)
     }
 
+    /// Apply an update of an entity's position, velocity or orientation, or the acknowledgement of
+    /// one of our inputs, which may have arrived over TCP or UDP. Updates of other entities are
+    /// buffered so that they can be interpolated.
+    fn apply_physics_update(&mut self, msg: ServerMsg) {
+        let time = self.state.get_time();
+        let own_uid = self
+            .state
+            .ecs()
+            .read_storage::<Uid>()
+            .get(self.entity)
+            .map(|uid| uid.id());
+        match msg {
+            ServerMsg::EntityPos { entity, pos } if Some(entity) != own_uid => {
+                self.interpolation.buffer_mut(entity).push_pos(time, pos.0)
+            }
+            ServerMsg::EntityVel { entity, vel } if Some(entity) != own_uid => {
+                self.interpolation.buffer_mut(entity).push_vel(time, vel.0)
+            }
+            ServerMsg::EntityOri { entity, ori } if Some(entity) != own_uid => {
+                self.interpolation.buffer_mut(entity).push_ori(time, ori.0)
+            }
+            ServerMsg::InputAck { seq, pos, vel, ori } => {
+                if let Some(corrected) =
+                    self.prediction
+                        .reconcile(&self.state, self.entity, seq, pos, vel, ori)
+                {
+                    corrected.write(&mut self.state, self.entity);
+                }
+            }
+            ServerMsg::EntityPos { entity, pos } => {
+                if let Some(entity) = self.state.ecs().entity_from_uid(entity) {
+                    self.state.write_component(entity, pos);
+                }
+            }
+            ServerMsg::EntityVel { entity, vel } => {
+                if let Some(entity) = self.state.ecs().entity_from_uid(entity) {
+                    self.state.write_component(entity, vel);
+                }
+            }
+            ServerMsg::EntityOri { entity, ori } => {
+                if let Some(entity) = self.state.ecs().entity_from_uid(entity) {
+                    self.state.write_component(entity, ori);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The transform that the entity with the given `Uid` is currently shown with, if its
+    /// movement is being interpolated.
+    #[allow(dead_code)]
+    pub fn interpolated_transform(&self, uid: u64) -> Option<Transform> {
+        self.interpolation.transform(uid, self.state.get_time())
+    }
+
     /// Get the player's entity.
     #[allow(dead_code)]
     pub fn entity(&self) -> EcsEntity {
@@ -547,6 +714,12 @@ impl Client {
         self.last_ping_delta * 1000.0
     }
 
+    /// Take how long the chunks that arrived since the last call took from being requested to
+    /// arriving. Only the most recent ones are kept if this isn't called regularly.
+    pub fn take_chunk_load_times(&mut self) -> Vec<Duration> {
+        self.chunk_load_times.drain(..).collect()
+    }
+
     /// Get a reference to the client's worker thread pool. This pool should be used for any
     /// computationally expensive operations that run outside of the main thread (i.e., threads that
     /// block on I/O operations are exempt).
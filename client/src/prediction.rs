@@ -0,0 +1,334 @@
+//! Prediction of the player's own movement.
+//!
+//! The client simulates its character as soon as the player gives an input, without waiting for
+//! the server. Every input is numbered, and the server acknowledges each number with the state
+//! that it accepted for the character. Whenever that differs from what the client predicted, the
+//! character is reset to the acknowledged state and the inputs that the server hasn't seen yet
+//! are replayed on top of it.
+
+use common::{comp, state::State};
+use specs::Entity as EcsEntity;
+use std::{collections::VecDeque, time::Duration};
+
+/// How far the acknowledged position may be from the predicted one before the prediction counts
+/// as wrong, in blocks.
+const POS_TOLERANCE: f32 = 0.01;
+/// The same for velocity, in blocks per second.
+const VEL_TOLERANCE: f32 = 0.05;
+/// How many inputs are kept while waiting for acknowledgements. Servers that never acknowledge
+/// anything mustn't make this grow forever.
+const MAX_PENDING_INPUTS: usize = 512;
+
+/// The part of a character's state that its inputs affect.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PredictedState {
+    pub pos: comp::Pos,
+    pub vel: comp::Vel,
+    pub ori: comp::Ori,
+    pub character: comp::CharacterState,
+    pub physics: comp::PhysicsState,
+}
+
+impl PredictedState {
+    pub fn read(state: &State, entity: EcsEntity) -> Option<Self> {
+        Some(Self {
+            pos: state.read_component_cloned(entity)?,
+            vel: state.read_component_cloned(entity)?,
+            ori: state.read_component_cloned(entity)?,
+            character: state.read_component_cloned(entity).unwrap_or_default(),
+            physics: state.read_component_cloned(entity).unwrap_or_default(),
+        })
+    }
+
+    pub fn write(&self, state: &mut State, entity: EcsEntity) {
+        state.write_component(entity, self.pos);
+        state.write_component(entity, self.vel);
+        state.write_component(entity, self.ori);
+        state.write_component(entity, self.character);
+        state.write_component(entity, self.physics);
+    }
+
+    fn matches(&self, pos: comp::Pos, vel: comp::Vel) -> bool {
+        self.pos.0.distance(pos.0) <= POS_TOLERANCE && self.vel.0.distance(vel.0) <= VEL_TOLERANCE
+    }
+}
+
+/// An input that the server hasn't acknowledged yet.
+struct Input {
+    seq: u64,
+    controller: comp::Controller,
+    dt: Duration,
+    /// The state of the character after the input.
+    result: PredictedState,
+}
+
+/// The inputs that are waiting for an acknowledgement.
+#[derive(Default)]
+pub struct Prediction {
+    next_seq: u64,
+    inputs: VecDeque<Input>,
+    replays: u64,
+}
+
+impl Prediction {
+    /// Allocate the sequence number of the next input.
+    pub fn next_seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    /// Remember that the input `seq` moved the character into `result` during a tick of `dt`.
+    pub fn record(
+        &mut self,
+        seq: u64,
+        controller: comp::Controller,
+        dt: Duration,
+        result: PredictedState,
+    ) {
+        if self.inputs.len() >= MAX_PENDING_INPUTS {
+            self.inputs.pop_front();
+        }
+        self.inputs.push_back(Input {
+            seq,
+            controller,
+            dt,
+            result,
+        });
+    }
+
+    /// The number of inputs that the server hasn't acknowledged yet.
+    pub fn pending(&self) -> usize {
+        self.inputs.len()
+    }
+
+    /// How often a wrong prediction had to be corrected.
+    pub fn replays(&self) -> u64 {
+        self.replays
+    }
+
+    /// Handle the server's acknowledgement of the input `seq`. If the prediction for it was
+    /// wrong, the later inputs are replayed for `entity`, starting from the acknowledged state,
+    /// and the corrected current state of the character is returned.
+    pub fn reconcile(
+        &mut self,
+        state: &State,
+        entity: EcsEntity,
+        seq: u64,
+        pos: comp::Pos,
+        vel: comp::Vel,
+        ori: comp::Ori,
+    ) -> Option<PredictedState> {
+        while self.inputs.front().map_or(false, |input| input.seq < seq) {
+            self.inputs.pop_front();
+        }
+        // Acknowledgements of inputs that were already reconciled are of no use.
+        if self.inputs.front().map_or(true, |input| input.seq != seq) {
+            return None;
+        }
+        let acked = self.inputs.pop_front()?;
+        if acked.result.matches(pos, vel) {
+            return None;
+        }
+
+        self.replays += 1;
+        let mut current = PredictedState {
+            pos,
+            vel,
+            ori,
+            ..acked.result
+        };
+        if self.inputs.is_empty() {
+            return Some(current);
+        }
+        let (mut replay, copy) = state.isolate(entity, seq)?;
+        current.write(&mut replay, copy);
+        for input in self.inputs.iter_mut() {
+            replay.write_component(copy, input.controller.clone());
+            replay.tick(input.dt);
+            replay.cleanup();
+            if let Some(result) = PredictedState::read(&replay, copy) {
+                input.result = result;
+                current = result;
+            }
+        }
+        Some(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{
+        comp::humanoid,
+        terrain::{Block, BlockKind, TerrainChunk, TerrainChunkMeta},
+        vol::Vox,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use specs::Builder;
+    use vek::*;
+
+    const DT: Duration = Duration::from_millis(16);
+
+    /// A state with flat ground and a character standing on it.
+    fn flat_world() -> (State, EcsEntity) {
+        let mut state = State::default();
+        state.seed_rng(0);
+        for x in -2..=2 {
+            for y in -2..=2 {
+                let chunk = TerrainChunk::new(
+                    0,
+                    Block::new(BlockKind::Dense, Rgb::broadcast(128)),
+                    Block::empty(),
+                    TerrainChunkMeta::void(),
+                );
+                state.insert_chunk(Vec2::new(x, y), chunk);
+            }
+        }
+        let entity = state
+            .ecs_mut()
+            .create_entity()
+            .with(comp::Body::Humanoid(humanoid::Body::random()))
+            .with(comp::Stats::new("Tester".to_string(), None))
+            .with(comp::Controller::default())
+            .with(comp::CharacterState::default())
+            .with(comp::Pos(Vec3::new(16.0, 16.0, 0.0)))
+            .with(comp::Vel(Vec3::zero()))
+            .with(comp::Ori(Vec3::unit_y()))
+            .build();
+        (state, entity)
+    }
+
+    /// A player that changes direction every now and then.
+    fn inputs(count: usize) -> Vec<comp::Controller> {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut controller = comp::Controller::default();
+        (0..count)
+            .map(|i| {
+                if i % 20 == 0 {
+                    controller.move_dir =
+                        Vec2::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0));
+                }
+                controller.jump = i % 50 == 25;
+                controller.clone()
+            })
+            .collect()
+    }
+
+    /// Run a client and a server, with `latency` ticks between them in either direction. The
+    /// server runs the client's inputs on its own copy of the character and pushes the character
+    /// at the ticks in `pushes`, which the client can't predict. Returns the positions of the
+    /// character on the client and the server after every input.
+    fn simulate(
+        inputs: &[comp::Controller],
+        latency: usize,
+        pushes: &[usize],
+    ) -> (Vec<Vec3<f32>>, Vec<Vec3<f32>>, Prediction) {
+        let (mut client, client_entity) = flat_world();
+        let (mut server, server_entity) = flat_world();
+        let mut prediction = Prediction::default();
+        let mut to_server = VecDeque::new();
+        let mut to_client = VecDeque::new();
+        let mut client_positions = Vec::new();
+        let mut server_positions = Vec::new();
+
+        for tick in 0..inputs.len() + 2 * latency {
+            if let Some(controller) = inputs.get(tick) {
+                let seq = prediction.next_seq();
+                client.write_component(client_entity, controller.clone());
+                client.tick(DT);
+                client.cleanup();
+                let result = PredictedState::read(&client, client_entity).unwrap();
+                prediction.record(seq, controller.clone(), DT, result);
+                client_positions.push(result.pos.0);
+                to_server.push_back((tick + latency, seq, controller.clone()));
+            }
+
+            while to_server.front().map_or(false, |(at, _, _)| *at <= tick) {
+                let (_, seq, controller) = to_server.pop_front().unwrap();
+                if pushes.contains(&(seq as usize)) {
+                    let vel = server.read_component_cloned::<comp::Vel>(server_entity);
+                    let vel = vel.unwrap().0 + Vec3::new(8.0, 0.0, 6.0);
+                    server.write_component(server_entity, comp::Vel(vel));
+                }
+                server.write_component(server_entity, controller);
+                server.tick(DT);
+                server.cleanup();
+                let result = PredictedState::read(&server, server_entity).unwrap();
+                server_positions.push(result.pos.0);
+                to_client.push_back((tick + latency, seq, result));
+            }
+
+            while to_client.front().map_or(false, |(at, _, _)| *at <= tick) {
+                let (_, seq, acked) = to_client.pop_front().unwrap();
+                if let Some(corrected) = prediction.reconcile(
+                    &client,
+                    client_entity,
+                    seq,
+                    acked.pos,
+                    acked.vel,
+                    acked.ori,
+                ) {
+                    corrected.write(&mut client, client_entity);
+                }
+            }
+        }
+        (client_positions, server_positions, prediction)
+    }
+
+    #[test]
+    fn isolated_ticks_are_deterministic() {
+        let (state, entity) = flat_world();
+        let run = || {
+            let (mut isolated, copy) = state.isolate(entity, 7).unwrap();
+            for controller in inputs(200) {
+                isolated.write_component(copy, controller);
+                isolated.tick(DT);
+                isolated.cleanup();
+            }
+            PredictedState::read(&isolated, copy).unwrap()
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn correct_predictions_are_not_replayed() {
+        let inputs = inputs(300);
+        let (client, server, prediction) = simulate(&inputs, 6, &[]);
+        assert_eq!(prediction.replays(), 0);
+        assert_eq!(prediction.pending(), 0);
+        assert_eq!(client, server);
+    }
+
+    #[test]
+    fn converges_after_mispredictions() {
+        let inputs = inputs(300);
+        let latency = 6;
+        let (client, server, prediction) = simulate(&inputs, latency, &[50, 120, 121, 200]);
+        assert!(prediction.replays() > 0);
+        assert_eq!(prediction.pending(), 0);
+
+        // The client can't know about a push before it is acknowledged, a round trip later, and
+        // the acknowledgement doesn't tell it whether the push changed the character's movement
+        // state, which takes a few more inputs to catch up. After that, its prediction of every
+        // later input has to match the server again.
+        let settled = |tick: usize| {
+            [50, 120, 121, 200]
+                .iter()
+                .all(|push| tick < *push || tick > push + 2 * latency + 10)
+        };
+        for (tick, (client, server)) in client.iter().zip(server.iter()).enumerate() {
+            if settled(tick) {
+                assert!(
+                    client.distance(*server) <= POS_TOLERANCE,
+                    "Client at {:?} but server at {:?} after input {}",
+                    client,
+                    server,
+                    tick
+                );
+            }
+        }
+        // In the end, both agree exactly on where the character is.
+        assert!(client.last().unwrap().distance(*server.last().unwrap()) <= POS_TOLERANCE);
+    }
+}
@@ -0,0 +1,134 @@
+//! Status queries for server browsers. A query tells a server to send its `ServerStatus` instead
+//! of letting the client in, so it is much cheaper than connecting.
+
+use crate::Error;
+use common::{
+    msg::{ClientMsg, ServerMsg, ServerStatus},
+    net::PostBox,
+};
+use std::{
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The answer of a server to a status query.
+#[derive(Clone, Debug)]
+pub struct ServerPing {
+    pub status: ServerStatus,
+    /// How long the server took to answer, not counting the time to connect.
+    pub latency: Duration,
+}
+
+/// Query the status of the server at `addr`, giving up after `timeout`.
+pub fn query_status(addr: SocketAddr, timeout: Duration) -> Result<ServerPing, Error> {
+    let start = Instant::now();
+    let mut postbox = PostBox::<ClientMsg, ServerMsg>::to_timeout(addr, timeout)?;
+
+    let sent = Instant::now();
+    postbox.send_message(ClientMsg::StatusQuery);
+    let remaining = timeout
+        .checked_sub(start.elapsed())
+        .unwrap_or(Duration::from_secs(0));
+    match postbox.next_message_timeout(remaining) {
+        Some(ServerMsg::Status(status)) => Ok(ServerPing {
+            status,
+            latency: sent.elapsed(),
+        }),
+        Some(_) => Err(Error::ServerWentMad),
+        None => match postbox.error() {
+            Some(err) => Err(err.into()),
+            None => Err(Error::ServerTimeout),
+        },
+    }
+}
+
+/// Query the status of all servers in `addrs` at once. The results are in the same order as
+/// `addrs`, and all of them are available after about `timeout`.
+pub fn ping_servers(addrs: &[SocketAddr], timeout: Duration) -> Vec<Result<ServerPing, Error>> {
+    let queries = addrs
+        .iter()
+        .map(|addr| {
+            let addr = *addr;
+            thread::Builder::new()
+                .name(format!("status-query-{}", addr))
+                .spawn(move || query_status(addr, timeout))
+        })
+        .collect::<Vec<_>>();
+
+    queries
+        .into_iter()
+        .map(|query| match query {
+            Ok(handle) => handle
+                .join()
+                .unwrap_or_else(|_| Err(Error::Other("Status query panicked".to_owned()))),
+            Err(err) => Err(Error::Other(format!(
+                "Failed to start status query: {}",
+                err
+            ))),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::net::PostOffice;
+    use std::net::TcpListener;
+
+    fn status() -> ServerStatus {
+        ServerStatus {
+            name: "Test".to_owned(),
+            description: "A server for tests".to_owned(),
+            player_count: 3,
+            max_players: 10,
+            protocol_version: common::msg::PROTOCOL_VERSION,
+            git_hash: "abc".to_owned(),
+            world_seed: 42,
+        }
+    }
+
+    /// Answer status queries on `addr` for `duration`.
+    fn serve_status(addr: SocketAddr, duration: Duration) {
+        let mut postoffice = PostOffice::<ServerMsg, ClientMsg>::bind(addr).unwrap();
+        thread::spawn(move || {
+            let start = Instant::now();
+            let mut postboxes = Vec::new();
+            while start.elapsed() < duration {
+                postboxes.extend(postoffice.new_postboxes());
+                for postbox in postboxes.iter_mut() {
+                    if let Some(ClientMsg::StatusQuery) = postbox.new_messages().next() {
+                        postbox.send_message(ServerMsg::Status(status()));
+                    }
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+        });
+    }
+
+    #[test]
+    fn pings_servers_concurrently() {
+        let answering = SocketAddr::from(([127, 0, 0, 1], 12460));
+        serve_status(answering, Duration::from_secs(5));
+        // Accepts connections, but never answers.
+        let silent = TcpListener::bind(([127, 0, 0, 1], 12461)).unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+
+        let timeout = Duration::from_millis(500);
+        let start = Instant::now();
+        let results = ping_servers(&[silent_addr, answering, silent_addr], timeout);
+        // The silent servers were waited for at the same time.
+        assert!(start.elapsed() < timeout * 2);
+
+        assert_eq!(results.len(), 3);
+        let ping = results[1].as_ref().unwrap();
+        assert_eq!(ping.status, status());
+        assert!(ping.latency < timeout);
+        for result in [&results[0], &results[2]].iter() {
+            match result {
+                Err(Error::ServerTimeout) => {}
+                other => panic!("Expected a timeout, got {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::black_box;
+use criterion::Criterion;
+
+use vek::*;
+use veloren_common::{
+    terrain::{
+        block::{Block, BlockKind},
+        EncodedTerrainChunk, TerrainChunk, TerrainChunkDelta, TerrainChunkMeta,
+    },
+    vol::*,
+};
+
+const MIN_Z: i32 = 140;
+const MAX_Z: i32 = 220;
+
+/// A chunk that resembles generated terrain: rolling hills of stone covered by a few layers of
+/// dirt and grass, whose colours vary slightly between columns.
+fn terrain_chunk() -> TerrainChunk {
+    let stone = Block::new(BlockKind::Dense, Rgb::new(120, 120, 120));
+    let mut chunk = TerrainChunk::new(MIN_Z, stone, Block::empty(), TerrainChunkMeta::void());
+    for x in 0..TerrainChunk::RECT_SIZE.x as i32 {
+        for y in 0..TerrainChunk::RECT_SIZE.y as i32 {
+            let height = MIN_Z + 40 + ((x as f32 * 0.3).sin() * 8.0 + y as f32 * 0.5) as i32;
+            let shade = (x + y) as u8 % 8;
+            for z in MIN_Z..height.min(MAX_Z) {
+                let block = if z == height - 1 {
+                    Block::new(BlockKind::Normal, Rgb::new(40, 150 + shade, 30))
+                } else if z > height - 4 {
+                    Block::new(BlockKind::Normal, Rgb::new(110 + shade, 80, 50))
+                } else {
+                    stone
+                };
+                chunk.set(Vec3::new(x, y, z), block).unwrap();
+            }
+        }
+    }
+    chunk
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let chunk = terrain_chunk();
+    let encoded = EncodedTerrainChunk::encode(&chunk);
+    let mut modified = chunk.clone();
+    modified
+        .set(Vec3::new(7, 9, MIN_Z + 30), Block::empty())
+        .unwrap();
+    let delta = TerrainChunkDelta::between(&chunk, &modified);
+
+    println!(
+        "Serialized sizes: full chunk {} bytes, encoded {} bytes, delta after one change {} bytes",
+        bincode::serialized_size(&chunk).unwrap(),
+        bincode::serialized_size(&encoded).unwrap(),
+        bincode::serialized_size(&delta).unwrap(),
+    );
+
+    c.bench_function("chunk: serialize full", |b| {
+        b.iter(|| black_box(bincode::serialize(&chunk).unwrap()))
+    });
+
+    c.bench_function("chunk: encode", |b| {
+        b.iter(|| black_box(EncodedTerrainChunk::encode(&chunk)))
+    });
+
+    c.bench_function("chunk: decode", |b| {
+        b.iter(|| black_box(encoded.decode().unwrap()))
+    });
+
+    c.bench_function("chunk: delta", |b| {
+        b.iter(|| black_box(TerrainChunkDelta::between(&chunk, &modified)))
+    });
+
+    c.bench_function("chunk: apply delta", |b| {
+        b.iter(|| black_box(delta.apply(&chunk).unwrap()))
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
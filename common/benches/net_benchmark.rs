@@ -0,0 +1,193 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::black_box;
+use criterion::{BenchmarkId, Criterion};
+
+use crossbeam::channel;
+use std::{
+    fs,
+    io::{self, Read},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use veloren_common::net::{PostBox, PostOffice};
+
+const IDLE_CLIENTS: [usize; 2] = [0, 100];
+
+/// Connect `n` clients to `postoffice` and return both ends of every connection.
+fn connect(
+    postoffice: &mut PostOffice<u32, u32>,
+    sock: SocketAddr,
+    n: usize,
+) -> (Vec<PostBox<u32, u32>>, Vec<PostBox<u32, u32>>) {
+    let clients = (0..n)
+        .map(|_| PostBox::to(sock).unwrap())
+        .collect::<Vec<_>>();
+
+    let mut servers = Vec::new();
+    let start = Instant::now();
+    while servers.len() < n && start.elapsed() < Duration::from_secs(5) {
+        servers.extend(postoffice.new_postboxes());
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(servers.len(), n, "Not every client could connect");
+
+    (clients, servers)
+}
+
+/// The connection handling that postboxes used before they shared a single I/O thread: every
+/// connection has a thread of its own that polls its stream and channel, then sleeps for 10ms.
+struct ThreadPerConnection {
+    _send_tx: channel::Sender<Vec<u8>>,
+    running: Arc<AtomicBool>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl ThreadPerConnection {
+    fn new(mut stream: TcpStream) -> Self {
+        stream.set_nonblocking(true).unwrap();
+        let (send_tx, send_rx) = channel::unbounded::<Vec<u8>>();
+        let running = Arc::new(AtomicBool::new(true));
+        let worker_running = running.clone();
+
+        let worker = thread::spawn(move || {
+            let mut incoming_buf = Vec::new();
+            while worker_running.load(Ordering::Relaxed) {
+                for _ in 0..30 {
+                    if let Ok(Some(_)) | Err(_) = stream.take_error() {
+                        return;
+                    }
+                    for _ in send_rx.try_iter() {}
+                    let mut buf = [0; 4096];
+                    match stream.read(&mut buf) {
+                        Ok(n) => incoming_buf.extend_from_slice(&buf[0..n]),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(_) => return,
+                    }
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        Self {
+            _send_tx: send_tx,
+            running,
+            worker: Some(worker),
+        }
+    }
+}
+
+impl Drop for ThreadPerConnection {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.worker.take().map(|handle| handle.join());
+    }
+}
+
+/// Connect `n` clients to `listener` the way postboxes used to handle them.
+fn connect_thread_per_connection(
+    listener: &TcpListener,
+    n: usize,
+) -> (Vec<ThreadPerConnection>, Vec<ThreadPerConnection>) {
+    let addr = listener.local_addr().unwrap();
+    (0..n)
+        .map(|_| {
+            let client = TcpStream::connect(addr).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            (
+                ThreadPerConnection::new(client),
+                ThreadPerConnection::new(server),
+            )
+        })
+        .unzip()
+}
+
+/// The CPU time that every thread of the process has used so far.
+fn process_cpu_time() -> Duration {
+    fs::read_dir("/proc/self/task")
+        .expect("Measuring CPU time needs procfs")
+        .filter_map(|task| fs::read_to_string(task.ok()?.path().join("schedstat")).ok())
+        .filter_map(|schedstat| schedstat.split_whitespace().next()?.parse::<u64>().ok())
+        .map(Duration::from_nanos)
+        .sum()
+}
+
+/// The CPU time used while the application itself sleeps for 10ms, which is the cost of keeping
+/// the connections alive.
+fn idle_cpu_time(iters: u64) -> Duration {
+    let start = process_cpu_time();
+    for _ in 0..iters {
+        thread::sleep(Duration::from_millis(10));
+    }
+    process_cpu_time() - start
+}
+
+/// Some CPU-bound work, to measure how much of the CPU the idle connections leave to the
+/// application.
+fn busy_work() -> u64 {
+    (0..100_000u64).fold(0, |a, x| black_box(a.wrapping_mul(31).wrapping_add(x)))
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let sock = SocketAddr::from(([127, 0, 0, 1], 14100));
+    let mut postoffice = PostOffice::<u32, u32>::bind(sock).unwrap();
+
+    let mut group = c.benchmark_group("idle clients");
+    for &n in IDLE_CLIENTS.iter() {
+        // The connection that is actually used, on top of the idle ones.
+        let (mut clients, mut servers) = connect(&mut postoffice, sock, n + 1);
+        let mut client = clients.pop().unwrap();
+        let mut server = servers.pop().unwrap();
+
+        group.bench_with_input(BenchmarkId::new("busy work", n), &n, |b, _| {
+            b.iter(busy_work)
+        });
+
+        group.bench_with_input(BenchmarkId::new("idle cpu time", n), &n, |b, _| {
+            b.iter_custom(idle_cpu_time)
+        });
+
+        group.bench_with_input(BenchmarkId::new("round trip", n), &n, |b, _| {
+            b.iter(|| {
+                client.send_message(1);
+                let msg = loop {
+                    if let Some(msg) = server.new_messages().next() {
+                        break msg;
+                    }
+                };
+                server.send_message(msg + 1);
+                loop {
+                    if let Some(msg) = client.new_messages().next() {
+                        break black_box(msg);
+                    }
+                }
+            })
+        });
+    }
+    group.finish();
+
+    // The same measurements for the old implementation, to compare against.
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 14101))).unwrap();
+    let mut group = c.benchmark_group("idle clients, thread per connection");
+    for &n in IDLE_CLIENTS.iter() {
+        let _connections = connect_thread_per_connection(&listener, n);
+
+        group.bench_with_input(BenchmarkId::new("busy work", n), &n, |b, _| {
+            b.iter(busy_work)
+        });
+
+        group.bench_with_input(BenchmarkId::new("idle cpu time", n), &n, |b, _| {
+            b.iter_custom(idle_cpu_time)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
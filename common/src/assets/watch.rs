@@ -4,7 +4,7 @@ use log::warn;
 use notify::{event::Flag, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
 use std::{
     collections::HashMap,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex, Weak,
@@ -22,7 +22,10 @@ lazy_static! {
 
 // This will need to be adjusted when specifier mapping to asset location becomes more dynamic
 struct Watcher {
-    watching: HashMap<PathBuf, (Handler, Vec<Weak<AtomicBool>>)>,
+    // The path that is actually being watched for each of these is stored alongside
+    watching: HashMap<PathBuf, (Handler, Vec<Weak<AtomicBool>>, PathBuf)>,
+    // How many of the watched paths each directory or file that is actually being watched covers
+    watched: HashMap<PathBuf, usize>,
     watcher: RecommendedWatcher,
     event_rx: Receiver<Result<Event, notify::Error>>,
 }
@@ -31,6 +34,7 @@ impl Watcher {
         let (event_tx, event_rx) = unbounded();
         Watcher {
             watching: HashMap::new(),
+            watched: HashMap::new(),
             watcher: notify::Watcher::new(event_tx, Duration::from_secs(2))
                 .expect("Failed to create notify::Watcher"),
             event_rx,
@@ -38,7 +42,7 @@ impl Watcher {
     }
     fn watch(&mut self, path: PathBuf, handler: Handler, signal: Weak<AtomicBool>) {
         match self.watching.get_mut(&path) {
-            Some((_, ref mut v)) => {
+            Some((_, ref mut v, _)) => {
                 if !v.iter().any(|s| match (s.upgrade(), signal.upgrade()) {
                     (Some(arc1), Some(arc2)) => Arc::ptr_eq(&arc1, &arc2),
                     _ => false,
@@ -47,11 +51,40 @@ impl Watcher {
                 }
             }
             None => {
-                if let Err(err) = self.watcher.watch(path.clone(), RecursiveMode::Recursive) {
-                    warn!("Could not start watching {:#?} due to: {}", &path, err);
-                    return;
+                let (target, mode) = Self::watch_target(&path);
+                if !self.watched.contains_key(&target) {
+                    if let Err(err) = self.watcher.watch(target.clone(), mode) {
+                        warn!("Could not start watching {:#?} due to: {}", &path, err);
+                        return;
+                    }
+                }
+                *self.watched.entry(target.clone()).or_insert(0) += 1;
+                self.watching.insert(path, (handler, vec![signal], target));
+            }
+        }
+    }
+    // Files are watched through their directory, so that they are still noticed after being
+    // replaced (as editors and atomic saves do) rather than modified in place
+    fn watch_target(path: &Path) -> (PathBuf, RecursiveMode) {
+        match path.parent() {
+            Some(parent) if path.is_file() && parent != Path::new("") => {
+                (parent.to_owned(), RecursiveMode::NonRecursive)
+            }
+            _ => (path.to_owned(), RecursiveMode::Recursive),
+        }
+    }
+    fn unwatch(&mut self, path: &Path) {
+        let target = match self.watching.remove(path) {
+            Some((_, _, target)) => target,
+            None => return,
+        };
+        if let Some(count) = self.watched.get_mut(&target) {
+            *count -= 1;
+            if *count == 0 {
+                self.watched.remove(&target);
+                if let Err(err) = self.watcher.unwatch(&target) {
+                    warn!("Error unwatching: {}", err);
                 }
-                self.watching.insert(path, (handler, vec![signal]));
             }
         }
     }
@@ -60,15 +93,11 @@ impl Watcher {
         if let Some(Flag::Notice) = event.flag() {
             return;
         }
-        if let Event {
-            kind: EventKind::Modify(_),
-            paths,
-            ..
-        } = event
-        {
-            for path in paths {
+        // Renames are modifications too
+        if let EventKind::Create(_) | EventKind::Modify(_) = event.kind {
+            for path in event.paths {
                 match self.watching.get_mut(&path) {
-                    Some((reloader, ref mut signals)) => {
+                    Some((reloader, ref mut signals, _)) => {
                         if !signals.is_empty() {
                             // Reload this file
                             reloader();
@@ -83,18 +112,11 @@ impl Watcher {
                         }
                         // If there is no one to signal stop watching this path
                         if signals.is_empty() {
-                            if let Err(err) = self.watcher.unwatch(&path) {
-                                warn!("Error unwatching: {}", err);
-                            }
-                            self.watching.remove(&path);
-                        }
-                    }
-                    None => {
-                        warn!("Watching {:#?} but there are no signals for this path. The path will be unwatched.", path);
-                        if let Err(err) = self.watcher.unwatch(&path) {
-                            warn!("Error unwatching: {}", err);
+                            self.unwatch(&path);
                         }
                     }
+                    // Another file in a watched directory
+                    None => {}
                 }
             }
         }
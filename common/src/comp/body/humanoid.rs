@@ -22,21 +22,25 @@ pub struct Body {
 
 impl Body {
     pub fn random() -> Self {
-        let mut rng = thread_rng();
-        let race = *(&ALL_RACES).choose(&mut rng).unwrap();
+        Self::random_with(&mut thread_rng())
+    }
+
+    /// Like `random`, but draws from `rng` so that the result can be reproduced.
+    pub fn random_with(rng: &mut impl Rng) -> Self {
+        let race = *(&ALL_RACES).choose(rng).unwrap();
         Self {
             race,
-            body_type: *(&ALL_BODY_TYPES).choose(&mut rng).unwrap(),
-            chest: *(&ALL_CHESTS).choose(&mut rng).unwrap(),
-            belt: *(&ALL_BELTS).choose(&mut rng).unwrap(),
-            pants: *(&ALL_PANTS).choose(&mut rng).unwrap(),
-            hand: *(&ALL_HANDS).choose(&mut rng).unwrap(),
-            foot: *(&ALL_FEET).choose(&mut rng).unwrap(),
-            shoulder: *(&ALL_SHOULDERS).choose(&mut rng).unwrap(),
-            hair_style: *(&ALL_HAIR_STYLES).choose(&mut rng).unwrap(),
-            beard: *(&ALL_BEARDS).choose(&mut rng).unwrap(),
-            eyebrows: *(&ALL_EYEBROWS).choose(&mut rng).unwrap(),
-            accessory: *(&ALL_ACCESSORIES).choose(&mut rng).unwrap(),
+            body_type: *(&ALL_BODY_TYPES).choose(rng).unwrap(),
+            chest: *(&ALL_CHESTS).choose(rng).unwrap(),
+            belt: *(&ALL_BELTS).choose(rng).unwrap(),
+            pants: *(&ALL_PANTS).choose(rng).unwrap(),
+            hand: *(&ALL_HANDS).choose(rng).unwrap(),
+            foot: *(&ALL_FEET).choose(rng).unwrap(),
+            shoulder: *(&ALL_SHOULDERS).choose(rng).unwrap(),
+            hair_style: *(&ALL_HAIR_STYLES).choose(rng).unwrap(),
+            beard: *(&ALL_BEARDS).choose(rng).unwrap(),
+            eyebrows: *(&ALL_EYEBROWS).choose(rng).unwrap(),
+            accessory: *(&ALL_ACCESSORIES).choose(rng).unwrap(),
             hair_color: rng.gen_range(0, race.num_hair_colors()) as u8,
             skin: rng.gen_range(0, race.num_skin_colors()) as u8,
             eye_color: rng.gen_range(0, race.num_eye_colors()) as u8,
@@ -1,4 +1,4 @@
-use rand::{seq::SliceRandom, thread_rng};
+use rand::{seq::SliceRandom, thread_rng, Rng};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Body {
@@ -10,12 +10,16 @@ pub struct Body {
 
 impl Body {
     pub fn random() -> Self {
-        let mut rng = thread_rng();
+        Self::random_with(&mut thread_rng())
+    }
+
+    /// Like `random`, but draws from `rng` so that the result can be reproduced.
+    pub fn random_with(rng: &mut impl Rng) -> Self {
         Self {
-            head: *(&ALL_HEADS).choose(&mut rng).unwrap(),
-            chest: *(&ALL_CHESTS).choose(&mut rng).unwrap(),
-            leg_l: *(&ALL_LEGS_L).choose(&mut rng).unwrap(),
-            leg_r: *(&ALL_LEGS_R).choose(&mut rng).unwrap(),
+            head: *(&ALL_HEADS).choose(rng).unwrap(),
+            chest: *(&ALL_CHESTS).choose(rng).unwrap(),
+            leg_l: *(&ALL_LEGS_L).choose(rng).unwrap(),
+            leg_r: *(&ALL_LEGS_R).choose(rng).unwrap(),
         }
     }
 }
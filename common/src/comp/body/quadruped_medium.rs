@@ -1,4 +1,4 @@
-use rand::{seq::SliceRandom, thread_rng};
+use rand::{seq::SliceRandom, thread_rng, Rng};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Body {
@@ -17,19 +17,23 @@ pub struct Body {
 
 impl Body {
     pub fn random() -> Self {
-        let mut rng = thread_rng();
+        Self::random_with(&mut thread_rng())
+    }
+
+    /// Like `random`, but draws from `rng` so that the result can be reproduced.
+    pub fn random_with(rng: &mut impl Rng) -> Self {
         Self {
-            head_upper: *(&ALL_HEADS_UPPER).choose(&mut rng).unwrap(),
-            jaw: *(&ALL_JAWS).choose(&mut rng).unwrap(),
-            head_lower: *(&ALL_HEADS_LOWER).choose(&mut rng).unwrap(),
-            tail: *(&ALL_TAILS).choose(&mut rng).unwrap(),
-            torso_back: *(&ALL_TORSOS_BACK).choose(&mut rng).unwrap(),
-            torso_mid: *(&ALL_TORSOS_MID).choose(&mut rng).unwrap(),
-            ears: *(&ALL_EARS).choose(&mut rng).unwrap(),
-            foot_lf: *(&ALL_FEETS_LF).choose(&mut rng).unwrap(),
-            foot_rf: *(&ALL_FEETS_RF).choose(&mut rng).unwrap(),
-            foot_lb: *(&ALL_FEETS_LB).choose(&mut rng).unwrap(),
-            foot_rb: *(&ALL_FEETS_RB).choose(&mut rng).unwrap(),
+            head_upper: *(&ALL_HEADS_UPPER).choose(rng).unwrap(),
+            jaw: *(&ALL_JAWS).choose(rng).unwrap(),
+            head_lower: *(&ALL_HEADS_LOWER).choose(rng).unwrap(),
+            tail: *(&ALL_TAILS).choose(rng).unwrap(),
+            torso_back: *(&ALL_TORSOS_BACK).choose(rng).unwrap(),
+            torso_mid: *(&ALL_TORSOS_MID).choose(rng).unwrap(),
+            ears: *(&ALL_EARS).choose(rng).unwrap(),
+            foot_lf: *(&ALL_FEETS_LF).choose(rng).unwrap(),
+            foot_rf: *(&ALL_FEETS_RF).choose(rng).unwrap(),
+            foot_lb: *(&ALL_FEETS_LB).choose(rng).unwrap(),
+            foot_rb: *(&ALL_FEETS_RB).choose(rng).unwrap(),
         }
     }
 }
@@ -1,4 +1,3 @@
-mod admin;
 mod agent;
 mod body;
 mod character_state;
@@ -12,7 +11,6 @@ mod stats;
 mod visual;
 
 // Reexports
-pub use admin::Admin;
 pub use agent::Agent;
 pub use body::{humanoid, object, quadruped, quadruped_medium, Body};
 pub use character_state::{ActionState, CharacterState, MovementState};
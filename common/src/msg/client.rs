@@ -1,10 +1,19 @@
 use super::ClientState;
 use crate::terrain::block::Block;
-use crate::{comp, ChatType};
+use crate::{comp, net::Superseding, ChatType};
 use vek::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMsg {
+    /// The first message of every connection. This must stay the first variant so that servers
+    /// running any version of the protocol can decode it.
+    Connect {
+        protocol_version: u32,
+    },
+    /// Asks for the server's `ServerStatus` instead of connecting. The server answers with
+    /// `ServerMsg::Status` and closes the connection. This must stay the second variant so that
+    /// server browsers can query servers running any version of the protocol.
+    StatusQuery,
     Register {
         player: comp::Player,
         password: String,
@@ -14,7 +23,11 @@ pub enum ClientMsg {
         body: comp::Body,
         main: Option<comp::item::Tool>,
     },
-    Controller(comp::Controller),
+    /// The input of a single client tick. `seq` counts up by one with every tick.
+    Controller {
+        controller: comp::Controller,
+        seq: u64,
+    },
     RequestState(ClientState),
     SetViewDistance(u32),
     BreakBlock(Vec3<i32>),
@@ -25,7 +38,9 @@ pub enum ClientMsg {
         chat_type: ChatType,
         message: String,
     },
+    /// The state of the player's character after the input with the sequence number `seq`.
     PlayerPhysics {
+        seq: u64,
         pos: comp::Pos,
         vel: comp::Vel,
         ori: comp::Ori,
@@ -40,6 +55,15 @@ pub enum ClientMsg {
     Disconnect,
 }
 
+impl Superseding for ClientMsg {
+    fn supersede_key(&self) -> Option<(u8, u64)> {
+        match self {
+            ClientMsg::PlayerPhysics { .. } => Some((0, 0)),
+            _ => None,
+        }
+    }
+}
+
 impl ClientMsg {
     pub fn chat(message: String) -> ClientMsg {
         ClientMsg::ChatMsg {
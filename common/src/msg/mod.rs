@@ -5,7 +5,13 @@ pub mod server;
 // Reexports
 pub use self::client::ClientMsg;
 pub use self::ecs_packet::{EcsCompPacket, EcsResPacket};
-pub use self::server::{RequestStateError, ServerError, ServerInfo, ServerMsg};
+pub use self::server::{RequestStateError, ServerError, ServerInfo, ServerMsg, ServerStatus};
+pub use sphynx::CompUpdateKind;
+
+/// The version of the network protocol. It must be increased whenever `ClientMsg`, `ServerMsg` or
+/// anything sent through them changes, since clients and servers of different versions can't
+/// understand each other.
+pub const PROTOCOL_VERSION: u32 = 6;
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ClientState {
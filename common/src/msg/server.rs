@@ -1,7 +1,8 @@
 use super::{ClientState, EcsCompPacket, EcsResPacket};
 use crate::{
     comp,
-    terrain::{Block, TerrainChunk},
+    net::{Priority, Superseding},
+    terrain::{Block, EncodedTerrainChunk, TerrainChunkDelta},
     ChatType,
 };
 use hashbrown::HashMap;
@@ -22,8 +23,28 @@ pub struct ServerInfo {
     pub git_hash: String,
 }
 
+/// What server browsers are told about a server, see `ClientMsg::StatusQuery`. Fields may only
+/// be added at the end, so that browsers can still decode the status of older servers.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServerStatus {
+    pub name: String,
+    pub description: String,
+    /// The number of players that have logged in.
+    pub player_count: u32,
+    pub max_players: u32,
+    pub protocol_version: u32,
+    pub git_hash: String,
+    pub world_seed: u32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMsg {
+    /// This must stay the first variant so that clients running any version of the protocol can
+    /// decode `ServerError::IncompatibleVersion`.
+    Error(ServerError),
+    /// The answer to `ClientMsg::StatusQuery`. This must stay the second variant for the same
+    /// reason.
+    Status(ServerStatus),
     InitialSync {
         ecs_state: sphynx::StatePackage<EcsCompPacket, EcsResPacket>,
         entity_uid: u64,
@@ -58,22 +79,118 @@ pub enum ServerMsg {
     InventoryUpdate(comp::Inventory),
     TerrainChunkUpdate {
         key: Vec2<i32>,
-        chunk: Box<TerrainChunk>,
+        chunk: EncodedTerrainChunk,
+    },
+    /// Updates a chunk that the client already has.
+    TerrainChunkDelta {
+        key: Vec2<i32>,
+        delta: TerrainChunkDelta,
     },
     TerrainBlockUpdates(HashMap<Vec3<i32>, Block>),
-    Error(ServerError),
     Disconnect,
-    Shutdown,
+    /// The server is shutting down and will close the connection.
+    Shutdown {
+        reason: String,
+    },
+    /// Invites the client to receive and send physics updates over UDP. Datagrams on both sides
+    /// carry `token` and are authenticated with `key`.
+    UdpOffer {
+        port: u16,
+        token: u64,
+        key: [u8; 32],
+    },
+    /// The state of the client's character that the server accepted for the input with the
+    /// sequence number `seq`.
+    InputAck {
+        seq: u64,
+        pos: comp::Pos,
+        vel: comp::Vel,
+        ori: comp::Ori,
+    },
+}
+
+impl Superseding for ServerMsg {
+    fn supersede_key(&self) -> Option<(u8, u64)> {
+        match self {
+            ServerMsg::EntityPos { entity, .. } => Some((0, *entity)),
+            ServerMsg::EntityVel { entity, .. } => Some((1, *entity)),
+            ServerMsg::EntityOri { entity, .. } => Some((2, *entity)),
+            ServerMsg::InputAck { .. } => Some((3, 0)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerError {
+    /// The client speaks a different version of the protocol than the server. This must stay the
+    /// first variant, see `ServerMsg::Error`.
+    IncompatibleVersion {
+        server: u32,
+        client: u32,
+    },
     TooManyPlayers,
     InvalidAuth,
+    /// The login token was malformed, not signed by the server's secret, or has expired.
+    InvalidToken {
+        expired: bool,
+    },
+    /// The server only admits whitelisted players and the account isn't one of them.
+    NotWhitelisted,
+    /// The account is banned. `until` is in seconds since the UNIX epoch, `None` if permanent.
+    Banned {
+        reason: String,
+        until: Option<u64>,
+    },
     //TODO: InvalidAlias,
 }
 
 impl ServerMsg {
+    /// How urgently the message has to reach the client.
+    pub fn priority(&self) -> Priority {
+        match self {
+            ServerMsg::EntityPos { .. }
+            | ServerMsg::EntityVel { .. }
+            | ServerMsg::EntityOri { .. }
+            | ServerMsg::EntityCharacterState { .. }
+            | ServerMsg::InputAck { .. } => Priority::Realtime,
+            // Block updates have to stay behind the chunks they apply to, or they could be
+            // overwritten by an older copy of the chunk.
+            ServerMsg::TerrainChunkUpdate { .. }
+            | ServerMsg::TerrainChunkDelta { .. }
+            | ServerMsg::TerrainBlockUpdates(_) => Priority::Bulk,
+            _ => Priority::Control,
+        }
+    }
+
+    /// The name of the variant, for statistics about the messages that are sent.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerMsg::Error(_) => "error",
+            ServerMsg::Status(_) => "status",
+            ServerMsg::InitialSync { .. } => "initial_sync",
+            ServerMsg::StateAnswer(_) => "state_answer",
+            ServerMsg::ForceState(_) => "force_state",
+            ServerMsg::Ping => "ping",
+            ServerMsg::Pong => "pong",
+            ServerMsg::ChatMsg { .. } => "chat_msg",
+            ServerMsg::SetPlayerEntity(_) => "set_player_entity",
+            ServerMsg::EcsSync(_) => "ecs_sync",
+            ServerMsg::EntityPos { .. } => "entity_pos",
+            ServerMsg::EntityVel { .. } => "entity_vel",
+            ServerMsg::EntityOri { .. } => "entity_ori",
+            ServerMsg::EntityCharacterState { .. } => "entity_character_state",
+            ServerMsg::InventoryUpdate(_) => "inventory_update",
+            ServerMsg::TerrainChunkUpdate { .. } => "terrain_chunk_update",
+            ServerMsg::TerrainChunkDelta { .. } => "terrain_chunk_delta",
+            ServerMsg::TerrainBlockUpdates(_) => "terrain_block_updates",
+            ServerMsg::Disconnect => "disconnect",
+            ServerMsg::Shutdown { .. } => "shutdown",
+            ServerMsg::UdpOffer { .. } => "udp_offer",
+            ServerMsg::InputAck { .. } => "input_ack",
+        }
+    }
+
     pub fn chat(message: String) -> ServerMsg {
         ServerMsg::ChatMsg {
             chat_type: ChatType::Chat,
@@ -1,13 +1,16 @@
 pub mod data;
 //pub mod post;
 pub mod post2;
+mod reactor;
+pub mod udp;
 
 pub use post2 as post;
 
 // Reexports
 pub use self::{
     data::{ClientMsg, ServerMsg},
-    post::{Error as PostError, PostBox, PostOffice},
+    post::{await_flushed, Error as PostError, PostBox, PostOffice, Priority},
+    udp::{Superseding, UdpClient, UdpHost, UdpLink},
 };
 
 pub trait PostSend = 'static + serde::Serialize + std::marker::Send + std::fmt::Debug;
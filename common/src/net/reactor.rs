@@ -0,0 +1,573 @@
+//! A single I/O thread that drives the TCP streams of every `PostBox` in the process.
+//!
+//! Postboxes hand their stream over to the thread when they are created and talk to it through a
+//! command channel from then on. The thread sleeps in `Poll::poll` until one of the streams or the
+//! command channel becomes ready, so idle connections cost nothing.
+
+use super::post2::{self, Error, Priority};
+use crossbeam::channel;
+use hashbrown::{HashMap, HashSet};
+use lazy_static::lazy_static;
+use log::{error, warn};
+use mio::{net::TcpStream, Events, Poll, PollOpt, Ready, Token};
+use mio_extras::channel::{self as mio_channel, Receiver, Sender};
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::TryRecvError,
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const COMMAND_TOKEN: Token = Token(0);
+/// How long a dropped `PostBox` may spend sending messages that are still queued.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref COMMAND_TX: Mutex<Sender<Command>> = Mutex::new(spawn());
+}
+
+static NEXT_TOKEN: AtomicUsize = AtomicUsize::new(COMMAND_TOKEN.0 + 1);
+
+/// The bytes that were written to and read from a stream, including the framing of each message,
+/// since the postbox last took them.
+#[derive(Default)]
+struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+enum Command {
+    Register {
+        token: Token,
+        stream: TcpStream,
+        recv_tx: channel::Sender<Result<Vec<u8>, Error>>,
+        queued: Arc<[AtomicUsize; 3]>,
+        traffic: Arc<Traffic>,
+    },
+    /// Send a serialized message.
+    Send {
+        token: Token,
+        msg_bytes: Vec<u8>,
+        priority: Priority,
+    },
+    SetBandwidthLimit {
+        token: Token,
+        bytes_per_sec: Option<u32>,
+    },
+    /// Flush what is still queued, then close the stream.
+    Close { token: Token },
+    /// Signal `done` once every closed stream has been flushed (or given up on).
+    AwaitFlushed { done: channel::Sender<()> },
+}
+
+/// A postbox's connection to the I/O thread. Dropping it closes the stream once everything that
+/// was sent through it has been written (or `FLUSH_TIMEOUT` has passed), without waiting for that
+/// to happen.
+pub(super) struct Handle {
+    token: Token,
+    cmd_tx: Sender<Command>,
+    /// The number of messages waiting to be sent, indexed by priority.
+    queued: Arc<[AtomicUsize; 3]>,
+    traffic: Arc<Traffic>,
+}
+
+impl Handle {
+    /// Hand `stream` over to the I/O thread. Received messages, or the error that ended the
+    /// connection, are delivered through `recv_tx`.
+    pub fn register(
+        stream: std::net::TcpStream,
+        recv_tx: channel::Sender<Result<Vec<u8>, Error>>,
+    ) -> Result<Self, Error> {
+        let stream = TcpStream::from_stream(stream)?;
+        let token = Token(NEXT_TOKEN.fetch_add(1, Ordering::Relaxed));
+        let cmd_tx = COMMAND_TX.lock().clone();
+        let queued = Arc::<[AtomicUsize; 3]>::default();
+        let traffic = Arc::<Traffic>::default();
+
+        cmd_tx
+            .send(Command::Register {
+                token,
+                stream,
+                recv_tx,
+                queued: queued.clone(),
+                traffic: traffic.clone(),
+            })
+            .map_err(|_| Error::ChannelFailure)?;
+
+        Ok(Self {
+            token,
+            cmd_tx,
+            queued,
+            traffic,
+        })
+    }
+
+    pub fn send(&self, msg_bytes: Vec<u8>, priority: Priority) {
+        self.queued[priority as usize].fetch_add(1, Ordering::Relaxed);
+        let _ = self.cmd_tx.send(Command::Send {
+            token: self.token,
+            msg_bytes,
+            priority,
+        });
+    }
+
+    pub fn set_bandwidth_limit(&self, bytes_per_sec: Option<u32>) {
+        let _ = self.cmd_tx.send(Command::SetBandwidthLimit {
+            token: self.token,
+            bytes_per_sec,
+        });
+    }
+
+    pub fn queue_depth(&self, priority: Priority) -> usize {
+        self.queued[priority as usize].load(Ordering::Relaxed)
+    }
+
+    /// The bytes sent and received since the last call.
+    pub fn take_traffic(&self) -> (u64, u64) {
+        (
+            self.traffic.sent.swap(0, Ordering::Relaxed),
+            self.traffic.received.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let _ = self.cmd_tx.send(Command::Close { token: self.token });
+    }
+}
+
+/// Wait until the streams of dropped postboxes have been flushed, or until `timeout` has passed.
+/// Returns whether they were flushed.
+pub(super) fn await_flushed(timeout: Duration) -> bool {
+    let (done_tx, done_rx) = channel::bounded(1);
+    let sent = COMMAND_TX
+        .lock()
+        .send(Command::AwaitFlushed { done: done_tx });
+    sent.is_ok() && done_rx.recv_timeout(timeout).is_ok()
+}
+
+fn spawn() -> Sender<Command> {
+    let (cmd_tx, cmd_rx) = mio_channel::channel();
+
+    thread::Builder::new()
+        .name("postbox-io".to_string())
+        .spawn(move || {
+            if let Err(err) = Reactor::new(cmd_rx).and_then(Reactor::run) {
+                error!("Network I/O thread failed: {:?}", err);
+            }
+        })
+        .expect("Failed to spawn the network I/O thread");
+
+    cmd_tx
+}
+
+/// Limits the rate at which bulk messages are sent. This is a token bucket that holds up to a
+/// second's worth of bytes.
+struct Bandwidth {
+    bytes_per_sec: f64,
+    allowance: f64,
+    last_update: Instant,
+}
+
+impl Bandwidth {
+    fn new(bytes_per_sec: u32) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            allowance: bytes_per_sec,
+            last_update: Instant::now(),
+        }
+    }
+
+    fn update(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        self.allowance = (self.allowance + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.last_update = now;
+    }
+
+    /// Count bytes that were sent, whatever their priority. The debt is capped at a second's worth
+    /// so that a burst of more urgent messages can't hold back bulk ones for long.
+    fn consume(&mut self, bytes: usize) {
+        self.allowance = (self.allowance - bytes as f64).max(-self.bytes_per_sec);
+    }
+
+    fn allows_bulk(&self) -> bool {
+        self.allowance > 0.0
+    }
+
+    /// When bulk messages may be sent again.
+    fn bulk_allowed_at(&self) -> Instant {
+        if self.allows_bulk() {
+            self.last_update
+        } else {
+            self.last_update + Duration::from_secs_f64((1.0 - self.allowance) / self.bytes_per_sec)
+        }
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    recv_tx: channel::Sender<Result<Vec<u8>, Error>>,
+    incoming_buf: Vec<u8>,
+    /// Packets waiting to be sent, indexed by priority.
+    outgoing_packets: [VecDeque<Vec<u8>>; 3],
+    /// The rest of a packet that was only partially written, which has to go out before any other.
+    partial_packet: Option<(Priority, Vec<u8>)>,
+    queued: Arc<[AtomicUsize; 3]>,
+    traffic: Arc<Traffic>,
+    bandwidth: Option<Bandwidth>,
+    /// Whether the last write would have blocked, so that sending has to wait until the stream
+    /// becomes writable again.
+    blocked: bool,
+    /// When to give up on flushing, set once the postbox was dropped.
+    closing: Option<Instant>,
+}
+
+impl Connection {
+    fn new(
+        stream: TcpStream,
+        recv_tx: channel::Sender<Result<Vec<u8>, Error>>,
+        queued: Arc<[AtomicUsize; 3]>,
+        traffic: Arc<Traffic>,
+    ) -> Self {
+        Self {
+            stream,
+            recv_tx,
+            incoming_buf: Vec::new(),
+            outgoing_packets: Default::default(),
+            partial_packet: None,
+            queued,
+            traffic,
+            bandwidth: None,
+            blocked: false,
+            closing: None,
+        }
+    }
+
+    /// Take the next packet to send, most urgent first.
+    fn next_packet(&mut self) -> Option<(Priority, Vec<u8>)> {
+        // Don't hold anything back once the postbox is gone.
+        let bulk_allowed = self.closing.is_some()
+            || self
+                .bandwidth
+                .as_ref()
+                .map(Bandwidth::allows_bulk)
+                .unwrap_or(true);
+        let outgoing_packets = &mut self.outgoing_packets;
+
+        Priority::ALL
+            .iter()
+            .filter(|priority| **priority != Priority::Bulk || bulk_allowed)
+            .find_map(|priority| {
+                outgoing_packets[*priority as usize]
+                    .pop_front()
+                    .map(|packet| (*priority, packet))
+            })
+    }
+
+    /// Write queued packets until the stream would block or the bandwidth limit is reached.
+    fn write(&mut self) -> io::Result<()> {
+        self.blocked = false;
+        if let Some(bandwidth) = &mut self.bandwidth {
+            bandwidth.update(Instant::now());
+        }
+
+        loop {
+            let (priority, mut packet) =
+                match self.partial_packet.take().or_else(|| self.next_packet()) {
+                    Some(next) => next,
+                    None => return Ok(()),
+                };
+
+            match self.stream.write(&packet) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.traffic.sent.fetch_add(n as u64, Ordering::Relaxed);
+                    if let Some(bandwidth) = &mut self.bandwidth {
+                        bandwidth.consume(n);
+                    }
+                    if n < packet.len() {
+                        self.partial_packet = Some((priority, packet.split_off(n)));
+                    } else {
+                        self.queued[priority as usize].fetch_sub(1, Ordering::Relaxed);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    self.partial_packet = Some((priority, packet))
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // Try again once the stream is writable.
+                    self.partial_packet = Some((priority, packet));
+                    self.blocked = true;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Whether everything that was queued has been written.
+    fn is_flushed(&self) -> bool {
+        self.partial_packet.is_none() && self.outgoing_packets.iter().all(VecDeque::is_empty)
+    }
+
+    /// When bulk messages that are held back by the bandwidth limit may be sent.
+    fn throttled_until(&self) -> Option<Instant> {
+        if self.blocked || self.outgoing_packets[Priority::Bulk as usize].is_empty() {
+            return None;
+        }
+        self.bandwidth.as_ref().map(Bandwidth::bulk_allowed_at)
+    }
+
+    /// Read until the stream would block, passing on every complete message.
+    fn read(&mut self) -> Result<(), Error> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "Connection closed by peer",
+                    )
+                    .into())
+                }
+                Ok(n) => {
+                    self.traffic.received.fetch_add(n as u64, Ordering::Relaxed);
+                    self.incoming_buf.extend_from_slice(&buf[0..n]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+
+            // Parse as we go so that a fast sender can't make the buffer grow without bounds.
+            while let Some(msg_bytes) = post2::next_incoming(&mut self.incoming_buf)? {
+                let _ = self.recv_tx.send(Ok(msg_bytes));
+            }
+        }
+    }
+}
+
+struct Reactor {
+    poll: Poll,
+    commands: Receiver<Command>,
+    connections: HashMap<Token, Connection>,
+    /// Waiting for every closing connection to be gone.
+    flush_waiters: Vec<channel::Sender<()>>,
+}
+
+impl Reactor {
+    fn new(commands: Receiver<Command>) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        poll.register(&commands, COMMAND_TOKEN, Ready::readable(), PollOpt::edge())?;
+
+        Ok(Self {
+            poll,
+            commands,
+            connections: HashMap::new(),
+            flush_waiters: Vec::new(),
+        })
+    }
+
+    fn run(mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            // Wake up in time to send throttled messages and to give up on connections that
+            // can't be flushed.
+            let now = Instant::now();
+            let timeout = self
+                .connections
+                .values()
+                .flat_map(|conn| conn.closing.into_iter().chain(conn.throttled_until()))
+                .map(|deadline| {
+                    if deadline > now {
+                        deadline - now
+                    } else {
+                        Duration::from_secs(0)
+                    }
+                })
+                .min();
+
+            match self.poll.poll(&mut events, timeout) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            };
+
+            for event in &events {
+                match event.token() {
+                    COMMAND_TOKEN => self.handle_commands(),
+                    token => {
+                        if event.readiness().is_writable() {
+                            self.flush(token);
+                        }
+                        if event.readiness().is_readable() {
+                            self.receive(token);
+                        }
+                    }
+                }
+            }
+
+            self.handle_timeouts();
+        }
+    }
+
+    fn handle_commands(&mut self) {
+        let mut dirty = HashSet::new();
+
+        loop {
+            match self.commands.try_recv() {
+                Ok(Command::Register {
+                    token,
+                    stream,
+                    recv_tx,
+                    queued,
+                    traffic,
+                }) => {
+                    let registered = self.poll.register(
+                        &stream,
+                        token,
+                        Ready::readable() | Ready::writable(),
+                        PollOpt::edge(),
+                    );
+                    match registered {
+                        Ok(()) => {
+                            self.connections
+                                .insert(token, Connection::new(stream, recv_tx, queued, traffic));
+                        }
+                        Err(err) => {
+                            let _ = recv_tx.send(Err(err.into()));
+                        }
+                    }
+                }
+                Ok(Command::Send {
+                    token,
+                    msg_bytes,
+                    priority,
+                }) => {
+                    if let Some(conn) = self.connections.get_mut(&token) {
+                        conn.outgoing_packets[priority as usize]
+                            .push_back(post2::packet_from(&msg_bytes));
+                        dirty.insert(token);
+                    }
+                }
+                Ok(Command::SetBandwidthLimit {
+                    token,
+                    bytes_per_sec,
+                }) => {
+                    if let Some(conn) = self.connections.get_mut(&token) {
+                        conn.bandwidth = bytes_per_sec.map(Bandwidth::new);
+                        dirty.insert(token);
+                    }
+                }
+                Ok(Command::Close { token }) => {
+                    if let Some(conn) = self.connections.get_mut(&token) {
+                        conn.closing = Some(Instant::now() + FLUSH_TIMEOUT);
+                        dirty.insert(token);
+                    }
+                }
+                Ok(Command::AwaitFlushed { done }) => self.flush_waiters.push(done),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+            }
+        }
+
+        // Write once per connection, after all of its new messages were queued.
+        for token in dirty {
+            self.flush(token);
+        }
+        self.notify_flushed();
+    }
+
+    fn flush(&mut self, token: Token) {
+        let (result, flushed) = match self.connections.get_mut(&token) {
+            Some(conn) => (conn.write(), conn.closing.is_some() && conn.is_flushed()),
+            None => return,
+        };
+
+        match result {
+            Err(err) => self.fail(token, err.into()),
+            Ok(()) if flushed => self.close(token),
+            Ok(()) => {}
+        }
+    }
+
+    fn receive(&mut self, token: Token) {
+        let result = match self.connections.get_mut(&token) {
+            // Nobody is listening any more.
+            Some(conn) if conn.closing.is_some() => return,
+            Some(conn) => conn.read(),
+            None => return,
+        };
+
+        if let Err(err) = result {
+            self.fail(token, err);
+        }
+    }
+
+    /// Drop a connection that failed, telling its postbox why.
+    fn fail(&mut self, token: Token, err: Error) {
+        if let Some(conn) = self.connections.get(&token) {
+            if conn.closing.is_none() {
+                let _ = conn.recv_tx.send(Err(err));
+            } else {
+                warn!("Failed to flush TCP stream before shutdown: {:?}", err);
+            }
+        }
+        self.close(token);
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(conn) = self.connections.remove(&token) {
+            let _ = self.poll.deregister(&conn.stream);
+            if let Err(err) = conn.stream.shutdown(Shutdown::Both) {
+                warn!("TCP stream shutdown failed: {:?}", err);
+            }
+            if conn.closing.is_some() {
+                self.notify_flushed();
+            }
+        }
+    }
+
+    fn notify_flushed(&mut self) {
+        if self.flush_waiters.is_empty()
+            || self.connections.values().any(|conn| conn.closing.is_some())
+        {
+            return;
+        }
+        for done in self.flush_waiters.drain(..) {
+            let _ = done.send(());
+        }
+    }
+
+    fn handle_timeouts(&mut self) {
+        let now = Instant::now();
+        let throttled = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.throttled_until().map_or(false, |at| at <= now))
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        for token in throttled {
+            self.flush(token);
+        }
+
+        let expired = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.closing.map_or(false, |deadline| deadline <= now))
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        for token in expired {
+            warn!("Failed to flush TCP stream before shutdown: timed out");
+            self.close(token);
+        }
+    }
+}
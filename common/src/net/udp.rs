@@ -0,0 +1,915 @@
+//! An optional UDP side channel for messages that are superseded by the next one of their kind,
+//! such as positions. Sending those over TCP means that a single lost packet holds back every
+//! later update until it has been retransmitted.
+//!
+//! The server hands each client a token and a secret key over the reliable connection. Every
+//! datagram carries the token, a counter that is never repeated and a MAC keyed with the secret,
+//! so that nobody else can inject or replay datagrams, whatever address they send them from. The
+//! client says `Hello` so that the server learns its address, and the server confirms with
+//! `Welcome`. Datagrams carry sequence numbers, and a message is dropped if a newer one with the
+//! same key has already arrived. Since updates are only sent when something changes, the latest
+//! message for each key is repeated a few times in case it gets lost.
+//!
+//! If the handshake fails or nothing arrives for a while, both sides fall back to sending these
+//! messages over the `PostBox` again.
+
+use super::post2::{Error, PostMsg};
+use crossbeam::channel;
+use hashbrown::HashMap;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+use std::{
+    convert::TryFrom,
+    hash::Hash,
+    io,
+    marker::PhantomData,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Keeps datagrams below the typical path MTU, so that they don't get fragmented.
+const MAX_DATAGRAM_SIZE: usize = 1200;
+/// How often the latest message for each key is sent.
+const REDUNDANCY: u8 = 3;
+const HELLO_INTERVAL: Duration = Duration::from_millis(250);
+/// How long the client tries to reach the server before falling back to TCP.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long either side waits for a datagram before falling back to TCP.
+const LINK_TIMEOUT: Duration = Duration::from_secs(3);
+const TAG_SIZE: usize = 32;
+/// The token, the counter and the MAC that precede the body of each datagram.
+const HEADER_SIZE: usize = 8 + 8 + TAG_SIZE;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Messages that may be sent over the UDP side channel.
+pub trait Superseding {
+    /// A message is only relevant until the next one with the same key arrives. Messages that
+    /// have to be delivered reliably return `None`.
+    fn supersede_key(&self) -> Option<(u8, u64)>;
+}
+
+#[derive(Serialize, Deserialize)]
+enum Datagram<M> {
+    Hello,
+    Welcome,
+    Keepalive,
+    Msgs { seq: u64, msgs: Vec<M> },
+}
+
+/// What is needed to authenticate the datagrams of one link, in both directions.
+struct Credentials {
+    token: u64,
+    key: [u8; 32],
+    /// Whether this is the server's end of the link, so that datagrams can't be reflected back to
+    /// their sender.
+    is_server: bool,
+    next_counter: u64,
+    /// The counter of the newest datagram that was accepted.
+    last_counter: Option<u64>,
+    /// Which of the 64 counters up to `last_counter` have been seen, so that datagrams that
+    /// arrive out of order aren't mistaken for replays.
+    seen: u64,
+}
+
+impl Credentials {
+    fn new(token: u64, key: [u8; 32], is_server: bool) -> Self {
+        Self {
+            token,
+            key,
+            is_server,
+            next_counter: 0,
+            last_counter: None,
+            seen: 0,
+        }
+    }
+
+    fn mac(&self, from_server: bool, counter: u64, body: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(&self.key).expect("HMAC accepts keys of any size");
+        mac.input(&[from_server as u8]);
+        mac.input(&self.token.to_le_bytes());
+        mac.input(&counter.to_le_bytes());
+        mac.input(body);
+        mac
+    }
+
+    /// Serialize `datagram` and put the header in front of it.
+    fn seal<M: Serialize>(&mut self, datagram: &Datagram<M>) -> Option<Vec<u8>> {
+        let body = match bincode::serialize(datagram) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Failed to serialize datagram: {:?}", err);
+                return None;
+            }
+        };
+        let counter = self.next_counter;
+        self.next_counter += 1;
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+        bytes.extend_from_slice(&self.token.to_le_bytes());
+        bytes.extend_from_slice(&counter.to_le_bytes());
+        bytes.extend_from_slice(&self.mac(self.is_server, counter, &body).result().code());
+        bytes.extend_from_slice(&body);
+        Some(bytes)
+    }
+
+    /// Check that `bytes` were sealed by the other end of the link and haven't been seen before,
+    /// and deserialize the datagram.
+    fn open<R: DeserializeOwned>(&mut self, bytes: &[u8]) -> Option<Datagram<R>> {
+        if token_of(bytes) != Some(self.token) {
+            return None;
+        }
+        let counter = u64::from_le_bytes(<[u8; 8]>::try_from(&bytes[8..16]).ok()?);
+        let age = match self.last_counter {
+            Some(last) if counter <= last => Some(last - counter),
+            _ => None,
+        };
+        if age.map_or(false, |age| age >= 64 || self.seen & (1 << age) != 0) {
+            return None;
+        }
+        let (tag, body) = bytes[16..].split_at(TAG_SIZE);
+        self.mac(!self.is_server, counter, body).verify(tag).ok()?;
+
+        match age {
+            Some(age) => self.seen |= 1 << age,
+            None => {
+                let shift = self.last_counter.map_or(64, |last| counter - last);
+                self.seen = (if shift < 64 { self.seen << shift } else { 0 }) | 1;
+                self.last_counter = Some(counter);
+            }
+        }
+        bincode::deserialize(body).ok()
+    }
+}
+
+/// The token of the link that a datagram claims to belong to.
+fn token_of(bytes: &[u8]) -> Option<u64> {
+    if bytes.len() < HEADER_SIZE {
+        return None;
+    }
+    <[u8; 8]>::try_from(&bytes[0..8])
+        .ok()
+        .map(u64::from_le_bytes)
+}
+
+fn bind_nonblocking(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Receive every datagram that is waiting on `socket`. Returns them along with the number of
+/// bytes received.
+fn recv_all(socket: &UdpSocket) -> (Vec<(SocketAddr, Vec<u8>)>, u64) {
+    let mut datagrams = Vec::new();
+    let mut bytes = 0;
+    let mut buf = [0; 65536];
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((n, addr)) => {
+                bytes += n as u64;
+                datagrams.push((addr, buf[0..n].to_vec()));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            // Some platforms report that an earlier datagram couldn't be delivered.
+            Err(e)
+                if e.kind() == io::ErrorKind::Interrupted
+                    || e.kind() == io::ErrorKind::ConnectionReset
+                    || e.kind() == io::ErrorKind::ConnectionRefused => {}
+            Err(e) => {
+                warn!("Failed to receive datagram: {:?}", e);
+                break;
+            }
+        }
+    }
+    (datagrams, bytes)
+}
+
+/// Send `bytes`, dropping them if the socket isn't ready. They are sent again anyway. Returns the
+/// number of bytes that were sent.
+fn send_to(socket: &UdpSocket, bytes: &[u8], addr: SocketAddr) -> u64 {
+    match socket.send_to(bytes, addr) {
+        Ok(n) => n as u64,
+        Err(err) => {
+            if err.kind() != io::ErrorKind::WouldBlock {
+                debug!("Failed to send datagram to {}: {:?}", addr, err);
+            }
+            0
+        }
+    }
+}
+
+/// The sending half of a link.
+struct Outgoing<S> {
+    next_seq: u64,
+    /// The latest message for each key and how many more times to send it.
+    latest: HashMap<(u8, u64), (S, u8)>,
+    last_sent: Instant,
+}
+
+impl<S: PostMsg> Outgoing<S> {
+    fn new() -> Self {
+        Self {
+            next_seq: 0,
+            latest: HashMap::new(),
+            last_sent: Instant::now(),
+        }
+    }
+
+    fn push(&mut self, key: (u8, u64), msg: S) {
+        self.latest.insert(key, (msg, REDUNDANCY));
+    }
+
+    /// Pack the pending messages into datagrams, or a keepalive if it is due.
+    fn take_datagrams(&mut self, credentials: &mut Credentials) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        let next_seq = &mut self.next_seq;
+        let mut seal = |msgs: Vec<&S>| {
+            let datagram = Datagram::Msgs {
+                seq: *next_seq,
+                msgs,
+            };
+            *next_seq += 1;
+            credentials.seal(&datagram)
+        };
+
+        let mut batch = Vec::new();
+        let mut batch_size = 0;
+        for (msg, _) in self.latest.values() {
+            let size = bincode::serialized_size(msg).unwrap_or(0) as usize;
+            if !batch.is_empty() && batch_size + size > MAX_DATAGRAM_SIZE {
+                datagrams.extend(seal(std::mem::replace(&mut batch, Vec::new())));
+                batch_size = 0;
+            }
+            batch.push(msg);
+            batch_size += size;
+        }
+        if !batch.is_empty() {
+            datagrams.extend(seal(batch));
+        }
+
+        for (_, remaining) in self.latest.values_mut() {
+            *remaining -= 1;
+        }
+        self.latest.retain(|_, (_, remaining)| *remaining > 0);
+
+        if datagrams.is_empty() && self.last_sent.elapsed() > KEEPALIVE_INTERVAL {
+            datagrams.extend(credentials.seal(&Datagram::<S>::Keepalive));
+        }
+        if !datagrams.is_empty() {
+            self.last_sent = Instant::now();
+        }
+        datagrams
+    }
+}
+
+/// The receiving half of a link.
+struct Incoming {
+    /// The sequence number of the newest message that arrived for each key.
+    last_seqs: HashMap<(u8, u64), u64>,
+    last_recv: Instant,
+}
+
+impl Incoming {
+    fn new() -> Self {
+        Self {
+            last_seqs: HashMap::new(),
+            last_recv: Instant::now(),
+        }
+    }
+
+    /// Filter out messages that arrived after a newer one with the same key.
+    fn accept<R: Superseding>(&mut self, seq: u64, msgs: Vec<R>) -> Vec<R> {
+        let last_seqs = &mut self.last_seqs;
+        msgs.into_iter()
+            .filter(|msg| match msg.supersede_key() {
+                Some(key) => {
+                    let last_seq = last_seqs.entry(key).or_insert(seq);
+                    if *last_seq > seq {
+                        return false;
+                    }
+                    *last_seq = seq;
+                    true
+                }
+                // Reliable messages have no business on this channel.
+                None => false,
+            })
+            .collect()
+    }
+
+    fn timed_out(&self) -> bool {
+        self.last_recv.elapsed() > LINK_TIMEOUT
+    }
+}
+
+enum LinkEvent<I, S> {
+    Send(I, S),
+    Closed(I),
+}
+
+/// Sends messages to one peer of a `UdpHost`. Dropping it removes the peer.
+pub struct UdpLink<I: Copy, S> {
+    id: I,
+    connected: Arc<AtomicBool>,
+    event_tx: channel::Sender<LinkEvent<I, S>>,
+}
+
+impl<I: Copy, S: Superseding> UdpLink<I, S> {
+    /// Whether the peer can currently be reached over UDP.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Queue `msg` to be sent with the next `UdpHost::flush`. The message is handed back if it
+    /// has to be sent over the reliable connection instead.
+    pub fn send(&self, msg: S) -> Result<(), S> {
+        if !self.is_connected() || msg.supersede_key().is_none() {
+            return Err(msg);
+        }
+        self.event_tx
+            .send(LinkEvent::Send(self.id, msg))
+            .map_err(|err| match err.into_inner() {
+                LinkEvent::Send(_, msg) => msg,
+                LinkEvent::Closed(_) => unreachable!(),
+            })
+    }
+}
+
+impl<I: Copy, S> Drop for UdpLink<I, S> {
+    fn drop(&mut self) {
+        let _ = self.event_tx.send(LinkEvent::Closed(self.id));
+    }
+}
+
+struct Peer<S> {
+    credentials: Credentials,
+    addr: Option<SocketAddr>,
+    connected: Arc<AtomicBool>,
+    outgoing: Outgoing<S>,
+    incoming: Incoming,
+}
+
+/// The server's end of the side channel, shared by all of its peers.
+pub struct UdpHost<I: Copy + Eq + Hash, S: PostMsg + Superseding, R: PostMsg + Superseding> {
+    socket: UdpSocket,
+    port: u16,
+    event_tx: channel::Sender<LinkEvent<I, S>>,
+    event_rx: channel::Receiver<LinkEvent<I, S>>,
+    peers: HashMap<I, Peer<S>>,
+    tokens: HashMap<u64, I>,
+    /// The bytes sent and received since the last call to `take_traffic`.
+    traffic: (u64, u64),
+    phantom: PhantomData<R>,
+}
+
+impl<I: Copy + Eq + Hash, S: PostMsg + Superseding, R: PostMsg + Superseding> UdpHost<I, S, R> {
+    pub fn bind<A: Into<SocketAddr>>(addr: A) -> Result<Self, Error> {
+        let socket = bind_nonblocking(addr.into())?;
+        let port = socket.local_addr()?.port();
+        let (event_tx, event_rx) = channel::unbounded();
+
+        Ok(Self {
+            socket,
+            port,
+            event_tx,
+            event_rx,
+            peers: HashMap::new(),
+            tokens: HashMap::new(),
+            traffic: (0, 0),
+            phantom: PhantomData,
+        })
+    }
+
+    /// The port that peers have to send their datagrams to.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The bytes sent and received since the last call.
+    pub fn take_traffic(&mut self) -> (u64, u64) {
+        std::mem::replace(&mut self.traffic, (0, 0))
+    }
+
+    /// Prepare the side channel for a new peer. Returns the token and the key that the peer has
+    /// to authenticate its datagrams with, which have to be passed on over the reliable
+    /// connection, and the link that messages to the peer are sent through.
+    pub fn add_peer(&mut self, id: I) -> (u64, [u8; 32], UdpLink<I, S>) {
+        let mut token = rand::random();
+        while self.tokens.contains_key(&token) {
+            token = rand::random();
+        }
+        let key = rand::random();
+
+        let connected = Arc::new(AtomicBool::new(false));
+        self.tokens.insert(token, id);
+        self.peers.insert(
+            id,
+            Peer {
+                credentials: Credentials::new(token, key, true),
+                addr: None,
+                connected: connected.clone(),
+                outgoing: Outgoing::new(),
+                incoming: Incoming::new(),
+            },
+        );
+
+        let link = UdpLink {
+            id,
+            connected,
+            event_tx: self.event_tx.clone(),
+        };
+        (token, key, link)
+    }
+
+    /// Receive the messages that arrived since the last call, and handle handshakes and
+    /// timeouts.
+    pub fn receive(&mut self) -> Vec<(I, R)> {
+        self.handle_link_events();
+
+        let mut received = Vec::new();
+        let (datagrams, bytes) = recv_all(&self.socket);
+        self.traffic.1 += bytes;
+        for (addr, bytes) in datagrams {
+            let id = match token_of(&bytes).and_then(|token| self.tokens.get(&token)) {
+                Some(id) => *id,
+                None => continue,
+            };
+            let peer = self.peers.get_mut(&id).unwrap(); // Tokens are only kept for peers
+
+            // Garbage and forgeries are simply ignored, like a lost datagram.
+            let datagram = match peer.credentials.open::<R>(&bytes) {
+                Some(datagram) => datagram,
+                None => continue,
+            };
+            match datagram {
+                Datagram::Hello => {
+                    if peer.addr != Some(addr) {
+                        debug!("UDP link to {} established", addr);
+                        peer.addr = Some(addr);
+                        peer.incoming = Incoming::new();
+                        peer.connected.store(true, Ordering::Relaxed);
+                    }
+                    peer.incoming.last_recv = Instant::now();
+                    if let Some(bytes) = peer.credentials.seal(&Datagram::<S>::Welcome) {
+                        self.traffic.0 += send_to(&self.socket, &bytes, addr);
+                    }
+                }
+                // Only the address that said `Hello` last is talked to.
+                _ if peer.addr != Some(addr) => {}
+                Datagram::Msgs { seq, msgs } => {
+                    peer.incoming.last_recv = Instant::now();
+                    received.extend(
+                        peer.incoming
+                            .accept(seq, msgs)
+                            .into_iter()
+                            .map(|msg| (id, msg)),
+                    );
+                }
+                _ => peer.incoming.last_recv = Instant::now(),
+            }
+        }
+
+        // Fall back to TCP for peers that went quiet. They may say `Hello` again later.
+        for peer in self.peers.values_mut() {
+            if let Some(addr) = peer.addr.filter(|_| peer.incoming.timed_out()) {
+                debug!("UDP link to {} timed out", addr);
+                peer.addr = None;
+                peer.connected.store(false, Ordering::Relaxed);
+                peer.outgoing = Outgoing::new();
+            }
+        }
+
+        received
+    }
+
+    /// Send the messages that were queued through the links.
+    pub fn flush(&mut self) {
+        self.handle_link_events();
+
+        for peer in self.peers.values_mut() {
+            if let Some(addr) = peer.addr {
+                for bytes in peer.outgoing.take_datagrams(&mut peer.credentials) {
+                    self.traffic.0 += send_to(&self.socket, &bytes, addr);
+                }
+            }
+        }
+    }
+
+    fn handle_link_events(&mut self) {
+        for event in self.event_rx.try_iter() {
+            match event {
+                LinkEvent::Send(id, msg) => {
+                    if let (Some(peer), Some(key)) = (self.peers.get_mut(&id), msg.supersede_key())
+                    {
+                        if peer.addr.is_some() {
+                            peer.outgoing.push(key, msg);
+                        }
+                    }
+                }
+                LinkEvent::Closed(id) => {
+                    if let Some(peer) = self.peers.remove(&id) {
+                        self.tokens.remove(&peer.credentials.token);
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum LinkState {
+    Connecting {
+        since: Instant,
+        last_hello: Option<Instant>,
+    },
+    Connected,
+    /// The server couldn't be reached, so everything goes over TCP.
+    Unavailable,
+}
+
+/// The client's end of the side channel.
+pub struct UdpClient<S: PostMsg + Superseding, R: PostMsg + Superseding> {
+    socket: UdpSocket,
+    server: SocketAddr,
+    credentials: Credentials,
+    state: LinkState,
+    outgoing: Outgoing<S>,
+    incoming: Incoming,
+    phantom: PhantomData<R>,
+}
+
+impl<S: PostMsg + Superseding, R: PostMsg + Superseding> UdpClient<S, R> {
+    /// Start the handshake with the server at `server`, using the token and key it sent over the
+    /// reliable connection.
+    pub fn connect(server: SocketAddr, token: u64, key: [u8; 32]) -> Result<Self, Error> {
+        let unspecified = match server.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = bind_nonblocking(SocketAddr::new(unspecified, 0))?;
+
+        Ok(Self {
+            socket,
+            server,
+            credentials: Credentials::new(token, key, false),
+            state: LinkState::Connecting {
+                since: Instant::now(),
+                last_hello: None,
+            },
+            outgoing: Outgoing::new(),
+            incoming: Incoming::new(),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Whether the server can currently be reached over UDP.
+    pub fn is_connected(&self) -> bool {
+        match self.state {
+            LinkState::Connected => true,
+            _ => false,
+        }
+    }
+
+    /// Queue `msg` to be sent with the next `flush`. The message is handed back if it has to be
+    /// sent over the reliable connection instead.
+    pub fn send(&mut self, msg: S) -> Result<(), S> {
+        match msg.supersede_key() {
+            Some(key) if self.is_connected() => {
+                self.outgoing.push(key, msg);
+                Ok(())
+            }
+            _ => Err(msg),
+        }
+    }
+
+    /// Receive the messages that arrived since the last call, and handle the handshake and
+    /// timeouts.
+    pub fn receive(&mut self) -> Vec<R> {
+        let mut received = Vec::new();
+        for (addr, bytes) in recv_all(&self.socket).0 {
+            if addr != self.server {
+                continue;
+            }
+            let datagram = match self.credentials.open::<R>(&bytes) {
+                Some(datagram) => datagram,
+                None => continue,
+            };
+            match datagram {
+                Datagram::Welcome => {
+                    if let LinkState::Connecting { .. } = self.state {
+                        info!("Connected to {} over UDP", self.server);
+                        self.state = LinkState::Connected;
+                        self.incoming = Incoming::new();
+                        self.outgoing = Outgoing::new();
+                    }
+                }
+                Datagram::Msgs { seq, msgs } if self.is_connected() => {
+                    received.extend(self.incoming.accept(seq, msgs));
+                }
+                _ => {}
+            }
+            self.incoming.last_recv = Instant::now();
+        }
+
+        match self.state {
+            LinkState::Connecting { since, .. } if since.elapsed() > CONNECT_TIMEOUT => {
+                info!("Server can't be reached over UDP, falling back to TCP");
+                self.state = LinkState::Unavailable;
+            }
+            LinkState::Connecting { last_hello, .. } => {
+                if last_hello.map_or(true, |at| at.elapsed() > HELLO_INTERVAL) {
+                    if let Some(bytes) = self.credentials.seal(&Datagram::<S>::Hello) {
+                        send_to(&self.socket, &bytes, self.server);
+                    }
+                    if let LinkState::Connecting { last_hello, .. } = &mut self.state {
+                        *last_hello = Some(Instant::now());
+                    }
+                }
+            }
+            LinkState::Connected if self.incoming.timed_out() => {
+                warn!("Lost the UDP link to the server, falling back to TCP");
+                self.state = LinkState::Connecting {
+                    since: Instant::now(),
+                    last_hello: None,
+                };
+            }
+            LinkState::Connected | LinkState::Unavailable => {}
+        }
+
+        received
+    }
+
+    /// Send the queued messages.
+    pub fn flush(&mut self) {
+        if self.is_connected() {
+            for bytes in self.outgoing.take_datagrams(&mut self.credentials) {
+                send_to(&self.socket, &bytes, self.server);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::thread;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Update {
+        key: Option<u64>,
+        value: u64,
+    }
+
+    impl Superseding for Update {
+        fn supersede_key(&self) -> Option<(u8, u64)> {
+            self.key.map(|key| (0, key))
+        }
+    }
+
+    fn update(value: u64) -> Update {
+        Update {
+            key: Some(0),
+            value,
+        }
+    }
+
+    type Host = UdpHost<u32, Update, Update>;
+    type Client = UdpClient<Update, Update>;
+
+    fn create_host() -> (Host, SocketAddr) {
+        let host = Host::bind(([127, 0, 0, 1], 0)).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], host.port()));
+        (host, addr)
+    }
+
+    /// Forward datagrams between `server` and the first client that sends something, dropping a
+    /// fraction `loss` of them and delaying some behind the next one. Runs until the returned flag
+    /// is set.
+    fn lossy_proxy(server: SocketAddr, loss: f64, seed: u64) -> (SocketAddr, Arc<AtomicBool>) {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let stop_proxy = stop.clone();
+        thread::spawn(move || {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut client = None;
+            let mut delayed = None;
+            let mut buf = [0; 65536];
+            while !stop_proxy.load(Ordering::Relaxed) {
+                let (n, from) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                };
+                let to = if from == server {
+                    match client {
+                        Some(client) => client,
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    server
+                };
+                if rng.gen_bool(loss) {
+                    continue;
+                }
+                if delayed.is_none() && rng.gen_bool(0.2) {
+                    delayed = Some((buf[0..n].to_vec(), to));
+                    continue;
+                }
+                let _ = socket.send_to(&buf[0..n], to);
+                if let Some((bytes, to)) = delayed.take() {
+                    let _ = socket.send_to(&bytes, to);
+                }
+            }
+        });
+
+        (addr, stop)
+    }
+
+    /// Poll both ends until the handshake is done.
+    fn wait_for_link(host: &mut Host, client: &mut Client, link: &UdpLink<u32, Update>) {
+        let start = Instant::now();
+        while !(client.is_connected() && link.is_connected()) {
+            assert!(
+                start.elapsed() < CONNECT_TIMEOUT,
+                "UDP link wasn't established"
+            );
+            host.receive();
+            client.receive();
+            host.flush();
+            client.flush();
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn connect_and_send() {
+        let (mut host, addr) = create_host();
+        let (token, key, link) = host.add_peer(0);
+        let mut client = Client::connect(addr, token, key).unwrap();
+
+        // Nothing goes over UDP before the handshake.
+        assert_eq!(link.send(update(1)), Err(update(1)));
+        assert_eq!(client.send(update(1)), Err(update(1)));
+
+        wait_for_link(&mut host, &mut client, &link);
+
+        // Messages that have to arrive reliably are handed back.
+        let reliable = Update {
+            key: None,
+            value: 2,
+        };
+        assert_eq!(
+            client.send(reliable),
+            Err(Update {
+                key: None,
+                value: 2
+            })
+        );
+
+        link.send(update(3)).unwrap();
+        client.send(update(4)).unwrap();
+        host.flush();
+        client.flush();
+
+        let start = Instant::now();
+        let (mut to_client, mut to_host) = (Vec::new(), Vec::new());
+        while (to_client.is_empty() || to_host.is_empty()) && start.elapsed() < LINK_TIMEOUT {
+            to_client.extend(client.receive());
+            to_host.extend(host.receive());
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(to_client, vec![update(3)]);
+        assert_eq!(to_host, vec![(0, update(4))]);
+    }
+
+    #[test]
+    fn wrong_token_is_ignored() {
+        let (mut host, addr) = create_host();
+        let (token, key, link) = host.add_peer(0);
+        let mut client = Client::connect(addr, token.wrapping_add(1), key).unwrap();
+
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(500) {
+            host.receive();
+            client.receive();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!link.is_connected());
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn wrong_key_is_ignored() {
+        let (mut host, addr) = create_host();
+        let (token, key, link) = host.add_peer(0);
+        let mut forged_key = key;
+        forged_key[0] ^= 1;
+        let mut client = Client::connect(addr, token, forged_key).unwrap();
+
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(500) {
+            host.receive();
+            client.receive();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!link.is_connected());
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn datagrams_are_authenticated() {
+        let mut client = Credentials::new(1, [7; 32], false);
+        let mut server = Credentials::new(1, [7; 32], true);
+
+        let hello = client.seal(&Datagram::<Update>::Hello).unwrap();
+        let keepalive = client.seal(&Datagram::<Update>::Keepalive).unwrap();
+        // Datagrams may arrive out of order...
+        assert!(server.open::<Update>(&keepalive).is_some());
+        assert!(server.open::<Update>(&hello).is_some());
+        // ...but not be replayed...
+        assert!(server.open::<Update>(&hello).is_none());
+        assert!(server.open::<Update>(&keepalive).is_none());
+        // ...reflected back to the sender...
+        let hello = client.seal(&Datagram::<Update>::Hello).unwrap();
+        assert!(client.open::<Update>(&hello).is_none());
+        // ...or tampered with.
+        let mut tampered = hello.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(server.open::<Update>(&tampered).is_none());
+        assert!(server.open::<Update>(&hello[0..HEADER_SIZE - 1]).is_none());
+
+        assert!(server.open::<Update>(&hello).is_some());
+    }
+
+    #[test]
+    fn updates_stay_in_order_under_loss() {
+        let (mut host, addr) = create_host();
+        let (proxy, stop) = lossy_proxy(addr, 0.3, 0);
+        let (token, key, link) = host.add_peer(0);
+        let mut client = Client::connect(proxy, token, key).unwrap();
+        wait_for_link(&mut host, &mut client, &link);
+
+        let mut received = Vec::new();
+        for value in 0..300 {
+            link.send(update(value)).unwrap();
+            host.flush();
+            thread::sleep(Duration::from_millis(2));
+            received.extend(client.receive().into_iter().map(|update| update.value));
+            host.receive();
+        }
+        // The last update is repeated, so it should make it through eventually.
+        let start = Instant::now();
+        while received.last() != Some(&299) && start.elapsed() < Duration::from_secs(1) {
+            host.flush();
+            thread::sleep(Duration::from_millis(2));
+            received.extend(client.receive().into_iter().map(|update| update.value));
+        }
+        stop.store(true, Ordering::Relaxed);
+
+        assert!(link.is_connected() && client.is_connected());
+        // Stale updates must never overwrite newer ones...
+        assert!(received.windows(2).all(|w| w[0] < w[1]), "{:?}", received);
+        // ...and the losses are spread out, so that movement continues smoothly.
+        assert!(
+            received.len() > 150,
+            "Only {} updates arrived",
+            received.len()
+        );
+        let largest_gap = received.windows(2).map(|w| w[1] - w[0]).max().unwrap();
+        assert!(
+            largest_gap <= 10,
+            "Lost {} updates in a row",
+            largest_gap - 1
+        );
+    }
+
+    #[test]
+    fn fall_back_when_link_goes_quiet() {
+        let (mut host, addr) = create_host();
+        let (token, key, link) = host.add_peer(0);
+        let mut client = Client::connect(addr, token, key).unwrap();
+        wait_for_link(&mut host, &mut client, &link);
+
+        // The client stops responding, e.g. because a firewall started dropping datagrams.
+        let start = Instant::now();
+        while link.is_connected() {
+            assert!(start.elapsed() < LINK_TIMEOUT * 2, "Link didn't time out");
+            host.receive();
+            host.flush();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(link.send(update(1)), Err(update(1)));
+    }
+}
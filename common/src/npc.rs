@@ -1,6 +1,6 @@
 use crate::assets;
 use lazy_static::lazy_static;
-use rand::seq::SliceRandom;
+use rand::{seq::SliceRandom, Rng};
 use serde_json;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -39,14 +39,14 @@ lazy_static! {
     static ref NPC_NAMES_JSON: Arc<serde_json::Value> = assets::load_expect("common.npc_names");
 }
 
-pub fn get_npc_name(npc_type: NpcKind) -> String {
+pub fn get_npc_name(npc_type: NpcKind, rng: &mut impl Rng) -> String {
     let npc_names = NPC_NAMES_JSON
         .get(npc_type.as_str())
         .expect("accessing json using NPC type provided as key")
         .as_array()
         .expect("parsing accessed json value into an array");
     let npc_name = npc_names
-        .choose(&mut rand::thread_rng())
+        .choose(rng)
         .expect("getting a random NPC name")
         .as_str()
         .expect("parsing NPC name json value into a &str");
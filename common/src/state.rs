@@ -5,21 +5,31 @@ use crate::{
     comp,
     event::{EventBus, LocalEvent, ServerEvent},
     msg::{EcsCompPacket, EcsResPacket},
-    sys,
+    sys::{
+        self,
+        profile::{SysProfiler, SysStats},
+    },
     terrain::{Block, TerrainChunk, TerrainGrid},
     vol::WriteVol,
 };
 use hashbrown::{HashMap, HashSet};
+use rand::{rngs::StdRng, SeedableRng};
 use rayon::{ThreadPool, ThreadPoolBuilder};
 use serde_derive::{Deserialize, Serialize};
 use specs::{
     saveload::Marker,
     shred::{Fetch, FetchMut},
     storage::{MaskedStorage as EcsMaskedStorage, Storage as EcsStorage},
-    Component, DispatcherBuilder, Entity as EcsEntity, Join,
+    Builder, Component, Dispatcher, DispatcherBuilder, Entity as EcsEntity, Join,
 };
 use sphynx;
-use std::{sync::Arc, time::Duration};
+use std::{
+    cell::RefCell,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
 use vek::*;
 
 /// How much faster should an in-game day be compared to a real day?
@@ -38,12 +48,22 @@ pub struct Time(pub f64);
 #[derive(Default)]
 pub struct DeltaTime(pub f32);
 
+/// A resource that stores the random number generator used by systems. Seeding it makes ticks
+/// reproducible.
+pub struct EcsRng(pub StdRng);
+
+impl Default for EcsRng {
+    fn default() -> Self {
+        Self(StdRng::from_entropy())
+    }
+}
+
 /// At what point should we stop speeding up physics to compensate for lag? If we speed physics up
 /// too fast, we'd skip important physics events like collisions. This constant determines the
 /// upper limit. If delta time exceeds this value, the game's physics will begin to produce time
 /// lag. Ideally, we'd avoid such a situation.
 const MAX_DELTA_TIME: f32 = 1.0;
-const HUMANOID_JUMP_ACCEL: f32 = 16.0;
+pub const HUMANOID_JUMP_ACCEL: f32 = 16.0;
 
 #[derive(Default)]
 pub struct BlockChange {
@@ -76,21 +96,27 @@ impl TerrainChanges {
     }
 }
 
+thread_local! {
+    /// The dispatcher of the systems in `sys` and the thread pool it was built with. `Dispatcher`
+    /// isn't `Send`, so rather than in each `State`, it is kept by the thread that ticks them.
+    static DISPATCHER: RefCell<Option<(Arc<ThreadPool>, Dispatcher<'static, 'static>)>> =
+        RefCell::new(None);
+}
+
 /// A type used to represent game state stored on both the client and the server. This includes
 /// things like entity components, terrain data, and global states like weather, time of day, etc.
 pub struct State {
     ecs: sphynx::World<EcsCompPacket, EcsResPacket>,
-    // Avoid lifetime annotation by storing a thread pool instead of the whole dispatcher
     thread_pool: Arc<ThreadPool>,
 }
 
 impl Default for State {
     /// Create a new `State`.
     fn default() -> Self {
-        Self {
-            ecs: sphynx::World::new(specs::World::new(), Self::setup_sphynx_world),
-            thread_pool: Arc::new(ThreadPoolBuilder::new().build().unwrap()),
-        }
+        Self::with_ecs(sphynx::World::new(
+            specs::World::new(),
+            Self::setup_sphynx_world,
+        ))
     }
 }
 
@@ -99,12 +125,16 @@ impl State {
     pub fn from_state_package(
         state_package: sphynx::StatePackage<EcsCompPacket, EcsResPacket>,
     ) -> Self {
+        Self::with_ecs(sphynx::World::from_state_package(
+            specs::World::new(),
+            Self::setup_sphynx_world,
+            state_package,
+        ))
+    }
+
+    fn with_ecs(ecs: sphynx::World<EcsCompPacket, EcsResPacket>) -> Self {
         Self {
-            ecs: sphynx::World::from_state_package(
-                specs::World::new(),
-                Self::setup_sphynx_world,
-                state_package,
-            ),
+            ecs,
             thread_pool: Arc::new(ThreadPoolBuilder::new().build().unwrap()),
         }
     }
@@ -145,7 +175,6 @@ impl State {
         ecs.register::<comp::ForceUpdate>();
         ecs.register::<comp::InventoryUpdate>();
         ecs.register::<comp::Inventory>();
-        ecs.register::<comp::Admin>();
 
         // Register synced resources used by the ECS.
         ecs.insert_synced(TimeOfDay(0.0));
@@ -153,11 +182,67 @@ impl State {
         // Register unsynced resources used by the ECS.
         ecs.add_resource(Time(0.0));
         ecs.add_resource(DeltaTime(0.0));
+        ecs.add_resource(EcsRng::default());
         ecs.add_resource(TerrainGrid::new().unwrap());
         ecs.add_resource(BlockChange::default());
         ecs.add_resource(TerrainChanges::default());
         ecs.add_resource(EventBus::<ServerEvent>::default());
         ecs.add_resource(EventBus::<LocalEvent>::default());
+        ecs.add_resource(SysProfiler::default());
+    }
+
+    /// Create a state that contains a copy of `entity` and the terrain, but no other entities.
+    /// Ticking it simulates the entity like `tick` would, except that nothing else can interfere,
+    /// and with the same results every time for the same `seed`. Returns `None` if `entity`
+    /// can't be simulated.
+    pub fn isolate(&self, entity: EcsEntity, seed: u64) -> Option<(State, EcsEntity)> {
+        let mut isolated = Self {
+            ecs: sphynx::World::new(specs::World::new(), Self::setup_sphynx_world),
+            thread_pool: self.thread_pool.clone(),
+        };
+        isolated.seed_rng(seed);
+        *isolated.ecs.write_resource::<Time>() = *self.ecs.read_resource::<Time>();
+        *isolated.ecs.write_resource::<TerrainGrid>() = (*self.terrain()).clone();
+
+        let copy = isolated.ecs.create_entity().build();
+        macro_rules! copy {
+            ($($comp:ty),*) => {$(
+                if let Some(comp) = self.read_component_cloned::<$comp>(entity) {
+                    isolated.write_component(copy, comp);
+                }
+            )*};
+        }
+        copy!(
+            comp::Body,
+            comp::Stats,
+            comp::Scale,
+            comp::Controller,
+            comp::CharacterState,
+            comp::Pos,
+            comp::Vel,
+            comp::Ori
+        );
+        // Entities get their physics state once physics has run for them.
+        let physics = self
+            .read_component_cloned::<comp::PhysicsState>(entity)
+            .unwrap_or_default();
+        isolated.write_component(copy, physics);
+
+        let can_simulate = {
+            let bodies = isolated.read_storage::<comp::Body>();
+            let positions = isolated.read_storage::<comp::Pos>();
+            bodies.get(copy).is_some() && positions.get(copy).is_some()
+        };
+        if can_simulate {
+            Some((isolated, copy))
+        } else {
+            None
+        }
+    }
+
+    /// Seed the random number generator that systems use.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.ecs.write_resource::<EcsRng>().0 = StdRng::seed_from_u64(seed);
     }
 
     /// Register a component with the state's ECS.
@@ -224,6 +309,53 @@ impl State {
         self.ecs.read_resource::<DeltaTime>().0
     }
 
+    /// A checksum of the entities in the world and the time. States that went through the same
+    /// ticks with the same inputs have the same checksum.
+    pub fn checksum(&self) -> u64 {
+        fn hash_storage<C: Component + serde::Serialize>(
+            ecs: &sphynx::World<EcsCompPacket, EcsResPacket>,
+            hasher: &mut DefaultHasher,
+        ) {
+            for (uid, comp) in (&ecs.read_storage::<Uid>(), &ecs.read_storage::<C>()).join() {
+                u64::from(*uid).hash(hasher);
+                // Floats can't be hashed, but their encoding can.
+                bincode::serialize(comp).unwrap_or_default().hash(hasher);
+            }
+        }
+
+        let mut hasher = DefaultHasher::new();
+        self.get_time().to_bits().hash(&mut hasher);
+        self.get_time_of_day().to_bits().hash(&mut hasher);
+        hash_storage::<comp::Body>(&self.ecs, &mut hasher);
+        hash_storage::<comp::Player>(&self.ecs, &mut hasher);
+        hash_storage::<comp::Stats>(&self.ecs, &mut hasher);
+        hash_storage::<comp::Inventory>(&self.ecs, &mut hasher);
+        hash_storage::<comp::Item>(&self.ecs, &mut hasher);
+        hash_storage::<comp::Pos>(&self.ecs, &mut hasher);
+        hash_storage::<comp::Vel>(&self.ecs, &mut hasher);
+        hash_storage::<comp::Ori>(&self.ecs, &mut hasher);
+        hash_storage::<comp::Scale>(&self.ecs, &mut hasher);
+        hash_storage::<comp::CharacterState>(&self.ecs, &mut hasher);
+        hash_storage::<comp::PhysicsState>(&self.ecs, &mut hasher);
+        hash_storage::<comp::Controller>(&self.ecs, &mut hasher);
+        hash_storage::<comp::MountState>(&self.ecs, &mut hasher);
+        hash_storage::<comp::Mounting>(&self.ecs, &mut hasher);
+        hash_storage::<comp::LightEmitter>(&self.ecs, &mut hasher);
+        hash_storage::<comp::CanBuild>(&self.ecs, &mut hasher);
+        hasher.finish()
+    }
+
+    /// Start or stop recording how long each system takes.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.ecs.read_resource::<SysProfiler>().set_enabled(enabled);
+    }
+
+    /// Get statistics about the recent runs of each system, by system name. They are empty unless
+    /// profiling was enabled with `set_profiling`.
+    pub fn sys_stats(&self) -> Vec<(&'static str, SysStats)> {
+        self.ecs.read_resource::<SysProfiler>().stats()
+    }
+
     /// Get a reference to this state's terrain.
     pub fn terrain(&self) -> Fetch<TerrainGrid> {
         self.ecs.read_resource()
@@ -360,11 +492,22 @@ impl State {
         }
 
         // Run systems to update the world.
-        // Create and run a dispatcher for ecs systems.
-        let mut dispatch_builder = DispatcherBuilder::new().with_pool(self.thread_pool.clone());
-        sys::add_local_systems(&mut dispatch_builder);
         // This dispatches all the systems in parallel.
-        dispatch_builder.build().dispatch(&self.ecs.res);
+        DISPATCHER.with(|dispatcher| {
+            let mut dispatcher = dispatcher.borrow_mut();
+            let outdated = dispatcher
+                .as_ref()
+                .map_or(true, |(pool, _)| !Arc::ptr_eq(pool, &self.thread_pool));
+            if outdated {
+                let mut dispatch_builder =
+                    DispatcherBuilder::new().with_pool(self.thread_pool.clone());
+                sys::add_local_systems(&mut dispatch_builder);
+                *dispatcher = Some((self.thread_pool.clone(), dispatch_builder.build()));
+            }
+            if let Some((_, dispatcher)) = dispatcher.as_mut() {
+                dispatcher.dispatch(&self.ecs.res);
+            }
+        });
 
         self.ecs.maintain();
 
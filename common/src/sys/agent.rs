@@ -1,8 +1,9 @@
-use crate::comp::{
-    Agent, CharacterState, Controller, MountState, MovementState::Glide, Pos, Stats,
+use crate::{
+    comp::{Agent, CharacterState, Controller, MountState, MovementState::Glide, Pos, Stats},
+    state::EcsRng,
 };
-use rand::{seq::SliceRandom, thread_rng};
-use specs::{Entities, Join, ReadStorage, System, WriteStorage};
+use rand::{seq::SliceRandom, Rng};
+use specs::{Entities, Join, ReadStorage, System, Write, WriteStorage};
 use vek::*;
 
 /// This system will allow NPCs to modify their controller
@@ -16,12 +17,23 @@ impl<'a> System<'a> for Sys {
         WriteStorage<'a, Agent>,
         WriteStorage<'a, Controller>,
         ReadStorage<'a, MountState>,
+        Write<'a, EcsRng>,
     );
 
     fn run(
         &mut self,
-        (entities, positions, stats, character_states, mut agents, mut controllers, mount_states): Self::SystemData,
+        (
+            entities,
+            positions,
+            stats,
+            character_states,
+            mut agents,
+            mut controllers,
+            mount_states,
+            mut rng,
+        ): Self::SystemData,
     ) {
+        let rng = &mut rng.0;
         for (entity, pos, agent, controller, mount_state) in (
             &entities,
             &positions,
@@ -49,8 +61,7 @@ impl<'a> System<'a> for Sys {
 
             match agent {
                 Agent::Wanderer(bearing) => {
-                    *bearing += Vec2::new(rand::random::<f32>() - 0.5, rand::random::<f32>() - 0.5)
-                        * 0.1
+                    *bearing += Vec2::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5) * 0.1
                         - *bearing * 0.01
                         - pos.0 * 0.0002;
 
@@ -82,10 +93,8 @@ impl<'a> System<'a> for Sys {
                     }
 
                     // Change offset occasionally.
-                    if rand::random::<f32>() < 0.003 {
-                        *offset =
-                            Vec2::new(rand::random::<f32>() - 0.5, rand::random::<f32>() - 0.5)
-                                * 10.0;
+                    if rng.gen::<f32>() < 0.003 {
+                        *offset = Vec2::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5) * 10.0;
                     }
                 }
                 Agent::Enemy { bearing, target } => {
@@ -116,7 +125,7 @@ impl<'a> System<'a> for Sys {
                             controller.move_dir =
                                 Vec2::<f32>::from(target_pos.0 - pos.0).normalized() * 0.96;
 
-                            if rand::random::<f32>() < 0.02 {
+                            if rng.gen::<f32>() < 0.02 {
                                 controller.roll = true;
                             }
 
@@ -129,10 +138,8 @@ impl<'a> System<'a> for Sys {
                             choose_new = true;
                         }
                     } else {
-                        *bearing +=
-                            Vec2::new(rand::random::<f32>() - 0.5, rand::random::<f32>() - 0.5)
-                                * 0.1
-                                - *bearing * 0.005;
+                        *bearing += Vec2::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5) * 0.1
+                            - *bearing * 0.005;
 
                         controller.move_dir = if bearing.magnitude_squared() > 0.001 {
                             bearing.normalized()
@@ -143,7 +150,7 @@ impl<'a> System<'a> for Sys {
                         choose_new = true;
                     }
 
-                    if choose_new && rand::random::<f32>() < 0.1 {
+                    if choose_new && rng.gen::<f32>() < 0.1 {
                         let entities = (&entities, &positions, &stats)
                             .join()
                             .filter(|(e, e_pos, e_stats)| {
@@ -154,8 +161,7 @@ impl<'a> System<'a> for Sys {
                             .map(|(e, _, _)| e)
                             .collect::<Vec<_>>();
 
-                        let mut rng = thread_rng();
-                        *target = (&entities).choose(&mut rng).cloned();
+                        *target = (&entities).choose(rng).cloned();
                     }
                 }
             }
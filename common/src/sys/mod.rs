@@ -4,10 +4,12 @@ pub mod combat;
 pub mod controller;
 pub mod movement;
 pub mod phys;
+pub mod profile;
 mod stats;
 
 // External
-use specs::DispatcherBuilder;
+use profile::Profiled;
+use specs::{DispatcherBuilder, System};
 
 // System names
 const AGENT_SYS: &str = "agent_sys";
@@ -18,16 +20,33 @@ const COMBAT_SYS: &str = "combat_sys";
 const STATS_SYS: &str = "stats_sys";
 const CLEANUP_SYS: &str = "cleanup_sys";
 
+fn add_profiled<S>(
+    dispatch_builder: &mut DispatcherBuilder,
+    sys: S,
+    name: &'static str,
+    dep: &[&str],
+) where
+    S: for<'a> System<'a> + Send + 'static,
+{
+    dispatch_builder.add(Profiled { name, sys }, name, dep);
+}
+
 pub fn add_local_systems(dispatch_builder: &mut DispatcherBuilder) {
-    dispatch_builder.add(agent::Sys, AGENT_SYS, &[]);
-    dispatch_builder.add(controller::Sys, CONTROLLER_SYS, &[AGENT_SYS]);
-    dispatch_builder.add(movement::Sys, MOVEMENT_SYS, &[]);
-    dispatch_builder.add(combat::Sys, COMBAT_SYS, &[CONTROLLER_SYS]);
-    dispatch_builder.add(stats::Sys, STATS_SYS, &[COMBAT_SYS]);
-    dispatch_builder.add(
+    add_profiled(dispatch_builder, agent::Sys, AGENT_SYS, &[]);
+    add_profiled(
+        dispatch_builder,
+        controller::Sys,
+        CONTROLLER_SYS,
+        &[AGENT_SYS],
+    );
+    add_profiled(dispatch_builder, movement::Sys, MOVEMENT_SYS, &[]);
+    add_profiled(dispatch_builder, combat::Sys, COMBAT_SYS, &[CONTROLLER_SYS]);
+    add_profiled(dispatch_builder, stats::Sys, STATS_SYS, &[COMBAT_SYS]);
+    add_profiled(
+        dispatch_builder,
         phys::Sys,
         PHYS_SYS,
         &[CONTROLLER_SYS, MOVEMENT_SYS, COMBAT_SYS, STATS_SYS],
     );
-    dispatch_builder.add(cleanup::Sys, CLEANUP_SYS, &[PHYS_SYS]);
+    add_profiled(dispatch_builder, cleanup::Sys, CLEANUP_SYS, &[PHYS_SYS]);
 }
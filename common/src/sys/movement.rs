@@ -14,20 +14,20 @@ use vek::*;
 pub const ROLL_DURATION: Duration = Duration::from_millis(600);
 
 const HUMANOID_ACCEL: f32 = 70.0;
-const HUMANOID_SPEED: f32 = 120.0;
+pub const HUMANOID_SPEED: f32 = 120.0;
 const HUMANOID_AIR_ACCEL: f32 = 10.0;
-const HUMANOID_AIR_SPEED: f32 = 100.0;
+pub const HUMANOID_AIR_SPEED: f32 = 100.0;
 const HUMANOID_WATER_ACCEL: f32 = 70.0;
-const HUMANOID_WATER_SPEED: f32 = 120.0;
+pub const HUMANOID_WATER_SPEED: f32 = 120.0;
 const HUMANOID_CLIMB_ACCEL: f32 = 5.0;
-const ROLL_SPEED: f32 = 13.0;
+pub const ROLL_SPEED: f32 = 13.0;
 const GLIDE_ACCEL: f32 = 15.0;
-const GLIDE_SPEED: f32 = 45.0;
+pub const GLIDE_SPEED: f32 = 45.0;
 const BLOCK_ACCEL: f32 = 30.0;
-const BLOCK_SPEED: f32 = 75.0;
+pub const BLOCK_SPEED: f32 = 75.0;
 // Gravity is 9.81 * 4, so this makes gravity equal to .15
 const GLIDE_ANTIGRAV: f32 = GRAVITY * 0.96;
-const CLIMB_SPEED: f32 = 5.0;
+pub const CLIMB_SPEED: f32 = 5.0;
 
 pub const MOVEMENT_THRESHOLD_VEL: f32 = 3.0;
 
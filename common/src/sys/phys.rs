@@ -11,6 +11,8 @@ use {
 };
 
 pub const GRAVITY: f32 = 9.81 * 4.0;
+/// The terminal velocity of falling entities.
+pub const MAX_FALL_SPEED: f32 = 50.0;
 const BOUYANCY: f32 = 0.0;
 // Friction values used for linear damping. They are unitless quantities. The
 // value of these quantities must be between zero and one. They represent the
@@ -32,7 +34,7 @@ fn integrate_forces(dt: f32, mut lv: Vec3<f32>, grav: f32, damp: f32) -> Vec3<f3
     // must be interpolated accordingly
     let linear_damp = (1.0 - damp.min(1.0)).powf(dt * 60.0);
 
-    lv.z = (lv.z - grav * dt).max(-50.0);
+    lv.z = (lv.z - grav * dt).max(-MAX_FALL_SPEED);
     lv * linear_damp
 }
 
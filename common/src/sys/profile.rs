@@ -0,0 +1,144 @@
+//! Opt-in profiling of the systems in `sys`. Every system is wrapped in `Profiled`, which records
+//! how long each run took in the `SysProfiler` resource while profiling is enabled.
+
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use specs::{Read, System};
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+/// How many of the most recent runs of each system the statistics cover.
+pub const PROFILE_WINDOW: usize = 120;
+
+/// Statistics about the recent runs of a system.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SysStats {
+    /// How long the last run took.
+    pub last: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+    /// The number of runs that the statistics cover, at most `PROFILE_WINDOW`.
+    pub runs: usize,
+}
+
+impl SysStats {
+    fn from_runs(runs: &VecDeque<Duration>) -> Self {
+        let total = runs.iter().sum::<Duration>();
+        Self {
+            last: runs.back().copied().unwrap_or_default(),
+            mean: total / runs.len().max(1) as u32,
+            max: runs.iter().max().copied().unwrap_or_default(),
+            runs: runs.len(),
+        }
+    }
+}
+
+/// A resource that records how long systems take while profiling is enabled.
+#[derive(Default)]
+pub struct SysProfiler {
+    enabled: AtomicBool,
+    runs: Mutex<HashMap<&'static str, VecDeque<Duration>>>,
+}
+
+impl SysProfiler {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Start or stop profiling. Stopping forgets what was recorded so far.
+    pub fn set_enabled(&self, enabled: bool) {
+        let was_enabled = self.enabled.swap(enabled, Ordering::Relaxed);
+        if was_enabled && !enabled {
+            self.runs.lock().clear();
+        }
+    }
+
+    pub fn record(&self, name: &'static str, duration: Duration) {
+        let mut runs = self.runs.lock();
+        let runs = runs.entry(name).or_default();
+        if runs.len() >= PROFILE_WINDOW {
+            runs.pop_front();
+        }
+        runs.push_back(duration);
+    }
+
+    /// The statistics of every system that has run since profiling was enabled, by system name.
+    pub fn stats(&self) -> Vec<(&'static str, SysStats)> {
+        let mut stats = self
+            .runs
+            .lock()
+            .iter()
+            .map(|(name, runs)| (*name, SysStats::from_runs(runs)))
+            .collect::<Vec<_>>();
+        stats.sort_by_key(|(name, _)| *name);
+        stats
+    }
+}
+
+/// Runs a system and records its wall time in `SysProfiler`. The profiler is only read, so
+/// profiled systems can still run in parallel.
+pub struct Profiled<S> {
+    pub name: &'static str,
+    pub sys: S,
+}
+
+impl<'a, S: System<'a>> System<'a> for Profiled<S> {
+    type SystemData = (S::SystemData, Read<'a, SysProfiler>);
+
+    fn run(&mut self, (data, profiler): Self::SystemData) {
+        if profiler.is_enabled() {
+            let start = Instant::now();
+            self.sys.run(data);
+            profiler.record(self.name, start.elapsed());
+        } else {
+            self.sys.run(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn stats_cover_recent_runs() {
+        let profiler = SysProfiler::default();
+        profiler.set_enabled(true);
+        profiler.record("a_sys", ms(100));
+        for _ in 0..PROFILE_WINDOW - 2 {
+            profiler.record("a_sys", ms(2));
+        }
+        profiler.record("a_sys", ms(4));
+        profiler.record("b_sys", ms(1));
+
+        let stats = profiler.stats();
+        assert_eq!(stats[0].0, "a_sys");
+        assert_eq!(stats[0].1.last, ms(4));
+        assert_eq!(stats[0].1.max, ms(100));
+        assert_eq!(stats[0].1.runs, PROFILE_WINDOW);
+        assert_eq!(stats[1].0, "b_sys");
+        assert_eq!(stats[1].1.mean, ms(1));
+
+        // The slow run drops out of the window.
+        profiler.record("a_sys", ms(2));
+        let stats = profiler.stats();
+        assert_eq!(stats[0].1.max, ms(4));
+        assert_eq!(stats[0].1.runs, PROFILE_WINDOW);
+    }
+
+    #[test]
+    fn disabling_forgets_runs() {
+        let profiler = SysProfiler::default();
+        profiler.set_enabled(true);
+        profiler.record("a_sys", ms(1));
+        profiler.set_enabled(false);
+        assert!(profiler.stats().is_empty());
+    }
+}
@@ -30,7 +30,7 @@ impl<ChonkSize: RectVolSize> VolSize for SubChunkSize<ChonkSize> {
     };
 }
 
-type SubChunk<V, S, M> = Chunk<V, SubChunkSize<S>, M>;
+pub(super) type SubChunk<V, S, M> = Chunk<V, SubChunkSize<S>, M>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chonk<V: Vox, S: RectVolSize, M: Clone> {
@@ -58,6 +58,37 @@ impl<V: Vox, S: RectVolSize, M: Clone> Chonk<V, S, M> {
         &self.meta
     }
 
+    /// The voxel that fills everything below the sub-chunks.
+    pub fn below(&self) -> &V {
+        &self.below
+    }
+
+    /// The voxel that fills everything above the sub-chunks.
+    pub fn above(&self) -> &V {
+        &self.above
+    }
+
+    pub(super) fn from_sub_chunks(
+        z_offset: i32,
+        sub_chunks: Vec<SubChunk<V, S, M>>,
+        below: V,
+        above: V,
+        meta: M,
+    ) -> Self {
+        Self {
+            z_offset,
+            sub_chunks,
+            below,
+            above,
+            meta,
+            phantom: PhantomData,
+        }
+    }
+
+    pub(super) fn sub_chunks(&self) -> &[SubChunk<V, S, M>] {
+        &self.sub_chunks
+    }
+
     pub fn get_min_z(&self) -> i32 {
         self.z_offset
     }
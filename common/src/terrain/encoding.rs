@@ -0,0 +1,437 @@
+//! A compact encoding of `Chonk`s for sending them over the network.
+//!
+//! Each sub-chunk is stored as a palette of the distinct voxels it contains and a run-length
+//! encoding of their palette indices. The voxels are visited column by column, bottom to top, so
+//! a column of uniform layers only takes a few runs, and a sub-chunk that lies entirely above or
+//! below the surface collapses into a single run.
+
+use super::chonk::{Chonk, SubChunk, SubChunkSize};
+use crate::vol::{ReadVol, RectVolSize, VolSize, Vox, WriteVol};
+use serde_derive::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use vek::*;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The runs of a sub-chunk ended in the middle of a number.
+    InvalidRuns,
+    InvalidPaletteIndex(u32),
+    /// The runs of a sub-chunk don't add up to its volume.
+    WrongLength {
+        expected: u32,
+        found: u64,
+    },
+    /// A delta refers to a sub-chunk that the old version doesn't have.
+    MissingSubChunk(u32),
+}
+
+/// The positions within a sub-chunk, in the order in which the runs cover them.
+fn positions<S: RectVolSize>() -> impl Iterator<Item = Vec3<i32>> {
+    let size = SubChunkSize::<S>::SIZE.map(|e| e as i32);
+    (0..size.x).flat_map(move |x| {
+        (0..size.y).flat_map(move |y| (0..size.z).map(move |z| Vec3::new(x, y, z)))
+    })
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut n: u32) {
+    while n >= 0x80 {
+        bytes.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.push(n as u8);
+}
+
+fn read_varint(bytes: &mut impl Iterator<Item = u8>) -> Result<u32, DecodeError> {
+    let mut n = 0u32;
+    for shift in (0..32).step_by(7) {
+        let byte = bytes.next().ok_or(DecodeError::InvalidRuns)?;
+        n |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(DecodeError::InvalidRuns)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct EncodedSubChunk<V> {
+    palette: Vec<V>,
+    /// Pairs of palette index and run length, both as LEB128 varints.
+    runs: Vec<u8>,
+}
+
+impl<V: Vox> EncodedSubChunk<V> {
+    fn encode<S: RectVolSize, M>(sub_chunk: &SubChunk<V, S, M>) -> Self {
+        let mut palette = Vec::<V>::new();
+        let mut runs = Vec::new();
+        let mut current: Option<(u32, u32)> = None;
+        for pos in positions::<S>() {
+            let vox = sub_chunk.get(pos).unwrap(); // `positions` stays within the sub-chunk
+            if let Some((idx, len)) = &mut current {
+                if palette[*idx as usize] == *vox {
+                    *len += 1;
+                    continue;
+                }
+                write_varint(&mut runs, *idx);
+                write_varint(&mut runs, *len);
+            }
+            let idx = match palette.iter().position(|v| v == vox) {
+                Some(idx) => idx,
+                None => {
+                    palette.push(vox.clone());
+                    palette.len() - 1
+                }
+            };
+            current = Some((idx as u32, 1));
+        }
+        if let Some((idx, len)) = current {
+            write_varint(&mut runs, idx);
+            write_varint(&mut runs, len);
+        }
+
+        Self { palette, runs }
+    }
+
+    fn decode<S: RectVolSize, M>(&self, meta: M) -> Result<SubChunk<V, S, M>, DecodeError> {
+        let mut runs = Vec::new();
+        let mut bytes = self.runs.iter().copied().peekable();
+        while bytes.peek().is_some() {
+            let idx = read_varint(&mut bytes)?;
+            let len = read_varint(&mut bytes)?;
+            if idx as usize >= self.palette.len() {
+                return Err(DecodeError::InvalidPaletteIndex(idx));
+            }
+            runs.push((idx as usize, len as usize));
+        }
+
+        let volume = SubChunkSize::<S>::SIZE.product();
+        let found = runs.iter().map(|(_, len)| *len as u64).sum();
+        if found != u64::from(volume) {
+            return Err(DecodeError::WrongLength {
+                expected: volume,
+                found,
+            });
+        }
+
+        // Voxels that are equal to the default don't have to be stored, so pick the most common
+        // one.
+        let mut counts = vec![0; self.palette.len()];
+        for (idx, len) in &runs {
+            counts[*idx] += len;
+        }
+        let default = (0..counts.len())
+            .max_by_key(|idx| counts[*idx])
+            .unwrap_or(0);
+        let mut sub_chunk = SubChunk::<V, S, M>::filled(
+            self.palette.get(default).cloned().unwrap_or_else(V::empty),
+            meta,
+        );
+
+        let mut positions = positions::<S>();
+        for (idx, len) in runs {
+            for pos in positions.by_ref().take(len) {
+                if idx != default {
+                    let _ = sub_chunk.set(pos, self.palette[idx].clone());
+                }
+            }
+        }
+        Ok(sub_chunk)
+    }
+}
+
+fn sub_chunks_eq<V: Vox, S: RectVolSize, M>(a: &SubChunk<V, S, M>, b: &SubChunk<V, S, M>) -> bool {
+    positions::<S>().all(|pos| a.get(pos).ok() == b.get(pos).ok())
+}
+
+/// The index in `chonk` of the sub-chunk that starts at `min_z`, if there is one.
+fn sub_chunk_at<V: Vox, S: RectVolSize, M: Clone>(
+    chonk: &Chonk<V, S, M>,
+    min_z: i32,
+) -> Option<usize> {
+    let height = SubChunkSize::<S>::SIZE.z as i32;
+    let diff = min_z - chonk.get_min_z();
+    if diff >= 0 && diff % height == 0 && ((diff / height) as usize) < chonk.sub_chunks_len() {
+        Some((diff / height) as usize)
+    } else {
+        None
+    }
+}
+
+/// A `Chonk` in its compact wire format.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncodedChonk<V, S, M> {
+    z_offset: i32,
+    below: V,
+    above: V,
+    meta: M,
+    sub_chunks: Vec<EncodedSubChunk<V>>,
+    phantom: PhantomData<S>,
+}
+
+impl<V: Vox, S: RectVolSize, M: Clone> EncodedChonk<V, S, M> {
+    pub fn encode(chonk: &Chonk<V, S, M>) -> Self {
+        Self {
+            z_offset: chonk.get_min_z(),
+            below: chonk.below().clone(),
+            above: chonk.above().clone(),
+            meta: chonk.meta().clone(),
+            sub_chunks: chonk
+                .sub_chunks()
+                .iter()
+                .map(EncodedSubChunk::encode::<S, M>)
+                .collect(),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn decode(&self) -> Result<Chonk<V, S, M>, DecodeError> {
+        Ok(Chonk::from_sub_chunks(
+            self.z_offset,
+            self.sub_chunks
+                .iter()
+                .map(|sub_chunk| sub_chunk.decode(self.meta.clone()))
+                .collect::<Result<_, _>>()?,
+            self.below.clone(),
+            self.above.clone(),
+            self.meta.clone(),
+        ))
+    }
+}
+
+/// The difference between two versions of a `Chonk`, for updating a chunk that the receiver
+/// already has. Only the sub-chunks that changed are included.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChonkDelta<V, S, M> {
+    z_offset: i32,
+    below: V,
+    above: V,
+    meta: M,
+    /// The number of sub-chunks of the new version.
+    len: u32,
+    /// The sub-chunks that changed, by their index in the new version.
+    changed: Vec<(u32, EncodedSubChunk<V>)>,
+    phantom: PhantomData<S>,
+}
+
+impl<V: Vox, S: RectVolSize, M: Clone> ChonkDelta<V, S, M> {
+    pub fn between(old: &Chonk<V, S, M>, new: &Chonk<V, S, M>) -> Self {
+        let height = SubChunkSize::<S>::SIZE.z as i32;
+        let changed = new
+            .sub_chunks()
+            .iter()
+            .enumerate()
+            .filter(|(i, sub_chunk)| {
+                sub_chunk_at(old, new.get_min_z() + *i as i32 * height)
+                    .map(|old_idx| !sub_chunks_eq(&old.sub_chunks()[old_idx], sub_chunk))
+                    .unwrap_or(true)
+            })
+            .map(|(i, sub_chunk)| (i as u32, EncodedSubChunk::encode::<S, M>(sub_chunk)))
+            .collect();
+
+        Self {
+            z_offset: new.get_min_z(),
+            below: new.below().clone(),
+            above: new.above().clone(),
+            meta: new.meta().clone(),
+            len: new.sub_chunks_len() as u32,
+            changed,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Whether both versions are the same, apart from their metadata.
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty()
+    }
+
+    /// Reconstruct the new version from the old one.
+    pub fn apply(&self, old: &Chonk<V, S, M>) -> Result<Chonk<V, S, M>, DecodeError> {
+        let height = SubChunkSize::<S>::SIZE.z as i32;
+        let mut changed = self.changed.iter().peekable();
+        let mut sub_chunks = Vec::with_capacity(self.len as usize);
+        for i in 0..self.len {
+            let sub_chunk = match changed.peek() {
+                Some((idx, sub_chunk)) if *idx == i => {
+                    changed.next();
+                    sub_chunk.decode(self.meta.clone())?
+                }
+                _ => {
+                    let min_z = self.z_offset + i as i32 * height;
+                    match sub_chunk_at(old, min_z) {
+                        Some(old_idx) => old.sub_chunks()[old_idx].clone(),
+                        None => return Err(DecodeError::MissingSubChunk(i)),
+                    }
+                }
+            };
+            sub_chunks.push(sub_chunk);
+        }
+
+        Ok(Chonk::from_sub_chunks(
+            self.z_offset,
+            sub_chunks,
+            self.below.clone(),
+            self.above.clone(),
+            self.meta.clone(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        terrain::{Block, BlockKind, TerrainChunk, TerrainChunkMeta, TerrainChunkSize},
+        vol::{IntoPosIterator, RectRasterableVol},
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_block(rng: &mut StdRng) -> Block {
+        let kind = match rng.gen_range(0, 4) {
+            0 => BlockKind::Normal,
+            1 => BlockKind::Dense,
+            2 => BlockKind::Water,
+            _ => BlockKind::Liana,
+        };
+        Block::new(kind, Rgb::new(rng.gen(), rng.gen(), rng.gen()))
+    }
+
+    /// Terrain with a random height map, a few layers with a small palette, and random blocks
+    /// scattered around.
+    fn random_chunk(rng: &mut StdRng) -> TerrainChunk {
+        let min_z = rng.gen_range(-100, 100);
+        let stone = Block::new(BlockKind::Dense, Rgb::new(100, 100, 100));
+        let mut chunk = TerrainChunk::new(min_z, stone, Block::empty(), TerrainChunkMeta::void());
+
+        let layers = (0..rng.gen_range(1, 4))
+            .map(|_| random_block(rng))
+            .collect::<Vec<_>>();
+        for x in 0..TerrainChunk::RECT_SIZE.x as i32 {
+            for y in 0..TerrainChunk::RECT_SIZE.y as i32 {
+                let height = rng.gen_range(0, 40);
+                for z in 0..height {
+                    let block = layers[(height - z) as usize % layers.len()];
+                    chunk.set(Vec3::new(x, y, min_z + z), block).unwrap();
+                }
+            }
+        }
+        for _ in 0..rng.gen_range(0, 200) {
+            let pos = Vec3::new(
+                rng.gen_range(0, TerrainChunk::RECT_SIZE.x as i32),
+                rng.gen_range(0, TerrainChunk::RECT_SIZE.y as i32),
+                rng.gen_range(min_z - 20, min_z + 80),
+            );
+            chunk.set(pos, random_block(rng)).unwrap();
+        }
+        chunk
+    }
+
+    fn assert_chunks_eq(a: &TerrainChunk, b: &TerrainChunk) {
+        assert_eq!(a.get_min_z(), b.get_min_z());
+        assert_eq!(a.get_max_z(), b.get_max_z());
+        assert_eq!(a.meta().name(), b.meta().name());
+        assert_eq!(a.meta().biome(), b.meta().biome());
+        for pos in a.pos_iter(
+            Vec3::new(0, 0, a.get_min_z() - 1),
+            Vec3::new(
+                TerrainChunk::RECT_SIZE.x as i32,
+                TerrainChunk::RECT_SIZE.y as i32,
+                a.get_max_z() + 1,
+            ),
+        ) {
+            assert_eq!(a.get(pos).ok(), b.get(pos).ok(), "at {:?}", pos);
+        }
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for &n in &[0, 1, 0x7f, 0x80, 0x3fff, 0x4000, 123_456, u32::max_value()] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, n);
+            assert_eq!(read_varint(&mut bytes.into_iter()).unwrap(), n);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for seed in 0..16 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let chunk = random_chunk(&mut rng);
+            let encoded = EncodedChonk::encode(&chunk);
+            let bytes = bincode::serialize(&encoded).unwrap();
+            let decoded = bincode::deserialize::<EncodedChonk<_, TerrainChunkSize, _>>(&bytes)
+                .unwrap()
+                .decode()
+                .unwrap();
+            assert_chunks_eq(&chunk, &decoded);
+        }
+    }
+
+    #[test]
+    fn empty_round_trip() {
+        let chunk = TerrainChunk::new(0, Block::empty(), Block::empty(), TerrainChunkMeta::void());
+        let decoded = EncodedChonk::encode(&chunk).decode().unwrap();
+        assert_chunks_eq(&chunk, &decoded);
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        for seed in 0..16 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let old = random_chunk(&mut rng);
+            let mut new = old.clone();
+            for _ in 0..rng.gen_range(0, 5) {
+                let pos = Vec3::new(
+                    rng.gen_range(0, TerrainChunk::RECT_SIZE.x as i32),
+                    rng.gen_range(0, TerrainChunk::RECT_SIZE.y as i32),
+                    // Sometimes outside of the existing sub-chunks.
+                    rng.gen_range(old.get_min_z() - 40, old.get_max_z() + 40),
+                );
+                new.set(pos, random_block(&mut rng)).unwrap();
+            }
+
+            let delta = ChonkDelta::between(&old, &new);
+            assert_chunks_eq(&new, &delta.apply(&old).unwrap());
+        }
+    }
+
+    #[test]
+    fn delta_is_small() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let old = random_chunk(&mut rng);
+        let mut new = old.clone();
+        new.set(Vec3::new(3, 4, old.get_min_z() + 5), Block::empty())
+            .unwrap();
+
+        let delta = ChonkDelta::between(&old, &new);
+        assert_eq!(delta.changed.len(), 1);
+        assert!(ChonkDelta::between(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_runs() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut encoded = EncodedChonk::encode(&random_chunk(&mut rng));
+
+        let runs = encoded.sub_chunks[0].runs.clone();
+        encoded.sub_chunks[0].runs.push(0x80);
+        assert!(match encoded.decode() {
+            Err(DecodeError::InvalidRuns) => true,
+            _ => false,
+        });
+
+        encoded.sub_chunks[0].runs = runs.clone();
+        write_varint(&mut encoded.sub_chunks[0].runs, 0);
+        write_varint(&mut encoded.sub_chunks[0].runs, 1);
+        assert!(match encoded.decode() {
+            Err(DecodeError::WrongLength { .. }) => true,
+            _ => false,
+        });
+
+        encoded.sub_chunks[0].runs = runs;
+        encoded.sub_chunks[0].runs[0] = 0x7f;
+        assert!(match encoded.decode() {
+            Err(DecodeError::InvalidPaletteIndex(0x7f)) => true,
+            _ => false,
+        });
+    }
+}
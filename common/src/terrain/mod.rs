@@ -1,6 +1,7 @@
 pub mod biome;
 pub mod block;
 pub mod chonk;
+pub mod encoding;
 pub mod structure;
 
 // Reexports
@@ -58,4 +59,6 @@ impl TerrainChunkMeta {
 // Terrain type aliases
 
 pub type TerrainChunk = chonk::Chonk<Block, TerrainChunkSize, TerrainChunkMeta>;
+pub type EncodedTerrainChunk = encoding::EncodedChonk<Block, TerrainChunkSize, TerrainChunkMeta>;
+pub type TerrainChunkDelta = encoding::ChonkDelta<Block, TerrainChunkSize, TerrainChunkMeta>;
 pub type TerrainGrid = VolGrid2d<TerrainChunk>;
@@ -0,0 +1,196 @@
+use crate::report::{BotReport, Failure, Stage};
+use client::{Client, Error, Event};
+use common::{
+    clock::Clock,
+    comp::{self, humanoid, item::Tool},
+    msg::ClientState,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use vek::*;
+
+/// How long a bot waits for the server to spawn its character.
+const CHARACTER_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often each bot samples its ping.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the bots do once they are in the game. Rates are in times per second, per bot.
+#[derive(Copy, Clone, Debug)]
+pub struct Behaviour {
+    /// How often a bot picks a new direction to walk in, or stops walking.
+    pub turn_rate: f64,
+    pub jump_rate: f64,
+    pub attack_rate: f64,
+    pub chat_rate: f64,
+}
+
+impl Default for Behaviour {
+    fn default() -> Self {
+        Self {
+            turn_rate: 0.5,
+            jump_rate: 0.2,
+            attack_rate: 0.5,
+            chat_rate: 0.05,
+        }
+    }
+}
+
+/// A scripted player.
+pub struct Bot {
+    index: usize,
+    client: Client,
+    rng: StdRng,
+    behaviour: Behaviour,
+    tps: u64,
+    move_dir: Vec2<f32>,
+    chat_sent: u64,
+}
+
+impl Bot {
+    /// Connect bot number `index` to the server at `addr`, register it and spawn its character.
+    pub fn connect(
+        index: usize,
+        addr: SocketAddr,
+        view_distance: u32,
+        behaviour: Behaviour,
+        tps: u64,
+    ) -> Result<Self, Failure> {
+        let fail = |stage: Stage| {
+            move |error: Error| Failure {
+                bot: index,
+                stage,
+                error,
+            }
+        };
+
+        let mut client = Client::new(addr, Some(view_distance)).map_err(fail(Stage::Connect))?;
+        let alias = format!("bot_{}", index);
+        client
+            .register(
+                comp::Player::new(alias.clone(), Some(view_distance)),
+                alias.clone(),
+            )
+            .map_err(fail(Stage::Register))?;
+
+        let mut rng = StdRng::seed_from_u64(index as u64);
+        let tools = [Tool::Sword, Tool::Axe, Tool::Hammer, Tool::Bow];
+        client.request_character(
+            alias,
+            comp::Body::Humanoid(humanoid::Body::random()),
+            Some(tools[rng.gen_range(0, tools.len())]),
+        );
+        let requested = Instant::now();
+        let mut clock = Clock::start();
+        while client.get_client_state() != ClientState::Character {
+            if requested.elapsed() > CHARACTER_TIMEOUT {
+                return Err(fail(Stage::Character)(Error::ServerTimeout));
+            }
+            client
+                .tick(comp::Controller::default(), clock.get_last_delta())
+                .map_err(fail(Stage::Character))?;
+            client.cleanup();
+            clock.tick(Duration::from_millis(1000 / tps));
+        }
+
+        Ok(Self {
+            index,
+            client,
+            rng,
+            behaviour,
+            tps,
+            move_dir: Vec2::zero(),
+            chat_sent: 0,
+        })
+    }
+
+    /// Play until `until`, recording what the bot experiences in `report`.
+    pub fn play(mut self, until: Instant, report: &mut BotReport) -> Result<(), Failure> {
+        let mut clock = Clock::start();
+        let mut last_ping_sample = Instant::now();
+        while Instant::now() < until {
+            let controller = self.next_controller();
+            let events = self
+                .client
+                .tick(controller, clock.get_last_delta())
+                .map_err(|error| Failure {
+                    bot: self.index,
+                    stage: Stage::Play,
+                    error,
+                })?;
+            for event in events {
+                if let Event::Disconnect = event {
+                    return Err(Failure {
+                        bot: self.index,
+                        stage: Stage::Play,
+                        error: Error::Other("Disconnected by the server".to_owned()),
+                    });
+                }
+            }
+            self.client.cleanup();
+
+            report
+                .chunk_load_times
+                .extend(self.client.take_chunk_load_times());
+            if last_ping_sample.elapsed() >= PING_INTERVAL {
+                last_ping_sample = Instant::now();
+                // There is no ping until the server answered the first one.
+                let ping = self.client.get_ping_ms();
+                if ping > 0.0 {
+                    report.pings_ms.push(ping);
+                }
+            }
+
+            clock.tick(Duration::from_millis(1000 / self.tps));
+        }
+        report.chat_sent = self.chat_sent;
+        Ok(())
+    }
+
+    /// Whether something that happens `rate` times per second happens this tick.
+    fn happens(&mut self, rate: f64) -> bool {
+        self.rng
+            .gen_bool((rate / self.tps as f64).min(1.0).max(0.0))
+    }
+
+    fn next_controller(&mut self) -> comp::Controller {
+        let mut controller = comp::Controller::default();
+        let is_dead = self
+            .client
+            .state()
+            .read_storage::<comp::Stats>()
+            .get(self.client.entity())
+            .map_or(false, |stats| stats.is_dead);
+        if is_dead {
+            controller.respawn = true;
+            return controller;
+        }
+
+        if self.happens(self.behaviour.turn_rate) {
+            // Stand still every now and then.
+            self.move_dir = if self.rng.gen_bool(0.2) {
+                Vec2::zero()
+            } else {
+                let angle = self.rng.gen_range(0.0, std::f32::consts::PI * 2.0);
+                Vec2::new(angle.cos(), angle.sin())
+            };
+        }
+        controller.move_dir = self.move_dir;
+        if self.move_dir != Vec2::zero() {
+            controller.look_dir = Vec3::new(self.move_dir.x, self.move_dir.y, 0.0);
+        }
+        controller.jump = self.happens(self.behaviour.jump_rate);
+        controller.primary = self.happens(self.behaviour.attack_rate);
+
+        if self.happens(self.behaviour.chat_rate) {
+            self.chat_sent += 1;
+            self.client.send_chat(format!(
+                "Message {} from bot {}",
+                self.chat_sent, self.index
+            ));
+        }
+        controller
+    }
+}
@@ -0,0 +1,168 @@
+#![deny(unsafe_code)]
+#![feature(duration_float)]
+
+//! Headless bots that play on a server to find out how many players it can handle.
+
+pub mod bot;
+pub mod report;
+
+// Reexports
+pub use crate::{
+    bot::{Behaviour, Bot},
+    report::{Failure, Report, Stage},
+};
+
+use common::clock::Clock;
+use log::{info, warn};
+use server::{Event, Input, Server, ServerSettings};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const SERVER_TPS: u64 = 30;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bots: usize,
+    /// How long each bot plays once it is in the game.
+    pub duration: Duration,
+    /// The time between connecting one bot and the next.
+    pub spawn_interval: Duration,
+    pub view_distance: u32,
+    /// How often each bot ticks its client per second.
+    pub tps: u64,
+    pub behaviour: Behaviour,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bots: 10,
+            duration: Duration::from_secs(60),
+            spawn_interval: Duration::from_millis(100),
+            view_distance: 5,
+            tps: 30,
+            behaviour: Behaviour::default(),
+        }
+    }
+}
+
+/// Let `config.bots` bots play on the server at `addr` and collect what they measured.
+pub fn run(addr: SocketAddr, config: &Config) -> Report {
+    let bots = (0..config.bots)
+        .map(|index| {
+            if index > 0 {
+                thread::sleep(config.spawn_interval);
+            }
+            let config = config.clone();
+            thread::Builder::new()
+                .name(format!("bot-{}", index))
+                .spawn(move || -> Result<_, Failure> {
+                    let mut report = report::BotReport::default();
+                    let bot = Bot::connect(
+                        index,
+                        addr,
+                        config.view_distance,
+                        config.behaviour,
+                        config.tps,
+                    )?;
+                    info!("Bot {} spawned", index);
+                    let result = bot.play(Instant::now() + config.duration, &mut report);
+                    Ok((report, result.err()))
+                })
+                .expect("Failed to start a bot thread")
+        })
+        .collect::<Vec<_>>();
+
+    let mut report = Report {
+        bots: config.bots,
+        ..Report::default()
+    };
+    for bot in bots {
+        match bot.join().expect("A bot panicked") {
+            Ok((bot_report, failure)) => {
+                report.spawned += 1;
+                report.add(bot_report);
+                report.failures.extend(failure);
+            }
+            Err(failure) => report.failures.push(failure),
+        }
+    }
+    for failure in &report.failures {
+        warn!(
+            "Bot {} failed while {}: {:?}",
+            failure.bot, failure.stage, failure.error
+        );
+    }
+    report
+}
+
+/// A server running on a background thread, for testing without a separate server process.
+pub struct LocalServer {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LocalServer {
+    /// Start a server for `max_players` players on `addr`. Its accounts and characters are kept
+    /// in `data_dir`.
+    pub fn start(
+        addr: SocketAddr,
+        max_players: usize,
+        data_dir: PathBuf,
+    ) -> Result<Self, server::Error> {
+        let settings = ServerSettings {
+            max_players,
+            accounts_file: data_dir.join("accounts.ron"),
+            banlist_file: data_dir.join("banlist.ron"),
+            data_dir,
+            ..ServerSettings::singleplayer()
+        };
+        let mut server = Server::bind(addr, settings)?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let running2 = running.clone();
+        let thread = thread::spawn(move || {
+            let mut clock = Clock::start();
+            while running2.load(Ordering::Relaxed) {
+                let events = server
+                    .tick(Input::default(), clock.get_last_delta())
+                    .expect("Failed to tick the server");
+                for event in events {
+                    if let Event::Shutdown = event {
+                        return;
+                    }
+                }
+                server.cleanup();
+                clock.tick(Duration::from_millis(1000 / SERVER_TPS));
+            }
+        });
+
+        Ok(Self {
+            addr,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,144 @@
+#![deny(unsafe_code)]
+
+use clap::{App, Arg, ArgMatches};
+use log::info;
+use std::{
+    env, fs,
+    net::{SocketAddr, ToSocketAddrs},
+    process,
+    str::FromStr,
+    time::Duration,
+};
+use veloren_load_test::{Behaviour, Config, LocalServer};
+
+fn value<T: FromStr>(matches: &ArgMatches, name: &str) -> T {
+    let value = matches.value_of(name).unwrap();
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid value for --{}: {}", name, value);
+        process::exit(2);
+    })
+}
+
+fn main() {
+    // Initialize logging.
+    pretty_env_logger::init();
+
+    let matches = App::new("veloren-load-test")
+        .about("Lets headless bots play on a server and reports how well it held up")
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .takes_value(true)
+                .required_unless("local")
+                .help("The address of the server to test"),
+        )
+        .arg(
+            Arg::with_name("local")
+                .long("local")
+                .conflicts_with("server")
+                .help("Start a server in this process and test it instead"),
+        )
+        .arg(Arg::with_name("bots").long("bots").default_value("10"))
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .default_value("60")
+                .help("How long each bot plays, in seconds"),
+        )
+        .arg(
+            Arg::with_name("spawn-interval")
+                .long("spawn-interval")
+                .default_value("100")
+                .help("The time between connecting one bot and the next, in milliseconds"),
+        )
+        .arg(
+            Arg::with_name("view-distance")
+                .long("view-distance")
+                .default_value("5"),
+        )
+        .arg(Arg::with_name("tps").long("tps").default_value("30"))
+        .arg(
+            Arg::with_name("turn-rate")
+                .long("turn-rate")
+                .default_value("0.5")
+                .help("How often each bot changes direction, per second"),
+        )
+        .arg(
+            Arg::with_name("jump-rate")
+                .long("jump-rate")
+                .default_value("0.2")
+                .help("How often each bot jumps, per second"),
+        )
+        .arg(
+            Arg::with_name("attack-rate")
+                .long("attack-rate")
+                .default_value("0.5")
+                .help("How often each bot attacks, per second"),
+        )
+        .arg(
+            Arg::with_name("chat-rate")
+                .long("chat-rate")
+                .default_value("0.05")
+                .help("How often each bot sends a chat message, per second"),
+        )
+        .arg(
+            Arg::with_name("max-failures")
+                .long("max-failures")
+                .default_value("0")
+                .help("Exit with an error if more bots than this fail"),
+        )
+        .get_matches();
+
+    let config = Config {
+        bots: value(&matches, "bots"),
+        duration: Duration::from_secs(value(&matches, "duration")),
+        spawn_interval: Duration::from_millis(value(&matches, "spawn-interval")),
+        view_distance: value(&matches, "view-distance"),
+        tps: value::<u64>(&matches, "tps").max(1),
+        behaviour: Behaviour {
+            turn_rate: value(&matches, "turn-rate"),
+            jump_rate: value(&matches, "jump-rate"),
+            attack_rate: value(&matches, "attack-rate"),
+            chat_rate: value(&matches, "chat-rate"),
+        },
+    };
+    let max_failures: usize = value(&matches, "max-failures");
+
+    // The local server's accounts and characters are thrown away afterwards.
+    let data_dir = env::temp_dir().join(format!("veloren-load-test-{}", process::id()));
+    let local_server = if matches.is_present("local") {
+        let addr = SocketAddr::from((
+            [127, 0, 0, 1],
+            portpicker::pick_unused_port().expect("Failed to find unused port!"),
+        ));
+        info!("Starting a local server on {}...", addr);
+        Some(
+            LocalServer::start(addr, config.bots.max(1), data_dir.clone())
+                .expect("Failed to create server instance!"),
+        )
+    } else {
+        None
+    };
+    let addr = match &local_server {
+        Some(server) => server.addr(),
+        None => matches
+            .value_of("server")
+            .unwrap()
+            .to_socket_addrs()
+            .expect("Invalid server address")
+            .next()
+            .unwrap(),
+    };
+
+    info!("Starting {} bots against {}...", config.bots, addr);
+    let report = veloren_load_test::run(addr, &config);
+    println!("{}", report);
+
+    if let Some(server) = local_server {
+        drop(server);
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+    if report.failures.len() > max_failures {
+        process::exit(1);
+    }
+}
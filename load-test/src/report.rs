@@ -0,0 +1,137 @@
+use client::Error;
+use std::{fmt, time::Duration};
+
+/// What a bot was doing when it failed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stage {
+    Connect,
+    Register,
+    Character,
+    Play,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Stage::Connect => "connecting",
+            Stage::Register => "registering",
+            Stage::Character => "spawning its character",
+            Stage::Play => "playing",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A bot that lost its connection or was turned away by the server.
+#[derive(Debug)]
+pub struct Failure {
+    pub bot: usize,
+    pub stage: Stage,
+    pub error: Error,
+}
+
+/// What a single bot measured.
+#[derive(Default)]
+pub struct BotReport {
+    pub pings_ms: Vec<f64>,
+    pub chunk_load_times: Vec<Duration>,
+    pub chat_sent: u64,
+}
+
+/// What all bots measured during a run.
+#[derive(Default)]
+pub struct Report {
+    pub bots: usize,
+    /// The number of bots that got into the game.
+    pub spawned: usize,
+    pub failures: Vec<Failure>,
+    pub pings_ms: Vec<f64>,
+    pub chunk_load_times: Vec<Duration>,
+    pub chat_sent: u64,
+}
+
+impl Report {
+    pub fn add(&mut self, bot: BotReport) {
+        self.pings_ms.extend(bot.pings_ms);
+        self.chunk_load_times.extend(bot.chunk_load_times);
+        self.chat_sent += bot.chat_sent;
+    }
+
+    /// The ping below which `percentile` percent of the pings are, in milliseconds.
+    pub fn ping_percentile(&self, percentile: f64) -> Option<f64> {
+        let mut pings = self.pings_ms.clone();
+        pings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        nearest_rank(&pings, percentile).copied()
+    }
+
+    /// The load time below which `percentile` percent of the chunk load times are.
+    pub fn chunk_load_percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut times = self.chunk_load_times.clone();
+        times.sort();
+        nearest_rank(&times, percentile).copied()
+    }
+}
+
+fn nearest_rank<T>(sorted: &[T], percentile: f64) -> Option<&T> {
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.max(1) - 1)
+}
+
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 100.0];
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Bots: {} started, {} spawned, {} failed",
+            self.bots,
+            self.spawned,
+            self.failures.len()
+        )?;
+        for failure in &self.failures {
+            writeln!(
+                f,
+                "  bot {} failed while {}: {:?}",
+                failure.bot, failure.stage, failure.error
+            )?;
+        }
+
+        writeln!(f, "Ping ({} samples):", self.pings_ms.len())?;
+        for percentile in PERCENTILES.iter() {
+            if let Some(ping) = self.ping_percentile(*percentile) {
+                writeln!(f, "  p{}: {:.1}ms", percentile, ping)?;
+            }
+        }
+
+        writeln!(
+            f,
+            "Chunk load time ({} chunks):",
+            self.chunk_load_times.len()
+        )?;
+        for percentile in PERCENTILES.iter() {
+            if let Some(time) = self.chunk_load_percentile(*percentile) {
+                writeln!(f, "  p{}: {:.1}ms", percentile, time.as_secs_f64() * 1000.0)?;
+            }
+        }
+
+        write!(f, "Chat messages sent: {}", self.chat_sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let report = Report {
+            pings_ms: (1..=100).rev().map(f64::from).collect(),
+            ..Report::default()
+        };
+        assert_eq!(report.ping_percentile(50.0), Some(50.0));
+        assert_eq!(report.ping_percentile(99.0), Some(99.0));
+        assert_eq!(report.ping_percentile(100.0), Some(100.0));
+        assert_eq!(report.ping_percentile(0.0), Some(1.0));
+        assert_eq!(Report::default().ping_percentile(50.0), None);
+    }
+}
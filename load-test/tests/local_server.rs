@@ -0,0 +1,30 @@
+use std::{env, fs, net::SocketAddr, time::Duration};
+use veloren_load_test::{Behaviour, Config, LocalServer};
+
+#[test]
+fn bots_play_on_a_local_server() {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 12470));
+    let data_dir = env::temp_dir().join("veloren-load-test-tests");
+    let server = LocalServer::start(addr, 4, data_dir.clone()).unwrap();
+
+    let config = Config {
+        bots: 3,
+        duration: Duration::from_secs(5),
+        spawn_interval: Duration::from_millis(50),
+        view_distance: 2,
+        tps: 20,
+        behaviour: Behaviour {
+            chat_rate: 2.0,
+            ..Behaviour::default()
+        },
+    };
+    let report = veloren_load_test::run(server.addr(), &config);
+    drop(server);
+    let _ = fs::remove_dir_all(&data_dir);
+
+    assert!(report.failures.is_empty(), "{}", report);
+    assert_eq!(report.spawned, 3);
+    assert!(report.ping_percentile(50.0).is_some());
+    assert!(!report.chunk_load_times.is_empty());
+    assert!(report.chat_sent > 0);
+}
@@ -3,17 +3,46 @@
 use common::clock::Clock;
 use heaptrack::track_mem;
 use log::info;
-use server::{Event, Input, Server, ServerSettings};
-use std::time::Duration;
+use server::{
+    replay::{Outcome, Replay},
+    Event, Input, Server, ServerSettings,
+};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
 
 track_mem!();
 
 const TPS: u64 = 30;
 
+/// Exit code after a requested shutdown (e.g. via `/shutdown`), so that a supervisor can tell it
+/// apart from a crash and restart the server.
+const EXIT_CODE_SHUTDOWN: i32 = 3;
+
+const USAGE: &str = "Usage: veloren-server-cli [--record <file> | --replay <file>]";
+
 fn main() {
     // Init logging
     pretty_env_logger::init();
 
+    let mut record = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--record", Some(path)) => record = Some(PathBuf::from(path)),
+            ("--replay", Some(path)) => replay(Path::new(&path)),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+
     info!("Starting server-cli...");
 
     // Set up an fps clock
@@ -23,11 +52,41 @@ fn main() {
     let settings = ServerSettings::load();
 
     // Create server
-    let mut server = Server::new(settings).expect("Failed to create server instance!");
+    let mut server = Server::new(settings)
+        .expect("Failed to create server instance!")
+        .with_settings_reloading();
+    if let Some(path) = record {
+        info!("Recording the session to {:?}", path);
+        server = server
+            .with_recording(&path)
+            .expect("Failed to start recording!");
+    }
+
+    // Read console commands without blocking the tick loop.
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut buffer = String::new();
+        loop {
+            buffer.clear();
+            match stdin.read_line(&mut buffer) {
+                // Stop reading once stdin is closed, e.g. when running as a service.
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if tx.send(buffer.trim().to_string()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
 
-    loop {
+    'running: loop {
+        let input = Input {
+            console: rx.try_iter().collect(),
+        };
         let events = server
-            .tick(Input::default(), clock.get_last_delta())
+            .tick(input, clock.get_last_delta())
             .expect("Failed to tick server");
 
         for event in events {
@@ -35,6 +94,8 @@ fn main() {
                 Event::ClientConnected { entity: _ } => info!("Client connected!"),
                 Event::ClientDisconnected { entity: _ } => info!("Client disconnected!"),
                 Event::Chat { entity: _, msg } => info!("[Client] {}", msg),
+                Event::ConsoleOutput { msg } => println!("{}", msg),
+                Event::Shutdown => break 'running,
             }
         }
 
@@ -44,4 +105,38 @@ fn main() {
         // Wait for the next tick.
         clock.tick(Duration::from_millis(1000 / TPS));
     }
+
+    info!("Shutting down server-cli...");
+
+    // `process::exit` doesn't run destructors, so drop the server first.
+    drop(server);
+    process::exit(EXIT_CODE_SHUTDOWN);
+}
+
+/// Replay a recorded session and exit with an error if it didn't lead to the recorded state.
+fn replay(path: &Path) -> ! {
+    info!("Replaying {:?}...", path);
+    let replay = Replay::open(path).expect("Failed to open the recording!");
+    // The replayed server's accounts and terrain changes are thrown away afterwards.
+    let data_dir = env::temp_dir().join(format!("veloren-replay-{}", process::id()));
+    let outcome = replay.run(data_dir.clone());
+    let _ = fs::remove_dir_all(&data_dir);
+
+    match outcome.expect("Failed to replay the recording!") {
+        Outcome::Finished { ticks } => {
+            println!("Replayed {} ticks, the state matched the recording", ticks);
+            process::exit(0);
+        }
+        Outcome::Diverged {
+            tick,
+            expected,
+            found,
+        } => {
+            println!(
+                "The replay diverged at tick {}: checksum {:016x} instead of {:016x}",
+                tick, found, expected
+            );
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,138 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::black_box;
+use criterion::Criterion;
+
+use common::{
+    comp,
+    msg::{CompUpdateKind, EcsCompPacket},
+};
+use hashbrown::HashMap;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use specs::{Builder, Entity as EcsEntity, World};
+use vek::*;
+use veloren_server::region::{self, RegionMap};
+
+const ENTITIES: usize = 1000;
+const CLIENTS: usize = 100;
+const VIEW_DISTANCE: u32 = 10;
+/// The width of the area that everything is spread over, in blocks.
+const AREA: f32 = 8192.0;
+
+fn random_pos(rng: &mut StdRng) -> Vec3<f32> {
+    Vec3::new(rng.gen_range(0.0, AREA), rng.gen_range(0.0, AREA), 0.0)
+}
+
+/// How the server decided who to send an entity update to before there were regions: by
+/// checking every client.
+fn in_vd(client_pos: Vec3<f32>, pos: Vec3<f32>) -> bool {
+    Vec2::from(pos - client_pos)
+        .map(|d: f32| (d.abs() as u32 / 32).checked_sub(2).unwrap_or(0))
+        .magnitude_squared()
+        < VIEW_DISTANCE.pow(2)
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut world = World::new();
+    let mut entities = (0..ENTITIES)
+        .map(|_| (world.create_entity().build(), random_pos(&mut rng)))
+        .collect::<Vec<(EcsEntity, _)>>();
+    let clients = (0..CLIENTS)
+        .map(|i| (entities[i].0, entities[i].1))
+        .collect::<Vec<_>>();
+
+    let mut regions = RegionMap::new();
+    regions.update(entities.iter().copied());
+    let subscriptions = clients
+        .iter()
+        .map(|(entity, pos)| {
+            (
+                *entity,
+                region::subscribed_regions(*pos, VIEW_DISTANCE, None),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    c.bench_function("regions: update", |b| {
+        b.iter(|| {
+            for (_, pos) in entities.iter_mut() {
+                *pos += Vec3::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), 0.0);
+            }
+            black_box(regions.update(entities.iter().copied()));
+        })
+    });
+
+    c.bench_function("regions: subscriptions", |b| {
+        b.iter(|| {
+            for ((_, pos), (_, old)) in clients.iter().zip(subscriptions.iter()) {
+                black_box(region::subscribed_regions(*pos, VIEW_DISTANCE, Some(old)));
+            }
+        })
+    });
+
+    // Find the recipients of an update of every entity.
+    c.bench_function("regions: recipients", |b| {
+        b.iter(|| {
+            let mut subscribers = HashMap::<Vec2<i32>, Vec<EcsEntity>>::new();
+            for (client, subscription) in &subscriptions {
+                for region in subscription {
+                    subscribers.entry(*region).or_default().push(*client);
+                }
+            }
+            let mut recipients = 0;
+            for (entity, _) in &entities {
+                if let Some(subscribers) = regions
+                    .region_of(*entity)
+                    .and_then(|region| subscribers.get(&region))
+                {
+                    recipients += subscribers.len();
+                }
+            }
+            black_box(recipients)
+        })
+    });
+
+    // Every client gets its own copy of the updates of a tick, without those of the entities it
+    // can't see, like in `Server::sync_clients`.
+    let comp_updates = entities
+        .iter()
+        .map(|(entity, _)| {
+            let packet = EcsCompPacket::from(comp::Ori(Vec3::unit_y()));
+            (entity.id() as u64, CompUpdateKind::Modified(packet))
+        })
+        .collect::<Vec<_>>();
+    c.bench_function("regions: sync package filtering", |b| {
+        b.iter(|| {
+            for (client, subscription) in &subscriptions {
+                let mut updates = comp_updates.clone();
+                updates.retain(|(uid, _)| {
+                    let entity = world.entities().entity(*uid as u32);
+                    entity == *client
+                        || regions
+                            .region_of(entity)
+                            .map_or(true, |region| subscription.contains(&region))
+                });
+                black_box(updates);
+            }
+        })
+    });
+
+    c.bench_function("naive: recipients", |b| {
+        b.iter(|| {
+            let mut recipients = 0;
+            for (_, pos) in &entities {
+                for (_, client_pos) in &clients {
+                    if in_vd(*client_pos, *pos) {
+                        recipients += 1;
+                    }
+                }
+            }
+            black_box(recipients)
+        })
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -1,32 +1,286 @@
+use crate::util;
 use hashbrown::HashMap;
+use hmac::{Hmac, Mac};
 use log::{info, warn};
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    InvalidCredentials,
+    InvalidToken,
+    ExpiredToken,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RegisterError {
+    AlreadyExists,
+    HashingFailed,
+    Unsupported,
+}
+
+/// A source of truth for who is allowed to log in.
+pub trait AuthBackend: Send {
+    /// Check the credentials a client sent with `ClientMsg::Register`. Depending on the backend,
+    /// `password` is either a plain password or a login token.
+    fn query(&mut self, username: &str, password: &str) -> Result<(), AuthError>;
+
+    /// Create a new account, if the backend manages its own accounts.
+    fn register(&mut self, _username: String, _password: String) -> Result<(), RegisterError> {
+        Err(RegisterError::Unsupported)
+    }
+}
+
+/// The on-disk representation of the account database. Passwords are only ever stored as
+/// salted argon2 hashes (in the PHC string format, which embeds the salt and parameters).
+#[derive(Default, Serialize, Deserialize)]
+struct AccountDb {
+    accounts: HashMap<String, String>,
+}
+
+/// Authenticates against the server's own username/password database.
 pub struct AuthProvider {
     accounts: HashMap<String, String>,
+    path: PathBuf,
+    auto_register: bool,
 }
 
 impl AuthProvider {
-    pub fn new() -> Self {
-        AuthProvider {
-            accounts: HashMap::new(),
+    /// Load the account database from `path`, starting with an empty one if it doesn't exist.
+    /// If `auto_register` is set, unknown usernames are registered on their first login.
+    pub fn load(path: PathBuf, auto_register: bool) -> Result<Self, String> {
+        let accounts = match fs::File::open(&path) {
+            // Refuse to start with an empty database if the file is broken, since the next flush
+            // would overwrite every existing account.
+            Ok(file) => {
+                ron::de::from_reader::<_, AccountDb>(file)
+                    .map_err(|e| format!("Failed to parse account database {:?}: {}", path, e))?
+                    .accounts
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            // Likewise, a file that exists but can't be read must not be treated as empty.
+            Err(e) => return Err(format!("Failed to open account database {:?}: {}", path, e)),
+        };
+
+        Ok(AuthProvider {
+            accounts,
+            path,
+            auto_register,
+        })
+    }
+
+    fn hash_password(password: &str) -> Option<String> {
+        let salt = rand::thread_rng().gen::<[u8; 16]>();
+        argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default()).ok()
+    }
+
+    fn save(&self) {
+        let db = AccountDb {
+            accounts: self.accounts.clone(),
+        };
+        let result = ron::ser::to_string_pretty(&db, ron::ser::PrettyConfig::default())
+            .map_err(|e| e.to_string())
+            .and_then(|s| util::write_atomically(&self.path, &s).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::error!("Failed to save account database {:?}: {}", self.path, e);
         }
     }
+}
+
+impl AuthBackend for AuthProvider {
+    fn query(&mut self, username: &str, password: &str) -> Result<(), AuthError> {
+        let valid = match self.accounts.get(username) {
+            Some(hash) => argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false),
+            None if self.auto_register => self
+                .register(username.to_string(), password.to_string())
+                .is_ok(),
+            None => {
+                warn!("Unknown user '{}' attempted to log in!", username);
+                return Err(AuthError::InvalidCredentials);
+            }
+        };
 
-    pub fn query(&mut self, username: String, password: String) -> bool {
-        let pwd = password.clone();
-        if self.accounts.entry(username.clone()).or_insert_with(|| {
-            info!("Registered new user '{}'", &username);
-            pwd
-        }) == &password
-        {
+        if valid {
             info!("User '{}' successfully authenticated", username);
-            true
+            Ok(())
         } else {
             warn!(
-                "User '{}' attempted to log in with invalid password '{}'!",
-                username, password
+                "User '{}' attempted to log in with an invalid password!",
+                username
+            );
+            Err(AuthError::InvalidCredentials)
+        }
+    }
+
+    /// Create a new account. Changes are flushed to disk immediately.
+    fn register(&mut self, username: String, password: String) -> Result<(), RegisterError> {
+        if self.accounts.contains_key(&username) {
+            return Err(RegisterError::AlreadyExists);
+        }
+
+        let hash = Self::hash_password(&password).ok_or(RegisterError::HashingFailed)?;
+        self.accounts.insert(username.clone(), hash);
+        info!("Registered new user '{}'", username);
+        self.save();
+        Ok(())
+    }
+}
+
+/// Authenticates using tokens issued by an external identity service that shares a secret with
+/// this server, so no account data has to be stored here.
+///
+/// A token has the form `<username>:<expiry>:<signature>`, where `expiry` is a UNIX timestamp in
+/// seconds and `signature` is the hex-encoded HMAC-SHA256 of `<username>:<expiry>`.
+pub struct TokenAuthProvider {
+    secret: Vec<u8>,
+}
+
+impl TokenAuthProvider {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(&self.secret).expect("HMAC accepts keys of any size");
+        mac.input(payload.as_bytes());
+        mac
+    }
+
+    /// Issue a token for `username` that is valid until `expiry` (in seconds since the UNIX
+    /// epoch).
+    pub fn issue(&self, username: &str, expiry: u64) -> String {
+        let payload = format!("{}:{}", username, expiry);
+        let signature = hex::encode(self.mac(&payload).result().code());
+        format!("{}:{}", payload, signature)
+    }
+}
+
+impl AuthBackend for TokenAuthProvider {
+    fn query(&mut self, username: &str, token: &str) -> Result<(), AuthError> {
+        // Split from the right so that the username is whatever remains.
+        let mut parts = token.rsplitn(3, ':');
+        let (signature, expiry, token_username) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(signature), Some(expiry), Some(token_username)) => {
+                (signature, expiry, token_username)
+            }
+            _ => return Err(AuthError::InvalidToken),
+        };
+
+        let signature = hex::decode(signature).map_err(|_| AuthError::InvalidToken)?;
+        let payload = format!("{}:{}", token_username, expiry);
+        if self.mac(&payload).verify(&signature).is_err() || token_username != username {
+            warn!(
+                "User '{}' attempted to log in with an invalid token!",
+                username
             );
-            false
+            return Err(AuthError::InvalidToken);
         }
+
+        let expiry = expiry.parse::<u64>().map_err(|_| AuthError::InvalidToken)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if now >= expiry {
+            info!(
+                "User '{}' attempted to log in with an expired token",
+                username
+            );
+            return Err(AuthError::ExpiredToken);
+        }
+
+        info!("User '{}' successfully authenticated", username);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn accounts_survive_reload() {
+        let dir = env::temp_dir().join(format!("veloren-auth-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("accounts.ron");
+        let _ = fs::remove_file(&path);
+
+        // A missing file is an empty database.
+        let mut auth = AuthProvider::load(path.clone(), false).unwrap();
+        assert_eq!(
+            auth.query("alice", "hunter2"),
+            Err(AuthError::InvalidCredentials)
+        );
+        auth.register("alice".to_owned(), "hunter2".to_owned())
+            .unwrap();
+
+        let mut auth = AuthProvider::load(path.clone(), false).unwrap();
+        assert_eq!(auth.query("alice", "hunter2"), Ok(()));
+        assert_eq!(
+            auth.query("alice", "wrong"),
+            Err(AuthError::InvalidCredentials)
+        );
+        assert!(!path.with_extension("ron.tmp").exists());
+
+        // A path that can't be read as a database must not be mistaken for an empty one.
+        assert!(AuthProvider::load(dir.clone(), false).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn issued_tokens_verify() {
+        let mut auth = TokenAuthProvider::new("secret");
+        let token = auth.issue("alice", now() + 60);
+        assert_eq!(auth.query("alice", &token), Ok(()));
+        // Usernames may contain the separator.
+        let token = auth.issue("a:b", now() + 60);
+        assert_eq!(auth.query("a:b", &token), Ok(()));
+    }
+
+    #[test]
+    fn forged_tokens_are_rejected() {
+        let mut auth = TokenAuthProvider::new("secret");
+        let expiry = now() + 60;
+        let token = auth.issue("alice", expiry);
+
+        // Someone else's token.
+        assert_eq!(auth.query("bob", &token), Err(AuthError::InvalidToken));
+        // Signed with a different secret.
+        let foreign = TokenAuthProvider::new("other").issue("alice", expiry);
+        assert_eq!(auth.query("alice", &foreign), Err(AuthError::InvalidToken));
+        // A later expiry than was signed.
+        let extended = token.replacen(&expiry.to_string(), &(expiry + 6000).to_string(), 1);
+        assert_eq!(auth.query("alice", &extended), Err(AuthError::InvalidToken));
+        // Malformed.
+        assert_eq!(auth.query("alice", "alice"), Err(AuthError::InvalidToken));
+        assert_eq!(
+            auth.query("alice", "alice:1:nothex"),
+            Err(AuthError::InvalidToken)
+        );
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let mut auth = TokenAuthProvider::new("secret");
+        let token = auth.issue("alice", now() - 1);
+        assert_eq!(auth.query("alice", &token), Err(AuthError::ExpiredToken));
     }
 }
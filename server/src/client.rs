@@ -1,32 +1,75 @@
 use common::{
     msg::{ClientMsg, ClientState, RequestStateError, ServerMsg},
-    net::PostBox,
+    net::{PostBox, Priority, UdpLink},
 };
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
+use prometheus::IntCounterVec;
 use specs::Entity as EcsEntity;
+use std::time::Instant;
+use vek::*;
 
 pub struct Client {
     pub client_state: ClientState,
     pub postbox: PostBox<ServerMsg, ClientMsg>,
+    /// The UDP side channel, once it has been offered to the client.
+    pub udp: Option<UdpLink<EcsEntity, ServerMsg>>,
     pub last_ping: f64,
+    /// The account the client authenticated as, once registered.
+    pub account: Option<String>,
+    /// The name of the client's role, once registered.
+    pub role: Option<String>,
+    /// The regions whose entities the client knows about. `None` until the first sync after the
+    /// initial one, which told the client about every entity.
+    pub regions: Option<HashSet<Vec2<i32>>>,
+    /// The sequence number of the last input that the client sent.
+    pub input_seq: Option<u64>,
+    /// When the ping that the client hasn't answered yet was sent.
+    pub ping_sent: Option<Instant>,
+    /// When the client is pinged next.
+    pub next_ping: Instant,
+    /// Counts the messages sent to the client by kind, see `ServerMetrics::messages_sent`.
+    pub sent_messages: IntCounterVec,
 }
 
 impl Client {
     pub fn notify(&mut self, msg: ServerMsg) {
-        self.postbox.send_message(msg);
+        self.sent_messages.with_label_values(&[msg.kind()]).inc();
+        // Physics updates go over UDP when possible.
+        let msg = match &self.udp {
+            Some(udp) => match udp.send(msg) {
+                Ok(()) => return,
+                Err(msg) => msg,
+            },
+            None => msg,
+        };
+        self.send_reliably(msg);
+    }
+    /// Send `msg` over the reliable connection, even if it could go over UDP.
+    pub fn notify_reliably(&mut self, msg: ServerMsg) {
+        self.sent_messages.with_label_values(&[msg.kind()]).inc();
+        self.send_reliably(msg);
+    }
+    /// Send `msg` over the reliable connection, queued like an `EcsSync` so that it can't arrive
+    /// before the syncs that were sent earlier, e.g. the one creating the entity it is about.
+    pub fn notify_after_sync(&mut self, msg: ServerMsg) {
+        self.sent_messages.with_label_values(&[msg.kind()]).inc();
+        self.postbox
+            .send_message_with_priority(msg, Priority::Control);
+    }
+    fn send_reliably(&mut self, msg: ServerMsg) {
+        let priority = msg.priority();
+        self.postbox.send_message_with_priority(msg, priority);
     }
     pub fn allow_state(&mut self, new_state: ClientState) {
         self.client_state = new_state;
-        self.postbox
-            .send_message(ServerMsg::StateAnswer(Ok(new_state)));
+        self.notify(ServerMsg::StateAnswer(Ok(new_state)));
     }
     pub fn error_state(&mut self, error: RequestStateError) {
-        self.postbox
-            .send_message(ServerMsg::StateAnswer(Err((error, self.client_state))));
+        self.notify(ServerMsg::StateAnswer(Err((error, self.client_state))));
     }
     pub fn force_state(&mut self, new_state: ClientState) {
         self.client_state = new_state;
-        self.postbox.send_message(ServerMsg::ForceState(new_state));
+        self.notify(ServerMsg::ForceState(new_state));
     }
 }
 
@@ -57,6 +100,22 @@ impl Clients {
         self.clients.get_mut(entity)
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (EcsEntity, &Client)> {
+        self.clients
+            .iter()
+            .map(|(entity, client)| (*entity, client))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (EcsEntity, &mut Client)> {
+        self.clients
+            .iter_mut()
+            .map(|(entity, client)| (*entity, client))
+    }
+
+    pub fn remove(&mut self, entity: &EcsEntity) -> Option<Client> {
+        self.clients.remove(entity)
+    }
+
     pub fn remove_if<F: FnMut(EcsEntity, &mut Client) -> bool>(&mut self, mut f: F) {
         self.clients.retain(|entity, client| !f(*entity, client));
     }
@@ -97,6 +156,40 @@ impl Clients {
         }
     }
 
+    /// Send `msg` to `entity` over the reliable connection if it is in the game.
+    pub fn notify_ingame_reliably(&mut self, entity: EcsEntity, msg: ServerMsg) {
+        if let Some(client) = self.clients.get_mut(&entity) {
+            if client.client_state == ClientState::Spectator
+                || client.client_state == ClientState::Character
+                || client.client_state == ClientState::Dead
+            {
+                client.notify_reliably(msg);
+            }
+        }
+    }
+
+    /// Send `msg` to those of `entities` that are in the game, apart from `except_entity`.
+    pub fn notify_ingame_among(
+        &mut self,
+        entities: &[EcsEntity],
+        except_entity: Option<EcsEntity>,
+        msg: ServerMsg,
+    ) {
+        for entity in entities {
+            if Some(*entity) == except_entity {
+                continue;
+            }
+            if let Some(client) = self.clients.get_mut(entity) {
+                if client.client_state == ClientState::Spectator
+                    || client.client_state == ClientState::Character
+                    || client.client_state == ClientState::Dead
+                {
+                    client.notify(msg.clone());
+                }
+            }
+        }
+    }
+
     pub fn notify_registered_except(&mut self, except_entity: EcsEntity, msg: ServerMsg) {
         for (entity, client) in self.clients.iter_mut() {
             if client.client_state != ClientState::Connected && *entity != except_entity {
@@ -2,22 +2,39 @@
 //! To implement a new command, add an instance of `ChatCommand` to `CHAT_COMMANDS`
 //! and provide a handler function.
 
-use crate::Server;
+use crate::{
+    auth_provider::RegisterError,
+    moderation::{self, Sanction},
+    Server,
+};
 use chrono::{NaiveTime, Timelike};
 use common::{
     comp,
     event::{EventBus, ServerEvent},
-    msg::ServerMsg,
+    msg::{ServerError, ServerMsg},
     npc::{get_npc_name, NpcKind},
-    state::TimeOfDay,
+    state::{EcsRng, TimeOfDay},
 };
 use rand::Rng;
 use specs::{Builder, Entity as EcsEntity, Join};
+use std::{iter, time::Duration};
 use vek::*;
 
 use lazy_static::lazy_static;
 use scan_fmt::{scan_fmt, scan_fmt_some};
 
+/// Seconds until the server stops when `/shutdown` is given no delay.
+const DEFAULT_SHUTDOWN_DELAY: u64 = 60;
+
+/// Who issued a chat command.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CommandSender {
+    /// A player, identified by the entity they control.
+    Player(EcsEntity),
+    /// The server console. It may run every command, but has no entity of its own.
+    Console,
+}
+
 /// Struct representing a command that a user can run from server chat.
 pub struct ChatCommand {
     /// The keyword used to invoke the command, omitting the leading '/'.
@@ -26,16 +43,14 @@ pub struct ChatCommand {
     arg_fmt: &'static str,
     /// A message that explains how the command is used.
     help_string: &'static str,
-    /// A boolean that is used to check whether the command requires administrator permissions or not.
-    needs_admin: bool,
     /// Handler function called when the command is executed.
     /// # Arguments
     /// * `&mut Server` - the `Server` instance executing the command.
-    /// * `EcsEntity` - an `Entity` corresponding to the player that invoked the command.
+    /// * `CommandSender` - the player or console that invoked the command.
     /// * `String` - a `String` containing the part of the command after the keyword.
     /// * `&ChatCommand` - the command to execute with the above arguments.
     /// Handler functions must parse arguments from the the given `String` (`scan_fmt!` is included for this purpose).
-    handler: fn(&mut Server, EcsEntity, String, &ChatCommand),
+    handler: fn(&mut Server, CommandSender, String, &ChatCommand),
 }
 
 impl ChatCommand {
@@ -44,37 +59,66 @@ impl ChatCommand {
         keyword: &'static str,
         arg_fmt: &'static str,
         help_string: &'static str,
-        needs_admin: bool,
-        handler: fn(&mut Server, EcsEntity, String, &ChatCommand),
+        handler: fn(&mut Server, CommandSender, String, &ChatCommand),
     ) -> Self {
         Self {
             keyword,
             arg_fmt,
             help_string,
-            needs_admin,
             handler,
         }
     }
-    /// Calls the contained handler function, passing `&self` as the last argument.
-    pub fn execute(&self, server: &mut Server, entity: EcsEntity, args: String) {
-        if self.needs_admin {
-            if !server.entity_is_admin(entity) {
-                server.clients.notify(
-                    entity,
-                    ServerMsg::private(String::from(
-                        "Unavailable command '/{}'.\nType '/help' for available commands",
-                    )),
-                );
-                return;
-            } else {
-                (self.handler)(server, entity, args, self);
-            }
+    /// Calls the contained handler function, passing `&self` as the last argument, if the role
+    /// of the invoking player allows it.
+    pub fn execute(&self, server: &mut Server, sender: CommandSender, args: String) {
+        if server.may_run(sender, self.keyword) {
+            (self.handler)(server, sender, args, self);
         } else {
-            (self.handler)(server, entity, args, self);
+            server.notify_sender(
+                sender,
+                ServerMsg::private(format!(
+                    "Unavailable command '/{}'.\nType '/help' for available commands",
+                    self.keyword
+                )),
+            );
         }
     }
 }
 
+/// Commands that send a message to other players, which muted players may not use.
+const MESSAGING_COMMANDS: [&str; 1] = ["tell"];
+
+/// Whether running `cmd` (without the leading '/') would send a message to other players.
+pub fn messages_players(cmd: &str) -> bool {
+    let keyword = cmd.split(' ').next().unwrap_or("");
+    MESSAGING_COMMANDS.contains(&keyword)
+}
+
+/// Commands with a password among their arguments, and how many arguments come before it.
+const SECRET_COMMANDS: [(&str, usize); 1] = [("register", 1)];
+
+/// `cmd` (without the leading '/') with the passwords among its arguments replaced by '*', which
+/// keeps it parseable.
+pub fn redact(cmd: &str) -> String {
+    let mut words = cmd.split(' ');
+    let keyword = words.next().unwrap_or("");
+    let secret = SECRET_COMMANDS.iter().find(|(kwd, _)| *kwd == keyword);
+    let public_args = match secret {
+        Some((_, public_args)) => *public_args,
+        None => return cmd.to_owned(),
+    };
+    iter::once(keyword)
+        .chain(words.enumerate().map(|(i, word)| {
+            if i < public_args || word.is_empty() {
+                word
+            } else {
+                "*"
+            }
+        }))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 lazy_static! {
     /// Static list of chat commands available to the server.
     pub static ref CHAT_COMMANDS: Vec<ChatCommand> = vec![
@@ -82,134 +126,174 @@ lazy_static! {
             "jump",
             "{d} {d} {d}",
             "/jump <dx> <dy> <dz> : Offset your current position",
-            true,
             handle_jump,
         ),
         ChatCommand::new(
             "goto",
             "{d} {d} {d}",
             "/goto <x> <y> <z> : Teleport to a position",
-            true,
             handle_goto,
         ),
         ChatCommand::new(
             "alias",
             "{}",
             "/alias <name> : Change your alias",
-            false,
             handle_alias,
         ),
         ChatCommand::new(
             "tp",
             "{}",
             "/tp <alias> : Teleport to another player",
-            true,
             handle_tp,
         ),
         ChatCommand::new(
             "kill",
             "{}",
             "/kill : Kill yourself",
-            false,
             handle_kill,
         ),
         ChatCommand::new(
             "time",
             "{} {s}",
             "/time <XY:XY> or [Time of day] : Set the time of day",
-            true,
             handle_time,
         ),
         ChatCommand::new(
             "spawn",
             "{} {} {d}",
             "/spawn <alignment> <entity> [amount] : Spawn a test entity",
-            true,
             handle_spawn,
         ),
         ChatCommand::new(
              "players",
              "{}",
              "/players : Lists players currently online",
-             false,
              handle_players,
          ),
         ChatCommand::new(
-            "help", "", "/help: Display this message", false, handle_help),
+            "help", "", "/help: Display this message", handle_help),
         ChatCommand::new(
             "health",
             "{}",
             "/health : Set your current health",
-            true,
             handle_health,
         ),
         ChatCommand::new(
             "build",
             "",
             "/build : Toggles build mode on and off",
-            true,
             handle_build,
         ),
         ChatCommand::new(
             "tell",
             "{}",
             "/tell <alias> <message>: Send a message to another player",
-            false,
             handle_tell,
         ),
         ChatCommand::new(
             "killnpcs",
             "{}",
             "/killnpcs : Kill the NPCs",
-            true,
             handle_killnpcs,
         ),
         ChatCommand::new(
             "object",
             "{}",
             "/object [Name]: Spawn an object",
-            true,
             handle_object,
         ),
         ChatCommand::new(
             "light",
             "{} {} {} {} {} {} {}",
             "/light <opt:  <<cr> <cg> <cb>> <<ox> <oy> <oz>> <<strength>>>: Spawn entity with light",
-            true,
             handle_light,
         ),
         ChatCommand::new(
             "lantern",
             "{}",
             "/lantern : adds/remove light near player",
-            false,
             handle_lantern,
         ),
         ChatCommand::new(
             "explosion",
             "{}",
             "/explosion <radius> : Explodes the ground around you",
-            false,
             handle_explosion,
         ),
         ChatCommand::new(
-            "adminify",
+            "role",
+            "{} {}",
+            "/role <playername> [role] : Show or permanently change the role of a player",
+            handle_role,
+        ),
+        ChatCommand::new(
+            "kick",
+            "{}",
+            "/kick <alias> [reason] : Kick a player from the server",
+            handle_kick,
+        ),
+        ChatCommand::new(
+            "ban",
+            "{}",
+            "/ban <alias> [duration] [reason] : Ban a player, e.g. '/ban foo 2d spamming'",
+            handle_ban,
+        ),
+        ChatCommand::new(
+            "unban",
             "{}",
-            "/adminify <playername> : Temporarily gives a player admin permissions or removes them",
-            true,
-            handle_adminify,
+            "/unban <alias> : Lift a player's ban",
+            handle_unban,
+        ),
+        ChatCommand::new(
+            "mute",
+            "{}",
+            "/mute <alias> [duration] [reason] : Prevent a player from chatting",
+            handle_mute,
+        ),
+        ChatCommand::new(
+            "unmute",
+            "{}",
+            "/unmute <alias> : Allow a muted player to chat again",
+            handle_unmute,
+        ),
+        ChatCommand::new(
+            "whitelist",
+            "{} {}",
+            "/whitelist <add|remove|list> [alias] : Manage who may join the server",
+            handle_whitelist,
+        ),
+        ChatCommand::new(
+            "violations",
+            "{}",
+            "/violations [alias] : List accounts that moved impossibly, or what one of them did",
+            handle_violations,
+        ),
+        ChatCommand::new(
+            "register",
+            "{} {}",
+            "/register <alias> <password> : Create a new account",
+            handle_register,
+        ),
+        ChatCommand::new(
+            "shutdown",
+            "",
+            "/shutdown [delay] [reason] : Stop the server after a countdown, e.g. '/shutdown 5m update'",
+            handle_shutdown,
         ),
         ChatCommand::new(
              "debug_column",
              "{} {}",
              "/debug_column <x> <y> : Prints some debug information about a column",
-             false,
              handle_debug_column,
          ),
     ];
 }
 
-fn handle_jump(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_jump(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     if let Ok((x, y, z)) = scan_fmt!(&args, action.arg_fmt, f32, f32, f32) {
         match server.state.read_component_cloned::<comp::Pos>(entity) {
             Some(current_pos) => {
@@ -226,7 +310,11 @@ fn handle_jump(server: &mut Server, entity: EcsEntity, args: String, action: &Ch
     }
 }
 
-fn handle_goto(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_goto(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     if let Ok((x, y, z)) = scan_fmt!(&args, action.arg_fmt, f32, f32, f32) {
         if server
             .state
@@ -250,7 +338,11 @@ fn handle_goto(server: &mut Server, entity: EcsEntity, args: String, action: &Ch
     }
 }
 
-fn handle_kill(server: &mut Server, entity: EcsEntity, _args: String, _action: &ChatCommand) {
+fn handle_kill(server: &mut Server, sender: CommandSender, _args: String, _action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     server
         .state
         .ecs_mut()
@@ -259,7 +351,7 @@ fn handle_kill(server: &mut Server, entity: EcsEntity, _args: String, _action: &
         .map(|s| s.health.set_to(0, comp::HealthSource::Suicide));
 }
 
-fn handle_time(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_time(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
     let time = scan_fmt_some!(&args, action.arg_fmt, String);
     let new_time = match time.as_ref().map(|s| s.as_str()) {
         Some("midnight") => NaiveTime::from_hms(0, 0, 0),
@@ -274,8 +366,8 @@ fn handle_time(server: &mut Server, entity: EcsEntity, args: String, action: &Ch
             Err(_) => match NaiveTime::parse_from_str(n, "%H:%M") {
                 Ok(time) => time,
                 Err(_) => {
-                    server.clients.notify(
-                        entity,
+                    server.notify_sender(
+                        sender,
                         ServerMsg::private(format!("'{}' is not a valid time.", n)),
                     );
                     return;
@@ -294,7 +386,7 @@ fn handle_time(server: &mut Server, entity: EcsEntity, args: String, action: &Ch
                 Some(time) => format!("It is {}", time.format("%H:%M").to_string()),
                 None => String::from("Unknown Time"),
             };
-            server.clients.notify(entity, ServerMsg::private(msg));
+            server.notify_sender(sender, ServerMsg::private(msg));
             return;
         }
     };
@@ -302,8 +394,8 @@ fn handle_time(server: &mut Server, entity: EcsEntity, args: String, action: &Ch
     server.state.ecs_mut().write_resource::<TimeOfDay>().0 =
         new_time.num_seconds_from_midnight() as f64;
 
-    server.clients.notify(
-        entity,
+    server.notify_sender(
+        sender,
         ServerMsg::private(format!(
             "Time changed to: {}",
             new_time.format("%H:%M").to_string()
@@ -311,7 +403,11 @@ fn handle_time(server: &mut Server, entity: EcsEntity, args: String, action: &Ch
     );
 }
 
-fn handle_health(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_health(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     if let Ok(hp) = scan_fmt!(&args, action.arg_fmt, u32) {
         if let Some(stats) = server
             .state
@@ -334,7 +430,11 @@ fn handle_health(server: &mut Server, entity: EcsEntity, args: String, action: &
     }
 }
 
-fn handle_alias(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_alias(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     if let Ok(alias) = scan_fmt!(&args, action.arg_fmt, String) {
         server
             .state
@@ -349,7 +449,11 @@ fn handle_alias(server: &mut Server, entity: EcsEntity, args: String, action: &C
     }
 }
 
-fn handle_tp(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_tp(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     if let Ok(alias) = scan_fmt!(&args, action.arg_fmt, String) {
         let ecs = server.state.ecs();
         let opt_player = (&ecs.entities(), &ecs.read_storage::<comp::Player>())
@@ -391,7 +495,11 @@ fn handle_tp(server: &mut Server, entity: EcsEntity, args: String, action: &Chat
     }
 }
 
-fn handle_spawn(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_spawn(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     match scan_fmt_some!(&args, action.arg_fmt, String, NpcKind, String) {
         (Some(opt_align), Some(id), opt_amount) => {
             if let Some(agent) = alignment_to_agent(&opt_align, entity) {
@@ -404,15 +512,19 @@ fn handle_spawn(server: &mut Server, entity: EcsEntity, args: String, action: &C
                 match server.state.read_component_cloned::<comp::Pos>(entity) {
                     Some(pos) => {
                         for _ in 0..amount {
-                            let vel = Vec3::new(
-                                rand::thread_rng().gen_range(-2.0, 3.0),
-                                rand::thread_rng().gen_range(-2.0, 3.0),
-                                10.0,
-                            );
+                            let (vel, name, body) = {
+                                let mut rng = server.state.ecs().write_resource::<EcsRng>();
+                                let rng = &mut rng.0;
+                                let vel = Vec3::new(
+                                    rng.gen_range(-2.0, 3.0),
+                                    rng.gen_range(-2.0, 3.0),
+                                    10.0,
+                                );
+                                (vel, get_npc_name(id, rng), kind_to_body(id, rng))
+                            };
 
-                            let body = kind_to_body(id);
                             server
-                                .create_npc(pos, comp::Stats::new(get_npc_name(id), None), body)
+                                .create_npc(pos, comp::Stats::new(name, None), body)
                                 .with(comp::Vel(vel))
                                 .with(comp::MountState::Unmounted)
                                 .with(agent)
@@ -438,32 +550,41 @@ fn handle_spawn(server: &mut Server, entity: EcsEntity, args: String, action: &C
     }
 }
 
-fn handle_players(server: &mut Server, entity: EcsEntity, _args: String, _action: &ChatCommand) {
-    let ecs = server.state.ecs();
-    let players = ecs.read_storage::<comp::Player>();
-    let count = players.join().count();
-    let header_message: String = format!("{} online players: \n", count);
-    if count > 0 {
-        let mut player_iter = players.join();
-        let first = player_iter.next().unwrap().alias.to_owned();
-        let player_list = player_iter.fold(first, |mut s, p| {
-            s += ",\n";
-            s += &p.alias;
-            s
-        });
-
-        server
-            .clients
-            .notify(entity, ServerMsg::private(header_message + &player_list));
-    } else {
-        server
-            .clients
-            .notify(entity, ServerMsg::private(header_message));
-    }
+fn handle_players(
+    server: &mut Server,
+    sender: CommandSender,
+    _args: String,
+    _action: &ChatCommand,
+) {
+    let aliases = server
+        .state
+        .ecs()
+        .read_storage::<comp::Player>()
+        .join()
+        .map(|player| player.alias.clone())
+        .collect::<Vec<_>>();
+    let header_message: String = format!("{} online players: \n", aliases.len());
+    server.notify_sender(
+        sender,
+        ServerMsg::private(header_message + &aliases.join(",\n")),
+    );
 }
 
-fn handle_build(server: &mut Server, entity: EcsEntity, _args: String, _action: &ChatCommand) {
-    if server
+fn handle_build(server: &mut Server, sender: CommandSender, _args: String, _action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
+    if !server
+        .role_of(entity)
+        .map(|role| role.can_build)
+        .unwrap_or(false)
+    {
+        server.clients.notify(
+            entity,
+            ServerMsg::private(String::from("Your role doesn't allow building.")),
+        );
+    } else if server
         .state
         .read_storage::<comp::CanBuild>()
         .get(entity)
@@ -491,12 +612,13 @@ fn handle_build(server: &mut Server, entity: EcsEntity, _args: String, _action:
     }
 }
 
-// TODO: Don't display commands that the player cannot use.
-fn handle_help(server: &mut Server, entity: EcsEntity, _args: String, _action: &ChatCommand) {
-    for cmd in CHAT_COMMANDS.iter() {
-        server
-            .clients
-            .notify(entity, ServerMsg::private(String::from(cmd.help_string)));
+fn handle_help(server: &mut Server, sender: CommandSender, _args: String, _action: &ChatCommand) {
+    let available = CHAT_COMMANDS
+        .iter()
+        .filter(|cmd| server.may_run(sender, cmd.keyword))
+        .collect::<Vec<_>>();
+    for cmd in available {
+        server.notify_sender(sender, ServerMsg::private(String::from(cmd.help_string)));
     }
 }
 
@@ -512,32 +634,45 @@ fn alignment_to_agent(alignment: &str, target: EcsEntity) -> Option<comp::Agent>
     }
 }
 
-fn kind_to_body(kind: NpcKind) -> comp::Body {
+fn kind_to_body(kind: NpcKind, rng: &mut impl Rng) -> comp::Body {
     match kind {
-        NpcKind::Humanoid => comp::Body::Humanoid(comp::humanoid::Body::random()),
-        NpcKind::Pig => comp::Body::Quadruped(comp::quadruped::Body::random()),
-        NpcKind::Wolf => comp::Body::QuadrupedMedium(comp::quadruped_medium::Body::random()),
+        NpcKind::Humanoid => comp::Body::Humanoid(comp::humanoid::Body::random_with(rng)),
+        NpcKind::Pig => comp::Body::Quadruped(comp::quadruped::Body::random_with(rng)),
+        NpcKind::Wolf => {
+            comp::Body::QuadrupedMedium(comp::quadruped_medium::Body::random_with(rng))
+        }
     }
 }
 
-fn handle_killnpcs(server: &mut Server, entity: EcsEntity, _args: String, _action: &ChatCommand) {
-    let ecs = server.state.ecs();
-    let mut stats = ecs.write_storage::<comp::Stats>();
-    let players = ecs.read_storage::<comp::Player>();
+fn handle_killnpcs(
+    server: &mut Server,
+    sender: CommandSender,
+    _args: String,
+    _action: &ChatCommand,
+) {
     let mut count = 0;
-    for (stats, ()) in (&mut stats, !&players).join() {
-        count += 1;
-        stats.health.set_to(0, comp::HealthSource::Command);
+    {
+        let ecs = server.state.ecs();
+        let mut stats = ecs.write_storage::<comp::Stats>();
+        let players = ecs.read_storage::<comp::Player>();
+        for (stats, ()) in (&mut stats, !&players).join() {
+            count += 1;
+            stats.health.set_to(0, comp::HealthSource::Command);
+        }
     }
     let text = if count > 0 {
         format!("Destroyed {} NPCs.", count)
     } else {
         "No NPCs on server.".to_string()
     };
-    server.clients.notify(entity, ServerMsg::private(text));
+    server.notify_sender(sender, ServerMsg::private(text));
 }
 
-fn handle_object(server: &mut Server, entity: EcsEntity, args: String, _action: &ChatCommand) {
+fn handle_object(server: &mut Server, sender: CommandSender, args: String, _action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     let obj_type = scan_fmt!(&args, _action.arg_fmt, String);
 
     let pos = server
@@ -640,7 +775,11 @@ fn handle_object(server: &mut Server, entity: EcsEntity, args: String, _action:
     }
 }
 
-fn handle_light(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_light(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     let (opt_r, opt_g, opt_b, opt_x, opt_y, opt_z, opt_s) =
         scan_fmt_some!(&args, action.arg_fmt, f32, f32, f32, f32, f32, f32, f32);
 
@@ -683,7 +822,11 @@ fn handle_light(server: &mut Server, entity: EcsEntity, args: String, action: &C
     }
 }
 
-fn handle_lantern(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_lantern(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     let opt_s = scan_fmt_some!(&args, action.arg_fmt, f32);
 
     if server
@@ -741,7 +884,16 @@ fn handle_lantern(server: &mut Server, entity: EcsEntity, args: String, action:
     }
 }
 
-fn handle_explosion(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_explosion(
+    server: &mut Server,
+    sender: CommandSender,
+    args: String,
+    action: &ChatCommand,
+) {
+    let entity = match player_of(server, sender) {
+        Some(entity) => entity,
+        None => return,
+    };
     let radius = scan_fmt!(&args, action.arg_fmt, f32).unwrap_or(8.0);
 
     match server.state.read_component_cloned::<comp::Pos>(entity) {
@@ -757,93 +909,113 @@ fn handle_explosion(server: &mut Server, entity: EcsEntity, args: String, action
     }
 }
 
-fn handle_adminify(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
-    if let Ok(alias) = scan_fmt!(&args, action.arg_fmt, String) {
-        let ecs = server.state.ecs();
-        let opt_player = (&ecs.entities(), &ecs.read_storage::<comp::Player>())
-            .join()
-            .find(|(_, player)| player.alias == alias)
-            .map(|(entity, _)| entity);
-        match opt_player {
-            Some(player) => match server.state.read_component_cloned::<comp::Admin>(player) {
-                Some(_admin) => {
-                    ecs.write_storage::<comp::Admin>().remove(player);
-                }
-                None => {
-                    server.state.write_component(player, comp::Admin);
-                }
-            },
-            None => {
-                server.clients.notify(
-                    entity,
-                    ServerMsg::private(format!("Player '{}' not found!", alias)),
+fn handle_role(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    match scan_fmt_some!(&args, action.arg_fmt, String, String) {
+        (Some(alias), None) => {
+            let (_, account) = account_of(server, &alias);
+            let msg = format!(
+                "'{}' has the role '{}'.",
+                account,
+                server.server_settings.role_of(&account)
+            );
+            server.notify_sender(sender, ServerMsg::private(msg));
+        }
+        (Some(alias), Some(role)) => {
+            if server.server_settings.role(&role).is_none() {
+                server.notify_sender(
+                    sender,
+                    ServerMsg::private(format!("There is no role called '{}'!", role)),
                 );
-                server
-                    .clients
-                    .notify(entity, ServerMsg::private(String::from(action.help_string)));
+                return;
+            }
+
+            if !server.outranks(sender, &role) {
+                server.notify_sender(
+                    sender,
+                    ServerMsg::private(format!("You may not give out the role '{}'.", role)),
+                );
+                return;
+            }
+
+            let (target, account) = account_of(server, &alias);
+            if !may_act_on(server, sender, &account) {
+                return;
             }
+            server
+                .server_settings
+                .player_roles
+                .insert(account.clone(), role.clone());
+            save_settings(server);
+            if let Some(target) = target {
+                server.set_role(target, role.clone());
+            }
+            server.notify_sender(
+                sender,
+                ServerMsg::private(format!("'{}' now has the role '{}'.", account, role)),
+            );
         }
-    } else {
-        server
-            .clients
-            .notify(entity, ServerMsg::private(String::from(action.help_string)));
+        _ => server.notify_sender(sender, ServerMsg::private(String::from(action.help_string))),
     }
 }
 
-fn handle_tell(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_tell(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
     if let Ok(alias) = scan_fmt!(&args, action.arg_fmt, String) {
-        let ecs = server.state.ecs();
         let msg = &args[alias.len()..args.len()];
-        if let Some(player) = (&ecs.entities(), &ecs.read_storage::<comp::Player>())
-            .join()
-            .find(|(_, player)| player.alias == alias)
-            .map(|(entity, _)| entity)
-        {
-            if player != entity {
+        if let Some(player) = find_player(server, &alias) {
+            if CommandSender::Player(player) != sender {
                 if msg.len() > 1 {
-                    if let Some(name) = ecs
-                        .read_storage::<comp::Player>()
-                        .get(entity)
-                        .map(|s| s.alias.clone())
-                    {
+                    let name = match sender {
+                        CommandSender::Player(entity) => server
+                            .state
+                            .ecs()
+                            .read_storage::<comp::Player>()
+                            .get(entity)
+                            .map(|s| s.alias.clone()),
+                        CommandSender::Console => Some(String::from("Server")),
+                    };
+                    if let Some(name) = name {
                         server
                             .clients
                             .notify(player, ServerMsg::tell(format!("[{}] tells:{}", name, msg)));
-                        server
-                            .clients
-                            .notify(entity, ServerMsg::tell(format!("To [{}]:{}", alias, msg)));
+                        server.notify_sender(
+                            sender,
+                            ServerMsg::tell(format!("To [{}]:{}", alias, msg)),
+                        );
                     } else {
-                        server.clients.notify(
-                            entity,
+                        server.notify_sender(
+                            sender,
                             ServerMsg::private(String::from("Failed to send message.")),
                         );
                     }
                 } else {
-                    server.clients.notify(
-                        entity,
+                    server.notify_sender(
+                        sender,
                         ServerMsg::private(format!("[{}] wants to talk to you.", alias)),
                     );
                 }
             } else {
-                server.clients.notify(
-                    entity,
+                server.notify_sender(
+                    sender,
                     ServerMsg::private(format!("You can't /tell yourself.")),
                 );
             }
         } else {
-            server.clients.notify(
-                entity,
+            server.notify_sender(
+                sender,
                 ServerMsg::private(format!("Player '{}' not found!", alias)),
             );
         }
     } else {
-        server
-            .clients
-            .notify(entity, ServerMsg::private(String::from(action.help_string)));
+        server.notify_sender(sender, ServerMsg::private(String::from(action.help_string)));
     }
 }
 
-fn handle_debug_column(server: &mut Server, entity: EcsEntity, args: String, action: &ChatCommand) {
+fn handle_debug_column(
+    server: &mut Server,
+    sender: CommandSender,
+    args: String,
+    action: &ChatCommand,
+) {
     let sim = server.world.sim();
     if let Ok((x, y)) = scan_fmt!(&args, action.arg_fmt, i32, i32) {
         let wpos = Vec2::new(x, y);
@@ -876,16 +1048,345 @@ spawn_rate {:?} "#,
             ))
         };
         if let Some(s) = foo() {
-            server.clients.notify(entity, ServerMsg::private(s));
+            server.notify_sender(sender, ServerMsg::private(s));
         } else {
-            server.clients.notify(
-                entity,
+            server.notify_sender(
+                sender,
                 ServerMsg::private(String::from("Not a pregenerated chunk.")),
             );
         }
     } else {
-        server
-            .clients
-            .notify(entity, ServerMsg::private(String::from(action.help_string)));
+        server.notify_sender(sender, ServerMsg::private(String::from(action.help_string)));
+    }
+}
+
+fn handle_register(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    if let Ok((alias, password)) = scan_fmt!(&args, action.arg_fmt, String, String) {
+        if !comp::Player::new(alias.clone(), None).is_valid() {
+            server.notify_sender(
+                sender,
+                ServerMsg::private(format!("'{}' is not a valid alias.", alias)),
+            );
+            return;
+        }
+        let msg = match server.accounts.register(alias.clone(), password) {
+            Ok(()) => format!("Created account '{}'.", alias),
+            Err(RegisterError::AlreadyExists) => format!("Account '{}' already exists!", alias),
+            Err(RegisterError::HashingFailed) => format!("Failed to create account '{}'.", alias),
+            Err(RegisterError::Unsupported) => {
+                String::from("Accounts are managed outside of this server.")
+            }
+        };
+        server.notify_sender(sender, ServerMsg::private(msg));
+    } else {
+        server.notify_sender(sender, ServerMsg::private(String::from(action.help_string)));
+    }
+}
+
+/// The entity of the player that issued a command. The console is told that the command needs a
+/// player instead.
+fn player_of(server: &mut Server, sender: CommandSender) -> Option<EcsEntity> {
+    match sender {
+        CommandSender::Player(entity) => Some(entity),
+        CommandSender::Console => {
+            server.notify_sender(
+                sender,
+                ServerMsg::private(String::from("This command can only be used by players.")),
+            );
+            None
+        }
+    }
+}
+
+/// Find the entity of the online player with the given alias.
+fn find_player(server: &Server, alias: &str) -> Option<EcsEntity> {
+    let ecs = server.state.ecs();
+    (&ecs.entities(), &ecs.read_storage::<comp::Player>())
+        .join()
+        .find(|(_, player)| player.alias == alias)
+        .map(|(entity, _)| entity)
+}
+
+/// Split `<alias> [duration] [reason]` into its parts, with the duration in seconds.
+fn parse_sanction_args(args: &str) -> Option<(String, Option<u64>, String)> {
+    let mut words = args.split_whitespace();
+    let alias = words.next()?.to_string();
+    let rest = words.collect::<Vec<_>>();
+    let (duration, reason) = match rest.first().and_then(|w| moderation::parse_duration(w)) {
+        Some(duration) => (Some(duration), &rest[1..]),
+        None => (None, &rest[..]),
+    };
+    let reason = if reason.is_empty() {
+        String::from("No reason given")
+    } else {
+        reason.join(" ")
+    };
+    Some((alias, duration, reason))
+}
+
+/// The account of the online player `alias`, or `alias` itself if they aren't online.
+fn account_of(server: &Server, alias: &str) -> (Option<EcsEntity>, String) {
+    let target = find_player(server, alias);
+    let account = target
+        .and_then(|target| server.clients.get(&target))
+        .and_then(|client| client.account.clone())
+        .unwrap_or_else(|| alias.to_string());
+    (target, account)
+}
+
+/// Whether `sender` may sanction or change the role of `account`, which they may not if it has a
+/// role with more rights than theirs. Tells the sender if they may not.
+fn may_act_on(server: &mut Server, sender: CommandSender, account: &str) -> bool {
+    let role = server.server_settings.role_of(account).to_owned();
+    let may = server.outranks(sender, &role);
+    if !may {
+        server.notify_sender(
+            sender,
+            ServerMsg::private(format!(
+                "You may not do that to '{}', who has the role '{}'.",
+                account, role
+            )),
+        );
+    }
+    may
+}
+
+fn handle_kick(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    match parse_sanction_args(&args) {
+        Some((alias, _, reason)) => match account_of(server, &alias) {
+            (Some(target), account) => {
+                if !may_act_on(server, sender, &account) {
+                    return;
+                }
+                server.kick_client(
+                    target,
+                    ServerMsg::private(format!("You have been kicked: {}", reason)),
+                );
+                server.notify_sender(
+                    sender,
+                    ServerMsg::private(format!("Kicked '{}': {}", alias, reason)),
+                );
+            }
+            (None, _) => server.notify_sender(
+                sender,
+                ServerMsg::private(format!("Player '{}' not found!", alias)),
+            ),
+        },
+        None => server.notify_sender(sender, ServerMsg::private(String::from(action.help_string))),
+    }
+}
+
+fn handle_ban(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    match parse_sanction_args(&args) {
+        Some((alias, duration, reason)) => {
+            let (target, account) = account_of(server, &alias);
+            if !may_act_on(server, sender, &account) {
+                return;
+            }
+            let until = duration.map(|d| moderation::now() + d);
+            server.moderation.ban(
+                account.clone(),
+                Sanction {
+                    reason: reason.clone(),
+                    until,
+                },
+            );
+            if let Some(target) = target {
+                server.kick_client(
+                    target,
+                    ServerMsg::Error(ServerError::Banned {
+                        reason: reason.clone(),
+                        until,
+                    }),
+                );
+            }
+            server.notify_sender(
+                sender,
+                ServerMsg::private(format!("Banned '{}': {}", account, reason)),
+            );
+        }
+        None => server.notify_sender(sender, ServerMsg::private(String::from(action.help_string))),
+    }
+}
+
+fn handle_unban(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    if let Ok(alias) = scan_fmt!(&args, action.arg_fmt, String) {
+        let msg = if server.moderation.unban(&alias) {
+            format!("Unbanned '{}'.", alias)
+        } else {
+            format!("'{}' is not banned.", alias)
+        };
+        server.notify_sender(sender, ServerMsg::private(msg));
+    } else {
+        server.notify_sender(sender, ServerMsg::private(String::from(action.help_string)));
+    }
+}
+
+fn handle_mute(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    match parse_sanction_args(&args) {
+        Some((alias, duration, reason)) => {
+            let (target, account) = account_of(server, &alias);
+            if !may_act_on(server, sender, &account) {
+                return;
+            }
+            server.moderation.mute(
+                account.clone(),
+                Sanction {
+                    reason: reason.clone(),
+                    until: duration.map(|d| moderation::now() + d),
+                },
+            );
+            if let Some(target) = target {
+                server.clients.notify(
+                    target,
+                    ServerMsg::private(format!("You have been muted: {}", reason)),
+                );
+            }
+            server.notify_sender(
+                sender,
+                ServerMsg::private(format!("Muted '{}': {}", account, reason)),
+            );
+        }
+        None => server.notify_sender(sender, ServerMsg::private(String::from(action.help_string))),
+    }
+}
+
+fn handle_unmute(server: &mut Server, sender: CommandSender, args: String, action: &ChatCommand) {
+    if let Ok(alias) = scan_fmt!(&args, action.arg_fmt, String) {
+        let (target, account) = account_of(server, &alias);
+        let msg = if server.moderation.unmute(&account) {
+            if let Some(target) = target {
+                server.clients.notify(
+                    target,
+                    ServerMsg::private(String::from("You can chat again.")),
+                );
+            }
+            format!("Unmuted '{}'.", account)
+        } else {
+            format!("'{}' is not muted.", account)
+        };
+        server.notify_sender(sender, ServerMsg::private(msg));
+    } else {
+        server.notify_sender(sender, ServerMsg::private(String::from(action.help_string)));
+    }
+}
+
+fn handle_whitelist(
+    server: &mut Server,
+    sender: CommandSender,
+    args: String,
+    action: &ChatCommand,
+) {
+    let msg = match scan_fmt_some!(&args, action.arg_fmt, String, String) {
+        (Some(ref cmd), Some(alias)) if cmd == "add" => {
+            if server.server_settings.whitelist.contains(&alias) {
+                format!("'{}' is already whitelisted.", alias)
+            } else {
+                server.server_settings.whitelist.push(alias.clone());
+                save_settings(server);
+                format!("Added '{}' to the whitelist.", alias)
+            }
+        }
+        (Some(ref cmd), Some(alias)) if cmd == "remove" => {
+            let len = server.server_settings.whitelist.len();
+            server
+                .server_settings
+                .whitelist
+                .retain(|entry| *entry != alias);
+            if server.server_settings.whitelist.len() < len {
+                save_settings(server);
+                format!("Removed '{}' from the whitelist.", alias)
+            } else {
+                format!("'{}' is not whitelisted.", alias)
+            }
+        }
+        (Some(ref cmd), None) if cmd == "list" => {
+            let settings = &server.server_settings;
+            format!(
+                "Whitelist ({}): {}",
+                if settings.whitelist_enabled {
+                    "enabled"
+                } else {
+                    "disabled"
+                },
+                settings.whitelist.join(", ")
+            )
+        }
+        _ => String::from(action.help_string),
+    };
+    server.notify_sender(sender, ServerMsg::private(msg));
+}
+
+fn handle_violations(
+    server: &mut Server,
+    sender: CommandSender,
+    args: String,
+    action: &ChatCommand,
+) {
+    let validator = &server.movement_validator;
+    let msg = match scan_fmt_some!(&args, action.arg_fmt, String) {
+        Some(alias) => {
+            let (_, account) = account_of(server, &alias);
+            let now = moderation::now();
+            let lines = validator
+                .violations_of(&account)
+                .map(|v| {
+                    format!(
+                        "{}s ago at ({:.0}, {:.0}, {:.0}): {}",
+                        now.saturating_sub(v.time),
+                        v.pos.x,
+                        v.pos.y,
+                        v.pos.z,
+                        v.kind
+                    )
+                })
+                .collect::<Vec<_>>();
+            if lines.is_empty() {
+                format!("'{}' has no recorded violations.", account)
+            } else {
+                format!("Violations of '{}':\n{}", account, lines.join("\n"))
+            }
+        }
+        None => {
+            let mut offenders = validator
+                .offenders()
+                .map(|(account, count)| format!("{} ({})", account, count))
+                .collect::<Vec<_>>();
+            offenders.sort();
+            if offenders.is_empty() {
+                String::from("No violations have been recorded.")
+            } else {
+                format!("Accounts with violations: {}", offenders.join(", "))
+            }
+        }
+    };
+    server.notify_sender(sender, ServerMsg::private(msg));
+}
+
+fn handle_shutdown(
+    server: &mut Server,
+    _sender: CommandSender,
+    args: String,
+    _action: &ChatCommand,
+) {
+    let mut words = args.split_whitespace().collect::<Vec<_>>();
+    let delay = match words.first().and_then(|w| moderation::parse_duration(w)) {
+        Some(delay) => {
+            words.remove(0);
+            delay
+        }
+        None => DEFAULT_SHUTDOWN_DELAY,
+    };
+    let reason = if words.is_empty() {
+        String::from("Server maintenance")
+    } else {
+        words.join(" ")
+    };
+    server.request_shutdown(Duration::from_secs(delay), reason);
+}
+
+fn save_settings(server: &Server) {
+    if let Err(err) = server.server_settings.save_to_file() {
+        log::error!("Failed to save settings: {}", err);
     }
 }
@@ -1,9 +1,14 @@
+/// Input passed to the server by its frontend each tick.
 pub struct Input {
-    // TODO: Use this type to manage server input.
+    /// Lines entered on the server console. Lines starting with '/' are run as chat commands with
+    /// every permission, anything else is broadcast to all players.
+    pub console: Vec<String>,
 }
 
 impl Default for Input {
     fn default() -> Self {
-        Input {}
+        Input {
+            console: Vec::new(),
+        }
     }
 }
@@ -1,5 +1,5 @@
 #![deny(unsafe_code)]
-#![feature(drain_filter, bind_by_move_pattern_guards)]
+#![feature(drain_filter, bind_by_move_pattern_guards, duration_float)]
 
 pub mod auth_provider;
 pub mod client;
@@ -7,34 +7,56 @@ pub mod cmd;
 pub mod error;
 pub mod input;
 pub mod metrics;
+pub mod moderation;
+pub mod persistence;
+pub mod region;
+pub mod replay;
 pub mod settings;
+pub mod terrain_persistence;
+pub mod util;
+pub mod validation;
 
 // Reexports
-pub use crate::{error::Error, input::Input, settings::ServerSettings};
+pub use crate::{
+    error::Error,
+    input::Input,
+    settings::{Role, ServerSettings},
+};
 
 use crate::{
-    auth_provider::AuthProvider,
+    auth_provider::{AuthBackend, AuthError, AuthProvider, TokenAuthProvider},
     client::{Client, Clients},
-    cmd::CHAT_COMMANDS,
+    cmd::{CommandSender, CHAT_COMMANDS},
+    moderation::Moderation,
+    persistence::{CharacterData, CharacterStore, FileCharacterStore},
+    region::RegionMap,
+    replay::{Recorder, Session},
+    terrain_persistence::TerrainPersistence,
+    validation::MovementValidator,
 };
 use common::{
+    assets::watch::ReloadIndicator,
     comp,
     event::{EventBus, ServerEvent},
-    msg::{ClientMsg, ClientState, RequestStateError, ServerError, ServerInfo, ServerMsg},
-    net::PostOffice,
-    state::{BlockChange, State, TimeOfDay, Uid},
-    terrain::{block::Block, TerrainChunk, TerrainChunkSize, TerrainGrid},
-    vol::{ReadVol, RectVolSize, Vox},
+    msg::{
+        ClientMsg, ClientState, CompUpdateKind, RequestStateError, ServerError, ServerInfo,
+        ServerMsg, ServerStatus, PROTOCOL_VERSION,
+    },
+    net::{self, PostBox, PostOffice, Priority, UdpHost},
+    state::{BlockChange, EcsRng, State, TimeOfDay, Uid},
+    terrain::{block::Block, EncodedTerrainChunk, TerrainChunk, TerrainChunkDelta, TerrainGrid},
+    vol::{ReadVol, Vox},
 };
 use crossbeam::channel;
-use hashbrown::HashSet;
-use log::debug;
+use hashbrown::{HashMap, HashSet};
+use log::{debug, info, warn};
 use metrics::ServerMetrics;
 use rand::Rng;
 use specs::{join::Join, world::EntityBuilder as EcsEntityBuilder, Builder, Entity as EcsEntity};
 use std::{
     i32,
     net::SocketAddr,
+    path::Path,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -43,6 +65,13 @@ use vek::*;
 use world::{ChunkSupplement, World};
 
 const CLIENT_TIMEOUT: f64 = 20.0; // Seconds
+/// How often clients are pinged to measure their latency.
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+const AUTOSAVE_INTERVAL: f64 = 60.0; // Seconds
+/// How long shutting down may wait for the goodbyes to reach the clients.
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many seconds before a shutdown players are reminded of it.
+const SHUTDOWN_ANNOUNCEMENTS: [u64; 11] = [600, 300, 120, 60, 30, 10, 5, 4, 3, 2, 1];
 
 pub enum Event {
     ClientConnected {
@@ -55,17 +84,43 @@ pub enum Event {
         entity: Option<EcsEntity>,
         msg: String,
     },
+    /// A reply to a command entered on the server console.
+    ConsoleOutput {
+        msg: String,
+    },
+    /// The server has shut down after a `request_shutdown` and should be dropped by the frontend.
+    Shutdown,
 }
 
 #[derive(Copy, Clone)]
 struct SpawnPoint(Vec3<f32>);
 
+/// A connection that hasn't told us yet whether it wants to play or only query the server's
+/// status. It only gets an entity once it has connected.
+struct PendingConnection {
+    postbox: PostBox<ServerMsg, ClientMsg>,
+    since: f64,
+}
+
+struct PendingShutdown {
+    /// When to shut down, in seconds of game time, so that replays shut down on the same tick.
+    at: f64,
+    reason: String,
+    /// The number of seconds that were left at the last announcement.
+    last_announced: u64,
+    done: bool,
+}
+
 pub struct Server {
     state: State,
     world: Arc<World>,
 
     postoffice: PostOffice<ServerMsg, ClientMsg>,
+    udp: Option<UdpHost<EcsEntity, ServerMsg, ClientMsg>>,
+    pending_connections: Vec<PendingConnection>,
     clients: Clients,
+    /// Which entities are in which region, for deciding which clients to tell about them.
+    regions: RegionMap,
 
     thread_pool: ThreadPool,
     chunk_tx: channel::Sender<(Vec2<i32>, (TerrainChunk, ChunkSupplement))>,
@@ -73,11 +128,20 @@ pub struct Server {
     pending_chunks: HashSet<Vec2<i32>>,
 
     server_settings: ServerSettings,
+    settings_indicator: Option<ReloadIndicator>,
     server_info: ServerInfo,
     metrics: ServerMetrics,
 
-    // TODO: anything but this
-    accounts: AuthProvider,
+    accounts: Box<dyn AuthBackend>,
+    moderation: Moderation,
+    movement_validator: MovementValidator,
+    persistence: Box<dyn CharacterStore>,
+    terrain_persistence: TerrainPersistence,
+    last_autosave: f64,
+
+    console_output: Vec<String>,
+    pending_shutdown: Option<PendingShutdown>,
+    session: Session,
 }
 
 impl Server {
@@ -88,6 +152,7 @@ impl Server {
 
     /// Create a new server bound to the given socket.
     pub fn bind<A: Into<SocketAddr>>(addrs: A, settings: ServerSettings) -> Result<Self, Error> {
+        let addr = addrs.into();
         let (chunk_tx, chunk_rx) = channel::unbounded();
 
         let mut state = State::default();
@@ -100,13 +165,44 @@ impl Server {
 
         // Set starting time for the server.
         state.ecs_mut().write_resource::<TimeOfDay>().0 = settings.start_time;
+        // Everything random on the server follows from the world seed, so that recorded sessions
+        // can be replayed.
+        state.seed_rng(u64::from(settings.world_seed));
+        // The time that systems take is only exported with the other metrics.
+        state.set_profiling(settings.metrics_enabled);
+
+        let accounts: Box<dyn AuthBackend> = match &settings.auth_token_secret {
+            Some(secret) => Box::new(TokenAuthProvider::new(secret)),
+            None => Box::new(
+                AuthProvider::load(settings.accounts_file.clone(), settings.auto_register)
+                    .map_err(Error::Other)?,
+            ),
+        };
+
+        let udp = if settings.udp_enabled {
+            match UdpHost::bind(addr) {
+                Ok(udp) => Some(udp),
+                Err(err) => {
+                    warn!(
+                        "Failed to bind UDP socket, clients will only use TCP: {:?}",
+                        err
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
 
         let this = Self {
             state,
             world: Arc::new(World::generate(settings.world_seed)),
 
-            postoffice: PostOffice::bind(addrs.into())?,
+            postoffice: PostOffice::bind(addr)?,
+            udp,
+            pending_connections: Vec::new(),
             clients: Clients::empty(),
+            regions: RegionMap::new(),
 
             thread_pool: ThreadPoolBuilder::new()
                 .name("veloren-worker".into())
@@ -115,13 +211,27 @@ impl Server {
             chunk_rx,
             pending_chunks: HashSet::new(),
 
+            settings_indicator: None,
             server_info: ServerInfo {
                 name: settings.server_name.clone(),
                 description: settings.server_description.clone(),
                 git_hash: common::util::GIT_HASH.to_string(),
             },
-            metrics: ServerMetrics::new(),
-            accounts: AuthProvider::new(),
+            metrics: ServerMetrics::new(if settings.metrics_enabled {
+                Some(settings.metrics_address)
+            } else {
+                None
+            }),
+            accounts,
+            moderation: Moderation::load(settings.banlist_file.clone()).map_err(Error::Other)?,
+            movement_validator: MovementValidator::new(),
+            persistence: Box::new(FileCharacterStore::new(settings.data_dir.clone())),
+            terrain_persistence: TerrainPersistence::new(settings.data_dir.join("terrain")),
+            last_autosave: 0.0,
+
+            console_output: Vec::new(),
+            pending_shutdown: None,
+            session: Session::Live,
             server_settings: settings,
         };
 
@@ -133,6 +243,34 @@ impl Server {
         self
     }
 
+    /// Replace the store that player characters are loaded from and saved to.
+    pub fn with_character_store(mut self, persistence: Box<dyn CharacterStore>) -> Self {
+        self.persistence = persistence;
+        self
+    }
+
+    /// Record everything that happens to the server to `path`, so that the session can be
+    /// replayed with `replay::Replay`.
+    pub fn with_recording(mut self, path: &Path) -> Result<Self, Error> {
+        self.session = Session::Recording(Recorder::create(path, &self.server_settings)?);
+        Ok(self)
+    }
+
+    /// Watch the settings file and apply changes to it while the server is running. Does nothing
+    /// if the settings weren't loaded from a file.
+    pub fn with_settings_reloading(mut self) -> Self {
+        let path = match &self.server_settings.file {
+            Some(path) => path.clone(),
+            None => return self,
+        };
+        // File system events carry absolute paths.
+        let path = std::fs::canonicalize(&path).unwrap_or(path);
+        let mut indicator = ReloadIndicator::new();
+        indicator.add(path, || {});
+        self.settings_indicator = Some(indicator);
+        self
+    }
+
     /// Get a reference to the server's game state.
     pub fn state(&self) -> &State {
         &self.state
@@ -147,6 +285,11 @@ impl Server {
         &self.world
     }
 
+    /// The address that metrics are served at, if the exporter is running.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics.addr()
+    }
+
     /// Build a non-player character.
     pub fn create_npc(
         &mut self,
@@ -209,33 +352,43 @@ impl Server {
         name: String,
         body: comp::Body,
         main: Option<comp::Item>,
-        server_settings: &ServerSettings,
+        character: Option<CharacterData>,
     ) {
         let spawn_point = state.ecs().read_resource::<SpawnPoint>().0;
 
+        // Restore the saved character if there is one, otherwise start from scratch.
+        let (body, stats, inventory, pos) = match character {
+            Some(CharacterData {
+                body,
+                mut stats,
+                inventory,
+                pos,
+            }) => {
+                if stats.is_dead || stats.should_die() {
+                    stats.revive();
+                }
+                (body, stats, inventory, pos)
+            }
+            None => (
+                body,
+                comp::Stats::new(name, main),
+                comp::Inventory::default(),
+                comp::Pos(spawn_point),
+            ),
+        };
+
         state.write_component(entity, body);
-        state.write_component(entity, comp::Stats::new(name, main));
+        state.write_component(entity, stats);
         state.write_component(entity, comp::Controller::default());
-        state.write_component(entity, comp::Pos(spawn_point));
+        state.write_component(entity, pos);
         state.write_component(entity, comp::Vel(Vec3::zero()));
         state.write_component(entity, comp::Ori(Vec3::unit_y()));
         state.write_component(entity, comp::CharacterState::default());
-        state.write_component(entity, comp::Inventory::default());
+        state.write_component(entity, inventory);
         state.write_component(entity, comp::InventoryUpdate);
         // Make sure physics are accepted.
         state.write_component(entity, comp::ForceUpdate);
 
-        // Give the Admin component to the player if their name exists in admin list
-        if server_settings.admins.contains(
-            &state
-                .ecs()
-                .read_storage::<comp::Player>()
-                .get(entity)
-                .unwrap()
-                .alias,
-        ) {
-            state.write_component(entity, comp::Admin);
-        }
         // Tell the client its request was successful.
         client.allow_state(ClientState::Character);
     }
@@ -257,21 +410,22 @@ impl Server {
                 ServerEvent::Explosion { pos, radius } => {
                     const RAYS: usize = 500;
 
+                    let ecs = state.ecs();
+                    let mut rng = ecs.write_resource::<EcsRng>();
+                    let mut block_change = ecs.write_resource::<BlockChange>();
+                    let terrain = ecs.read_resource::<TerrainGrid>();
+
                     for _ in 0..RAYS {
                         let dir = Vec3::new(
-                            rand::random::<f32>() - 0.5,
-                            rand::random::<f32>() - 0.5,
-                            rand::random::<f32>() - 0.5,
+                            rng.0.gen::<f32>() - 0.5,
+                            rng.0.gen::<f32>() - 0.5,
+                            rng.0.gen::<f32>() - 0.5,
                         )
                         .normalized();
 
-                        let ecs = state.ecs_mut();
-                        let mut block_change = ecs.write_resource::<BlockChange>();
-
-                        let _ = ecs
-                            .read_resource::<TerrainGrid>()
+                        let _ = terrain
                             .ray(pos, pos + dir * radius)
-                            .until(|_| rand::random::<f32>() < 0.05)
+                            .until(|_| rng.0.gen::<f32>() < 0.05)
                             .for_each(|pos| block_change.set(pos, Block::empty()))
                             .cast();
                     }
@@ -419,7 +573,7 @@ impl Server {
     }
 
     /// Execute a single server tick, handle input and update the game state by the given duration.
-    pub fn tick(&mut self, _input: Input, dt: Duration) -> Result<Vec<Event>, Error> {
+    pub fn tick(&mut self, input: Input, dt: Duration) -> Result<Vec<Event>, Error> {
         // This tick function is the centre of the Veloren universe. Most server-side things are
         // managed from here, and as such it's important that it stays organised. Please consult
         // the core developers before making significant changes to this code. Here is the
@@ -444,11 +598,44 @@ impl Server {
             return Err(err.into());
         }
 
-        // 2)
+        self.session.begin_tick(dt, &input.console);
+
+        // Run commands entered on the server console.
+        for line in input.console {
+            let line = line.trim();
+            if line.starts_with("/") && line.len() > 1 {
+                self.process_chat_cmd(CommandSender::Console, String::from(&line[1..]));
+            } else if !line.is_empty() {
+                self.clients
+                    .notify_registered(ServerMsg::broadcast(format!("[Server] {}", line)));
+            }
+        }
+        frontend_events.extend(
+            self.console_output
+                .drain(..)
+                .map(|msg| Event::ConsoleOutput { msg }),
+        );
+
+        // 2) Apply changes to the settings file.
+        if self
+            .settings_indicator
+            .as_ref()
+            .map(|indicator| indicator.reloaded())
+            .unwrap_or(false)
+        {
+            self.reload_settings();
+        }
+
+        // Count down to a requested shutdown.
+        if self.tick_shutdown() {
+            frontend_events.push(Event::Shutdown);
+            return Ok(frontend_events);
+        }
 
         // 3) Handle inputs from clients
         frontend_events.append(&mut self.handle_new_connections()?);
         frontend_events.append(&mut self.handle_new_messages()?);
+        self.handle_udp_messages();
 
         // Handle game events
         self.handle_events();
@@ -457,37 +644,87 @@ impl Server {
         // 4) Tick the client's LocalState.
         self.state.tick(dt);
 
+        // Remember the blocks that changed in loaded chunks, so they survive the chunk being
+        // unloaded and generated again.
+        for (pos, block) in &self.state.terrain_changes().modified_blocks {
+            if self.state.terrain().get(*pos).is_ok() {
+                self.terrain_persistence.record(*pos, *block);
+            }
+        }
+
         // Tick the world
         self.world.tick(dt);
 
         let before_tick_5 = Instant::now();
         // 5) Fetch any generated `TerrainChunk`s and insert them into the terrain.
         // Also, send the chunk data to anybody that is close by.
-        if let Ok((key, (chunk, supplement))) = self.chunk_rx.try_recv() {
-            // Send the chunk to all nearby players.
-            for (entity, view_distance, pos) in (
-                &self.state.ecs().entities(),
-                &self.state.ecs().read_storage::<comp::Player>(),
-                &self.state.ecs().read_storage::<comp::Pos>(),
-            )
-                .join()
-                .filter_map(|(entity, player, pos)| {
-                    player.view_distance.map(|vd| (entity, vd, pos))
+        // Chunks that replace an existing one are sent as deltas in step 6.
+        let mut chunk_deltas = HashMap::new();
+        let world = &self.world;
+        let terrain_persistence = &mut self.terrain_persistence;
+        let generated_chunks = match &mut self.session {
+            // A replay generates the chunks that were ready in this tick, with the changes that
+            // were restored in them.
+            Session::Replaying(playback) => playback
+                .take_chunks()
+                .into_iter()
+                .map(|(key, restored)| {
+                    let (mut chunk, supplement) = world.generate_chunk(key);
+                    TerrainPersistence::apply_blocks(key, &mut chunk, &restored);
+                    (key, chunk, supplement)
                 })
-            {
-                let chunk_pos = self.state.terrain().pos_key(pos.0.map(|e| e as i32));
-                let adjusted_dist_sqr = (Vec2::from(chunk_pos) - Vec2::from(key))
-                    .map(|e: i32| (e.abs() as u32).checked_sub(2).unwrap_or(0))
-                    .magnitude_squared();
-
-                if adjusted_dist_sqr <= view_distance.pow(2) {
-                    self.clients.notify(
-                        entity,
-                        ServerMsg::TerrainChunkUpdate {
-                            key,
-                            chunk: Box::new(chunk.clone()),
-                        },
-                    );
+                .collect::<Vec<_>>(),
+            session => self
+                .chunk_rx
+                .try_recv()
+                .ok()
+                .map(|(key, (mut chunk, supplement))| {
+                    // Bring back changes that players made before the chunk was last unloaded.
+                    let restored = terrain_persistence.apply(key, &mut chunk);
+                    session.record_chunk(key, restored);
+                    (key, chunk, supplement)
+                })
+                .into_iter()
+                .collect(),
+        };
+        for (key, chunk, supplement) in generated_chunks {
+            let delta = self
+                .state
+                .terrain()
+                .get_key(key)
+                .map(|old| TerrainChunkDelta::between(old, &chunk));
+            match delta {
+                Some(delta) => {
+                    chunk_deltas.insert(key, delta);
+                }
+                None => {
+                    // Send the chunk to all nearby players.
+                    let encoded = EncodedTerrainChunk::encode(&chunk);
+                    for (entity, view_distance, pos) in (
+                        &self.state.ecs().entities(),
+                        &self.state.ecs().read_storage::<comp::Player>(),
+                        &self.state.ecs().read_storage::<comp::Pos>(),
+                    )
+                        .join()
+                        .filter_map(|(entity, player, pos)| {
+                            player.view_distance.map(|vd| (entity, vd, pos))
+                        })
+                    {
+                        let chunk_pos = self.state.terrain().pos_key(pos.0.map(|e| e as i32));
+                        let adjusted_dist_sqr = (Vec2::from(chunk_pos) - Vec2::from(key))
+                            .map(|e: i32| (e.abs() as u32).checked_sub(2).unwrap_or(0))
+                            .magnitude_squared();
+
+                        if adjusted_dist_sqr <= view_distance.pow(2) {
+                            self.clients.notify(
+                                entity,
+                                ServerMsg::TerrainChunkUpdate {
+                                    key,
+                                    chunk: encoded.clone(),
+                                },
+                            );
+                        }
+                    }
                 }
             }
 
@@ -496,37 +733,44 @@ impl Server {
 
             // Handle chunk supplement
             for npc in supplement.npcs {
-                let (mut stats, mut body) = if rand::random() {
-                    let stats = comp::Stats::new(
-                        "Humanoid".to_string(),
-                        Some(comp::Item::Tool {
-                            kind: comp::item::Tool::Sword,
-                            power: 10,
-                        }),
-                    );
-                    let body = comp::Body::Humanoid(comp::humanoid::Body::random());
-                    (stats, body)
-                } else {
-                    let stats = comp::Stats::new("Wolf".to_string(), None);
-                    let body = comp::Body::QuadrupedMedium(comp::quadruped_medium::Body::random());
-                    (stats, body)
-                };
-                let mut scale = 1.0;
-
-                if npc.boss {
-                    if rand::random::<f32>() < 0.8 {
-                        stats = comp::Stats::new(
+                let (stats, body, scale) = {
+                    let mut rng = self.state.ecs().write_resource::<EcsRng>();
+                    let rng = &mut rng.0;
+                    let (mut stats, mut body) = if rng.gen() {
+                        let stats = comp::Stats::new(
                             "Humanoid".to_string(),
                             Some(comp::Item::Tool {
                                 kind: comp::item::Tool::Sword,
                                 power: 10,
                             }),
                         );
-                        body = comp::Body::Humanoid(comp::humanoid::Body::random());
+                        let body = comp::Body::Humanoid(comp::humanoid::Body::random_with(rng));
+                        (stats, body)
+                    } else {
+                        let stats = comp::Stats::new("Wolf".to_string(), None);
+                        let body = comp::Body::QuadrupedMedium(
+                            comp::quadruped_medium::Body::random_with(rng),
+                        );
+                        (stats, body)
+                    };
+                    let mut scale = 1.0;
+
+                    if npc.boss {
+                        if rng.gen::<f32>() < 0.8 {
+                            stats = comp::Stats::new(
+                                "Humanoid".to_string(),
+                                Some(comp::Item::Tool {
+                                    kind: comp::item::Tool::Sword,
+                                    power: 10,
+                                }),
+                            );
+                            body = comp::Body::Humanoid(comp::humanoid::Body::random_with(rng));
+                        }
+                        stats = stats.with_max_health(500 + rng.gen::<u32>() % 400);
+                        scale = 2.5 + rng.gen::<f32>();
                     }
-                    stats = stats.with_max_health(500 + rand::random::<u32>() % 400);
-                    scale = 2.5 + rand::random::<f32>();
-                }
+                    (stats, body, scale)
+                };
 
                 self.create_npc(comp::Pos(npc.pos), stats, body)
                     .with(comp::Agent::enemy())
@@ -578,15 +822,32 @@ impl Server {
         });
         for key in chunks_to_remove {
             self.state.remove_chunk(key);
+            self.terrain_persistence.unload(key);
         }
 
         let before_tick_6 = Instant::now();
         // 6) Synchronise clients with the new state of the world.
         self.sync_clients();
+        if let Some(udp) = &mut self.udp {
+            udp.flush();
+        }
 
         // Sync changed chunks
-        'chunk: for chunk_key in &self.state.terrain_changes().modified_chunks {
+        for chunk_key in &self.state.terrain_changes().modified_chunks {
             let terrain = self.state.terrain();
+            let msg = match chunk_deltas.remove(chunk_key) {
+                Some(delta) => ServerMsg::TerrainChunkDelta {
+                    key: *chunk_key,
+                    delta,
+                },
+                None => match terrain.get_key(*chunk_key) {
+                    Some(chunk) => ServerMsg::TerrainChunkUpdate {
+                        key: *chunk_key,
+                        chunk: EncodedTerrainChunk::encode(chunk),
+                    },
+                    None => continue,
+                },
+            };
 
             for (entity, player, pos) in (
                 &self.state.ecs().entities(),
@@ -600,16 +861,7 @@ impl Server {
                     .map(|vd| chunk_in_vd(pos.0, *chunk_key, &terrain, vd))
                     .unwrap_or(false)
                 {
-                    self.clients.notify(
-                        entity,
-                        ServerMsg::TerrainChunkUpdate {
-                            key: *chunk_key,
-                            chunk: Box::new(match self.state.terrain().get_key(*chunk_key) {
-                                Some(chunk) => chunk.clone(),
-                                None => break 'chunk,
-                            }),
-                        },
-                    );
+                    self.clients.notify(entity, msg.clone());
                 }
             }
         }
@@ -645,6 +897,13 @@ impl Server {
             let _ = self.state.ecs_mut().delete_entity(entity);
         }
 
+        // Periodically save characters so that a crash doesn't lose too much progress.
+        if self.state.get_time() - self.last_autosave > AUTOSAVE_INTERVAL {
+            self.save_all_characters();
+            self.terrain_persistence.save_all();
+            self.last_autosave = self.state.get_time();
+        }
+
         let before_tick_7 = Instant::now();
         // 7) Update Metrics
         self.metrics
@@ -663,7 +922,36 @@ impl Server {
             .tick_time
             .with_label_values(&["sync"])
             .set((before_tick_7 - before_tick_6).as_nanos() as i64);
+        for (system, stats) in self.state.sys_stats() {
+            let stats = [
+                ("last", stats.last),
+                ("mean", stats.mean),
+                ("max", stats.max),
+            ];
+            for (stat, duration) in stats.iter() {
+                self.metrics
+                    .system_time
+                    .with_label_values(&[system, stat])
+                    .set(duration.as_nanos() as i64);
+            }
+        }
         self.metrics.player_online.set(self.clients.len() as i64);
+        // Clients come and go, so start over every tick rather than removing stale labels.
+        self.metrics.queued_messages.reset();
+        for (entity, client) in self.clients.iter() {
+            self.metrics
+                .record_traffic("tcp", client.postbox.take_traffic());
+            let client_label = entity.id().to_string();
+            for priority in Priority::ALL.iter() {
+                self.metrics
+                    .queued_messages
+                    .with_label_values(&[&client_label, priority.name()])
+                    .set(client.postbox.queue_depth(*priority) as i64);
+            }
+        }
+        if let Some(udp) = &mut self.udp {
+            self.metrics.record_traffic("udp", udp.take_traffic());
+        }
         self.metrics
             .time_of_day
             .set(self.state.ecs().read_resource::<TimeOfDay>().0);
@@ -675,14 +963,15 @@ impl Server {
             });
             self.metrics.chonks_count.set(chonk_cnt as i64);
             self.metrics.chunks_count.set(chunk_cnt as i64);
+            self.metrics.update_entity_counts(self.state.ecs());
         }
-        //self.metrics.entity_count.set(self.state.);
         self.metrics
             .tick_time
             .with_label_values(&["metrics"])
             .set(before_tick_7.elapsed().as_nanos() as i64);
 
         // 8) Finish the tick, pass control back to the frontend.
+        self.session.end_tick(&self.state)?;
 
         Ok(frontend_events)
     }
@@ -693,35 +982,219 @@ impl Server {
         self.state.cleanup();
     }
 
-    /// Handle new client connections.
+    /// Handle new client connections and the first message of each, which is either the
+    /// handshake or a status query.
     fn handle_new_connections(&mut self) -> Result<Vec<Event>, Error> {
         let mut frontend_events = Vec::new();
+        let time = self.state.get_time();
+
+        // A replay connects the recorded clients itself, in the recorded order.
+        if let Session::Replaying(playback) = &mut self.session {
+            for (uid, postbox) in playback.connect_recorded(&mut self.postoffice)? {
+                let event = self.accept_connection(postbox);
+                if let Event::ClientConnected { entity } = &event {
+                    let found = self.state.ecs().uid_from_entity(*entity).map(u64::from);
+                    if found != Some(uid) {
+                        return Err(Error::Other(format!(
+                            "The replay diverged: a client got uid {:?} instead of {}",
+                            found, uid
+                        )));
+                    }
+                }
+                frontend_events.push(event);
+            }
+            return Ok(frontend_events);
+        }
 
-        for postbox in self.postoffice.new_postboxes() {
-            let entity = self.state.ecs_mut().create_entity_synced().build();
-            let mut client = Client {
-                client_state: ClientState::Connected,
+        for mut postbox in self.postoffice.new_postboxes() {
+            postbox.set_bandwidth_limit(self.server_settings.client_bandwidth_limit);
+            self.pending_connections.push(PendingConnection {
                 postbox,
-                last_ping: self.state.get_time(),
+                since: time,
+            });
+        }
+
+        // Clients don't send anything else before they have received the answer to their first
+        // message. Connections that are dropped here are closed once the answer has been sent.
+        for mut pending in std::mem::replace(&mut self.pending_connections, Vec::new()) {
+            let reply = match pending.postbox.new_messages().next() {
+                Some(ClientMsg::Connect { protocol_version })
+                    if protocol_version == PROTOCOL_VERSION =>
+                {
+                    if self.server_settings.max_players <= self.clients.len() {
+                        Some(ServerMsg::Error(ServerError::TooManyPlayers))
+                    } else {
+                        frontend_events.push(self.accept_connection(pending.postbox));
+                        continue;
+                    }
+                }
+                Some(ClientMsg::Connect { protocol_version }) => {
+                    warn!(
+                        "Client with protocol version {} tried to connect (expected {})",
+                        protocol_version, PROTOCOL_VERSION
+                    );
+                    Some(ServerMsg::Error(ServerError::IncompatibleVersion {
+                        server: PROTOCOL_VERSION,
+                        client: protocol_version,
+                    }))
+                }
+                Some(ClientMsg::StatusQuery) => Some(ServerMsg::Status(self.status())),
+                // Nothing but the handshake is accepted before it has completed.
+                Some(_) => None,
+                None if pending.postbox.error().is_none()
+                    && time - pending.since <= CLIENT_TIMEOUT =>
+                {
+                    self.pending_connections.push(pending);
+                    continue;
+                }
+                None => None,
             };
+            if let Some(msg) = reply {
+                self.metrics
+                    .messages_sent
+                    .with_label_values(&[msg.kind()])
+                    .inc();
+                pending.postbox.send_message(msg);
+            }
+        }
 
-            if self.server_settings.max_players <= self.clients.len() {
-                client.notify(ServerMsg::Error(ServerError::TooManyPlayers));
-            } else {
-                // Return the state of the current world (all of the components that Sphynx tracks).
-                client.notify(ServerMsg::InitialSync {
-                    ecs_state: self.state.ecs().gen_state_package(),
-                    entity_uid: self.state.ecs().uid_from_entity(entity).unwrap().into(), // Can't fail.
-                    server_info: self.server_info.clone(),
-                });
+        Ok(frontend_events)
+    }
 
-                frontend_events.push(Event::ClientConnected { entity });
-            }
+    /// Create the entity of a client that has completed the handshake and send it the state of
+    /// the world.
+    fn accept_connection(&mut self, postbox: PostBox<ServerMsg, ClientMsg>) -> Event {
+        let entity = self.state.ecs_mut().create_entity_synced().build();
+        let uid = self.state.ecs().uid_from_entity(entity).unwrap().into(); // Can't fail.
+        let mut client = Client {
+            client_state: ClientState::Connected,
+            postbox,
+            udp: None,
+            last_ping: self.state.get_time(),
+            account: None,
+            role: None,
+            regions: None,
+            input_seq: None,
+            ping_sent: None,
+            next_ping: Instant::now(),
+            sent_messages: self.metrics.messages_sent.clone(),
+        };
+
+        // Return the state of the current world (all of the components that Sphynx tracks).
+        client.notify(ServerMsg::InitialSync {
+            ecs_state: self.state.ecs().gen_state_package(),
+            entity_uid: uid,
+            server_info: self.server_info.clone(),
+        });
+        if let Some(udp) = self.udp.as_mut() {
+            let (token, key, link) = udp.add_peer(entity);
+            client.udp = Some(link);
+            client.notify(ServerMsg::UdpOffer {
+                port: udp.port(),
+                token,
+                key,
+            });
+        }
 
-            self.clients.add(entity, client);
+        self.clients.add(entity, client);
+        self.session.record_connection(uid);
+        Event::ClientConnected { entity }
+    }
+
+    /// What server browsers are told about this server.
+    pub fn status(&self) -> ServerStatus {
+        let player_count = self
+            .clients
+            .iter()
+            .filter(|(_, client)| match client.client_state {
+                ClientState::Pending | ClientState::Connected => false,
+                _ => true,
+            })
+            .count();
+        ServerStatus {
+            name: self.server_info.name.clone(),
+            description: self.server_info.description.clone(),
+            player_count: player_count as u32,
+            max_players: self.server_settings.max_players as u32,
+            protocol_version: PROTOCOL_VERSION,
+            git_hash: self.server_info.git_hash.clone(),
+            world_seed: self.server_settings.world_seed,
         }
+    }
 
-        Ok(frontend_events)
+    /// Handle the physics updates that clients sent over UDP.
+    fn handle_udp_messages(&mut self) {
+        let udp_msgs = match self.session.replayed_udp_messages(&self.state) {
+            Some(msgs) => msgs,
+            None => match &mut self.udp {
+                Some(udp) => {
+                    let msgs = udp.receive();
+                    self.session.record_udp_messages(&self.state, &msgs);
+                    msgs
+                }
+                None => return,
+            },
+        };
+
+        for (entity, msg) in udp_msgs {
+            let is_character = self
+                .clients
+                .get(&entity)
+                .map(|client| client.client_state == ClientState::Character)
+                .unwrap_or(false);
+            // Anything else arrives over TCP.
+            if let (ClientMsg::PlayerPhysics { seq, pos, vel, ori }, true) = (msg, is_character) {
+                let validator = if self.server_settings.movement_validation {
+                    Some(&mut self.movement_validator)
+                } else {
+                    None
+                };
+                let account = self
+                    .clients
+                    .get(&entity)
+                    .and_then(|client| client.account.as_ref())
+                    .map(String::as_str);
+                let ack = Self::apply_player_physics(
+                    &mut self.state,
+                    validator,
+                    entity,
+                    account,
+                    seq,
+                    pos,
+                    vel,
+                    ori,
+                );
+                self.clients.notify(entity, ack);
+            }
+        }
+    }
+
+    /// Apply the physics that a client reported for its character, after checking them if
+    /// `validator` is given. Returns the acknowledgement of the state the character ended up in.
+    fn apply_player_physics(
+        state: &mut State,
+        validator: Option<&mut MovementValidator>,
+        entity: EcsEntity,
+        account: Option<&str>,
+        seq: u64,
+        pos: comp::Pos,
+        vel: comp::Vel,
+        ori: comp::Ori,
+    ) -> ServerMsg {
+        match validator {
+            Some(validator) => validator.apply(state, entity, account, pos, vel, ori),
+            None => {
+                state.write_component(entity, pos);
+                state.write_component(entity, vel);
+                state.write_component(entity, ori);
+            }
+        }
+        ServerMsg::InputAck {
+            seq,
+            pos: state.read_component_cloned(entity).unwrap_or(pos),
+            vel: state.read_component_cloned(entity).unwrap_or(vel),
+            ori: state.read_component_cloned(entity).unwrap_or(ori),
+        }
     }
 
     /// Handle new client messages.
@@ -729,7 +1202,12 @@ impl Server {
         let mut frontend_events = Vec::new();
 
         let accounts = &mut self.accounts;
+        let moderation = &mut self.moderation;
+        let movement_validator = &mut self.movement_validator;
+        let persistence = &mut self.persistence;
         let server_settings = &self.server_settings;
+        let metrics = &self.metrics;
+        let session = &mut self.session;
 
         let state = &mut self.state;
         let mut new_chat_msgs = Vec::new();
@@ -740,7 +1218,8 @@ impl Server {
 
         self.clients.remove_if(|entity, client| {
             let mut disconnect = false;
-            let new_msgs = client.postbox.new_messages();
+            let uid = state.ecs().uid_from_entity(entity).unwrap().into(); // Can't fail.
+            let (new_msgs, network_error) = session.client_messages(uid, &mut client.postbox);
 
             // Update client ping.
             if new_msgs.len() > 0 {
@@ -749,6 +1228,11 @@ impl Server {
                 // Process incoming messages.
                 for msg in new_msgs {
                     match msg {
+                        // The handshake happened before the client was added.
+                        ClientMsg::Connect { .. } => client.error_state(RequestStateError::Already),
+                        ClientMsg::StatusQuery => {
+                            client.error_state(RequestStateError::WrongMessage)
+                        }
                         ClientMsg::RequestState(requested_state) => match requested_state {
                             ClientState::Connected => disconnect = true, // Default state
                             ClientState::Registered => match client.client_state {
@@ -761,7 +1245,17 @@ impl Server {
                                 }
                                 ClientState::Spectator
                                 | ClientState::Character
-                                | ClientState::Dead => client.allow_state(ClientState::Registered),
+                                | ClientState::Dead => {
+                                    if let Some(account) = &client.account {
+                                        Self::save_character(
+                                            state,
+                                            &mut **persistence,
+                                            entity,
+                                            account,
+                                        );
+                                    }
+                                    client.allow_state(ClientState::Registered)
+                                }
                                 ClientState::Pending => {}
                             },
                             ClientState::Spectator => match requested_state {
@@ -786,12 +1280,26 @@ impl Server {
                         },
                         // Valid player
                         ClientMsg::Register { player, password } if player.is_valid() => {
-                            if !accounts.query(player.alias.clone(), password) {
-                                client.error_state(RequestStateError::Denied);
+                            let client_state = client.client_state;
+                            let login = session.login(|| {
+                                Self::check_login(
+                                    server_settings,
+                                    moderation,
+                                    &mut **accounts,
+                                    client_state,
+                                    &player,
+                                    &password,
+                                )
+                            });
+                            if let Err(msg) = login {
+                                client.notify(msg);
                                 break;
                             }
                             match client.client_state {
                                 ClientState::Connected => {
+                                    client.account = Some(player.alias.clone());
+                                    client.role =
+                                        Some(server_settings.role_of(&player.alias).to_string());
                                     Self::initialize_player(state, entity, client, player);
                                 }
                                 // Use RequestState instead (No need to send `player` again).
@@ -909,25 +1417,40 @@ impl Server {
                             ClientState::Registered
                             | ClientState::Spectator
                             | ClientState::Dead => {
-                                Self::create_player_character(
-                                    state,
-                                    entity,
-                                    client,
-                                    name,
-                                    body,
-                                    main.map(|t| comp::Item::Tool { kind: t, power: 10 }),
-                                    &server_settings,
-                                );
-                                if let Some(player) =
-                                    state.ecs().read_storage::<comp::Player>().get(entity)
-                                {
-                                    new_chat_msgs.push((
-                                        None,
-                                        ServerMsg::broadcast(format!(
-                                            "[{}] is now online.",
-                                            &player.alias
-                                        )),
-                                    ));
+                                let character = session.character(|| match &client.account {
+                                    Some(account) => Self::load_character(&**persistence, account),
+                                    None => Ok(None),
+                                });
+                                match character {
+                                    Ok(character) => {
+                                        Self::create_player_character(
+                                            state,
+                                            entity,
+                                            client,
+                                            name,
+                                            body,
+                                            main.map(|t| comp::Item::Tool { kind: t, power: 10 }),
+                                            character,
+                                        );
+                                        if let Some(player) =
+                                            state.ecs().read_storage::<comp::Player>().get(entity)
+                                        {
+                                            new_chat_msgs.push((
+                                                None,
+                                                ServerMsg::broadcast(format!(
+                                                    "[{}] is now online.",
+                                                    &player.alias
+                                                )),
+                                            ));
+                                        }
+                                    }
+                                    Err(_) => {
+                                        client.notify(ServerMsg::private(String::from(
+                                            "Your saved character couldn't be loaded, please \
+                                             contact an admin.",
+                                        )));
+                                        client.error_state(RequestStateError::Denied);
+                                    }
                                 }
                             }
                             ClientState::Character => {
@@ -935,14 +1458,18 @@ impl Server {
                             }
                             ClientState::Pending => {}
                         },
-                        ClientMsg::Controller(controller) => match client.client_state {
+                        ClientMsg::Controller { controller, seq } => match client.client_state {
                             ClientState::Connected
                             | ClientState::Registered
                             | ClientState::Spectator => {
                                 client.error_state(RequestStateError::Impossible)
                             }
                             ClientState::Dead | ClientState::Character => {
-                                state.write_component(entity, controller);
+                                // Inputs that are older than the current one are out of date.
+                                if client.input_seq.map_or(true, |last| seq > last) {
+                                    client.input_seq = Some(seq);
+                                    state.write_component(entity, controller);
+                                }
                             }
                             ClientState::Pending => {}
                         },
@@ -957,11 +1484,19 @@ impl Server {
                                 .push((Some(entity), ServerMsg::ChatMsg { chat_type, message })),
                             ClientState::Pending => {}
                         },
-                        ClientMsg::PlayerPhysics { pos, vel, ori } => match client.client_state {
+                        ClientMsg::PlayerPhysics { seq, pos, vel, ori } => match client.client_state
+                        {
                             ClientState::Character => {
-                                state.write_component(entity, pos);
-                                state.write_component(entity, vel);
-                                state.write_component(entity, ori);
+                                let validator = if server_settings.movement_validation {
+                                    Some(&mut *movement_validator)
+                                } else {
+                                    None
+                                };
+                                let account = client.account.as_ref().map(String::as_str);
+                                let ack = Self::apply_player_physics(
+                                    state, validator, entity, account, seq, pos, vel, ori,
+                                );
+                                client.notify(ack);
                             }
                             // Only characters can send positions.
                             _ => client.error_state(RequestStateError::Impossible),
@@ -995,9 +1530,9 @@ impl Server {
                             ClientState::Spectator | ClientState::Character => {
                                 match state.terrain().get_key(key) {
                                     Some(chunk) => {
-                                        client.postbox.send_message(ServerMsg::TerrainChunkUpdate {
+                                        client.notify(ServerMsg::TerrainChunkUpdate {
                                             key,
-                                            chunk: Box::new(chunk.clone()),
+                                            chunk: EncodedTerrainChunk::encode(chunk),
                                         })
                                     }
                                     None => requested_chunks.push(key),
@@ -1006,24 +1541,40 @@ impl Server {
                             ClientState::Pending => {}
                         },
                         // Always possible.
-                        ClientMsg::Ping => client.postbox.send_message(ServerMsg::Pong),
-                        ClientMsg::Pong => {}
+                        ClientMsg::Ping => client.notify(ServerMsg::Pong),
+                        ClientMsg::Pong => {
+                            if let Some(sent) = client.ping_sent.take() {
+                                metrics.client_ping.observe(sent.elapsed().as_secs_f64());
+                            }
+                        }
                         ClientMsg::Disconnect => {
                             disconnect = true;
                         }
                     }
                 }
-            } else if state.get_time() - client.last_ping > CLIENT_TIMEOUT || // Timeout
-                client.postbox.error().is_some()
-            // Postbox error
-            {
+            } else if let Some(err) = network_error {
+                // Postbox error
+                debug!("Disconnecting client because of a network error: {:?}", err);
+                metrics.record_network_error(&err);
+                disconnect = true;
+            } else if state.get_time() - client.last_ping > CLIENT_TIMEOUT {
+                // Timeout
                 disconnect = true;
-            } else if state.get_time() - client.last_ping > CLIENT_TIMEOUT * 0.5 {
-                // Try pinging the client if the timeout is nearing.
-                client.postbox.send_message(ServerMsg::Ping);
+            }
+
+            // Ping the client regularly to measure its latency, which also keeps the connection
+            // from timing out.
+            let now = Instant::now();
+            if !disconnect && client.ping_sent.is_none() && client.next_ping <= now {
+                client.notify(ServerMsg::Ping);
+                client.ping_sent = Some(now);
+                client.next_ping = now + PING_INTERVAL;
             }
 
             if disconnect {
+                if let Some(account) = &client.account {
+                    Self::save_character(state, &mut **persistence, entity, account);
+                }
                 if let Some(player) = state.ecs().read_storage::<comp::Player>().get(entity) {
                     new_chat_msgs.push((
                         None,
@@ -1031,7 +1582,7 @@ impl Server {
                     ));
                 }
                 disconnected_clients.push(entity);
-                client.postbox.send_message(ServerMsg::Disconnect);
+                client.notify(ServerMsg::Disconnect);
                 true
             } else {
                 false
@@ -1043,20 +1594,51 @@ impl Server {
             match msg {
                 ServerMsg::ChatMsg { chat_type, message } => {
                     if let Some(entity) = entity {
-                        // Handle chat commands.
-                        if message.starts_with("/") && message.len() > 1 {
+                        let mute = match self
+                            .clients
+                            .get(&entity)
+                            .and_then(|client| client.account.clone())
+                        {
+                            Some(account) => self.moderation.mute_of(&account).cloned(),
+                            None => None,
+                        };
+
+                        // Muted players may still use the commands that don't message other
+                        // players.
+                        let is_command = message.starts_with("/") && message.len() > 1;
+                        let mute =
+                            mute.filter(|_| !is_command || cmd::messages_players(&message[1..]));
+
+                        if let Some(mute) = mute {
+                            self.clients.notify(
+                                entity,
+                                ServerMsg::private(format!("You are muted: {}", mute.reason)),
+                            );
+                        } else if is_command {
+                            // Handle chat commands.
                             let argv = String::from(&message[1..]);
-                            self.process_chat_cmd(entity, argv);
+                            self.process_chat_cmd(CommandSender::Player(entity), argv);
                         } else {
                             let message =
                                 match self.state.ecs().read_storage::<comp::Player>().get(entity) {
-                                    Some(player) => {
-                                        if self.entity_is_admin(entity) {
-                                            format!("[ADMIN][{}] {}", &player.alias, message)
-                                        } else {
-                                            format!("[{}] {}", &player.alias, message)
+                                    Some(player) => match self
+                                        .clients
+                                        .get(&entity)
+                                        .and_then(|client| client.role.as_ref())
+                                    {
+                                        // Show the role of everybody that isn't a regular player.
+                                        Some(role)
+                                            if *role != self.server_settings.default_role =>
+                                        {
+                                            format!(
+                                                "[{}][{}] {}",
+                                                role.to_uppercase(),
+                                                &player.alias,
+                                                message
+                                            )
                                         }
-                                    }
+                                        _ => format!("[{}] {}", &player.alias, message),
+                                    },
                                     None => format!("[<Unknown>] {}", message),
                                 };
                             self.clients
@@ -1075,6 +1657,7 @@ impl Server {
 
         // Handle client disconnects.
         for entity in disconnected_clients {
+            self.movement_validator.forget(entity);
             if let Err(err) = self.state.ecs_mut().delete_entity_synced(entity) {
                 debug!("Failed to delete disconnected client: {:?}", err);
             }
@@ -1092,9 +1675,11 @@ impl Server {
         }
 
         for (pos, ori, item) in dropped_items {
-            let vel = ori.0.normalized() * 5.0
-                + Vec3::unit_z() * 10.0
-                + Vec3::<f32>::zero().map(|_| rand::thread_rng().gen::<f32>() - 0.5) * 4.0;
+            let spread = {
+                let mut rng = self.state.ecs().write_resource::<EcsRng>();
+                Vec3::<f32>::zero().map(|_| rng.0.gen::<f32>() - 0.5)
+            };
+            let vel = ori.0.normalized() * 5.0 + Vec3::unit_z() * 10.0 + spread * 4.0;
             self.create_object(Default::default(), comp::object::Body::Pouch)
                 .with(comp::Pos(pos.0 + Vec3::unit_z() * 0.25))
                 .with(item)
@@ -1155,121 +1740,221 @@ impl Server {
 
     /// Sync client states with the most up to date information.
     fn sync_clients(&mut self) {
-        // Sync 'logical' state using Sphynx.
-        self.clients
-            .notify_registered(ServerMsg::EcsSync(self.state.ecs_mut().next_sync_package()));
-
-        let ecs = self.state.ecs_mut();
-
-        // Sync physics
-        for (entity, &uid, &pos, force_update) in (
-            &ecs.entities(),
-            &ecs.read_storage::<Uid>(),
-            &ecs.read_storage::<comp::Pos>(),
-            ecs.read_storage::<comp::ForceUpdate>().maybe(),
-        )
-            .join()
+        let sync_package = self.state.ecs_mut().next_sync_package();
         {
+            let ecs = self.state.ecs();
+            let regions = &mut self.regions;
             let clients = &mut self.clients;
 
-            let in_vd = |entity| {
-                if let (Some(client_pos), Some(client_vd)) = (
-                    ecs.read_storage::<comp::Pos>().get(entity),
+            // Sort the entities into regions.
+            let moves = regions.update(
+                (&ecs.entities(), &ecs.read_storage::<comp::Pos>())
+                    .join()
+                    .map(|(entity, pos)| (entity, pos.0)),
+            );
+            let regions = &*regions;
+            let old_regions = moves
+                .iter()
+                .map(|m| (m.entity, m.from))
+                .collect::<HashMap<_, _>>();
+            let created = sync_package
+                .created_entities
+                .iter()
+                .copied()
+                .collect::<HashSet<u64>>();
+
+            // Sync 'logical' state using Sphynx, but only tell each client about the entities near
+            // it. Entities without a position are visible everywhere, and so is a client's own
+            // entity.
+            let uids = ecs.read_storage::<Uid>();
+            let mut subscribers = HashMap::<Vec2<i32>, Vec<EcsEntity>>::new();
+            for (client_entity, client) in clients.iter_mut() {
+                if client.client_state == ClientState::Pending
+                    || client.client_state == ClientState::Connected
+                {
+                    continue;
+                }
+
+                let old_subscription = client.regions.take();
+                let subscription = match (
+                    ecs.read_storage::<comp::Pos>().get(client_entity),
                     ecs.read_storage::<comp::Player>()
-                        .get(entity)
-                        .map(|pl| pl.view_distance)
-                        .and_then(|v| v),
+                        .get(client_entity)
+                        .and_then(|player| player.view_distance),
                 ) {
-                    {
-                        // Check if the entity is in the client's range
-                        Vec2::from(pos.0 - client_pos.0)
-                            .map2(TerrainChunkSize::RECT_SIZE, |d: f32, sz| {
-                                (d.abs() as u32 / sz).checked_sub(2).unwrap_or(0)
-                            })
-                            .magnitude_squared()
-                            < client_vd.pow(2)
+                    (Some(pos), Some(vd)) => {
+                        region::subscribed_regions(pos.0, vd, old_subscription.as_ref())
+                    }
+                    _ => HashSet::new(),
+                };
+
+                let was_visible = |entity| {
+                    let region = match old_regions.get(&entity) {
+                        Some(from) => *from,
+                        None => regions.region_of(entity),
+                    };
+                    entity == client_entity
+                        || match (region, &old_subscription) {
+                            (Some(region), Some(old)) => old.contains(&region),
+                            _ => true,
+                        }
+                };
+                let is_visible = |entity| {
+                    entity == client_entity
+                        || regions
+                            .region_of(entity)
+                            .map_or(true, |region| subscription.contains(&region))
+                };
+
+                // Entities created this tick are in the package already, and deletions go to
+                // everyone.
+                let mut package = sync_package.clone();
+                package
+                    .created_entities
+                    .retain(|uid| ecs.entity_from_uid(*uid).map_or(true, &is_visible));
+                package
+                    .comp_updates
+                    .retain(|(uid, _)| ecs.entity_from_uid(*uid).map_or(true, &is_visible));
+
+                // Entities that came into or went out of view, either because they moved or
+                // because the client did.
+                let candidates = match &old_subscription {
+                    Some(old) => old
+                        .symmetric_difference(&subscription)
+                        .flat_map(|region| regions.entities_in(*region))
+                        .chain(moves.iter().map(|m| m.entity))
+                        .collect::<HashSet<_>>(),
+                    None => regions
+                        .entities()
+                        .chain(moves.iter().map(|m| m.entity))
+                        .collect::<HashSet<_>>(),
+                };
+                // `CharacterState` isn't part of the sync, and is only sent again once it changes.
+                let mut character_states = Vec::new();
+                for entity in candidates {
+                    let uid: u64 = match uids.get(entity) {
+                        Some(uid) => (*uid).into(),
+                        None => continue,
+                    };
+                    if created.contains(&uid) || !ecs.is_alive(entity) {
+                        continue;
+                    }
+                    match (was_visible(entity), is_visible(entity)) {
+                        (false, true) => {
+                            package.created_entities.extend(Some(uid));
+                            package.comp_updates.extend(
+                                region::entity_packets(ecs, entity)
+                                    .into_iter()
+                                    .map(|packet| (uid, CompUpdateKind::Inserted(packet))),
+                            );
+                            if let Some(character_state) =
+                                ecs.read_storage::<comp::CharacterState>().get(entity)
+                            {
+                                character_states.push(ServerMsg::EntityCharacterState {
+                                    entity: uid,
+                                    character_state: *character_state,
+                                });
+                            }
+                        }
+                        (true, false) => package.deleted_entities.push(uid),
+                        _ => {}
                     }
-                } else {
-                    false
                 }
-            };
+                client.notify(ServerMsg::EcsSync(package));
+                for msg in character_states {
+                    client.notify_after_sync(msg);
+                }
 
+                for region in &subscription {
+                    subscribers.entry(*region).or_default().push(client_entity);
+                }
+                client.regions = Some(subscription);
+            }
+
+            // Sync physics to the clients subscribed to the entity's region
             let mut last_pos = ecs.write_storage::<comp::Last<comp::Pos>>();
             let mut last_vel = ecs.write_storage::<comp::Last<comp::Vel>>();
             let mut last_ori = ecs.write_storage::<comp::Last<comp::Ori>>();
             let mut last_character_state = ecs.write_storage::<comp::Last<comp::CharacterState>>();
-
-            if let Some(client_pos) = ecs.read_storage::<comp::Pos>().get(entity) {
-                if last_pos
-                    .get(entity)
-                    .map(|&l| l.0 != *client_pos)
-                    .unwrap_or(true)
+            for (entity, &uid, force_update) in (
+                &ecs.entities(),
+                &ecs.read_storage::<Uid>(),
+                ecs.read_storage::<comp::ForceUpdate>().maybe(),
+            )
+                .join()
+            {
+                let subscribers = match regions
+                    .region_of(entity)
+                    .and_then(|region| subscribers.get(&region))
                 {
-                    let _ = last_pos.insert(entity, comp::Last(*client_pos));
-                    let msg = ServerMsg::EntityPos {
-                        entity: uid.into(),
-                        pos: *client_pos,
-                    };
-                    match force_update {
-                        Some(_) => clients.notify_ingame_if(msg, in_vd),
-                        None => clients.notify_ingame_if_except(entity, msg, in_vd),
+                    Some(subscribers) => subscribers,
+                    None => continue,
+                };
+                let mut send_msg = |msg: ServerMsg| {
+                    // Corrections must reach the client that is being corrected, so they don't go
+                    // over UDP.
+                    if force_update.is_some() && subscribers.contains(&entity) {
+                        clients.notify_ingame_reliably(entity, msg.clone());
+                    }
+                    clients.notify_ingame_among(subscribers, Some(entity), msg);
+                };
+
+                if let Some(client_pos) = ecs.read_storage::<comp::Pos>().get(entity) {
+                    if last_pos
+                        .get(entity)
+                        .map(|&l| l.0 != *client_pos)
+                        .unwrap_or(true)
+                    {
+                        let _ = last_pos.insert(entity, comp::Last(*client_pos));
+                        send_msg(ServerMsg::EntityPos {
+                            entity: uid.into(),
+                            pos: *client_pos,
+                        });
                     }
                 }
-            }
 
-            if let Some(client_vel) = ecs.read_storage::<comp::Vel>().get(entity) {
-                if last_vel
-                    .get(entity)
-                    .map(|&l| l.0 != *client_vel)
-                    .unwrap_or(true)
-                {
-                    let _ = last_vel.insert(entity, comp::Last(*client_vel));
-                    let msg = ServerMsg::EntityVel {
-                        entity: uid.into(),
-                        vel: *client_vel,
-                    };
-                    match force_update {
-                        Some(_) => clients.notify_ingame_if(msg, in_vd),
-                        None => clients.notify_ingame_if_except(entity, msg, in_vd),
+                if let Some(client_vel) = ecs.read_storage::<comp::Vel>().get(entity) {
+                    if last_vel
+                        .get(entity)
+                        .map(|&l| l.0 != *client_vel)
+                        .unwrap_or(true)
+                    {
+                        let _ = last_vel.insert(entity, comp::Last(*client_vel));
+                        send_msg(ServerMsg::EntityVel {
+                            entity: uid.into(),
+                            vel: *client_vel,
+                        });
                     }
                 }
-            }
 
-            if let Some(client_ori) = ecs.read_storage::<comp::Ori>().get(entity) {
-                if last_ori
-                    .get(entity)
-                    .map(|&l| l.0 != *client_ori)
-                    .unwrap_or(true)
-                {
-                    let _ = last_ori.insert(entity, comp::Last(*client_ori));
-                    let msg = ServerMsg::EntityOri {
-                        entity: uid.into(),
-                        ori: *client_ori,
-                    };
-                    match force_update {
-                        Some(_) => clients.notify_ingame_if(msg, in_vd),
-                        None => clients.notify_ingame_if_except(entity, msg, in_vd),
+                if let Some(client_ori) = ecs.read_storage::<comp::Ori>().get(entity) {
+                    if last_ori
+                        .get(entity)
+                        .map(|&l| l.0 != *client_ori)
+                        .unwrap_or(true)
+                    {
+                        let _ = last_ori.insert(entity, comp::Last(*client_ori));
+                        send_msg(ServerMsg::EntityOri {
+                            entity: uid.into(),
+                            ori: *client_ori,
+                        });
                     }
                 }
-            }
 
-            if let Some(client_character_state) =
-                ecs.read_storage::<comp::CharacterState>().get(entity)
-            {
-                if last_character_state
-                    .get(entity)
-                    .map(|&l| !client_character_state.is_same_state(&l.0))
-                    .unwrap_or(true)
+                if let Some(client_character_state) =
+                    ecs.read_storage::<comp::CharacterState>().get(entity)
                 {
-                    let _ =
-                        last_character_state.insert(entity, comp::Last(*client_character_state));
-                    let msg = ServerMsg::EntityCharacterState {
-                        entity: uid.into(),
-                        character_state: *client_character_state,
-                    };
-                    match force_update {
-                        Some(_) => clients.notify_ingame_if(msg, in_vd),
-                        None => clients.notify_ingame_if_except(entity, msg, in_vd),
+                    if last_character_state
+                        .get(entity)
+                        .map(|&l| !client_character_state.is_same_state(&l.0))
+                        .unwrap_or(true)
+                    {
+                        let _ = last_character_state
+                            .insert(entity, comp::Last(*client_character_state));
+                        send_msg(ServerMsg::EntityCharacterState {
+                            entity: uid.into(),
+                            character_state: *client_character_state,
+                        });
                     }
                 }
             }
@@ -1287,7 +1972,17 @@ impl Server {
                 .notify(entity, ServerMsg::InventoryUpdate(inventory.clone()));
         }
 
-        // Remove all force flags.
+        // Remove all force flags. Clients take a while to notice, so their updates are judged
+        // leniently for a moment.
+        let time = self.state.get_time();
+        for (entity, _) in (
+            &self.state.ecs().entities(),
+            &self.state.ecs().read_storage::<comp::ForceUpdate>(),
+        )
+            .join()
+        {
+            self.movement_validator.forced(entity, time);
+        }
         self.state
             .ecs_mut()
             .write_storage::<comp::ForceUpdate>()
@@ -1298,17 +1993,271 @@ impl Server {
             .clear();
     }
 
+    /// Check whether `player` may log in with `password`. If not, returns the message that tells
+    /// the client why.
+    fn check_login(
+        server_settings: &ServerSettings,
+        moderation: &mut Moderation,
+        accounts: &mut dyn AuthBackend,
+        client_state: ClientState,
+        player: &comp::Player,
+        password: &str,
+    ) -> Result<(), ServerMsg> {
+        if !server_settings.is_whitelisted(&player.alias) {
+            return Err(ServerMsg::Error(ServerError::NotWhitelisted));
+        }
+        if let Some(ban) = moderation.ban_of(&player.alias) {
+            return Err(ServerMsg::Error(ServerError::Banned {
+                reason: ban.reason.clone(),
+                until: ban.until,
+            }));
+        }
+        accounts
+            .query(&player.alias, password)
+            .map_err(|err| match err {
+                AuthError::InvalidCredentials => {
+                    ServerMsg::StateAnswer(Err((RequestStateError::Denied, client_state)))
+                }
+                AuthError::InvalidToken => {
+                    ServerMsg::Error(ServerError::InvalidToken { expired: false })
+                }
+                AuthError::ExpiredToken => {
+                    ServerMsg::Error(ServerError::InvalidToken { expired: true })
+                }
+            })
+    }
+
+    /// Load the saved character of an account. Failures are logged and passed on, since
+    /// starting over with a new character would overwrite the save once it is saved again.
+    fn load_character(
+        persistence: &dyn CharacterStore,
+        account: &str,
+    ) -> Result<Option<CharacterData>, String> {
+        persistence.load(account).map_err(|err| {
+            warn!("Failed to load character of '{}': {}", account, err);
+            err.to_string()
+        })
+    }
+
+    /// Save the character controlled by `entity`, if it has one.
+    fn save_character(
+        state: &State,
+        persistence: &mut dyn CharacterStore,
+        entity: EcsEntity,
+        account: &str,
+    ) {
+        let ecs = state.ecs();
+        if let (Some(body), Some(stats), Some(inventory), Some(pos)) = (
+            ecs.read_storage::<comp::Body>().get(entity).copied(),
+            ecs.read_storage::<comp::Stats>().get(entity).cloned(),
+            ecs.read_storage::<comp::Inventory>().get(entity).cloned(),
+            ecs.read_storage::<comp::Pos>().get(entity).copied(),
+        ) {
+            let character = CharacterData {
+                body,
+                stats,
+                inventory,
+                pos,
+            };
+            if let Err(err) = persistence.save(account, &character) {
+                warn!("Failed to save character of '{}': {}", account, err);
+            }
+        }
+    }
+
+    /// Forcefully disconnect a client after sending it `msg` to explain why.
+    pub fn kick_client(&mut self, entity: EcsEntity, msg: ServerMsg) {
+        if let Some(mut client) = self.clients.remove(&entity) {
+            if let Some(account) = &client.account {
+                Self::save_character(&self.state, &mut *self.persistence, entity, account);
+            }
+            client.notify(msg);
+            client.notify(ServerMsg::Disconnect);
+
+            if let Some(player) = self.state.ecs().read_storage::<comp::Player>().get(entity) {
+                self.clients.notify_registered(ServerMsg::broadcast(format!(
+                    "{} was kicked.",
+                    &player.alias
+                )));
+            }
+            if let Err(err) = self.state.ecs_mut().delete_entity_synced(entity) {
+                debug!("Failed to delete kicked client: {:?}", err);
+            }
+        }
+    }
+
+    /// Save the characters of all connected players.
+    pub fn save_all_characters(&mut self) {
+        let state = &self.state;
+        let persistence = &mut self.persistence;
+        for (entity, _) in (
+            &state.ecs().entities(),
+            &state.ecs().read_storage::<comp::Player>(),
+        )
+            .join()
+        {
+            if let Some(account) = self.clients.get(&entity).and_then(|c| c.account.as_ref()) {
+                Self::save_character(state, &mut **persistence, entity, account);
+            }
+        }
+    }
+
     pub fn generate_chunk(&mut self, key: Vec2<i32>) {
-        if self.pending_chunks.insert(key) {
+        // A replay generates the chunks when the recording says they were ready.
+        if self.pending_chunks.insert(key) && !self.session.is_replaying() {
             let chunk_tx = self.chunk_tx.clone();
             let world = self.world.clone();
+            let chunk_gen_time = self.metrics.chunk_gen_time.clone();
             self.thread_pool.execute(move || {
-                let _ = chunk_tx.send((key, world.generate_chunk(key)));
+                let start = Instant::now();
+                let chunk = world.generate_chunk(key);
+                chunk_gen_time.observe(start.elapsed().as_secs_f64());
+                let _ = chunk_tx.send((key, chunk));
+            });
+        }
+    }
+
+    /// Shut the server down once `after` has passed, warning players in the meantime. Once the
+    /// time is up, characters are saved, clients are disconnected with `reason` and `tick`
+    /// returns `Event::Shutdown`, after which the frontend should drop the server. A new request
+    /// replaces the previous one.
+    pub fn request_shutdown(&mut self, after: Duration, reason: String) {
+        let secs = after.as_secs();
+        info!("Shutting down in {}s: {}", secs, reason);
+        self.clients.notify_registered(ServerMsg::broadcast(format!(
+            "The server will shut down in {}: {}",
+            format_secs(secs),
+            reason
+        )));
+        self.pending_shutdown = Some(PendingShutdown {
+            at: self.state.get_time() + after.as_secs_f64(),
+            reason,
+            last_announced: secs,
+            done: false,
+        });
+    }
+
+    /// Announce the remaining time of a pending shutdown and perform it once the time is up.
+    /// Returns whether the server has shut down.
+    fn tick_shutdown(&mut self) -> bool {
+        let shutdown = match &mut self.pending_shutdown {
+            Some(shutdown) => shutdown,
+            None => return false,
+        };
+
+        let now = self.state.get_time();
+        if now >= shutdown.at {
+            // Keep reporting the shutdown (and not accepting connections) if the frontend
+            // continues to tick.
+            if !shutdown.done {
+                shutdown.done = true;
+                let reason = shutdown.reason.clone();
+                info!("Shutting down: {}", reason);
+                self.shutdown(reason);
+            }
+            return true;
+        }
+
+        // Round up so that "1 second" is announced during the last second.
+        let secs = (shutdown.at - now).ceil() as u64;
+        if let Some(&t) = SHUTDOWN_ANNOUNCEMENTS
+            .iter()
+            .find(|&&t| t < shutdown.last_announced && t >= secs)
+        {
+            shutdown.last_announced = t;
+            self.clients.notify_registered(ServerMsg::broadcast(format!(
+                "The server will shut down in {}.",
+                format_secs(t)
+            )));
+        }
+        false
+    }
+
+    /// Read the settings file again and apply what can be changed without a restart. Invalid
+    /// files are reported and otherwise ignored.
+    fn reload_settings(&mut self) {
+        let settings = match self
+            .server_settings
+            .file
+            .as_ref()
+            .map(|path| ServerSettings::load_from_file(path))
+        {
+            Some(Ok(settings)) => settings,
+            None => return,
+            Some(Err(err)) => {
+                warn!("Ignoring changes to the settings: {}", err);
+                return;
+            }
+        };
+
+        // Roles derived from the settings may change, but temporary ones shouldn't be touched.
+        let roles_before = self
+            .clients
+            .iter()
+            .filter_map(|(entity, client)| {
+                client
+                    .account
+                    .as_ref()
+                    .map(|account| (entity, self.server_settings.role_of(account).to_string()))
+            })
+            .collect::<Vec<_>>();
+
+        let changed = self.server_settings.update(settings);
+        if changed.is_empty() {
+            return;
+        }
+        info!("Reloaded settings, changed: {}", changed.join(", "));
+
+        self.server_info.name = self.server_settings.server_name.clone();
+        self.server_info.description = self.server_settings.server_description.clone();
+
+        if changed.contains(&"client_bandwidth_limit") {
+            let limit = self.server_settings.client_bandwidth_limit;
+            for (_, client) in self.clients.iter_mut() {
+                client.postbox.set_bandwidth_limit(limit);
+            }
+        }
+
+        for (entity, role_before) in roles_before {
+            let role = self
+                .clients
+                .get(&entity)
+                .and_then(|client| client.account.as_ref())
+                .map(|account| self.server_settings.role_of(account).to_string());
+            if let Some(role) = role.filter(|role| *role != role_before) {
+                self.set_role(entity, role);
+            }
+        }
+    }
+
+    /// Save all persistent state and disconnect every client, telling them why.
+    fn shutdown(&mut self, reason: String) {
+        self.save_all_characters();
+        self.terrain_persistence.save_all();
+        self.clients.remove_if(|_, client| {
+            client.notify(ServerMsg::Shutdown {
+                reason: reason.clone(),
             });
+            true
+        });
+        if !net::await_flushed(SHUTDOWN_FLUSH_TIMEOUT) {
+            warn!("Not every client was told about the shutdown in time");
+        }
+    }
+
+    /// Send a reply to whoever issued a chat command.
+    fn notify_sender(&mut self, sender: CommandSender, msg: ServerMsg) {
+        match sender {
+            CommandSender::Player(entity) => self.clients.notify(entity, msg),
+            CommandSender::Console => {
+                if let ServerMsg::ChatMsg { message, .. } = msg {
+                    self.console_output.push(message);
+                }
+            }
         }
     }
 
-    fn process_chat_cmd(&mut self, entity: EcsEntity, cmd: String) {
+    fn process_chat_cmd(&mut self, sender: CommandSender, cmd: String) {
         // Separate string into keyword and arguments.
         let sep = cmd.find(' ');
         let (kwd, args) = match sep {
@@ -1319,11 +2268,11 @@ impl Server {
         // Find the command object and run its handler.
         let action_opt = CHAT_COMMANDS.iter().find(|x| x.keyword == kwd);
         match action_opt {
-            Some(action) => action.execute(self, entity, args),
+            Some(action) => action.execute(self, sender, args),
             // Unknown command
             None => {
-                self.clients.notify(
-                    entity,
+                self.notify_sender(
+                    sender,
                     ServerMsg::private(format!(
                         "Unknown command '/{}'.\nType '/help' for available commands",
                         kwd
@@ -1333,16 +2282,72 @@ impl Server {
         }
     }
 
-    fn entity_is_admin(&self, entity: EcsEntity) -> bool {
-        self.state
-            .read_storage::<comp::Admin>()
-            .get(entity)
-            .is_some()
+    /// The role of the player controlling `entity`, if they have registered.
+    fn role_of(&self, entity: EcsEntity) -> Option<&Role> {
+        self.clients
+            .get(&entity)
+            .and_then(|client| client.role.as_ref())
+            .and_then(|role| self.server_settings.role(role))
+    }
+
+    /// Whether `sender` may run the chat command `keyword`. The console may run everything.
+    fn may_run(&self, sender: CommandSender, keyword: &str) -> bool {
+        match sender {
+            CommandSender::Player(entity) => self
+                .role_of(entity)
+                .map(|role| role.allows(keyword))
+                .unwrap_or(false),
+            CommandSender::Console => true,
+        }
+    }
+
+    /// Whether `sender` may act on players with the role `role`, which they may unless it allows
+    /// a command that their own role doesn't. The console may act on everyone.
+    fn outranks(&self, sender: CommandSender, role: &str) -> bool {
+        let role = match self.server_settings.role(role) {
+            Some(role) => role,
+            None => return true,
+        };
+        match sender {
+            CommandSender::Player(entity) => self
+                .role_of(entity)
+                .map(|own| own.includes(role))
+                .unwrap_or(false),
+            CommandSender::Console => true,
+        }
+    }
+
+    /// Change the role of the player controlling `entity` for the rest of their session.
+    fn set_role(&mut self, entity: EcsEntity, role: String) {
+        if let Some(client) = self.clients.get_mut(&entity) {
+            client.notify(ServerMsg::private(format!("Your role is now '{}'.", role)));
+            client.role = Some(role);
+        }
+        if !self
+            .role_of(entity)
+            .map(|role| role.can_build)
+            .unwrap_or(false)
+        {
+            self.state
+                .ecs()
+                .write_storage::<comp::CanBuild>()
+                .remove(entity);
+        }
     }
 }
 
 impl Drop for Server {
     fn drop(&mut self) {
-        self.clients.notify_registered(ServerMsg::Shutdown);
+        self.shutdown(String::from("The server was stopped."));
     }
 }
+
+/// Format a number of seconds for a shutdown announcement, e.g. "5 minutes" or "1 second".
+fn format_secs(secs: u64) -> String {
+    let (amount, unit) = if secs >= 60 && secs % 60 == 0 {
+        (secs / 60, "minute")
+    } else {
+        (secs, "second")
+    };
+    format!("{} {}{}", amount, unit, if amount == 1 { "" } else { "s" })
+}
@@ -1,14 +1,20 @@
 extern crate prometheus;
 extern crate prometheus_static_metric;
 extern crate rouille;
-use prometheus::{Encoder, Gauge, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use common::{comp, net::PostError};
+use log::warn;
+use prometheus::{
+    exponential_buckets, Encoder, Gauge, Histogram, HistogramOpts, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
 use rouille::{router, Server};
+use specs::{Join, World};
 use std::{
     convert::TryInto,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::SocketAddr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        mpsc, Arc,
     },
     thread,
     time::{SystemTime, UNIX_EPOCH},
@@ -18,8 +24,15 @@ pub struct ServerMetrics {
     pub chonks_count: IntGauge,
     pub chunks_count: IntGauge,
     pub player_online: IntGauge,
-    pub entity_count: IntGauge,
+    pub entity_count: IntGaugeVec,
     pub tick_time: IntGaugeVec,
+    pub system_time: IntGaugeVec,
+    pub network_errors: IntCounterVec,
+    pub network_bytes: IntCounterVec,
+    pub messages_sent: IntCounterVec,
+    pub queued_messages: IntGaugeVec,
+    pub chunk_gen_time: Histogram,
+    pub client_ping: Histogram,
     pub build_info: IntGauge,
     pub start_time: IntGauge,
     pub time_of_day: Gauge,
@@ -27,20 +40,27 @@ pub struct ServerMetrics {
     pub thread_running: Arc<AtomicBool>,
     pub handle: Option<thread::JoinHandle<()>>,
     pub every_100th: i8,
+    registry: Registry,
+    addr: Option<SocketAddr>,
 }
 
 impl ServerMetrics {
-    pub fn new() -> Self {
+    /// Create the metrics and serve them at `http://<addr>/metrics`. With `None`, or if the
+    /// address can't be bound, the metrics are still recorded but not served.
+    pub fn new(addr: Option<SocketAddr>) -> Self {
         let opts = Opts::new(
             "player_online",
             "shows the number of clients connected to the server",
         );
         let player_online = IntGauge::with_opts(opts).unwrap();
-        let opts = Opts::new(
-            "entity_count",
-            "number of all entities currently active on the server",
-        );
-        let entity_count = IntGauge::with_opts(opts).unwrap();
+        let entity_count = IntGaugeVec::new(
+            Opts::new(
+                "entity_count",
+                "number of all entities currently active on the server",
+            ),
+            &["kind", "body"],
+        )
+        .unwrap();
         let opts = Opts::new("veloren_build_info", "Build information")
             .const_label("hash", common::util::GIT_HASH)
             .const_label("version", "");
@@ -73,6 +93,59 @@ impl ServerMetrics {
         )
         .unwrap();
         let tick_time = IntGaugeVec::from(vec);
+        let system_time = IntGaugeVec::new(
+            Opts::new(
+                "system_time",
+                "time in ns that each ECS system took in its recent runs",
+            ),
+            &["system", "stat"],
+        )
+        .unwrap();
+        let queued_messages = IntGaugeVec::new(
+            Opts::new(
+                "queued_messages",
+                "number of messages waiting to be sent to a client",
+            ),
+            &["client", "priority"],
+        )
+        .unwrap();
+        let network_errors = IntCounterVec::new(
+            Opts::new(
+                "network_errors",
+                "number of clients disconnected because of a network error",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        let network_bytes = IntCounterVec::new(
+            Opts::new(
+                "network_bytes",
+                "number of bytes sent to and received from clients",
+            ),
+            &["direction", "protocol"],
+        )
+        .unwrap();
+        let messages_sent = IntCounterVec::new(
+            Opts::new("messages_sent", "number of messages sent to clients"),
+            &["kind"],
+        )
+        .unwrap();
+        let chunk_gen_time = Histogram::with_opts(
+            HistogramOpts::new(
+                "chunk_generation_seconds",
+                "time in seconds that generating a chunk took",
+            )
+            .buckets(exponential_buckets(0.005, 2.0, 12).unwrap()),
+        )
+        .unwrap();
+        let client_ping = Histogram::with_opts(
+            HistogramOpts::new(
+                "client_ping_seconds",
+                "time in seconds until clients answered a ping",
+            )
+            .buckets(exponential_buckets(0.005, 2.0, 10).unwrap()),
+        )
+        .unwrap();
 
         let since_the_epoch = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -80,40 +153,64 @@ impl ServerMetrics {
         start_time.set(since_the_epoch.as_secs().try_into().unwrap());
 
         let registry = Registry::new();
-        //registry.register(Box::new(chonks_count.clone())).unwrap();
         registry.register(Box::new(player_online.clone())).unwrap();
         registry.register(Box::new(entity_count.clone())).unwrap();
         registry.register(Box::new(build_info.clone())).unwrap();
         registry.register(Box::new(start_time.clone())).unwrap();
         registry.register(Box::new(time_of_day.clone())).unwrap();
-        //registry.register(Box::new(light_count.clone())).unwrap();
+        registry.register(Box::new(light_count.clone())).unwrap();
         registry.register(Box::new(chonks_count.clone())).unwrap();
         registry.register(Box::new(chunks_count.clone())).unwrap();
         registry.register(Box::new(tick_time.clone())).unwrap();
+        registry.register(Box::new(system_time.clone())).unwrap();
+        registry.register(Box::new(network_errors.clone())).unwrap();
+        registry.register(Box::new(network_bytes.clone())).unwrap();
+        registry.register(Box::new(messages_sent.clone())).unwrap();
+        registry
+            .register(Box::new(queued_messages.clone()))
+            .unwrap();
+        registry.register(Box::new(chunk_gen_time.clone())).unwrap();
+        registry.register(Box::new(client_ping.clone())).unwrap();
 
         let thread_running = Arc::new(AtomicBool::new(true));
         let thread_running2 = thread_running.clone();
+        let registry2 = registry.clone();
+        let (addr_tx, addr_rx) = mpsc::channel();
 
         //TODO: make this a job
-        let handle = Some(thread::spawn(move || {
-            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 14005);
-            let server = Server::new(addr, move |request| {
-                router!(request,
-                        (GET) (/metrics) => {
-                        let encoder = TextEncoder::new();
-                        let mut buffer = vec![];
-                        let mf = registry.gather();
-                        encoder.encode(&mf, &mut buffer).unwrap();
-                        rouille::Response::text(String::from_utf8(buffer).unwrap())
-                },
-                _ => rouille::Response::empty_404()
-                )
+        let handle = addr.map(|addr| {
+            thread::spawn(move || {
+                let server = Server::new(addr, move |request| {
+                    router!(request,
+                            (GET) (/metrics) => {
+                            rouille::Response::text(encode(&registry2))
+                    },
+                    _ => rouille::Response::empty_404()
+                    )
+                });
+                let server = match server {
+                    Ok(server) => {
+                        let _ = addr_tx.send(Ok(server.server_addr()));
+                        server
+                    }
+                    Err(err) => {
+                        let _ = addr_tx.send(Err(err.to_string()));
+                        return;
+                    }
+                };
+                while thread_running2.load(Ordering::Relaxed) {
+                    server.poll();
+                }
             })
-            .expect("Failed to start server");
-            while thread_running2.load(Ordering::Relaxed) {
-                server.poll();
+        });
+        let addr = match handle.as_ref().map(|_| addr_rx.recv()) {
+            Some(Ok(Ok(addr))) => Some(addr),
+            Some(Ok(Err(err))) => {
+                warn!("Failed to start the metrics exporter: {}", err);
+                None
             }
-        }));
+            Some(Err(_)) | None => None,
+        };
 
         Self {
             chonks_count,
@@ -121,6 +218,13 @@ impl ServerMetrics {
             player_online,
             entity_count,
             tick_time,
+            system_time,
+            network_errors,
+            network_bytes,
+            messages_sent,
+            queued_messages,
+            chunk_gen_time,
+            client_ping,
             build_info,
             start_time,
             time_of_day,
@@ -128,9 +232,77 @@ impl ServerMetrics {
             thread_running,
             handle,
             every_100th: 0,
+            registry,
+            addr,
         }
     }
 
+    /// The address that the metrics are served at, if they are.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    /// All metrics in the Prometheus text format, as they are served.
+    pub fn encode(&self) -> String {
+        encode(&self.registry)
+    }
+
+    /// Count a client that was disconnected because its connection failed.
+    pub fn record_network_error(&self, err: &PostError) {
+        let kind = match err {
+            PostError::Io(_) => "io",
+            PostError::Bincode(_) => "bincode",
+            PostError::ChannelFailure => "channel",
+            PostError::InvalidLength(_) => "length",
+            PostError::InvalidChecksum { .. } => "checksum",
+            PostError::InvalidCompression => "compression",
+        };
+        self.network_errors.with_label_values(&[kind]).inc();
+    }
+
+    /// Count the bytes that were sent and received over `protocol`.
+    pub fn record_traffic(&self, protocol: &str, (sent, received): (u64, u64)) {
+        self.network_bytes
+            .with_label_values(&["out", protocol])
+            .inc_by(sent as i64);
+        self.network_bytes
+            .with_label_values(&["in", protocol])
+            .inc_by(received as i64);
+    }
+
+    /// Count the entities and lights in `ecs`. Entities are counted by whether they are players,
+    /// NPCs, items or something else, and by the kind of their body.
+    pub fn update_entity_counts(&self, ecs: &World) {
+        // Kinds of entities that are gone shouldn't keep their last count.
+        self.entity_count.reset();
+        let players = ecs.read_storage::<comp::Player>();
+        let agents = ecs.read_storage::<comp::Agent>();
+        let items = ecs.read_storage::<comp::Item>();
+        let bodies = ecs.read_storage::<comp::Body>();
+        for (entity, body) in (&ecs.entities(), bodies.maybe()).join() {
+            let kind = if players.get(entity).is_some() {
+                "player"
+            } else if agents.get(entity).is_some() {
+                "npc"
+            } else if items.get(entity).is_some() {
+                "item"
+            } else {
+                "other"
+            };
+            let body = match body {
+                Some(comp::Body::Humanoid(_)) => "humanoid",
+                Some(comp::Body::Quadruped(_)) => "quadruped",
+                Some(comp::Body::QuadrupedMedium(_)) => "quadruped_medium",
+                Some(comp::Body::Object(_)) => "object",
+                None => "none",
+            };
+            self.entity_count.with_label_values(&[kind, body]).inc();
+        }
+
+        let lights = ecs.read_storage::<comp::LightEmitter>().join().count();
+        self.light_count.set(lights as i64);
+    }
+
     pub fn is_100th_tick(&mut self) -> bool {
         self.every_100th += 1;
         if self.every_100th == 100 {
@@ -142,13 +314,21 @@ impl ServerMetrics {
     }
 }
 
+fn encode(registry: &Registry) -> String {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    let mf = registry.gather();
+    encoder.encode(&mf, &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
 impl Drop for ServerMetrics {
     fn drop(&mut self) {
         self.thread_running.store(false, Ordering::Relaxed);
-        let handle = self.handle.take();
-        handle
-            .unwrap()
-            .join()
-            .expect("Error shutting down prometheus metric exporter");
+        if let Some(handle) = self.handle.take() {
+            handle
+                .join()
+                .expect("Error shutting down prometheus metric exporter");
+        }
     }
 }
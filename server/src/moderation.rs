@@ -0,0 +1,192 @@
+//! Persistent bans and mutes, keyed by account alias.
+
+use crate::util;
+use hashbrown::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A ban or mute placed on an account.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sanction {
+    pub reason: String,
+    /// When the sanction ends, in seconds since the UNIX epoch. `None` means it never does.
+    pub until: Option<u64>,
+}
+
+impl Sanction {
+    fn is_active(&self, now: u64) -> bool {
+        self.until.map(|until| now < until).unwrap_or(true)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ModerationDb {
+    bans: HashMap<String, Sanction>,
+    mutes: HashMap<String, Sanction>,
+}
+
+pub struct Moderation {
+    db: ModerationDb,
+    path: PathBuf,
+}
+
+impl Moderation {
+    /// Load the ban and mute lists from `path`, starting with empty ones if it doesn't exist.
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let db = match fs::File::open(&path) {
+            Ok(file) => ron::de::from_reader(file)
+                .map_err(|e| format!("Failed to parse ban list {:?}: {}", path, e))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => ModerationDb::default(),
+            // Starting with empty lists would lift every ban with the next save.
+            Err(e) => return Err(format!("Failed to open ban list {:?}: {}", path, e)),
+        };
+
+        Ok(Self { db, path })
+    }
+
+    /// Return the active ban on `alias`, if there is one. Expired bans are removed.
+    pub fn ban_of(&mut self, alias: &str) -> Option<&Sanction> {
+        Self::active(&mut self.db.bans, alias)
+    }
+
+    /// Return the active mute on `alias`, if there is one. Expired mutes are removed.
+    pub fn mute_of(&mut self, alias: &str) -> Option<&Sanction> {
+        Self::active(&mut self.db.mutes, alias)
+    }
+
+    pub fn ban(&mut self, alias: String, sanction: Sanction) {
+        self.db.bans.insert(alias, sanction);
+        self.save();
+    }
+
+    /// Returns whether `alias` was banned.
+    pub fn unban(&mut self, alias: &str) -> bool {
+        let removed = self.db.bans.remove(alias).is_some();
+        self.save();
+        removed
+    }
+
+    pub fn mute(&mut self, alias: String, sanction: Sanction) {
+        self.db.mutes.insert(alias, sanction);
+        self.save();
+    }
+
+    /// Returns whether `alias` was muted.
+    pub fn unmute(&mut self, alias: &str) -> bool {
+        let removed = self.db.mutes.remove(alias).is_some();
+        self.save();
+        removed
+    }
+
+    fn active<'a>(
+        sanctions: &'a mut HashMap<String, Sanction>,
+        alias: &str,
+    ) -> Option<&'a Sanction> {
+        let now = now();
+        if sanctions
+            .get(alias)
+            .map(|s| !s.is_active(now))
+            .unwrap_or(false)
+        {
+            sanctions.remove(alias);
+        }
+        sanctions.get(alias)
+    }
+
+    fn save(&self) {
+        let result = ron::ser::to_string_pretty(&self.db, ron::ser::PrettyConfig::default())
+            .map_err(|e| e.to_string())
+            .and_then(|s| util::write_atomically(&self.path, &s).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::error!("Failed to save ban list {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// The current time in seconds since the UNIX epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Parse a duration such as `30s`, `15m`, `2h` or `7d` into seconds.
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let split = s.len().checked_sub(1)?;
+    if !s.is_char_boundary(split) {
+        return None;
+    }
+    let (amount, unit) = s.split_at(split);
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.parse::<u64>().ok()?.checked_mul(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn durations_parse() {
+        assert_eq!(parse_duration("30s"), Some(30));
+        assert_eq!(parse_duration("15m"), Some(15 * 60));
+        assert_eq!(parse_duration("2h"), Some(2 * 60 * 60));
+        assert_eq!(parse_duration("7d"), Some(7 * 24 * 60 * 60));
+        assert_eq!(parse_duration("0s"), Some(0));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("10w"), None);
+        assert_eq!(parse_duration("-1h"), None);
+        assert_eq!(parse_duration("1é"), None);
+        assert_eq!(parse_duration("99999999999999999d"), None);
+    }
+
+    #[test]
+    fn sanctions_expire() {
+        let dir = env::temp_dir().join(format!("veloren-moderation-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("banlist.ron");
+        let _ = fs::remove_file(&path);
+
+        let sanction = |until| Sanction {
+            reason: "test".to_owned(),
+            until,
+        };
+        let mut moderation = Moderation::load(path.clone()).unwrap();
+        moderation.ban("expired".to_owned(), sanction(Some(now() - 1)));
+        moderation.ban("active".to_owned(), sanction(Some(now() + 60)));
+        moderation.ban("forever".to_owned(), sanction(None));
+        moderation.mute("expired".to_owned(), sanction(Some(now() - 1)));
+        moderation.mute("active".to_owned(), sanction(Some(now() + 60)));
+
+        // The lists survive a restart.
+        let mut moderation = Moderation::load(path.clone()).unwrap();
+        assert!(moderation.ban_of("expired").is_none());
+        assert!(moderation.ban_of("active").is_some());
+        assert!(moderation.ban_of("forever").is_some());
+        assert!(moderation.ban_of("unknown").is_none());
+        assert!(moderation.mute_of("expired").is_none());
+        assert!(moderation.mute_of("active").is_some());
+        assert!(!moderation.db.bans.contains_key("expired"));
+
+        assert!(moderation.unban("active"));
+        assert!(!moderation.unban("active"));
+        assert!(moderation.ban_of("active").is_none());
+
+        assert!(Moderation::load(dir.clone()).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
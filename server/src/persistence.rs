@@ -0,0 +1,122 @@
+//! Storage of player characters between sessions.
+//!
+//! The server only talks to a `CharacterStore`, so the file-backed store below can be replaced
+//! with something like a database without touching the rest of the server.
+
+use crate::util;
+use common::comp;
+use serde_derive::{Deserialize, Serialize};
+use std::{fmt, fs, io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Serialize(String),
+    Deserialize(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Serialize(err) => write!(f, "Failed to serialize character: {}", err),
+            Error::Deserialize(err) => write!(f, "Failed to deserialize character: {}", err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Everything about a player's character that survives a disconnect.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CharacterData {
+    pub body: comp::Body,
+    pub stats: comp::Stats,
+    pub inventory: comp::Inventory,
+    pub pos: comp::Pos,
+}
+
+pub trait CharacterStore: Send {
+    /// Load the character belonging to `account`, if one has been saved before.
+    fn load(&self, account: &str) -> Result<Option<CharacterData>, Error>;
+
+    /// Store the character belonging to `account`, replacing any previous save.
+    fn save(&mut self, account: &str, data: &CharacterData) -> Result<(), Error>;
+}
+
+/// A `CharacterStore` keeping one RON file per account in a directory.
+pub struct FileCharacterStore {
+    dir: PathBuf,
+}
+
+impl FileCharacterStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path_for(&self, account: &str) -> PathBuf {
+        // Account names are validated by `comp::Player::is_valid`, so they can't escape `dir`.
+        self.dir.join(format!("{}.ron", account))
+    }
+}
+
+impl CharacterStore for FileCharacterStore {
+    fn load(&self, account: &str) -> Result<Option<CharacterData>, Error> {
+        match fs::File::open(self.path_for(account)) {
+            Ok(file) => ron::de::from_reader(file)
+                .map(Some)
+                .map_err(|err| Error::Deserialize(err.to_string())),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&mut self, account: &str, data: &CharacterData) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)?;
+
+        let s = ron::ser::to_string_pretty(data, ron::ser::PrettyConfig::default())
+            .map_err(|err| Error::Serialize(err.to_string()))?;
+        util::write_atomically(&self.path_for(account), &s)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::comp::humanoid;
+    use std::env;
+    use vek::*;
+
+    #[test]
+    fn characters_round_trip() {
+        let dir = env::temp_dir().join(format!("veloren-persistence-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut store = FileCharacterStore::new(dir.clone());
+        assert!(store.load("tester").unwrap().is_none());
+
+        let character = CharacterData {
+            body: comp::Body::Humanoid(humanoid::Body::random()),
+            stats: comp::Stats::new("Tester".to_owned(), None),
+            inventory: comp::Inventory::default(),
+            pos: comp::Pos(Vec3::new(1.0, 2.0, 3.0)),
+        };
+        store.save("tester", &character).unwrap();
+        let loaded = store.load("tester").unwrap().unwrap();
+        // `Stats` can't be compared, but what they print can.
+        assert_eq!(format!("{:?}", loaded), format!("{:?}", character));
+        assert!(store.load("someone_else").unwrap().is_none());
+
+        // A broken save is an error rather than no save, so that it doesn't get replaced.
+        fs::write(store.path_for("tester"), "(body: ").unwrap();
+        match store.load("tester") {
+            Err(Error::Deserialize(_)) => {}
+            result => panic!("Expected a broken save, got {:?}", result),
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,271 @@
+//! Interest management. The world is divided into a grid of regions, and clients are only told
+//! about the entities in the regions within their view distance.
+
+use common::{comp, msg::EcsCompPacket, terrain::TerrainChunkSize, vol::RectVolSize};
+use hashbrown::{HashMap, HashSet};
+use specs::{Entity as EcsEntity, World};
+use vek::*;
+
+/// The width of a region, in chunks.
+pub const REGION_SIZE: i32 = 4;
+/// How much further than its view distance (in chunks) a client stays subscribed to a region,
+/// so that walking back and forth along a border doesn't recreate the same entities every time.
+const UNSUBSCRIBE_MARGIN: u32 = 1;
+
+/// An entity that changed its region since the last update. `None` stands for not having a
+/// position, or not existing at all.
+pub struct Move {
+    pub entity: EcsEntity,
+    pub from: Option<Vec2<i32>>,
+    pub to: Option<Vec2<i32>>,
+}
+
+/// Tracks which entities are in which region.
+#[derive(Default)]
+pub struct RegionMap {
+    regions: HashMap<Vec2<i32>, HashSet<EcsEntity>>,
+    entity_regions: HashMap<EcsEntity, Vec2<i32>>,
+}
+
+impl RegionMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The region that contains `pos`.
+    pub fn region_key(pos: Vec3<f32>) -> Vec2<i32> {
+        chunk_key(pos).map(|e| e.div_euclid(REGION_SIZE))
+    }
+
+    /// The region that `entity` was in at the last update.
+    pub fn region_of(&self, entity: EcsEntity) -> Option<Vec2<i32>> {
+        self.entity_regions.get(&entity).copied()
+    }
+
+    pub fn entities_in(&self, region: Vec2<i32>) -> impl Iterator<Item = EcsEntity> + '_ {
+        self.regions
+            .get(&region)
+            .into_iter()
+            .flat_map(|entities| entities.iter().copied())
+    }
+
+    /// All entities that have a region.
+    pub fn entities(&self) -> impl Iterator<Item = EcsEntity> + '_ {
+        self.entity_regions.keys().copied()
+    }
+
+    /// Move every entity into the region of its current position. Entities that aren't listed in
+    /// `positions` are removed. Returns the entities that changed their region.
+    pub fn update(&mut self, positions: impl Iterator<Item = (EcsEntity, Vec3<f32>)>) -> Vec<Move> {
+        let mut moves = Vec::new();
+        let mut seen = HashSet::new();
+        for (entity, pos) in positions {
+            seen.insert(entity);
+            let to = Self::region_key(pos);
+            let from = self.entity_regions.insert(entity, to);
+            if from != Some(to) {
+                if let Some(from) = from {
+                    self.remove_from_region(entity, from);
+                }
+                self.regions.entry(to).or_default().insert(entity);
+                moves.push(Move {
+                    entity,
+                    from,
+                    to: Some(to),
+                });
+            }
+        }
+
+        let gone = self
+            .entity_regions
+            .keys()
+            .filter(|entity| !seen.contains(*entity))
+            .copied()
+            .collect::<Vec<_>>();
+        for entity in gone {
+            if let Some(from) = self.entity_regions.remove(&entity) {
+                self.remove_from_region(entity, from);
+                moves.push(Move {
+                    entity,
+                    from: Some(from),
+                    to: None,
+                });
+            }
+        }
+
+        moves
+    }
+
+    fn remove_from_region(&mut self, entity: EcsEntity, region: Vec2<i32>) {
+        if let Some(entities) = self.regions.get_mut(&region) {
+            entities.remove(&entity);
+            if entities.is_empty() {
+                self.regions.remove(&region);
+            }
+        }
+    }
+}
+
+fn chunk_key(pos: Vec3<f32>) -> Vec2<i32> {
+    Vec2::from(pos).map2(TerrainChunkSize::RECT_SIZE, |e: f32, sz| {
+        (e.floor() as i32).div_euclid(sz as i32)
+    })
+}
+
+/// Whether any chunk of `region` is within `vd` of the chunk at `chunk_pos`, measured like the
+/// view distance of terrain.
+fn region_in_range(chunk_pos: Vec2<i32>, region: Vec2<i32>, vd: u32) -> bool {
+    let min = region * REGION_SIZE;
+    let max = min + REGION_SIZE - 1;
+    let dist = (min - chunk_pos).map2(chunk_pos - max, |below, above| {
+        below.max(above).max(0) as u32
+    });
+    dist.map(|e| e.checked_sub(2).unwrap_or(0))
+        .magnitude_squared()
+        <= vd.pow(2)
+}
+
+/// The regions that a client at `pos` with the view distance `vd` has to be subscribed to, given
+/// that it was subscribed to `old` before.
+pub fn subscribed_regions(
+    pos: Vec3<f32>,
+    vd: u32,
+    old: Option<&HashSet<Vec2<i32>>>,
+) -> HashSet<Vec2<i32>> {
+    let chunk_pos = chunk_key(pos);
+    let reach = (vd + UNSUBSCRIBE_MARGIN + 2) as i32;
+    let min = (chunk_pos - reach).map(|e| e.div_euclid(REGION_SIZE));
+    let max = (chunk_pos + reach).map(|e| e.div_euclid(REGION_SIZE));
+
+    let mut regions = HashSet::new();
+    for x in min.x..=max.x {
+        for y in min.y..=max.y {
+            let region = Vec2::new(x, y);
+            let subscribed = old.map_or(false, |old| old.contains(&region));
+            let vd = if subscribed {
+                vd + UNSUBSCRIBE_MARGIN
+            } else {
+                vd
+            };
+            if region_in_range(chunk_pos, region, vd) {
+                regions.insert(region);
+            }
+        }
+    }
+    regions
+}
+
+/// Everything a client needs to know about an entity that it hasn't seen before.
+pub fn entity_packets(ecs: &World, entity: EcsEntity) -> Vec<EcsCompPacket> {
+    let mut packets = Vec::new();
+    macro_rules! add {
+        ($($comp:ty),*) => {
+            $(
+                if let Some(comp) = ecs.read_storage::<$comp>().get(entity) {
+                    packets.push(EcsCompPacket::from(comp.clone()));
+                }
+            )*
+        };
+    }
+    add!(
+        comp::Body,
+        comp::Player,
+        comp::Stats,
+        comp::CanBuild,
+        comp::LightEmitter,
+        comp::Item,
+        comp::Scale,
+        comp::MountState,
+        comp::Mounting,
+        comp::Pos,
+        comp::Vel,
+        comp::Ori
+    );
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use specs::Builder;
+
+    /// The position in the middle of the given chunk.
+    fn chunk_center(x: i32, y: i32) -> Vec3<f32> {
+        Vec3::new(x as f32 * 32.0 + 16.0, y as f32 * 32.0 + 16.0, 0.0)
+    }
+
+    fn moves_of(moves: &[Move]) -> Vec<(EcsEntity, Option<Vec2<i32>>, Option<Vec2<i32>>)> {
+        let mut moves = moves
+            .iter()
+            .map(|m| (m.entity, m.from, m.to))
+            .collect::<Vec<_>>();
+        moves.sort_by_key(|(entity, _, _)| *entity);
+        moves
+    }
+
+    #[test]
+    fn update_reports_moves() {
+        let mut world = World::new();
+        let a = world.create_entity().build();
+        let b = world.create_entity().build();
+        let mut regions = RegionMap::new();
+
+        // Entities that appear are created...
+        let moves =
+            regions.update(vec![(a, chunk_center(0, 0)), (b, chunk_center(5, 0))].into_iter());
+        assert_eq!(
+            moves_of(&moves),
+            vec![
+                (a, None, Some(Vec2::new(0, 0))),
+                (b, None, Some(Vec2::new(1, 0))),
+            ]
+        );
+        assert_eq!(regions.region_of(a), Some(Vec2::new(0, 0)));
+        assert_eq!(
+            regions.entities_in(Vec2::new(1, 0)).collect::<Vec<_>>(),
+            vec![b]
+        );
+
+        // ...staying within a region isn't a move...
+        let moves =
+            regions.update(vec![(a, chunk_center(3, 3)), (b, chunk_center(8, 0))].into_iter());
+        assert_eq!(
+            moves_of(&moves),
+            vec![(b, Some(Vec2::new(1, 0)), Some(Vec2::new(2, 0)))]
+        );
+        assert_eq!(regions.entities_in(Vec2::new(1, 0)).count(), 0);
+        assert_eq!(
+            regions.entities_in(Vec2::new(2, 0)).collect::<Vec<_>>(),
+            vec![b]
+        );
+
+        // ...and those that are missing are deleted.
+        let moves = regions.update(vec![(a, chunk_center(-1, 0))].into_iter());
+        assert_eq!(
+            moves_of(&moves),
+            vec![
+                (a, Some(Vec2::new(0, 0)), Some(Vec2::new(-1, 0))),
+                (b, Some(Vec2::new(2, 0)), None),
+            ]
+        );
+        assert_eq!(regions.region_of(b), None);
+        assert_eq!(regions.entities().collect::<Vec<_>>(), vec![a]);
+    }
+
+    #[test]
+    fn subscriptions_have_hysteresis() {
+        let vd = 5;
+        let region = Vec2::new(1, 0);
+
+        let subscribed = subscribed_regions(chunk_center(-3, 0), vd, None);
+        assert!(subscribed.contains(&region));
+
+        // Out of view, but close enough to stay subscribed.
+        assert!(!subscribed_regions(chunk_center(-4, 0), vd, None).contains(&region));
+        let kept = subscribed_regions(chunk_center(-4, 0), vd, Some(&subscribed));
+        assert!(kept.contains(&region));
+
+        // Too far away even for that.
+        assert!(!subscribed_regions(chunk_center(-5, 0), vd, Some(&kept)).contains(&region));
+    }
+}
@@ -0,0 +1,464 @@
+//! Recording of server sessions and deterministic replays of them.
+//!
+//! Everything random on the server is drawn from generators seeded with the world seed, so a
+//! session only depends on what comes from outside: the `dt` of each tick, console input, messages
+//! from clients, which chunks finished generating, and what was looked up in the account and
+//! character stores. A recording stores these per tick, together with a checksum of the entities
+//! after the tick. A replay feeds them to a new server without touching the network or the
+//! original data files and stops at the first tick whose checksum differs.
+//!
+//! Settings reloads are not recorded, so a replay runs with the settings the recording started
+//! with.
+
+use crate::{
+    cmd,
+    persistence::{self, CharacterData, CharacterStore},
+    Error, Input, Server, ServerSettings,
+};
+use common::{
+    msg::{ClientMsg, ServerError, ServerMsg, PROTOCOL_VERSION},
+    net::{PostBox, PostError, PostOffice},
+    state::State,
+    terrain::Block,
+};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use specs::Entity as EcsEntity;
+use std::{
+    fs::File,
+    io::{prelude::*, BufReader, BufWriter},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+use vek::*;
+
+/// How long a replay waits for the server to accept the connection of a recorded client.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a recording starts with.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    protocol_version: u32,
+    git_hash: String,
+    settings: ServerSettings,
+}
+
+/// Everything that reached the server from outside during one tick, in the order it was used.
+#[derive(Default, Serialize, Deserialize)]
+struct TickRecord {
+    tick: u64,
+    dt: Duration,
+    console: Vec<String>,
+    /// The uids of the clients that completed the handshake.
+    connections: Vec<u64>,
+    /// The messages that each client sent over TCP, by uid. Passwords are left out.
+    messages: Vec<(u64, Vec<ClientMsg>)>,
+    /// Clients that lost their connection.
+    network_errors: Vec<u64>,
+    udp_messages: Vec<(u64, ClientMsg)>,
+    /// The outcome of each login attempt.
+    logins: Vec<Result<(), ServerMsg>>,
+    /// The saved character that was found for each character that was requested, or why it
+    /// couldn't be loaded.
+    characters: Vec<Result<Option<CharacterData>, String>>,
+    /// The chunks that finished generating, with the blocks that players had changed in them.
+    chunks: Vec<(Vec2<i32>, Vec<(Vec3<i32>, Block)>)>,
+    /// The checksum of the state after the tick.
+    checksum: u64,
+}
+
+/// Whether the server records what happens to it, or replays a recording.
+pub(crate) enum Session {
+    Live,
+    Recording(Recorder),
+    Replaying(Playback),
+}
+
+impl Session {
+    pub(crate) fn is_replaying(&self) -> bool {
+        match self {
+            Session::Replaying(_) => true,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn begin_tick(&mut self, dt: Duration, console: &[String]) {
+        if let Session::Recording(recorder) = self {
+            recorder.record.dt = dt;
+            recorder.record.console = console.iter().map(|line| redact_command(line)).collect();
+        }
+    }
+
+    /// Write the record of the tick that just finished.
+    pub(crate) fn end_tick(&mut self, state: &State) -> Result<(), Error> {
+        match self {
+            Session::Recording(recorder) => recorder.write_tick(state.checksum()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn record_connection(&mut self, uid: u64) {
+        if let Session::Recording(recorder) = self {
+            recorder.record.connections.push(uid);
+        }
+    }
+
+    /// The messages that the client with `uid` sent since the last tick, and the error of its
+    /// connection if it failed.
+    pub(crate) fn client_messages(
+        &mut self,
+        uid: u64,
+        postbox: &mut PostBox<ServerMsg, ClientMsg>,
+    ) -> (Vec<ClientMsg>, Option<PostError>) {
+        if let Session::Replaying(playback) = self {
+            let record = &mut playback.record;
+            let msgs = match record.messages.iter().position(|(u, _)| *u == uid) {
+                Some(index) => record.messages.remove(index).1,
+                None => Vec::new(),
+            };
+            let error = if record.network_errors.contains(&uid) {
+                Some(PostError::ChannelFailure)
+            } else {
+                None
+            };
+            return (msgs, error);
+        }
+
+        let msgs = postbox.new_messages().collect::<Vec<_>>();
+        let error = postbox.error();
+        if let Session::Recording(recorder) = self {
+            if !msgs.is_empty() {
+                let redacted = msgs.iter().map(redact).collect();
+                recorder.record.messages.push((uid, redacted));
+            }
+            if error.is_some() {
+                recorder.record.network_errors.push(uid);
+            }
+        }
+        (msgs, error)
+    }
+
+    /// The messages that clients sent over UDP during the recorded tick, if this is a replay.
+    pub(crate) fn replayed_udp_messages(
+        &mut self,
+        state: &State,
+    ) -> Option<Vec<(EcsEntity, ClientMsg)>> {
+        match self {
+            Session::Replaying(playback) => Some(
+                playback
+                    .record
+                    .udp_messages
+                    .drain(..)
+                    .filter_map(|(uid, msg)| Some((state.ecs().entity_from_uid(uid)?, msg)))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn record_udp_messages(&mut self, state: &State, msgs: &[(EcsEntity, ClientMsg)]) {
+        if let Session::Recording(recorder) = self {
+            for (entity, msg) in msgs {
+                if let Some(uid) = state.ecs().uid_from_entity(*entity) {
+                    recorder.record.udp_messages.push((uid.into(), msg.clone()));
+                }
+            }
+        }
+    }
+
+    /// Decide whether a player may log in, using `check` unless this is a replay.
+    pub(crate) fn login(
+        &mut self,
+        check: impl FnOnce() -> Result<(), ServerMsg>,
+    ) -> Result<(), ServerMsg> {
+        match self {
+            Session::Live => check(),
+            Session::Recording(recorder) => {
+                let result = check();
+                recorder.record.logins.push(result.clone());
+                result
+            }
+            Session::Replaying(playback) => {
+                if playback.record.logins.is_empty() {
+                    // The replay has diverged, which the checksum will tell.
+                    Err(ServerMsg::Error(ServerError::InvalidAuth))
+                } else {
+                    playback.record.logins.remove(0)
+                }
+            }
+        }
+    }
+
+    /// Find the saved character of a player, using `load` unless this is a replay.
+    pub(crate) fn character(
+        &mut self,
+        load: impl FnOnce() -> Result<Option<CharacterData>, String>,
+    ) -> Result<Option<CharacterData>, String> {
+        match self {
+            Session::Live => load(),
+            Session::Recording(recorder) => {
+                let character = load();
+                recorder.record.characters.push(character.clone());
+                character
+            }
+            Session::Replaying(playback) => {
+                if playback.record.characters.is_empty() {
+                    Ok(None)
+                } else {
+                    playback.record.characters.remove(0)
+                }
+            }
+        }
+    }
+
+    pub(crate) fn record_chunk(&mut self, key: Vec2<i32>, restored: Vec<(Vec3<i32>, Block)>) {
+        if let Session::Recording(recorder) = self {
+            recorder.record.chunks.push((key, restored));
+        }
+    }
+}
+
+/// Passwords, whether in a `Register` message or a chat command, must not end up in a recording.
+fn redact(msg: &ClientMsg) -> ClientMsg {
+    match msg {
+        ClientMsg::Register { player, .. } => ClientMsg::Register {
+            player: player.clone(),
+            password: String::new(),
+        },
+        ClientMsg::ChatMsg { chat_type, message } => ClientMsg::ChatMsg {
+            chat_type: chat_type.clone(),
+            message: redact_command(message),
+        },
+        msg => msg.clone(),
+    }
+}
+
+/// Redact a chat message or console line if it is a chat command.
+fn redact_command(line: &str) -> String {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with('/') {
+        format!("/{}", cmd::redact(&trimmed[1..]))
+    } else {
+        line.to_owned()
+    }
+}
+
+/// Writes a recording while the server runs.
+pub(crate) struct Recorder {
+    writer: BufWriter<File>,
+    record: TickRecord,
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &Path, settings: &ServerSettings) -> Result<Self, Error> {
+        let file = File::create(path).map_err(|err| {
+            Error::Other(format!("Failed to create recording {:?}: {}", path, err))
+        })?;
+        let mut writer = BufWriter::new(file);
+        let header = Header {
+            protocol_version: PROTOCOL_VERSION,
+            git_hash: common::util::GIT_HASH.to_string(),
+            settings: ServerSettings {
+                // The secret isn't needed to replay logins.
+                auth_token_secret: settings.auth_token_secret.as_ref().map(|_| String::new()),
+                ..settings.clone()
+            },
+        };
+        bincode::serialize_into(&mut writer, &header)
+            .map_err(|err| Error::Other(format!("Failed to write recording: {}", err)))?;
+
+        Ok(Self {
+            writer,
+            record: TickRecord::default(),
+        })
+    }
+
+    fn write_tick(&mut self, checksum: u64) -> Result<(), Error> {
+        let tick = self.record.tick;
+        self.record.checksum = checksum;
+        bincode::serialize_into(&mut self.writer, &self.record)
+            .map_err(|err| Error::Other(format!("Failed to write recording: {}", err)))?;
+        // Keep the recording complete in case the server crashes.
+        self.writer
+            .flush()
+            .map_err(|err| Error::Other(format!("Failed to write recording: {}", err)))?;
+        self.record = TickRecord {
+            tick: tick + 1,
+            ..TickRecord::default()
+        };
+        Ok(())
+    }
+}
+
+/// The inputs of the tick that is being replayed, and the connections that stand in for the
+/// recorded clients.
+pub(crate) struct Playback {
+    addr: SocketAddr,
+    record: TickRecord,
+    sinks: Vec<PostBox<ClientMsg, ServerMsg>>,
+}
+
+impl Playback {
+    /// Open a connection to `postoffice` for each client that connected during this tick.
+    pub(crate) fn connect_recorded(
+        &mut self,
+        postoffice: &mut PostOffice<ServerMsg, ClientMsg>,
+    ) -> Result<Vec<(u64, PostBox<ServerMsg, ClientMsg>)>, Error> {
+        let mut postboxes = Vec::new();
+        for uid in self.record.connections.drain(..) {
+            self.sinks.push(PostBox::to(self.addr)?);
+            let started = Instant::now();
+            let postbox = loop {
+                if let Some(postbox) = postoffice.new_postboxes().next() {
+                    break postbox;
+                }
+                if started.elapsed() > CONNECT_TIMEOUT {
+                    return Err(Error::Other(
+                        "Timed out connecting a recorded client".to_owned(),
+                    ));
+                }
+                thread::sleep(Duration::from_millis(1));
+            };
+            postboxes.push((uid, postbox));
+        }
+        Ok(postboxes)
+    }
+
+    /// The chunks that finished generating during this tick.
+    pub(crate) fn take_chunks(&mut self) -> Vec<(Vec2<i32>, Vec<(Vec3<i32>, Block)>)> {
+        std::mem::replace(&mut self.record.chunks, Vec::new())
+    }
+
+    /// Throw away what the server sent to the recorded clients.
+    fn drain_sinks(&mut self) {
+        for sink in &mut self.sinks {
+            sink.new_messages().for_each(drop);
+        }
+    }
+}
+
+/// A `CharacterStore` for replays, which get the loaded characters from the recording.
+struct DiscardingStore;
+
+impl CharacterStore for DiscardingStore {
+    fn load(&self, _account: &str) -> Result<Option<CharacterData>, persistence::Error> {
+        Ok(None)
+    }
+
+    fn save(&mut self, _account: &str, _data: &CharacterData) -> Result<(), persistence::Error> {
+        Ok(())
+    }
+}
+
+/// How a replay ended.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Every tick led to the recorded state.
+    Finished { ticks: u64 },
+    /// The state after `tick` differed from the recorded one.
+    Diverged {
+        tick: u64,
+        expected: u64,
+        found: u64,
+    },
+}
+
+/// A recorded session, opened to be replayed.
+pub struct Replay {
+    header: Header,
+    reader: BufReader<File>,
+}
+
+impl Replay {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path)
+            .map_err(|err| Error::Other(format!("Failed to open recording {:?}: {}", path, err)))?;
+        let mut reader = BufReader::new(file);
+        let header: Header = bincode::deserialize_from(&mut reader)
+            .map_err(|err| Error::Other(format!("Failed to read recording: {}", err)))?;
+        if header.protocol_version != PROTOCOL_VERSION {
+            return Err(Error::Other(format!(
+                "The recording was made with protocol version {} (expected {})",
+                header.protocol_version, PROTOCOL_VERSION
+            )));
+        }
+        if header.git_hash != common::util::GIT_HASH {
+            warn!(
+                "The recording was made with version {}, the replay may diverge",
+                header.git_hash
+            );
+        }
+
+        Ok(Self { header, reader })
+    }
+
+    /// The settings of the recorded server.
+    pub fn settings(&self) -> &ServerSettings {
+        &self.header.settings
+    }
+
+    /// Replay the recording on a new server, which keeps its accounts, bans and terrain changes
+    /// in `data_dir`. Stops at the first tick that leads to a different state than recorded.
+    pub fn run(mut self, data_dir: PathBuf) -> Result<Outcome, Error> {
+        let settings = ServerSettings {
+            accounts_file: data_dir.join("accounts.ron"),
+            banlist_file: data_dir.join("banlist.ron"),
+            data_dir,
+            udp_enabled: false,
+            metrics_enabled: false,
+            ..self.header.settings.clone()
+        };
+        let mut server = Server::bind(SocketAddr::from(([127, 0, 0, 1], 0)), settings)?
+            .with_character_store(Box::new(DiscardingStore));
+        server.session = Session::Replaying(Playback {
+            addr: server.postoffice.local_addr()?,
+            record: TickRecord::default(),
+            sinks: Vec::new(),
+        });
+
+        let mut ticks = 0;
+        while let Some(record) = self.next_record()? {
+            let (tick, dt, expected) = (record.tick, record.dt, record.checksum);
+            let input = Input {
+                console: record.console.clone(),
+            };
+            if let Session::Replaying(playback) = &mut server.session {
+                playback.record = record;
+            }
+
+            server.tick(input, dt)?;
+            let found = server.state.checksum();
+            server.cleanup();
+            if let Session::Replaying(playback) = &mut server.session {
+                playback.drain_sinks();
+            }
+
+            if found != expected {
+                return Ok(Outcome::Diverged {
+                    tick,
+                    expected,
+                    found,
+                });
+            }
+            ticks += 1;
+        }
+        Ok(Outcome::Finished { ticks })
+    }
+
+    fn next_record(&mut self) -> Result<Option<TickRecord>, Error> {
+        // A recording ends wherever the server stopped.
+        let at_end = self
+            .reader
+            .fill_buf()
+            .map_err(|err| Error::Other(format!("Failed to read recording: {}", err)))?
+            .is_empty();
+        if at_end {
+            return Ok(None);
+        }
+        bincode::deserialize_from(&mut self.reader)
+            .map(Some)
+            .map_err(|err| Error::Other(format!("Failed to read recording: {}", err)))
+    }
+}
@@ -1,5 +1,72 @@
+use crate::util;
+use hashbrown::HashMap;
 use serde_derive::{Deserialize, Serialize};
-use std::{fs, io::prelude::*, net::SocketAddr, path::PathBuf};
+use std::{
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+/// A named set of permissions that players can be assigned.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Role {
+    pub name: String,
+    /// Keywords of the chat commands this role may run. `"*"` allows every command.
+    pub commands: Vec<String>,
+    /// Whether players with this role may use build mode.
+    pub can_build: bool,
+}
+
+impl Role {
+    fn new(name: &str, commands: &[&str], can_build: bool) -> Self {
+        Self {
+            name: name.to_owned(),
+            commands: commands.iter().map(|c| c.to_string()).collect(),
+            can_build,
+        }
+    }
+
+    pub fn allows(&self, keyword: &str) -> bool {
+        self.commands.iter().any(|c| c == "*" || c == keyword)
+    }
+
+    /// Whether this role allows every command that `other` does.
+    pub fn includes(&self, other: &Role) -> bool {
+        other.commands.iter().all(|c| match c.as_str() {
+            "*" => self.commands.iter().any(|c| c == "*"),
+            keyword => self.allows(keyword),
+        })
+    }
+}
+
+/// Commands that every player may use.
+const PLAYER_COMMANDS: &[&str] = &[
+    "alias",
+    "kill",
+    "players",
+    "help",
+    "tell",
+    "lantern",
+    "explosion",
+    "debug_column",
+];
+
+fn default_roles() -> Vec<Role> {
+    vec![
+        Role::new("player", PLAYER_COMMANDS, false),
+        Role::new("builder", &[PLAYER_COMMANDS, &["build"]].concat(), true),
+        Role::new(
+            "moderator",
+            &[
+                PLAYER_COMMANDS,
+                &["tp", "kick", "ban", "unban", "mute", "unmute", "violations"],
+            ]
+            .concat(),
+            false,
+        ),
+        Role::new("admin", &["*"], true),
+    ]
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
@@ -12,7 +79,43 @@ pub struct ServerSettings {
     pub server_description: String,
     //pub login_server: whatever
     pub start_time: f64,
+    /// Players that are given the `admin` role unless `player_roles` says otherwise.
     pub admins: Vec<String>,
+    pub roles: Vec<Role>,
+    /// The role of players that aren't listed in `player_roles` or `admins`.
+    pub default_role: String,
+    /// Maps player aliases to the name of their role.
+    pub player_roles: HashMap<String, String>,
+    /// Directory that player characters and changes to the terrain are saved to.
+    pub data_dir: PathBuf,
+    /// File that accounts and their password hashes are stored in.
+    pub accounts_file: PathBuf,
+    /// Whether logging in with an unknown username creates a new account.
+    pub auto_register: bool,
+    /// If set, clients log in with tokens signed with this secret instead of passwords.
+    pub auth_token_secret: Option<String>,
+    /// File that bans and mutes are stored in.
+    pub banlist_file: PathBuf,
+    /// Whether only players listed in `whitelist` may join.
+    pub whitelist_enabled: bool,
+    pub whitelist: Vec<String>,
+    /// Bytes per second that may be sent to each client. Once a client reaches it, terrain is held
+    /// back so that positions and chat still arrive promptly. `None` means unlimited.
+    pub client_bandwidth_limit: Option<u32>,
+    /// Whether clients may exchange physics updates over UDP, on the same port as `address`.
+    /// Clients that can't reach the server over UDP keep using TCP.
+    pub udp_enabled: bool,
+    /// Whether the positions that clients report for their characters are checked for speed and
+    /// terrain collisions. Impossible moves are corrected and recorded for `/violations`.
+    pub movement_validation: bool,
+    /// Whether Prometheus metrics are served at `http://<metrics_address>/metrics`.
+    pub metrics_enabled: bool,
+    pub metrics_address: SocketAddr,
+    /// The file these settings were loaded from, where changes made with commands such as
+    /// `/role` are saved. Settings that didn't come from a file, like those of singleplayer, are
+    /// never saved.
+    #[serde(skip)]
+    pub file: Option<PathBuf>,
 }
 
 impl Default for ServerSettings {
@@ -25,6 +128,22 @@ impl Default for ServerSettings {
             max_players: 100,
             start_time: 9.0 * 3600.0,
             admins: vec!["Pfau".to_owned()],
+            roles: default_roles(),
+            default_role: "player".to_owned(),
+            player_roles: HashMap::new(),
+            data_dir: PathBuf::from("saves"),
+            accounts_file: PathBuf::from("accounts.ron"),
+            auto_register: true,
+            auth_token_secret: None,
+            banlist_file: PathBuf::from("banlist.ron"),
+            whitelist_enabled: false,
+            whitelist: Vec::new(),
+            client_bandwidth_limit: Some(1 << 20),
+            udp_enabled: true,
+            movement_validation: true,
+            metrics_enabled: true,
+            metrics_address: SocketAddr::from(([0; 4], 14005)),
+            file: None,
         }
     }
 }
@@ -33,16 +152,30 @@ impl ServerSettings {
     pub fn load() -> Self {
         let path = ServerSettings::get_settings_path();
 
-        if let Ok(file) = fs::File::open(path) {
-            match ron::de::from_reader(file) {
-                Ok(x) => x,
+        if let Ok(file) = fs::File::open(&path) {
+            match ron::de::from_reader::<_, Self>(file) {
+                Ok(settings) => match settings.validate() {
+                    Ok(()) => Self {
+                        file: Some(path),
+                        ..settings
+                    },
+                    // Don't overwrite the broken file when the settings are changed.
+                    Err(e) => {
+                        log::warn!("Invalid setting file! Fallback to default. {}", e);
+                        Self::default()
+                    }
+                },
                 Err(e) => {
+                    // Don't overwrite the broken file when the settings are changed.
                     log::warn!("Failed to parse setting file! Fallback to default. {}", e);
                     Self::default()
                 }
             }
         } else {
-            let default_settings = Self::default();
+            let default_settings = Self {
+                file: Some(path),
+                ..Self::default()
+            };
 
             match default_settings.save_to_file() {
                 Err(e) => log::error!("Failed to create default setting file! {}", e),
@@ -52,15 +185,107 @@ impl ServerSettings {
         }
     }
 
-    pub fn save_to_file(&self) -> std::io::Result<()> {
-        let path = ServerSettings::get_settings_path();
-        let mut config_file = fs::File::create(path)?;
+    /// Read the settings from `path`, failing instead of falling back to the defaults if the
+    /// file is missing or invalid.
+    pub fn load_from_file(path: &Path) -> Result<Self, String> {
+        let file = fs::File::open(path).map_err(|e| format!("Failed to open {:?}: {}", path, e))?;
+        let settings: Self =
+            ron::de::from_reader(file).map_err(|e| format!("Failed to parse {:?}: {}", path, e))?;
+        settings.validate()?;
+        Ok(Self {
+            file: Some(path.to_owned()),
+            ..settings
+        })
+    }
 
-        let s: &str = &ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).unwrap();
-        config_file.write_all(s.as_bytes()).unwrap();
+    /// Check for mistakes that the file format can't catch.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_players == 0 {
+            return Err(String::from("'max_players' must be at least 1"));
+        }
+        if self.role(&self.default_role).is_none() {
+            return Err(format!(
+                "'default_role' is '{}', which is not one of the roles",
+                self.default_role
+            ));
+        }
+        if let Some((alias, role)) = self
+            .player_roles
+            .iter()
+            .find(|(_, role)| self.role(role).is_none())
+        {
+            return Err(format!("'{}' has the unknown role '{}'", alias, role));
+        }
+        if self.client_bandwidth_limit == Some(0) {
+            return Err(String::from("'client_bandwidth_limit' must be at least 1"));
+        }
         Ok(())
     }
 
+    /// Take over the fields of `new` that can safely change while the server is running and
+    /// return the names of those that did. Changes to other fields are logged and ignored until
+    /// the next restart.
+    pub fn update(&mut self, new: ServerSettings) -> Vec<&'static str> {
+        macro_rules! needs_restart {
+            ($($field:ident),*) => {$(
+                if self.$field != new.$field {
+                    log::warn!(
+                        "Changing '{}' requires a restart of the server",
+                        stringify!($field)
+                    );
+                }
+            )*};
+        }
+        needs_restart!(
+            address,
+            world_seed,
+            start_time,
+            data_dir,
+            accounts_file,
+            auto_register,
+            auth_token_secret,
+            banlist_file,
+            udp_enabled,
+            metrics_enabled,
+            metrics_address
+        );
+
+        let mut changed = Vec::new();
+        macro_rules! update {
+            ($($field:ident),*) => {$(
+                if self.$field != new.$field {
+                    self.$field = new.$field;
+                    changed.push(stringify!($field));
+                }
+            )*};
+        }
+        update!(
+            server_name,
+            server_description,
+            max_players,
+            admins,
+            roles,
+            default_role,
+            player_roles,
+            whitelist_enabled,
+            whitelist,
+            client_bandwidth_limit,
+            movement_validation
+        );
+        changed
+    }
+
+    /// Write the settings back to the file they were loaded from, if any.
+    pub fn save_to_file(&self) -> std::io::Result<()> {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let s = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        util::write_atomically(path, &s)
+    }
+
     pub fn singleplayer() -> Self {
         Self {
             address: SocketAddr::from(([0; 4], 14004)),
@@ -70,10 +295,144 @@ impl ServerSettings {
             max_players: 100,
             start_time: 9.0 * 3600.0,
             admins: vec!["singleplayer".to_string()], // TODO: Let the player choose if they want to use admin commands or not
+            roles: default_roles(),
+            default_role: "player".to_owned(),
+            player_roles: HashMap::new(),
+            data_dir: PathBuf::from("saves"),
+            accounts_file: PathBuf::from("accounts.ron"),
+            auto_register: true,
+            auth_token_secret: None,
+            banlist_file: PathBuf::from("banlist.ron"),
+            whitelist_enabled: false,
+            whitelist: Vec::new(),
+            client_bandwidth_limit: None,
+            udp_enabled: false,
+            movement_validation: false,
+            metrics_enabled: false,
+            metrics_address: SocketAddr::from(([127, 0, 0, 1], 14005)),
+            file: None,
         }
     }
 
-    fn get_settings_path() -> PathBuf {
+    /// The name of the role assigned to `alias`.
+    pub fn role_of(&self, alias: &str) -> &str {
+        match self.player_roles.get(alias) {
+            Some(role) => role.as_str(),
+            None if self.admins.iter().any(|admin| admin == alias) => "admin",
+            None => self.default_role.as_str(),
+        }
+    }
+
+    /// Whether `alias` may join the server.
+    pub fn is_whitelisted(&self, alias: &str) -> bool {
+        !self.whitelist_enabled || self.whitelist.iter().any(|entry| entry == alias)
+    }
+
+    /// Look up a role by name.
+    pub fn role(&self, name: &str) -> Option<&Role> {
+        self.roles.iter().find(|role| role.name == name)
+    }
+
+    pub fn get_settings_path() -> PathBuf {
         PathBuf::from(r"settings.ron")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn settings_are_saved_where_they_came_from() {
+        let dir = env::temp_dir().join(format!("veloren-settings-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.ron");
+
+        // Singleplayer settings have nowhere to be saved.
+        let singleplayer = ServerSettings::singleplayer();
+        assert_eq!(singleplayer.file, None);
+        singleplayer.save_to_file().unwrap();
+
+        let settings = ServerSettings {
+            file: Some(path.clone()),
+            max_players: 7,
+            ..ServerSettings::default()
+        };
+        settings.save_to_file().unwrap();
+        let loaded = ServerSettings::load_from_file(&path).unwrap();
+        assert_eq!(loaded.max_players, 7);
+        assert_eq!(loaded.file, Some(path));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn validate_catches_mistakes() {
+        assert_eq!(ServerSettings::default().validate(), Ok(()));
+        assert_eq!(ServerSettings::singleplayer().validate(), Ok(()));
+
+        let mut player_roles = HashMap::new();
+        player_roles.insert("alice".to_owned(), "nonexistent".to_owned());
+        let invalid = vec![
+            ServerSettings {
+                max_players: 0,
+                ..ServerSettings::default()
+            },
+            ServerSettings {
+                default_role: "nonexistent".to_owned(),
+                ..ServerSettings::default()
+            },
+            ServerSettings {
+                player_roles,
+                ..ServerSettings::default()
+            },
+            ServerSettings {
+                client_bandwidth_limit: Some(0),
+                ..ServerSettings::default()
+            },
+        ];
+        for settings in invalid {
+            assert!(settings.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn update_only_applies_live_fields() {
+        let mut settings = ServerSettings::default();
+        let new = ServerSettings {
+            server_name: "Renamed".to_owned(),
+            max_players: settings.max_players + 1,
+            world_seed: settings.world_seed + 1,
+            data_dir: PathBuf::from("elsewhere"),
+            ..ServerSettings::default()
+        };
+
+        let mut changed = settings.update(new);
+        changed.sort();
+        assert_eq!(changed, vec!["max_players", "server_name"]);
+        assert_eq!(settings.server_name, "Renamed");
+        assert_eq!(
+            settings.max_players,
+            ServerSettings::default().max_players + 1
+        );
+        // These only take effect after a restart.
+        assert_eq!(settings.world_seed, ServerSettings::default().world_seed);
+        assert_eq!(settings.data_dir, ServerSettings::default().data_dir);
+
+        assert!(settings.update(settings.clone()).is_empty());
+    }
+
+    #[test]
+    fn roles_include_the_commands_of_lesser_roles() {
+        let roles = default_roles();
+        let role = |name| roles.iter().find(|role| role.name == name).unwrap();
+
+        assert!(role("admin").includes(role("moderator")));
+        assert!(role("moderator").includes(role("player")));
+        assert!(role("moderator").includes(role("moderator")));
+        assert!(!role("moderator").includes(role("admin")));
+        assert!(!role("moderator").includes(role("builder")));
+        assert!(!role("player").includes(role("moderator")));
+    }
+}
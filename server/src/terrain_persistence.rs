@@ -0,0 +1,284 @@
+//! Storage of player-made changes to the terrain.
+//!
+//! Chunks are regenerated from the world seed every time they are loaded, so only the blocks that
+//! were changed afterwards are stored, in one RON file per chunk.
+
+use crate::util;
+use common::{
+    terrain::{Block, TerrainChunk, TerrainGrid},
+    vol::WriteVol,
+};
+use hashbrown::{HashMap, HashSet};
+use log::{error, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::{fs, io, path::PathBuf};
+use vek::*;
+
+/// The blocks of a chunk that differ from the generated terrain, keyed by their position within
+/// the chunk.
+#[derive(Default)]
+struct ChunkDiff {
+    blocks: HashMap<Vec3<i32>, Block>,
+}
+
+/// The on-disk representation of a `ChunkDiff`.
+#[derive(Default, Serialize, Deserialize)]
+struct ChunkDiffFile {
+    blocks: Vec<(Vec3<i32>, Block)>,
+}
+
+pub struct TerrainPersistence {
+    dir: PathBuf,
+    /// Diffs of the chunks that are currently loaded or have been changed.
+    chunks: HashMap<Vec2<i32>, ChunkDiff>,
+    /// Chunks with changes that haven't been written to disk yet.
+    dirty: HashSet<Vec2<i32>>,
+    /// Loaded chunks whose file couldn't be read. Their changes aren't recorded, so that the file
+    /// isn't overwritten before someone had a chance to look at it.
+    unreadable: HashSet<Vec2<i32>>,
+}
+
+impl TerrainPersistence {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            chunks: HashMap::new(),
+            dirty: HashSet::new(),
+            unreadable: HashSet::new(),
+        }
+    }
+
+    /// Re-apply the stored changes to a freshly generated chunk. Returns the blocks that were
+    /// restored, keyed by their position within the chunk.
+    pub fn apply(&mut self, key: Vec2<i32>, chunk: &mut TerrainChunk) -> Vec<(Vec3<i32>, Block)> {
+        let blocks = self
+            .load(key)
+            .map(|diff| {
+                diff.blocks
+                    .iter()
+                    .map(|(offs, block)| (*offs, *block))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        Self::apply_blocks(key, chunk, &blocks);
+        blocks
+    }
+
+    /// Set blocks of a chunk, keyed by their position within the chunk.
+    pub fn apply_blocks(key: Vec2<i32>, chunk: &mut TerrainChunk, blocks: &[(Vec3<i32>, Block)]) {
+        for (offs, block) in blocks {
+            if let Err(err) = chunk.set(*offs, *block) {
+                warn!(
+                    "Failed to restore block {:?} of chunk {:?}: {:?}",
+                    offs, key, err
+                );
+            }
+        }
+    }
+
+    /// Remember that the block at `pos` was changed to `block`.
+    pub fn record(&mut self, pos: Vec3<i32>, block: Block) {
+        let key = TerrainGrid::chunk_key(pos);
+        if let Some(diff) = self.load(key) {
+            diff.blocks.insert(TerrainGrid::chunk_offs(pos), block);
+            self.dirty.insert(key);
+        }
+    }
+
+    /// Write the changes of a chunk that is no longer loaded to disk and forget about them. If
+    /// they can't be written, they are kept for `save_all` to try again.
+    pub fn unload(&mut self, key: Vec2<i32>) {
+        self.save(key);
+        if !self.dirty.contains(&key) {
+            self.chunks.remove(&key);
+        }
+        self.unreadable.remove(&key);
+    }
+
+    /// Write all unsaved changes to disk.
+    pub fn save_all(&mut self) {
+        let dirty = self.dirty.iter().copied().collect::<Vec<_>>();
+        for key in dirty {
+            self.save(key);
+        }
+    }
+
+    fn path_for(&self, key: Vec2<i32>) -> PathBuf {
+        self.dir.join(format!("{}_{}.ron", key.x, key.y))
+    }
+
+    /// The diff of the chunk at `key`, reading it from disk if it isn't in memory yet. Returns
+    /// `None` if the file exists but can't be read.
+    fn load(&mut self, key: Vec2<i32>) -> Option<&mut ChunkDiff> {
+        if self.unreadable.contains(&key) {
+            return None;
+        }
+        if !self.chunks.contains_key(&key) {
+            let path = self.path_for(key);
+            let diff = match fs::File::open(&path) {
+                Ok(file) => match ron::de::from_reader::<_, ChunkDiffFile>(file) {
+                    Ok(diff) => ChunkDiff {
+                        blocks: diff.blocks.into_iter().collect(),
+                    },
+                    Err(err) => {
+                        error!("Failed to parse terrain changes {:?}: {}", path, err);
+                        self.unreadable.insert(key);
+                        return None;
+                    }
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => ChunkDiff::default(),
+                Err(err) => {
+                    error!("Failed to open terrain changes {:?}: {}", path, err);
+                    self.unreadable.insert(key);
+                    return None;
+                }
+            };
+            self.chunks.insert(key, diff);
+        }
+        self.chunks.get_mut(&key)
+    }
+
+    fn save(&mut self, key: Vec2<i32>) {
+        if !self.dirty.contains(&key) {
+            return;
+        }
+        let diff = match self.chunks.get(&key) {
+            Some(diff) => ChunkDiffFile {
+                blocks: diff
+                    .blocks
+                    .iter()
+                    .map(|(pos, block)| (*pos, *block))
+                    .collect(),
+            },
+            None => return,
+        };
+
+        let path = self.path_for(key);
+        let result = fs::create_dir_all(&self.dir)
+            .map_err(|e| e.to_string())
+            .and_then(|_| {
+                ron::ser::to_string_pretty(&diff, ron::ser::PrettyConfig::default())
+                    .map_err(|e| e.to_string())
+            })
+            .and_then(|s| util::write_atomically(&path, &s).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => {
+                self.dirty.remove(&key);
+            }
+            Err(err) => error!("Failed to save terrain changes {:?}: {}", path, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{
+        terrain::{BlockKind, TerrainChunkMeta},
+        vol::{ReadVol, Vox},
+    };
+    use std::env;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "veloren-terrain-persistence-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_file(&dir);
+        dir
+    }
+
+    fn empty_chunk() -> TerrainChunk {
+        TerrainChunk::new(0, Block::empty(), Block::empty(), TerrainChunkMeta::void())
+    }
+
+    fn stone() -> Block {
+        Block::new(BlockKind::Normal, Rgb::broadcast(128))
+    }
+
+    #[test]
+    fn changes_survive_unloading() {
+        let dir = test_dir("unload");
+        let mut persistence = TerrainPersistence::new(dir.clone());
+        let pos = Vec3::new(40, -5, 10);
+        let key = TerrainGrid::chunk_key(pos);
+        let offs = TerrainGrid::chunk_offs(pos);
+        persistence.record(pos, stone());
+        persistence.unload(key);
+        assert!(persistence.chunks.is_empty());
+
+        // They are read back after a restart...
+        let mut chunk = empty_chunk();
+        let mut persistence = TerrainPersistence::new(dir.clone());
+        assert_eq!(persistence.apply(key, &mut chunk), vec![(offs, stone())]);
+        assert_eq!(chunk.get(offs).ok(), Some(&stone()));
+
+        // ...and for chunks that have never been changed.
+        let mut chunk = empty_chunk();
+        assert_eq!(persistence.apply(key + Vec2::unit_x(), &mut chunk), vec![]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn only_dirty_chunks_are_saved() {
+        let dir = test_dir("dirty");
+        let mut persistence = TerrainPersistence::new(dir.clone());
+        let a = Vec3::new(0, 0, 0);
+        let b = Vec3::new(100, 0, 0);
+        persistence.record(a, stone());
+        persistence.record(b, stone());
+        persistence.save_all();
+        let path_a = persistence.path_for(TerrainGrid::chunk_key(a));
+        let path_b = persistence.path_for(TerrainGrid::chunk_key(b));
+        assert!(path_a.exists() && path_b.exists());
+
+        fs::remove_file(&path_a).unwrap();
+        fs::remove_file(&path_b).unwrap();
+        persistence.record(b + Vec3::unit_z(), stone());
+        persistence.save_all();
+        assert!(!path_a.exists());
+        assert!(path_b.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unreadable_files_are_left_alone() {
+        let dir = test_dir("unreadable");
+        fs::create_dir_all(&dir).unwrap();
+        let mut persistence = TerrainPersistence::new(dir.clone());
+        let pos = Vec3::new(0, 0, 0);
+        let key = TerrainGrid::chunk_key(pos);
+        let path = persistence.path_for(key);
+        fs::write(&path, "not terrain changes").unwrap();
+
+        let mut chunk = empty_chunk();
+        assert_eq!(persistence.apply(key, &mut chunk), vec![]);
+        persistence.record(pos, stone());
+        persistence.save_all();
+        persistence.unload(key);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not terrain changes");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_saves_are_retried() {
+        let dir = test_dir("retry");
+        let mut persistence = TerrainPersistence::new(dir.clone());
+        let pos = Vec3::new(0, 0, 0);
+        let key = TerrainGrid::chunk_key(pos);
+        persistence.record(pos, stone());
+
+        // A file in the way of the directory makes saving fail.
+        fs::write(&dir, "").unwrap();
+        persistence.unload(key);
+        assert!(persistence.dirty.contains(&key));
+
+        fs::remove_file(&dir).unwrap();
+        persistence.save_all();
+        assert!(persistence.dirty.is_empty());
+        assert!(persistence.path_for(key).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
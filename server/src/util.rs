@@ -0,0 +1,17 @@
+//! Helpers shared by the parts of the server that keep their data in files.
+
+use std::{
+    fs,
+    io::{self, prelude::*},
+    path::Path,
+};
+
+/// Replace the file at `path` with `contents`. They are written to a temporary file next to it
+/// first, so that a crash can't leave a truncated or half-written file behind.
+pub fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::File::create(&tmp_path)?.write_all(contents.as_bytes())?;
+    fs::rename(tmp_path, path)
+}
@@ -0,0 +1,518 @@
+//! Checks of the physics updates that clients send for their characters. Clients simulate their
+//! own movement, so the server only makes sure that what they report is possible.
+
+use crate::moderation;
+use common::{
+    comp,
+    state::{State, HUMANOID_JUMP_ACCEL},
+    sys::{
+        movement::{
+            BLOCK_SPEED, CLIMB_SPEED, GLIDE_SPEED, HUMANOID_AIR_SPEED, HUMANOID_SPEED,
+            HUMANOID_WATER_SPEED, ROLL_SPEED,
+        },
+        phys::MAX_FALL_SPEED,
+    },
+    terrain::TerrainGrid,
+    vol::ReadVol,
+};
+use hashbrown::HashMap;
+use specs::Entity as EcsEntity;
+use std::{collections::VecDeque, fmt};
+use vek::*;
+
+/// Characters step up onto blocks without any vertical velocity.
+const STEP_HEIGHT: f32 = 1.0;
+/// How many seconds of movement a character can save up, by standing still or while its updates
+/// are delayed. Any more, and a client could pause sending to save up for a teleport.
+const MAX_BUDGET: f64 = 1.0;
+/// A client keeps sending updates from where it thinks it is until a correction arrives, so it is
+/// corrected at most this often, in seconds.
+const CORRECTION_INTERVAL: f64 = 0.5;
+/// After the server moved a character, the client may still send a few updates from before it
+/// knew. Those are corrected, but not recorded as violations.
+const FORCE_UPDATE_GRACE: f64 = 2.0;
+/// The height above a character's position that is checked for terrain.
+const BODY_CENTER: f32 = 0.75;
+/// How many violations are kept per player.
+const MAX_VIOLATIONS: usize = 64;
+
+/// Characters stop accelerating once they reach the speed of their current movement, so none of
+/// them can be faster than the fastest one.
+fn max_horizontal_speed() -> f32 {
+    [
+        HUMANOID_SPEED,
+        HUMANOID_AIR_SPEED,
+        HUMANOID_WATER_SPEED,
+        ROLL_SPEED,
+        GLIDE_SPEED,
+        BLOCK_SPEED,
+    ]
+    .iter()
+    .fold(0.0, |a, b| a.max(*b))
+}
+
+fn max_rise_speed() -> f32 {
+    HUMANOID_JUMP_ACCEL.max(CLIMB_SPEED)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ViolationKind {
+    /// The update contained infinite or NaN values.
+    InvalidValue,
+    /// The character moved at `speed` blocks per second, although only `max` were possible.
+    TooFast { speed: f32, max: f32 },
+    /// The character moved into or through terrain.
+    IntoTerrain,
+    /// The character moved into terrain that isn't loaded, where it couldn't have moved.
+    IntoUnloadedTerrain,
+}
+
+impl fmt::Display for ViolationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ViolationKind::InvalidValue => write!(f, "sent an invalid position"),
+            ViolationKind::TooFast { speed, max } => {
+                write!(f, "moved at {:.1} blocks/s (max {:.1})", speed, max)
+            }
+            ViolationKind::IntoTerrain => write!(f, "moved into terrain"),
+            ViolationKind::IntoUnloadedTerrain => write!(f, "moved into unloaded terrain"),
+        }
+    }
+}
+
+/// An impossible physics update sent by a player.
+#[derive(Clone, Debug)]
+pub struct Violation {
+    /// When the update arrived, in seconds since the UNIX epoch.
+    pub time: u64,
+    pub kind: ViolationKind,
+    /// Where the character was according to the server.
+    pub pos: Vec3<f32>,
+}
+
+/// Validates physics updates and remembers the violations of each player.
+#[derive(Default)]
+pub struct MovementValidator {
+    /// How many seconds of movement at top speed each character has left, and when that was last
+    /// topped up, in seconds of game time. Every move uses up some of it, however many updates it
+    /// is split into.
+    budgets: HashMap<EcsEntity, (f64, f64)>,
+    /// When each character was last moved by the server, other than to correct it.
+    last_forced: HashMap<EcsEntity, f64>,
+    /// When each character was last corrected.
+    last_corrected: HashMap<EcsEntity, f64>,
+    /// The most recent violations, by account. Aliases can be changed, accounts can't.
+    violations: HashMap<String, VecDeque<Violation>>,
+}
+
+impl MovementValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note that the server has moved `entity` itself, e.g. by teleporting it or correcting it.
+    pub fn forced(&mut self, entity: EcsEntity, time: f64) {
+        // Corrections don't excuse what the client sends next.
+        if self.last_corrected.get(&entity) != Some(&time) {
+            self.last_forced.insert(entity, time);
+        }
+    }
+
+    pub fn forget(&mut self, entity: EcsEntity) {
+        self.budgets.remove(&entity);
+        self.last_forced.remove(&entity);
+        self.last_corrected.remove(&entity);
+    }
+
+    /// The recorded violations of `account`, oldest first.
+    pub fn violations_of(&self, account: &str) -> impl Iterator<Item = &Violation> {
+        self.violations.get(account).into_iter().flatten()
+    }
+
+    /// All accounts with recorded violations and how many they have.
+    pub fn offenders(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.violations
+            .iter()
+            .map(|(account, violations)| (account.as_str(), violations.len()))
+    }
+
+    /// Apply a physics update that the client controlling `entity` sent for its character.
+    /// Impossible moves are clamped or rejected, in which case the client is sent a correction,
+    /// and recorded as violations of the client's `account`.
+    pub fn apply(
+        &mut self,
+        state: &mut State,
+        entity: EcsEntity,
+        account: Option<&str>,
+        pos: comp::Pos,
+        vel: comp::Vel,
+        ori: comp::Ori,
+    ) {
+        let time = state.get_time();
+        let old_pos = match state.read_component_cloned::<comp::Pos>(entity) {
+            Some(old_pos) => old_pos,
+            None => {
+                state.write_component(entity, pos);
+                state.write_component(entity, vel);
+                state.write_component(entity, ori);
+                return;
+            }
+        };
+        let budget = self.budgets.entry(entity).or_insert((MAX_BUDGET, time));
+        *budget = ((budget.0 + (time - budget.1)).min(MAX_BUDGET), time);
+
+        let result = check_move(
+            &state.terrain(),
+            old_pos.0,
+            pos.0,
+            vel.0,
+            ori.0,
+            budget.0.max(0.0) as f32,
+        );
+        match result {
+            Ok(()) => {
+                state.write_component(entity, pos);
+                state.write_component(entity, vel);
+                state.write_component(entity, ori);
+                budget.0 -= travel_time(pos.0 - old_pos.0);
+            }
+            Err((kind, correction)) => {
+                if let Some(Correction { pos, vel }) = correction {
+                    state.write_component(entity, comp::Pos(pos));
+                    state.write_component(entity, comp::Vel(vel));
+                    budget.0 -= travel_time(pos - old_pos.0);
+                }
+                if ori.0.map(f32::is_finite).reduce_and() {
+                    state.write_component(entity, ori);
+                }
+                let corrected_recently = self
+                    .last_corrected
+                    .get(&entity)
+                    .map_or(false, |corrected| time - corrected < CORRECTION_INTERVAL);
+                if !corrected_recently {
+                    state.write_component(entity, comp::ForceUpdate);
+                    self.last_corrected.insert(entity, time);
+                }
+
+                let in_grace = self
+                    .last_forced
+                    .get(&entity)
+                    .map_or(false, |forced| time - forced < FORCE_UPDATE_GRACE);
+                if let (false, Some(account)) = (in_grace, account) {
+                    log::debug!("Account '{}' {}", account, kind);
+                    let violations = self.violations.entry(account.to_owned()).or_default();
+                    if violations.len() >= MAX_VIOLATIONS {
+                        violations.pop_front();
+                    }
+                    violations.push_back(Violation {
+                        time: moderation::now(),
+                        kind,
+                        pos: old_pos.0,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Where a character is moved instead of the position that its client sent.
+struct Correction {
+    pos: Vec3<f32>,
+    vel: Vec3<f32>,
+}
+
+/// How long a move by `delta` takes at least, in seconds.
+fn travel_time(delta: Vec3<f32>) -> f64 {
+    let horizontal = Vec2::from(delta).magnitude() / max_horizontal_speed();
+    let rise = delta.z.max(0.0) / max_rise_speed();
+    let fall = (-delta.z).max(0.0) / MAX_FALL_SPEED;
+    horizontal.max(rise).max(fall) as f64
+}
+
+/// Check a move from `old_pos` to `pos` that may take up to `elapsed` seconds. When it is
+/// impossible, it is clamped to a possible one if that can be done without entering terrain.
+fn check_move(
+    terrain: &TerrainGrid,
+    old_pos: Vec3<f32>,
+    pos: Vec3<f32>,
+    vel: Vec3<f32>,
+    ori: Vec3<f32>,
+    elapsed: f32,
+) -> Result<(), (ViolationKind, Option<Correction>)> {
+    if !(pos.map(f32::is_finite).reduce_and()
+        && vel.map(f32::is_finite).reduce_and()
+        && ori.map(f32::is_finite).reduce_and())
+    {
+        return Err((ViolationKind::InvalidValue, None));
+    }
+
+    let delta = pos - old_pos;
+    let horizontal = Vec2::from(delta).magnitude();
+    let max_horizontal = max_horizontal_speed() * elapsed;
+    // Stepping up takes time like any other move.
+    let step_height = if elapsed > 0.0 { STEP_HEIGHT } else { 0.0 };
+    let max_rise = max_rise_speed() * elapsed + step_height;
+    let max_fall = MAX_FALL_SPEED * elapsed;
+    if horizontal > max_horizontal || delta.z > max_rise || -delta.z > max_fall {
+        // Report whichever limit was exceeded the most. Without any time left, that is infinitely.
+        let (speed, max) = [
+            (horizontal, max_horizontal, max_horizontal_speed()),
+            (delta.z, max_rise, max_rise_speed()),
+            (-delta.z, max_fall, MAX_FALL_SPEED),
+        ]
+        .iter()
+        .filter(|(moved, allowed, _)| moved > allowed)
+        .max_by(|(a, max_a, _), (b, max_b, _)| (a / max_a).partial_cmp(&(b / max_b)).unwrap())
+        .map(|(moved, _, max_speed)| (moved / elapsed, *max_speed))
+        .unwrap();
+        let kind = ViolationKind::TooFast { speed, max };
+
+        let scale = if horizontal > max_horizontal {
+            max_horizontal / horizontal
+        } else {
+            1.0
+        };
+        let clamped = Vec3::new(
+            delta.x * scale,
+            delta.y * scale,
+            delta.z.max(-max_fall).min(max_rise),
+        );
+        let correction = if terrain_on_path(terrain, old_pos, old_pos + clamped).is_some() {
+            None
+        } else {
+            Some(Correction {
+                pos: old_pos + clamped,
+                vel: clamp_vel(vel),
+            })
+        };
+        return Err((kind, correction));
+    }
+
+    if let Some(kind) = terrain_on_path(terrain, old_pos, pos) {
+        return Err((kind, None));
+    }
+
+    if clamp_vel(vel) != vel {
+        let kind = ViolationKind::TooFast {
+            speed: Vec2::from(vel).magnitude().max(vel.z.abs()),
+            max: max_horizontal_speed(),
+        };
+        return Err((
+            kind,
+            Some(Correction {
+                pos,
+                vel: clamp_vel(vel),
+            }),
+        ));
+    }
+
+    Ok(())
+}
+
+fn clamp_vel(vel: Vec3<f32>) -> Vec3<f32> {
+    let horizontal = Vec2::from(vel);
+    let horizontal = if horizontal.magnitude() > max_horizontal_speed() {
+        horizontal.normalized() * max_horizontal_speed()
+    } else {
+        horizontal
+    };
+    Vec3::new(
+        horizontal.x,
+        horizontal.y,
+        vel.z.max(-MAX_FALL_SPEED).min(max_rise_speed()),
+    )
+}
+
+/// Whether a character's body would hit solid or unloaded terrain on the way from `from` to
+/// `to`. Clients don't move through terrain that isn't loaded either.
+fn terrain_on_path(terrain: &TerrainGrid, from: Vec3<f32>, to: Vec3<f32>) -> Option<ViolationKind> {
+    let steps = (from.distance(to) * 2.0).ceil().max(1.0) as usize;
+    (1..=steps).find_map(|i| {
+        let pos = Lerp::lerp(from, to, i as f32 / steps as f32) + Vec3::unit_z() * BODY_CENTER;
+        match terrain.get(pos.map(|e| e.floor() as i32)) {
+            Ok(block) if block.is_solid() => Some(ViolationKind::IntoTerrain),
+            Ok(_) => None,
+            Err(_) => Some(ViolationKind::IntoUnloadedTerrain),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{
+        state::Time,
+        terrain::{Block, BlockKind, TerrainChunk, TerrainChunkMeta},
+        vol::Vox,
+    };
+    use specs::Builder;
+
+    /// How far the test terrain extends in each direction, in chunks.
+    const LOADED: i32 = 8;
+
+    /// A flat world with a wall that fills the chunk at (1, 0), and the character of a cheating
+    /// player standing in the middle of the chunk at (0, 0).
+    fn setup() -> (State, EcsEntity) {
+        let mut state = State::default();
+        let stone = Block::new(BlockKind::Normal, Rgb::broadcast(128));
+        for x in -LOADED..LOADED {
+            for y in -LOADED..LOADED {
+                let z_offset = if (x, y) == (1, 0) { 100 } else { 0 };
+                let chunk =
+                    TerrainChunk::new(z_offset, stone, Block::empty(), TerrainChunkMeta::void());
+                state.insert_chunk(Vec2::new(x, y), chunk);
+            }
+        }
+        let entity = state
+            .ecs_mut()
+            .create_entity()
+            .with(comp::Pos(Vec3::new(16.0, 16.0, 0.0)))
+            .build();
+        (state, entity)
+    }
+
+    fn set_time(state: &mut State, time: f64) {
+        state.ecs_mut().write_resource::<Time>().0 = time;
+    }
+
+    fn pos_of(state: &State, entity: EcsEntity) -> Vec3<f32> {
+        state.read_component_cloned::<comp::Pos>(entity).unwrap().0
+    }
+
+    /// Send the client's position to `pos`, and pass on a correction like the server would at the
+    /// end of the tick. Returns whether the client was corrected.
+    fn send(
+        validator: &mut MovementValidator,
+        state: &mut State,
+        entity: EcsEntity,
+        pos: Vec3<f32>,
+    ) -> bool {
+        validator.apply(
+            state,
+            entity,
+            Some("cheater"),
+            comp::Pos(pos),
+            comp::Vel(Vec3::zero()),
+            comp::Ori(Vec3::unit_y()),
+        );
+        let corrected = state
+            .ecs_mut()
+            .write_storage::<comp::ForceUpdate>()
+            .remove(entity)
+            .is_some();
+        if corrected {
+            validator.forced(entity, state.get_time());
+        }
+        corrected
+    }
+
+    fn violations(validator: &MovementValidator) -> Vec<ViolationKind> {
+        validator
+            .violations_of("cheater")
+            .map(|violation| violation.kind)
+            .collect()
+    }
+
+    #[test]
+    fn speed() {
+        let (mut state, entity) = setup();
+        let mut validator = MovementValidator::new();
+
+        // Running at a possible speed is fine...
+        let mut pos = pos_of(&state, entity);
+        for tick in 1..=30 {
+            set_time(&mut state, tick as f64 / 30.0);
+            pos.y -= 2.0;
+            assert!(!send(&mut validator, &mut state, entity, pos));
+        }
+        assert_eq!(pos_of(&state, entity), pos);
+        assert_eq!(violations(&validator), vec![]);
+
+        // ...but a faster move is clamped to one that could have been made.
+        set_time(&mut state, 31.0 / 30.0);
+        let target = pos - Vec3::unit_y() * 200.0;
+        assert!(send(&mut validator, &mut state, entity, target));
+        let moved = pos.distance(pos_of(&state, entity));
+        assert!(moved > 0.0 && moved <= max_horizontal_speed() * MAX_BUDGET as f32 + 0.01);
+        match violations(&validator).as_slice() {
+            [ViolationKind::TooFast { .. }] => {}
+            violations => panic!("Expected to be too fast, got {:?}", violations),
+        }
+    }
+
+    #[test]
+    fn batching() {
+        let (mut state, entity) = setup();
+        let mut validator = MovementValidator::new();
+
+        // Many updates that each are possible on their own don't add up to more than one could
+        // have moved in the meantime.
+        set_time(&mut state, 1.0);
+        let start = pos_of(&state, entity);
+        for i in 1..=40 {
+            let pos = start - Vec3::unit_y() * 5.0 * i as f32;
+            send(&mut validator, &mut state, entity, pos);
+        }
+        let moved = start.distance(pos_of(&state, entity));
+        assert!(moved <= max_horizontal_speed() * MAX_BUDGET as f32 + 0.01);
+        assert!(!violations(&validator).is_empty());
+    }
+
+    #[test]
+    fn noclip() {
+        let (mut state, entity) = setup();
+        let mut validator = MovementValidator::new();
+        set_time(&mut state, 1.0);
+        let start = pos_of(&state, entity);
+
+        // Into the wall...
+        let in_wall = start + Vec3::unit_x() * 24.0;
+        send(&mut validator, &mut state, entity, in_wall);
+        assert_eq!(pos_of(&state, entity), start);
+        assert_eq!(violations(&validator), vec![ViolationKind::IntoTerrain]);
+
+        // ...or beyond the loaded terrain.
+        let edge = Vec3::new(16.0, 252.0, 0.0);
+        state.write_component(entity, comp::Pos(edge));
+        set_time(&mut state, 2.0);
+        let beyond = edge + Vec3::unit_y() * 8.0;
+        send(&mut validator, &mut state, entity, beyond);
+        assert_eq!(pos_of(&state, entity), edge);
+        assert_eq!(
+            violations(&validator),
+            vec![
+                ViolationKind::IntoTerrain,
+                ViolationKind::IntoUnloadedTerrain
+            ]
+        );
+    }
+
+    #[test]
+    fn grace() {
+        let (mut state, entity) = setup();
+        let mut validator = MovementValidator::new();
+        let start = pos_of(&state, entity);
+        let in_wall = start + Vec3::unit_x() * 24.0;
+
+        // Updates from before the server moved a character are corrected without being held
+        // against the player...
+        validator.forced(entity, 1.0);
+        set_time(&mut state, 1.1);
+        assert!(send(&mut validator, &mut state, entity, in_wall));
+        assert_eq!(pos_of(&state, entity), start);
+        assert_eq!(violations(&validator), vec![]);
+
+        // ...but corrections don't excuse cheating on. That is recorded every time, while the
+        // player is only corrected every so often.
+        let mut corrections = 0;
+        for tick in 0..60 {
+            set_time(&mut state, 1.1 + FORCE_UPDATE_GRACE + tick as f64 / 30.0);
+            if send(&mut validator, &mut state, entity, in_wall) {
+                corrections += 1;
+            }
+        }
+        assert_eq!(pos_of(&state, entity), start);
+        assert_eq!(violations(&validator).len(), 60);
+        assert!(corrections > 1 && corrections <= (2.0 / CORRECTION_INTERVAL) as usize + 1);
+    }
+}
@@ -0,0 +1,96 @@
+use common::{
+    msg::{ClientMsg, ServerError, ServerMsg, PROTOCOL_VERSION},
+    net::PostBox,
+};
+use std::{
+    env, fs,
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+use veloren_server::{Event, Input, Server, ServerSettings};
+
+/// Tick the server until `client` receives a message or is disconnected, returning the number of
+/// clients that connected in the meantime.
+fn tick_until_answered(
+    server: &mut Server,
+    client: &mut PostBox<ClientMsg, ServerMsg>,
+) -> (usize, Option<ServerMsg>) {
+    let mut connected = 0;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+        let events = server
+            .tick(Input::default(), Duration::from_millis(33))
+            .unwrap();
+        server.cleanup();
+        connected += events
+            .iter()
+            .filter(|event| match event {
+                Event::ClientConnected { .. } => true,
+                _ => false,
+            })
+            .count();
+
+        if let Some(msg) = client.new_messages().next() {
+            return (connected, Some(msg));
+        }
+        if client.error().is_some() {
+            break;
+        }
+    }
+    (connected, None)
+}
+
+#[test]
+fn connecting_past_the_limit() {
+    let dir = env::temp_dir().join("veloren-server-connection-test");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 12472));
+    let settings = ServerSettings {
+        max_players: 1,
+        accounts_file: dir.join("accounts.ron"),
+        banlist_file: dir.join("banlist.ron"),
+        data_dir: dir.join("saves"),
+        ..ServerSettings::singleplayer()
+    };
+    let mut server = Server::bind(addr, settings).unwrap();
+    let connect = ClientMsg::Connect {
+        protocol_version: PROTOCOL_VERSION,
+    };
+
+    let mut first = PostBox::<ClientMsg, ServerMsg>::to(addr).unwrap();
+    first.send_message(connect.clone());
+    match tick_until_answered(&mut server, &mut first) {
+        (1, Some(ServerMsg::InitialSync { .. })) => {}
+        (connected, msg) => panic!("Expected to connect, got {} and {:?}", connected, msg),
+    }
+
+    let mut second = PostBox::<ClientMsg, ServerMsg>::to(addr).unwrap();
+    second.send_message(connect);
+    match tick_until_answered(&mut server, &mut second) {
+        (0, Some(ServerMsg::Error(ServerError::TooManyPlayers))) => {}
+        (connected, msg) => panic!(
+            "Expected to be turned away, got {} and {:?}",
+            connected, msg
+        ),
+    }
+
+    // The rejected connection is closed rather than kept around.
+    let start = Instant::now();
+    while second.error().is_none() && start.elapsed() < Duration::from_secs(5) {
+        server
+            .tick(Input::default(), Duration::from_millis(33))
+            .unwrap();
+        server.cleanup();
+        second.new_messages().for_each(drop);
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(second.error().is_some(), "The connection should be closed");
+    assert_eq!(server.status().player_count, 0);
+
+    drop(server);
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,169 @@
+use common::{
+    comp::{self, humanoid},
+    msg::{ClientMsg, ServerMsg, PROTOCOL_VERSION},
+    net::PostBox,
+    state::State,
+};
+use specs::Builder;
+use std::{
+    env, fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::Duration,
+};
+use vek::*;
+use veloren_server::{metrics::ServerMetrics, Input, Server, ServerSettings};
+
+/// Fetch the metrics page the way Prometheus does.
+fn scrape(addr: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.0\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200") || response.starts_with("HTTP/1.0 200"));
+    response
+}
+
+/// The value of the sample with exactly the given name and labels.
+fn sample(page: &str, series: &str) -> Option<f64> {
+    page.lines()
+        .filter(|line| !line.starts_with('#'))
+        .find_map(|line| {
+            let mut parts = line.rsplitn(2, ' ');
+            let value = parts.next()?;
+            match parts.next() {
+                Some(name) if name == series => value.parse().ok(),
+                _ => None,
+            }
+        })
+}
+
+#[test]
+fn scrape_on_loopback() {
+    let metrics = ServerMetrics::new(Some(SocketAddr::from(([127, 0, 0, 1], 0))));
+    let addr = metrics.addr().expect("The exporter should have started");
+
+    let mut state = State::default();
+    for _ in 0..3 {
+        state
+            .ecs_mut()
+            .create_entity()
+            .with(comp::Body::Humanoid(humanoid::Body::random()))
+            .with(comp::Agent::enemy())
+            .with(comp::Pos(Vec3::zero()))
+            .build();
+    }
+    state
+        .ecs_mut()
+        .create_entity()
+        .with(comp::LightEmitter::default())
+        .build();
+    metrics.update_entity_counts(state.ecs());
+
+    metrics.record_traffic("tcp", (1200, 34));
+    metrics.record_traffic("tcp", (800, 0));
+    for msg in &[ServerMsg::Ping, ServerMsg::Ping, ServerMsg::Disconnect] {
+        metrics.messages_sent.with_label_values(&[msg.kind()]).inc();
+    }
+    metrics.chunk_gen_time.observe(0.02);
+    metrics.client_ping.observe(0.05);
+    metrics.client_ping.observe(0.15);
+    metrics
+        .system_time
+        .with_label_values(&["phys_sys", "max"])
+        .set(12_345);
+
+    let page = scrape(addr);
+    assert_eq!(
+        sample(&page, r#"entity_count{body="humanoid",kind="npc"}"#),
+        Some(3.0)
+    );
+    assert_eq!(
+        sample(&page, r#"entity_count{body="none",kind="other"}"#),
+        Some(1.0)
+    );
+    assert_eq!(sample(&page, "light_count"), Some(1.0));
+    assert_eq!(
+        sample(&page, r#"network_bytes{direction="out",protocol="tcp"}"#),
+        Some(2000.0)
+    );
+    assert_eq!(
+        sample(&page, r#"network_bytes{direction="in",protocol="tcp"}"#),
+        Some(34.0)
+    );
+    assert_eq!(sample(&page, r#"messages_sent{kind="ping"}"#), Some(2.0));
+    assert_eq!(
+        sample(&page, r#"messages_sent{kind="disconnect"}"#),
+        Some(1.0)
+    );
+    assert_eq!(sample(&page, "chunk_generation_seconds_count"), Some(1.0));
+    assert_eq!(sample(&page, "client_ping_seconds_count"), Some(2.0));
+    assert_eq!(
+        sample(&page, r#"system_time{stat="max",system="phys_sys"}"#),
+        Some(12345.0)
+    );
+}
+
+#[test]
+fn disabled_exporter_still_records() {
+    let metrics = ServerMetrics::new(None);
+    assert_eq!(metrics.addr(), None);
+    metrics.record_traffic("udp", (10, 20));
+    assert_eq!(
+        sample(
+            &metrics.encode(),
+            r#"network_bytes{direction="in",protocol="udp"}"#
+        ),
+        Some(20.0)
+    );
+}
+
+#[test]
+fn server_records_metrics() {
+    let dir = env::temp_dir().join("veloren-server-metrics-test");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 12473));
+    let settings = ServerSettings {
+        metrics_enabled: true,
+        metrics_address: SocketAddr::from(([127, 0, 0, 1], 0)),
+        accounts_file: dir.join("accounts.ron"),
+        banlist_file: dir.join("banlist.ron"),
+        data_dir: dir.join("saves"),
+        ..ServerSettings::singleplayer()
+    };
+    let mut server = Server::bind(addr, settings).unwrap();
+    let metrics_addr = server
+        .metrics_addr()
+        .expect("The exporter should have started");
+
+    let mut client = PostBox::<ClientMsg, ServerMsg>::to(addr).unwrap();
+    client.send_message(ClientMsg::Connect {
+        protocol_version: PROTOCOL_VERSION,
+    });
+    for _ in 0..20 {
+        thread::sleep(Duration::from_millis(10));
+        server
+            .tick(Input::default(), Duration::from_millis(33))
+            .unwrap();
+        server.cleanup();
+        client.new_messages().for_each(drop);
+    }
+
+    let page = scrape(metrics_addr);
+    let recorded = |series| sample(&page, series).map_or(false, |value| value > 0.0);
+    assert!(recorded(r#"system_time{stat="max",system="phys_sys"}"#));
+    assert!(recorded(r#"messages_sent{kind="initial_sync"}"#));
+    assert!(recorded(r#"network_bytes{direction="out",protocol="tcp"}"#));
+    assert!(recorded(r#"network_bytes{direction="in",protocol="tcp"}"#));
+
+    drop(server);
+    let _ = fs::remove_dir_all(&dir);
+}
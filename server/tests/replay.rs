@@ -0,0 +1,108 @@
+use common::{
+    comp::{self, humanoid, item::Tool},
+    msg::{ClientMsg, ServerMsg, PROTOCOL_VERSION},
+    net::PostBox,
+    ChatType,
+};
+use std::{env, fs, net::SocketAddr, thread, time::Duration};
+use vek::*;
+use veloren_server::{
+    replay::{Outcome, Replay},
+    Input, Server, ServerSettings,
+};
+
+const TICKS: u64 = 60;
+
+/// What the test client sends before the given tick.
+fn client_input(tick: u64) -> Option<ClientMsg> {
+    match tick {
+        0 => Some(ClientMsg::Connect {
+            protocol_version: PROTOCOL_VERSION,
+        }),
+        // An admin, so that it may spawn NPCs.
+        10 => Some(ClientMsg::Register {
+            player: comp::Player::new("singleplayer".to_owned(), Some(2)),
+            password: "secret".to_owned(),
+        }),
+        20 => Some(ClientMsg::Character {
+            name: "tester".to_owned(),
+            body: comp::Body::Humanoid(humanoid::Body::random()),
+            main: Some(Tool::Sword),
+        }),
+        // The chunk around the spawn point, which also spawns NPCs.
+        30 => Some(ClientMsg::TerrainChunkRequest {
+            key: Vec2::new(512, 512),
+        }),
+        35 => Some(ClientMsg::ChatMsg {
+            chat_type: ChatType::Broadcast,
+            message: "/spawn hostile wolf 3".to_owned(),
+        }),
+        36 => Some(ClientMsg::ChatMsg {
+            chat_type: ChatType::Broadcast,
+            message: "/register newcomer hunter2".to_owned(),
+        }),
+        tick if tick > 30 => Some(ClientMsg::Controller {
+            controller: comp::Controller {
+                move_dir: Vec2::unit_x(),
+                primary: tick % 5 == 0,
+                ..comp::Controller::default()
+            },
+            seq: tick,
+        }),
+        _ => None,
+    }
+}
+
+#[test]
+fn replay_reproduces_recording() {
+    let dir = env::temp_dir().join("veloren-server-replay-test");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let recording = dir.join("session.rec");
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 12471));
+    let settings = ServerSettings {
+        accounts_file: dir.join("accounts.ron"),
+        banlist_file: dir.join("banlist.ron"),
+        data_dir: dir.join("saves"),
+        ..ServerSettings::singleplayer()
+    };
+    let mut server = Server::bind(addr, settings)
+        .unwrap()
+        .with_recording(&recording)
+        .unwrap();
+    let mut client = PostBox::<ClientMsg, ServerMsg>::to(addr).unwrap();
+    for tick in 0..TICKS {
+        if let Some(msg) = client_input(tick) {
+            client.send_message(msg);
+        }
+        // Give the messages time to arrive, the recording keeps whatever the server saw.
+        thread::sleep(Duration::from_millis(10));
+        server
+            .tick(Input::default(), Duration::from_millis(33))
+            .unwrap();
+        server.cleanup();
+        client.new_messages().for_each(drop);
+    }
+    drop(server);
+    drop(client);
+
+    // Passwords are left out of the recording.
+    let recorded = fs::read(&recording).unwrap();
+    for password in &["secret", "hunter2"] {
+        assert!(
+            !recorded
+                .windows(password.len())
+                .any(|window| window == password.as_bytes()),
+            "The recording contains the password '{}'",
+            password
+        );
+    }
+
+    let outcome = Replay::open(&recording)
+        .unwrap()
+        .run(dir.join("replay"))
+        .unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(outcome, Outcome::Finished { ticks: TICKS });
+}
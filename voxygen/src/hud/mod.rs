@@ -39,7 +39,7 @@ use crate::{
     GlobalState,
 };
 use client::{Client, Event as ClientEvent};
-use common::{comp, terrain::TerrainChunk, vol::RectRasterableVol};
+use common::{comp, sys::profile::SysStats, terrain::TerrainChunk, vol::RectRasterableVol};
 use conrod_core::{
     text::cursor::Index,
     widget::{self, Button, Image, Rectangle, Text},
@@ -96,6 +96,7 @@ widget_ids! {
         velocity,
         loaded_distance,
         time,
+        sys_stats[],
 
         // Game Version
         version,
@@ -145,6 +146,8 @@ pub struct DebugInfo {
     pub ping_ms: f64,
     pub coordinates: Option<comp::Pos>,
     pub velocity: Option<comp::Vel>,
+    /// How long each ECS system took recently, by system name.
+    pub sys_stats: Vec<(&'static str, SysStats)>,
 }
 
 pub enum Event {
@@ -645,6 +648,31 @@ impl Hud {
             .font_id(self.fonts.opensans)
             .font_size(14)
             .set(self.ids.time, ui_widgets);
+            // ECS systems
+            if self.ids.sys_stats.len() < debug_info.sys_stats.len() {
+                self.ids.sys_stats.resize(
+                    debug_info.sys_stats.len(),
+                    &mut ui_widgets.widget_id_generator(),
+                );
+            }
+            let ms = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+            let mut above = self.ids.time;
+            for ((system, stats), id) in debug_info.sys_stats.iter().zip(self.ids.sys_stats.iter())
+            {
+                Text::new(&format!(
+                    "{}: {:.2}ms (mean {:.2}ms, max {:.2}ms)",
+                    system,
+                    ms(stats.last),
+                    ms(stats.mean),
+                    ms(stats.max)
+                ))
+                .color(TEXT_COLOR)
+                .down_from(above, 5.0)
+                .font_id(self.fonts.opensans)
+                .font_size(14)
+                .set(*id, ui_widgets);
+                above = *id;
+            }
         }
 
         // Add Bag-Space Button.
@@ -923,6 +951,11 @@ impl Hud {
         events
     }
 
+    /// Whether the debug info is shown.
+    pub fn shows_debug_info(&self) -> bool {
+        self.show.debug
+    }
+
     pub fn new_message(&mut self, msg: ClientEvent) {
         self.new_messages.push_back(msg);
     }
@@ -45,6 +45,9 @@ pub struct GlobalState {
     settings: Settings,
     window: Window,
     audio: AudioFrontend,
+    /// A message for the main menu to show when the player returns to it, e.g. why they were
+    /// disconnected.
+    info_message: Option<String>,
 }
 
 impl GlobalState {
@@ -118,6 +121,7 @@ fn main() {
         audio,
         window: Window::new(&settings).expect("Failed to create window!"),
         settings,
+        info_message: None,
     };
     let settings = &global_state.settings;
 
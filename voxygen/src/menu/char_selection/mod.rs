@@ -116,6 +116,9 @@ impl PlayState for CharSelectionState {
                 .tick(comp::Controller::default(), clock.get_last_delta())
             {
                 error!("Failed to tick the scene: {:?}", err);
+                if let client::Error::ServerShutdown { reason } = err {
+                    global_state.info_message = Some(format!("The server shut down: {}", reason));
+                }
                 return PlayStateResult::Pop;
             }
             self.client.borrow_mut().cleanup();
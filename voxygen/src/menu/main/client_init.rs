@@ -16,6 +16,10 @@ pub enum Error {
     // Parsing/host name resolution successful but could not connect.
     ConnectionFailed(ClientError),
     InvalidAuth,
+    InvalidToken { expired: bool },
+    Banned { reason: String, until: Option<u64> },
+    NotWhitelisted,
+    IncompatibleVersion { server: u32, client: u32 },
     ClientCrashed,
     ServerIsFull,
 }
@@ -58,11 +62,24 @@ impl ClientInit {
                     for socket_addr in first_addrs.into_iter().chain(second_addrs) {
                         match Client::new(socket_addr, player.view_distance) {
                             Ok(mut client) => {
-                                if let Err(ClientError::InvalidAuth) =
-                                    client.register(player, password)
-                                {
-                                    last_err = Some(Error::InvalidAuth);
-                                    break;
+                                match client.register(player, password) {
+                                    Err(ClientError::InvalidAuth) => {
+                                        last_err = Some(Error::InvalidAuth);
+                                        break;
+                                    }
+                                    Err(ClientError::InvalidToken { expired }) => {
+                                        last_err = Some(Error::InvalidToken { expired });
+                                        break;
+                                    }
+                                    Err(ClientError::Banned { reason, until }) => {
+                                        last_err = Some(Error::Banned { reason, until });
+                                        break;
+                                    }
+                                    Err(ClientError::NotWhitelisted) => {
+                                        last_err = Some(Error::NotWhitelisted);
+                                        break;
+                                    }
+                                    _ => {}
                                 }
                                 //client.register(player, password);
                                 let _ = tx.send(Ok(client));
@@ -89,6 +106,11 @@ impl ClientInit {
                                         last_err = Some(Error::ServerIsFull);
                                         break;
                                     }
+                                    ClientError::IncompatibleVersion { server, client } => {
+                                        last_err =
+                                            Some(Error::IncompatibleVersion { server, client });
+                                        break;
+                                    }
                                     ClientError::InvalidAuth => {
                                         last_err = Some(Error::InvalidAuth);
                                     }
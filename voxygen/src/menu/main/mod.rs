@@ -4,6 +4,7 @@ mod ui;
 
 use super::char_selection::CharSelectionState;
 use crate::{window::Event, Direction, GlobalState, PlayState, PlayStateResult};
+use chrono::NaiveDateTime;
 use client_init::{ClientInit, Error as InitError};
 use common::{clock::Clock, comp};
 use log::warn;
@@ -34,6 +35,11 @@ impl PlayState for MainMenuState {
         // Used for client creation.
         let mut client_init: Option<ClientInit> = None;
 
+        // Tell the player why they were sent back here, if there is a reason.
+        if let Some(msg) = global_state.info_message.take() {
+            self.main_menu_ui.login_error(msg);
+        }
+
         loop {
             // Handle window events.
             for event in global_state.window.fetch_events() {
@@ -61,16 +67,42 @@ impl PlayState for MainMenuState {
                 }
                 Some(Err(err)) => {
                     client_init = None;
-                    self.main_menu_ui.login_error(
-                        match err {
-                            InitError::BadAddress(_) | InitError::NoAddress => "Server not found",
-                            InitError::InvalidAuth => "Invalid credentials",
-                            InitError::ServerIsFull => "Server is Full!",
-                            InitError::ConnectionFailed(_) => "Connection failed",
-                            InitError::ClientCrashed => "Client crashed",
+                    self.main_menu_ui.login_error(match err {
+                        InitError::BadAddress(_) | InitError::NoAddress => {
+                            "Server not found".to_string()
+                        }
+                        InitError::InvalidAuth => "Invalid credentials".to_string(),
+                        InitError::InvalidToken { expired: true } => {
+                            "Login token expired".to_string()
+                        }
+                        InitError::InvalidToken { expired: false } => {
+                            "Invalid login token".to_string()
+                        }
+                        InitError::Banned {
+                            reason,
+                            until: Some(until),
+                        } => format!(
+                            "Banned until {}: {}",
+                            NaiveDateTime::from_timestamp(until as i64, 0)
+                                .format("%Y-%m-%d %H:%M UTC"),
+                            reason
+                        ),
+                        InitError::Banned {
+                            reason,
+                            until: None,
+                        } => format!("Banned: {}", reason),
+                        InitError::NotWhitelisted => {
+                            "You are not on this server's whitelist".to_string()
                         }
-                        .to_string(),
-                    );
+                        InitError::IncompatibleVersion { server, client } => format!(
+                            "Incompatible version: the server uses protocol version {}, \
+                             but this client uses version {}",
+                            server, client
+                        ),
+                        InitError::ServerIsFull => "Server is Full!".to_string(),
+                        InitError::ConnectionFailed(_) => "Connection failed".to_string(),
+                        InitError::ClientCrashed => "Client crashed".to_string(),
+                    });
                 }
                 None => {}
             }
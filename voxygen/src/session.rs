@@ -320,12 +320,21 @@ impl PlayState for SessionState {
             // Perform an in-game tick.
             if let Err(err) = self.tick(clock.get_avg_delta()) {
                 error!("Failed to tick the scene: {:?}", err);
+                if let Error::ClientError(client::Error::ServerShutdown { reason }) = err {
+                    global_state.info_message = Some(format!("The server shut down: {}", reason));
+                }
                 return PlayStateResult::Pop;
             }
 
             // Maintain global state.
             global_state.maintain(clock.get_last_delta().as_secs_f32());
 
+            // Only profile the ECS systems while the debug info shows how long they take.
+            self.client
+                .borrow_mut()
+                .state_mut()
+                .set_profiling(self.hud.shows_debug_info());
+
             // Extract HUD events ensuring the client borrow gets dropped.
             let hud_events = self.hud.maintain(
                 &self.client.borrow(),
@@ -349,6 +358,7 @@ impl PlayState for SessionState {
                         .read_storage::<Vel>()
                         .get(self.client.borrow().entity())
                         .cloned(),
+                    sys_stats: self.client.borrow().state().sys_stats(),
                 },
                 &self.scene.camera(),
             );
@@ -79,7 +79,7 @@ fn run_server(mut server: Server, rec: Receiver<Msg>) {
         ]);
     }
 
-    loop {
+    'running: loop {
         let events = server
             .tick(Input::default(), clock.get_last_delta())
             .expect("Failed to tick server!");
@@ -89,6 +89,8 @@ fn run_server(mut server: Server, rec: Receiver<Msg>) {
                 Event::ClientConnected { .. } => info!("Client connected!"),
                 Event::ClientDisconnected { .. } => info!("Client disconnected!"),
                 Event::Chat { entity: _, msg } => info!("[Client] {}", msg),
+                Event::ConsoleOutput { msg } => info!("[Server] {}", msg),
+                Event::Shutdown => break 'running,
             }
         }
 
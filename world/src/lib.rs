@@ -27,7 +27,7 @@ use common::{
     terrain::{Block, BlockKind, TerrainChunk, TerrainChunkMeta, TerrainChunkSize},
     vol::{ReadVol, RectVolSize, Vox, WriteVol},
 };
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use std::time::Duration;
 use vek::*;
 
@@ -126,9 +126,15 @@ impl World {
             }
         }
 
-        let gen_entity_pos = || {
-            let lpos2d = TerrainChunkSize::RECT_SIZE
-                .map(|sz| rand::thread_rng().gen::<u32>().rem_euclid(sz));
+        // Seeded by the world seed and the chunk's position, so that a chunk gets the same NPCs
+        // every time it is generated.
+        let mut rng = StdRng::seed_from_u64(
+            u64::from(self.sim.seed) << 32
+                | u64::from(chunk_pos.x as u16) << 16
+                | u64::from(chunk_pos.y as u16),
+        );
+        let gen_entity_pos = |rng: &mut StdRng| {
+            let lpos2d = TerrainChunkSize::RECT_SIZE.map(|sz| rng.gen::<u32>().rem_euclid(sz));
             let mut lpos = Vec3::new(lpos2d.x as i32, lpos2d.y as i32, 0);
 
             while chunk.get(lpos).map(|vox| !vox.is_empty()).unwrap_or(false) {
@@ -141,10 +147,10 @@ impl World {
         const SPAWN_RATE: f32 = 0.1;
         const BOSS_RATE: f32 = 0.03;
         let supplement = ChunkSupplement {
-            npcs: if rand::thread_rng().gen::<f32>() < SPAWN_RATE && sim_chunk.chaos < 0.5 {
+            npcs: if rng.gen::<f32>() < SPAWN_RATE && sim_chunk.chaos < 0.5 {
                 vec![NpcInfo {
-                    pos: gen_entity_pos(),
-                    boss: rand::thread_rng().gen::<f32>() < BOSS_RATE,
+                    pos: gen_entity_pos(&mut rng),
+                    boss: rng.gen::<f32>() < BOSS_RATE,
                 }]
             } else {
                 Vec::new()